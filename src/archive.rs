@@ -0,0 +1,239 @@
+//! Support for reading merger runs out of a tar archive, which may be
+//! gzip compressed. The members of an archive are indexed once, and are
+//! extracted on demand to temporary files.
+use color_eyre::eyre::{eyre, Result};
+use flate2::read::GzDecoder;
+use rustc_hash::FxHashMap;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tar::{Archive, Entry};
+
+/// Counter used to give each extracted file a unique name
+static EXTRACTED_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// A temporary file which is removed when it is dropped.
+#[derive(Debug)]
+pub struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        // Nothing useful can be done if this fails, and it is only scratch
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// The index of each archive, built the first time the archive is read
+static INDEXES: Mutex<Vec<(PathBuf, Arc<ArchiveIndex>)>> = Mutex::new(Vec::new());
+
+/// Whether an archive is gzip compressed, from its extension
+fn is_compressed(archive_path: &Path) -> bool {
+    matches!(
+        archive_path.extension().and_then(|ext| ext.to_str()),
+        Some("gz") | Some("tgz")
+    )
+}
+
+/// Open the (decompressed) contents of an archive for reading. Archives with
+/// a .gz or .tgz extension are decompressed.
+fn open_archive(archive_path: &Path) -> Result<Box<dyn Read>> {
+    let file = BufReader::new(std::fs::File::open(archive_path)?);
+    if is_compressed(archive_path) {
+        Ok(Box::new(GzDecoder::new(file)))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+/// Get the file name of an archive member, ignoring any directories
+fn member_file_name<R: Read>(entry: &Entry<'_, R>) -> Result<Option<String>> {
+    Ok(entry
+        .path()?
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned()))
+}
+
+/// Where the data of an archive member is, in the decompressed archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Member {
+    offset: u64,
+    size: u64,
+}
+
+/// The members of an archive, keyed by file name. Building it reads through
+/// the archive once; afterwards members are extracted from their offset.
+#[derive(Debug)]
+struct ArchiveIndex {
+    members: FxHashMap<String, Member>,
+}
+
+impl ArchiveIndex {
+    fn build(archive_path: &Path) -> Result<Self> {
+        let mut members = FxHashMap::default();
+        let mut archive = Archive::new(open_archive(archive_path)?);
+        for entry in archive.entries()? {
+            let entry = entry?;
+            if let Some(name) = member_file_name(&entry)? {
+                let member = Member {
+                    offset: entry.raw_file_position(),
+                    size: entry.size(),
+                };
+                members.insert(name, member);
+            }
+        }
+        Ok(Self { members })
+    }
+
+    /// Get the index of an archive, building it if it has not been built yet
+    fn get(archive_path: &Path) -> Result<Arc<Self>> {
+        // Held while building, so that the archive is only indexed once
+        let mut indexes = INDEXES.lock().unwrap();
+        if let Some((_, index)) = indexes.iter().find(|(path, _)| path == archive_path) {
+            return Ok(index.clone());
+        }
+        let index = Arc::new(Self::build(archive_path)?);
+        indexes.push((archive_path.to_path_buf(), index.clone()));
+        Ok(index)
+    }
+}
+
+/// Get the size (in bytes) of every member of the archive, keyed by file name.
+pub fn member_sizes(archive_path: &Path) -> Result<FxHashMap<String, u64>> {
+    Ok(ArchiveIndex::get(archive_path)?
+        .members
+        .iter()
+        .map(|(name, member)| (name.clone(), member.size))
+        .collect())
+}
+
+/// Extract the archive member with the given file name to a temporary file
+/// in the temp directory. Returns None if there is no such member.
+///
+/// Members are found with the index of the archive. An uncompressed archive
+/// is read only at the member; a compressed one has to be decompressed up to
+/// the member, as gzip streams cannot be seeked.
+pub fn extract_member(
+    archive_path: &Path,
+    file_name: &str,
+    temp_dir: &Path,
+) -> Result<Option<TempFile>> {
+    let Some(member) = ArchiveIndex::get(archive_path)?
+        .members
+        .get(file_name)
+        .copied()
+    else {
+        return Ok(None);
+    };
+    let reader = if is_compressed(archive_path) {
+        let mut reader = open_archive(archive_path)?;
+        std::io::copy(
+            &mut reader.by_ref().take(member.offset),
+            &mut std::io::sink(),
+        )?;
+        reader
+    } else {
+        let mut file = std::fs::File::open(archive_path)?;
+        file.seek(SeekFrom::Start(member.offset))?;
+        Box::new(BufReader::new(file))
+    };
+    let temp = TempFile {
+        path: temp_dir.join(format!(
+            "harmonizer_{}_{}_{file_name}",
+            std::process::id(),
+            EXTRACTED_COUNT.fetch_add(1, Ordering::Relaxed)
+        )),
+    };
+    let mut temp_file = std::fs::File::create(temp.path())?;
+    let copied = std::io::copy(&mut reader.take(member.size), &mut temp_file)?;
+    if copied != member.size {
+        return Err(eyre!(
+            "Archive {} ended inside of member {file_name}",
+            archive_path.display()
+        ));
+    }
+    Ok(Some(temp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ScratchDir;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    /// The members written to the test archives, the first in a directory
+    const MEMBERS: [(&str, &[u8]); 3] = [
+        ("runs/run_0001.h5", b"first run"),
+        ("run_0002.h5", b"the second run, which is longer"),
+        ("notes.txt", b""),
+    ];
+
+    /// Write a tar archive of the members, gzip compressed if the path says so
+    fn write_archive(path: &Path) {
+        let file = std::fs::File::create(path).unwrap();
+        let writer: Box<dyn std::io::Write> = if is_compressed(path) {
+            Box::new(GzEncoder::new(file, Compression::default()))
+        } else {
+            Box::new(file)
+        };
+        let mut builder = tar::Builder::new(writer);
+        for (name, data) in MEMBERS {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, data).unwrap();
+        }
+        builder.into_inner().unwrap().flush().unwrap();
+    }
+
+    fn check_extraction(name: &str) {
+        let dir = ScratchDir::new("archive");
+        let path = dir.path().join(name);
+        write_archive(&path);
+
+        let sizes = member_sizes(&path).unwrap();
+        assert_eq!(sizes.len(), MEMBERS.len());
+        // Extracted in reverse, so that members are not just read in order
+        for (member, data) in MEMBERS.iter().rev() {
+            let file_name = Path::new(member).file_name().unwrap().to_str().unwrap();
+            assert_eq!(sizes[file_name], data.len() as u64);
+            let extracted = extract_member(&path, file_name, dir.path())
+                .unwrap()
+                .unwrap();
+            assert_eq!(&std::fs::read(extracted.path()).unwrap(), data);
+        }
+        assert!(extract_member(&path, "run_0003.h5", dir.path())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn members_are_extracted_from_a_tar() {
+        check_extraction("runs.tar");
+    }
+
+    #[test]
+    fn members_are_extracted_from_a_compressed_tar() {
+        check_extraction("runs.tar.gz");
+    }
+
+    #[test]
+    fn archives_are_indexed_once() {
+        let dir = ScratchDir::new("archive_index");
+        let path = dir.path().join("runs.tar");
+        write_archive(&path);
+        let index = ArchiveIndex::get(&path).unwrap();
+        assert!(Arc::ptr_eq(&index, &ArchiveIndex::get(&path).unwrap()));
+    }
+}
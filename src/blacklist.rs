@@ -0,0 +1,103 @@
+//! A blacklist of individual merger events to exclude from harmonization,
+//! such as bad events found in a QA pass.
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use rustc_hash::FxHashSet;
+use std::path::Path;
+
+/// Pack a (run, event) pair into a single key. Event numbers must fit in
+/// 32 bits, which is checked when the blacklist is read.
+fn pack_event(run: i32, event: u64) -> u64 {
+    ((run as u32 as u64) << 32) | (event & 0xffff_ffff)
+}
+
+/// A set of (run, event) pairs
+#[derive(Debug, Clone, Default)]
+pub struct Blacklist {
+    events: FxHashSet<u64>,
+}
+
+impl Blacklist {
+    /// Read a blacklist from a CSV file of run,event lines. Blank lines and
+    /// lines starting with # are ignored, as is a header in the first line.
+    pub fn read(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Could not read blacklist {}", path.display()))?;
+        let mut events = FxHashSet::default();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parsed = line.split_once(',').and_then(|(run, event)| {
+                Some((
+                    run.trim().parse::<i32>().ok()?,
+                    event.trim().parse::<u64>().ok()?,
+                ))
+            });
+            let (run, event) = match parsed {
+                Some(pair) => pair,
+                None if index == 0 => continue,
+                None => {
+                    return Err(eyre!(
+                        "Line {} of blacklist {} is not a run,event pair: {line}",
+                        index + 1,
+                        path.display()
+                    ))
+                }
+            };
+            if event > u32::MAX as u64 {
+                return Err(eyre!(
+                    "Event {event} of run {run} in blacklist {} is too large",
+                    path.display()
+                ));
+            }
+            events.insert(pack_event(run, event));
+        }
+        Ok(Self { events })
+    }
+
+    /// Whether the event of the run is blacklisted
+    pub fn contains(&self, run: i32, event: u64) -> bool {
+        event <= u32::MAX as u64 && self.events.contains(&pack_event(run, event))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ScratchDir;
+
+    fn read_blacklist(text: &str) -> Result<Blacklist> {
+        let dir = ScratchDir::new("blacklist");
+        let path = dir.path().join("blacklist.csv");
+        std::fs::write(&path, text).unwrap();
+        Blacklist::read(&path)
+    }
+
+    #[test]
+    fn blacklist_skips_the_header_and_comments() {
+        let blacklist = read_blacklist("run,event\n# from QA\n\n55, 3\n55,7\n-1,2\n").unwrap();
+        assert!(blacklist.contains(55, 3));
+        assert!(blacklist.contains(55, 7));
+        assert!(blacklist.contains(-1, 2));
+        assert!(!blacklist.contains(55, 4));
+        assert!(!blacklist.contains(56, 3));
+    }
+
+    #[test]
+    fn packed_events_do_not_collide() {
+        let blacklist = read_blacklist("1,4294967295\n").unwrap();
+        assert!(blacklist.contains(1, u32::MAX as u64));
+        assert!(!blacklist.contains(2, 0));
+        // Events past 32 bits cannot be blacklisted, so they never match
+        assert!(!blacklist.contains(1, u32::MAX as u64 * 2 + 1));
+    }
+
+    #[test]
+    fn malformed_blacklists_are_rejected() {
+        let error = read_blacklist("55,3\n55;4\n").unwrap_err().to_string();
+        assert!(error.contains("Line 2"), "{error}");
+        let error = read_blacklist("55,4294967296\n").unwrap_err().to_string();
+        assert!(error.contains("too large"), "{error}");
+    }
+}
@@ -0,0 +1,215 @@
+//! Combination of harmonic datasets. The events of harmonic data are read
+//! back and written again as a single dataset, renumbered continuously, so
+//! that separately harmonized run ranges can be joined without re-reading
+//! the merger data.
+use super::config::Config;
+use super::metadata::{
+    EventMetadata, HAS_FRIB, HAS_FRIB_OK, HAS_GET, HAS_GET_ID, HAS_GET_OK, HAS_GET_TIMESTAMP_OTHER,
+};
+use super::reader::{
+    read_get_traces, read_quality_flag, Coincidence, FribEvent, GetEvent, MergerEvent,
+};
+use super::stacked::{is_stacked, read_stacked_event};
+use super::stats::HarmonizeStats;
+use super::validate::find_harmonic_files;
+use super::writer::HarmonicWriter;
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use hdf5_metno::{File, Group, H5Type, Location};
+use ndarray::{Array1, Array2};
+use polars::prelude::*;
+use std::path::{Path, PathBuf};
+
+/// The name of the combined scalers file (parquet format)
+const SCALERS_NAME: &str = "scalers.parquet";
+
+/// Read an attribute if it exists
+fn read_attr_if_present<T: H5Type>(location: &Location, name: &str) -> Result<Option<T>> {
+    if !location.attr_names()?.iter().any(|attr| attr == name) {
+        return Ok(None);
+    }
+    Ok(Some(location.attr(name)?.read_scalar()?))
+}
+
+/// Rebuild the 2-D GET traces of the per_channel trace layout from the
+/// channel_# datasets of the get_traces group
+fn read_channel_traces(group: &Group) -> Result<Array2<i16>> {
+    let n_channels = group.attr("n_channels")?.read_scalar::<u64>()?;
+    let channels = (0..n_channels)
+        .map(|channel| {
+            Ok(group
+                .dataset(&format!("channel_{channel}"))?
+                .read_1d::<i16>()?)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let width = channels.first().map_or(0, |trace| trace.len());
+    let mut traces = Array2::zeros((channels.len(), width));
+    for (mut row, trace) in traces.outer_iter_mut().zip(channels) {
+        if trace.len() != width {
+            return Err(eyre!(
+                "The channels of {} have differing widths",
+                group.name()
+            ));
+        }
+        row.assign(&trace);
+    }
+    Ok(traces)
+}
+
+/// Assemble the GET data of a harmonic event from its traces and the
+/// attributes of the traces (dataset or group), or the packed metadata
+fn read_get_event(
+    traces: Array2<i16>,
+    location: &Location,
+    packed: Option<EventMetadata>,
+) -> Result<GetEvent> {
+    Ok(match packed {
+        Some(metadata) => GetEvent {
+            traces,
+            id: (metadata.flags & HAS_GET_ID != 0).then_some(metadata.get_id),
+            timestamp: metadata.get_timestamp,
+            timestamp_other: (metadata.flags & HAS_GET_TIMESTAMP_OTHER != 0)
+                .then_some(metadata.get_timestamp_other),
+            ok: (metadata.flags & HAS_GET_OK != 0).then_some(metadata.get_ok),
+        },
+        None => GetEvent {
+            traces,
+            id: read_attr_if_present(location, "id")?,
+            timestamp: location.attr("timestamp")?.read_scalar()?,
+            timestamp_other: read_attr_if_present(location, "timestamp_other")?,
+            ok: read_quality_flag(location)?,
+        },
+    })
+}
+
+/// Read an event back from a harmonic file, with the original run and event
+/// number it was harmonized from. Both metadata layouts are understood, and
+/// transposed or per channel traces are returned to the usual 2-D array.
+pub fn read_harmonic_event(event_group: &Group) -> Result<MergerEvent> {
+    let packed = EventMetadata::read(event_group)?;
+    let has = |flag: u8| packed.map(|metadata| metadata.flags & flag != 0);
+
+    let mut maybe_get = None;
+    if has(HAS_GET).unwrap_or(true) {
+        if let Ok(get_group) = event_group.group("get_traces") {
+            let traces = read_channel_traces(&get_group)?;
+            maybe_get = Some(read_get_event(traces, &get_group, packed)?);
+        } else if let Ok(get_data) = event_group.dataset("get_traces") {
+            let traces = read_get_traces(&get_data)?;
+            maybe_get = Some(read_get_event(traces, &get_data, packed)?);
+        }
+    }
+
+    let mut maybe_frib = None;
+    if has(HAS_FRIB).unwrap_or(true) {
+        if let Ok(frib_group) = event_group.group("frib_physics") {
+            // The coincidence data is absent if it was excluded
+            let coincidence = match frib_group.dataset("977") {
+                Ok(frib_977) => Coincidence::read(&frib_977)?,
+                Err(_) => Coincidence::U8(Array1::zeros(0)),
+            };
+            let (event, timestamp, ok) = match packed {
+                Some(metadata) => (
+                    metadata.frib_event,
+                    metadata.frib_timestamp,
+                    (metadata.flags & HAS_FRIB_OK != 0).then_some(metadata.frib_ok),
+                ),
+                None => (
+                    frib_group.attr("event")?.read_scalar()?,
+                    frib_group.attr("timestamp")?.read_scalar()?,
+                    read_quality_flag(&frib_group)?,
+                ),
+            };
+            maybe_frib = Some(FribEvent {
+                traces: frib_group.dataset("1903")?.read_2d()?,
+                coincidence,
+                event,
+                timestamp,
+                ok,
+            });
+        }
+    }
+
+    let (run_number, event) = match packed {
+        Some(metadata) => (metadata.orig_run, metadata.orig_event),
+        None => (
+            event_group.attr("orig_run")?.read_scalar()?,
+            event_group.attr("orig_event")?.read_scalar()?,
+        ),
+    };
+    Ok(MergerEvent {
+        get: maybe_get,
+        frib: maybe_frib,
+        run_number,
+        event,
+    })
+}
+
+/// Read the event with the given index back from the events group of a
+/// harmonic file, whether its events are stored in groups or stacked
+pub fn read_harmonic_event_at(events_group: &Group, index: u64) -> Result<MergerEvent> {
+    if is_stacked(events_group)? {
+        return read_stacked_event(events_group, index);
+    }
+    read_harmonic_event(&events_group.group(&format!("event_{index}"))?)
+}
+
+/// Write all of the events of a harmonic file
+fn combine_harmonic_file(path: &Path, writer: &mut HarmonicWriter) -> Result<()> {
+    let events_group = File::open(path)?.group("events")?;
+    let n_events = events_group.attr("max_event")?.read_scalar::<u64>()?;
+    for index in 0..n_events {
+        writer.write(read_harmonic_event_at(&events_group, index)?)?;
+    }
+    Ok(())
+}
+
+/// Stack the parquet scalers of the harmonic datasets, in order, into the
+/// output directory. Datasets without parquet scalers contribute none.
+fn combine_scalers(inputs: &[PathBuf], output: &Path) -> Result<()> {
+    let mut combined: Option<DataFrame> = None;
+    for input in inputs {
+        let path = input.join(SCALERS_NAME);
+        if !path.exists() {
+            println!(
+                "Warning: {} has no {SCALERS_NAME}, its scalers are not combined",
+                input.display()
+            );
+            continue;
+        }
+        let frame = ParquetReader::new(std::fs::File::open(&path)?).finish()?;
+        match combined.as_mut() {
+            Some(combined) => {
+                combined
+                    .vstack_mut(&frame)
+                    .wrap_err_with(|| format!("Incompatible scalers in {}", path.display()))?;
+            }
+            None => combined = Some(frame),
+        }
+    }
+    if let Some(mut frame) = combined {
+        let mut parquet_file = std::fs::File::create(output.join(SCALERS_NAME))?;
+        ParquetWriter::new(&mut parquet_file).finish(&mut frame)?;
+    }
+    Ok(())
+}
+
+/// Combine harmonic datasets into a single dataset in the harmonic path of
+/// the config, which is written with the writer settings of the config.
+/// Events are written in the order of the inputs and of their harmonic runs.
+pub fn combine_harmonic_data(config: &Config, inputs: &[PathBuf]) -> Result<HarmonizeStats> {
+    if inputs.iter().any(|input| *input == config.harmonic_path) {
+        return Err(eyre!(
+            "The combined output cannot be written into one of its inputs"
+        ));
+    }
+    let mut writer = HarmonicWriter::new(config)?;
+    for input in inputs {
+        for path in find_harmonic_files(input)? {
+            combine_harmonic_file(&path, &mut writer)
+                .wrap_err_with(|| format!("Failed to combine {}", path.display()))?;
+        }
+    }
+    let stats = writer.close()?;
+    combine_scalers(inputs, &config.harmonic_path)?;
+    Ok(stats)
+}
@@ -0,0 +1,87 @@
+//! A comparison of the compression settings of the harmonic output. A sample
+//! of the events is harmonized under each setting, and the resulting size and
+//! write time are reported, to help pick the best tradeoff.
+use super::config::{CompressionSetting, Config, OutputLayout, OverwritePolicy};
+use super::reader::MergerReader;
+use super::writer::HarmonicWriter;
+use color_eyre::eyre::Result;
+use std::time::{Duration, Instant};
+
+/// The outcome of harmonizing the sample under one compression setting
+#[derive(Debug, Clone)]
+pub struct CompressionResult {
+    pub events: u64,
+    pub bytes: u64,
+    pub writing: Duration,
+}
+
+/// Harmonize the first events of the run set (up to the sample size) into a
+/// single harmonic file under a compression setting, in the harmonic path of
+/// the config. Only the time spent writing is measured.
+fn harmonize_sample(
+    config: &Config,
+    sample: u64,
+    setting: CompressionSetting,
+) -> Result<CompressionResult> {
+    let mut config = config.clone();
+    config.compression = setting.compression;
+    config.compression_level = setting.level;
+    // A single, flat, file without any of the extra outputs
+    config.single_file = true;
+    config.harmonic_size_gb = None;
+    config.num_output_files = None;
+    config.pads_per_file = None;
+    config.max_events_per_file = None;
+    config.max_output_bytes = None;
+    config.output_layout = OutputLayout::Flat;
+    config.overwrite = OverwritePolicy::Overwrite;
+    config.append = false;
+    config.run_counter_file = None;
+    config.write_event_metadata = false;
+    config.min_free_space_gb = None;
+
+    let mut reader = MergerReader::new(&config)?;
+    let mut writer = HarmonicWriter::new(&config)?;
+    let mut writing = Duration::ZERO;
+    for _ in 0..sample {
+        let Some(event) = reader.read_event()? else {
+            break;
+        };
+        let write_start = Instant::now();
+        writer.write(event)?;
+        writing += write_start.elapsed();
+    }
+    let write_start = Instant::now();
+    let stats = writer.close()?;
+    writing += write_start.elapsed();
+    Ok(CompressionResult {
+        events: stats.events_written,
+        bytes: stats.bytes_written,
+        writing,
+    })
+}
+
+/// Harmonize a sample of the events under each of the compression settings
+/// of the config, in a scratch directory in the temporary directory. A
+/// setting which fails (for example szip, if HDF5 was built without it) has
+/// its error reported instead. The results are named after their settings.
+pub fn compression_report(
+    config: &Config,
+    sample: u64,
+) -> Result<Vec<(String, Result<CompressionResult>)>> {
+    let scratch = config
+        .temp_dir()
+        .join(format!("harmonizer_compress_report_{}", std::process::id()));
+    let mut results = Vec::new();
+    for setting in config.compress_report_settings.iter() {
+        std::fs::create_dir_all(&scratch)?;
+        let mut sample_config = config.clone();
+        sample_config.harmonic_path = scratch.clone();
+        results.push((
+            setting.name(),
+            harmonize_sample(&sample_config, sample, *setting),
+        ));
+        std::fs::remove_dir_all(&scratch)?;
+    }
+    Ok(results)
+}
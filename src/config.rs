@@ -1,17 +1,900 @@
 //! The definition of a configuration for the harmonizer
+use super::reader::get_total_merger_bytes;
+use super::scalers::{REQUIRED_SCALER_COLUMNS, SCALER_COLUMNS};
 use color_eyre::eyre::{eyre, Result};
 use serde::{Deserialize, Serialize};
-use std::io::Write;
-use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+use std::ops::RangeInclusive;
+use std::path::{Component, Path, PathBuf};
+
+/// The placeholder for the run number in a merger file pattern
+const RUN_PLACEHOLDER: &str = "{run}";
+
+/// The placeholder for the scaler number in a scaler dataset name pattern
+pub const SCALER_PLACEHOLDER: &str = "{n}";
+/// The placeholder for the event number in the 0.1.0 dataset name patterns
+pub const EVENT_PLACEHOLDER: &str = "{n}";
+
+fn default_scaler_name_010() -> String {
+    String::from("scaler{n}_data")
+}
+
+fn default_scaler_name_020() -> String {
+    String::from("event_{n}")
+}
+
+fn default_merger_pattern() -> String {
+    String::from("run_{run}.h5")
+}
+
+fn default_merger_run_width() -> usize {
+    4
+}
+
+fn default_max_buffered_events() -> usize {
+    100
+}
+
+fn default_scaler_order() -> Vec<usize> {
+    (0..(SCALER_COLUMNS.len() - REQUIRED_SCALER_COLUMNS.len())).collect()
+}
+
+fn default_hdf5_file_locking() -> bool {
+    true
+}
+
+fn default_compression_level() -> u8 {
+    4
+}
+
+fn default_compress_report_settings() -> Vec<CompressionSetting> {
+    vec![
+        CompressionSetting::new(Compression::None, 0),
+        CompressionSetting::new(Compression::Gzip, 1),
+        CompressionSetting::new(Compression::Gzip, 4),
+        CompressionSetting::new(Compression::Gzip, 9),
+        CompressionSetting::new(Compression::Szip, 0),
+    ]
+}
+
+fn default_sort_window() -> usize {
+    1000
+}
+
+fn default_progress_update_events() -> u64 {
+    1000
+}
+
+fn default_warn_timestamp_overlap() -> bool {
+    true
+}
+
+fn default_extract_scalers() -> bool {
+    true
+}
+
+fn default_write_info_file() -> bool {
+    true
+}
+
+fn default_free_space_check_interval() -> u64 {
+    1
+}
+
+/// How the harmonic size bounds the size of a harmonic file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SizePolicy {
+    /// Files are rolled once they reach the harmonic size, so they are
+    /// slightly larger than it. The size is checked after each event.
+    #[default]
+    #[serde(alias = "after")]
+    AtLeast,
+    /// Files are rolled before an event which would make them exceed the
+    /// harmonic size (plus the size tolerance), so they are (approximately)
+    /// no larger than it. The size is checked before each event.
+    #[serde(alias = "before")]
+    AtMost,
+}
+
+/// The format of the combined scaler output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ScalerOutput {
+    /// A scalers.parquet file
+    #[default]
+    Parquet,
+    /// A scalers.h5 file in the merger scaler layout
+    Hdf5,
+    /// A scalers_run_#.parquet file for each run, written in parallel
+    ParquetPerRun,
+    /// A scalers_run_#.parquet file for each harmonic run, with the scalers
+    /// of the runs whose events it holds
+    ParquetPerHarmonicRun,
+}
+
+/// What to do when an optional attribute is missing from a source event
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MissingAttrPolicy {
+    /// The missing attribute is an error
+    #[default]
+    Error,
+    /// The attribute is given the value 0
+    Default,
+    /// The attribute is omitted from the output
+    Skip,
+}
+
+/// The merger version every run must have
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RequireVersion {
+    /// Runs of both versions are accepted
+    #[default]
+    Any,
+    /// Every run must be 0.1.0
+    V010,
+    /// Every run must be 0.2.0
+    V020,
+}
+
+/// Check that a directory exists and that we can create files in it
+fn check_writable_dir(dir: &Path) -> Result<()> {
+    if !dir.is_dir() {
+        return Err(eyre!(
+            "Temporary directory {} does not exist",
+            dir.display()
+        ));
+    }
+    let probe = dir.join(format!(".harmonizer_probe_{}", std::process::id()));
+    std::fs::File::create(&probe)
+        .and_then(|_| std::fs::remove_file(&probe))
+        .map_err(|err| {
+            eyre!(
+                "Temporary directory {} is not writable: {err}",
+                dir.display()
+            )
+        })
+}
+
+/// Parameters of the HDF5 chunk cache of the harmonic files
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChunkCache {
+    /// The size of the cache in bytes
+    pub nbytes: usize,
+    /// The number of slots in the cache's hash table
+    pub nslots: usize,
+    /// The preemption policy, between 0 and 1
+    pub w0: f64,
+}
+
+/// Which copy of a merger run to read when it is found in more than one of
+/// the merger directories
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateRunPolicy {
+    /// Duplicate runs are an error
+    #[default]
+    Error,
+    /// The copy in the first directory (in the order they are given)
+    First,
+    /// The copy in the last directory
+    Last,
+    /// The largest copy
+    Largest,
+}
+
+/// What to do when a harmonic run file to be written already exists
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OverwritePolicy {
+    /// Existing output is an error
+    #[default]
+    Error,
+    /// Existing output is overwritten
+    Overwrite,
+    /// Existing output is left alone, and the next run number is used instead
+    Skip,
+}
+
+/// Alignment of the objects in the harmonic files (H5Pset_alignment)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Alignment {
+    /// Objects at least this large (in bytes) are aligned
+    pub threshold: u64,
+    /// The boundary (in bytes) objects are aligned to
+    pub alignment: u64,
+}
+
+/// The HDF5 virtual file driver used to write the harmonic files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Hdf5Driver {
+    /// POSIX unbuffered I/O (H5Pset_fapl_sec2), the HDF5 default
+    Sec2,
+    /// Buffered C standard I/O (H5Pset_fapl_stdio)
+    Stdio,
+    /// The file is built in memory and written out when closed (H5Pset_fapl_core)
+    Core,
+}
+
+/// The smallest allowed max_open_files
+const MIN_MAX_OPEN_FILES: usize = 4;
+
+/// The bound on the files held open at once when the scalers are extracted
+/// concurrently without a max_open_files
+const CONCURRENT_MAX_OPEN_FILES: usize = 16;
+
+/// The lower bound of the HDF5 file format versions (libver) used to write
+/// the harmonic files. Newer format features are only used when the bound
+/// allows them, at the cost of older readers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Libver {
+    /// The earliest possible format for each object (H5F_LIBVER_EARLIEST)
+    Earliest,
+    /// Formats readable by HDF5 1.8 (H5F_LIBVER_V18)
+    V18,
+    /// Formats readable by HDF5 1.10 (H5F_LIBVER_V110)
+    V110,
+    /// The latest formats of the library (H5F_LIBVER_LATEST)
+    Latest,
+}
+
+/// The absolute form of a path with its . and .. components resolved, without
+/// requiring it to exist (symbolic links are not followed)
+fn normalized_path(path: &Path) -> Result<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in std::path::absolute(path)?.components() {
+        match component {
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::CurDir => (),
+            component => normalized.push(component),
+        }
+    }
+    Ok(normalized)
+}
+
+/// A renumbering of the merger runs in the output, for pseudonymized data.
+/// Either an offset added to every run, or a map from each run to its number.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum RunRemap {
+    Offset(i32),
+    Map(BTreeMap<i32, i32>),
+}
+
+impl RunRemap {
+    /// The number a run is written with
+    pub fn apply(&self, run: i32) -> Result<i32> {
+        match self {
+            Self::Offset(offset) => run
+                .checked_add(*offset)
+                .ok_or_else(|| eyre!("Run {run} overflows with the run_remap offset {offset}")),
+            Self::Map(map) => map
+                .get(&run)
+                .copied()
+                .ok_or_else(|| eyre!("Run {run} is not in the run_remap")),
+        }
+    }
+
+    /// The merger run of a run number written with the remapping
+    pub fn invert(&self, run: i32) -> Result<i32> {
+        match self {
+            Self::Offset(offset) => run
+                .checked_sub(*offset)
+                .ok_or_else(|| eyre!("Run {run} underflows with the run_remap offset {offset}")),
+            Self::Map(map) => map
+                .iter()
+                .find(|(_, remapped)| **remapped == run)
+                .map(|(merger_run, _)| *merger_run)
+                .ok_or_else(|| eyre!("No run is remapped to {run} by the run_remap")),
+        }
+    }
+
+    /// Save the remapping of the given runs as a YAML map from each run to
+    /// the number it is written with.
+    pub fn save(&self, path: &Path, runs: &[i32]) -> Result<()> {
+        let remapped = runs
+            .iter()
+            .map(|run| Ok((*run, self.apply(*run)?)))
+            .collect::<Result<BTreeMap<i32, i32>>>()?;
+        std::fs::write(path, serde_yaml::to_string(&remapped)?)?;
+        Ok(())
+    }
+}
+
+/// For each merger version, the index in the scaler data of the value of each
+/// scaler column (after run and event), so that both versions fill the same
+/// columns even if they order the scalers differently.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScalerOrder {
+    #[serde(default = "default_scaler_order")]
+    pub v010: Vec<usize>,
+    #[serde(default = "default_scaler_order")]
+    pub v020: Vec<usize>,
+}
+
+impl Default for ScalerOrder {
+    fn default() -> Self {
+        Self {
+            v010: default_scaler_order(),
+            v020: default_scaler_order(),
+        }
+    }
+}
+
+/// For each merger version, the name pattern of the scaler datasets, where
+/// {n} is replaced by the scaler number, for merger builds which name them
+/// differently
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScalerNames {
+    #[serde(default = "default_scaler_name_010")]
+    pub v010: String,
+    #[serde(default = "default_scaler_name_020")]
+    pub v020: String,
+}
+
+impl Default for ScalerNames {
+    fn default() -> Self {
+        Self {
+            v010: default_scaler_name_010(),
+            v020: default_scaler_name_020(),
+        }
+    }
+}
+
+/// The type of a scaler column in the parquet scalers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScalerType {
+    U32,
+    I32,
+    U64,
+    I64,
+    F64,
+}
+
+/// The HDF5 compression filter applied to the trace datasets
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    /// Traces are stored uncompressed
+    #[default]
+    None,
+    /// The gzip (deflate) filter, at the compression level
+    Gzip,
+    /// The szip filter
+    Szip,
+}
+
+/// A compression filter and its level (which only matters for gzip), as
+/// compared by the compress-report command
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CompressionSetting {
+    pub compression: Compression,
+    #[serde(default)]
+    pub level: u8,
+}
+
+impl CompressionSetting {
+    pub fn new(compression: Compression, level: u8) -> Self {
+        Self { compression, level }
+    }
+
+    /// The name of the setting in the report, such as "gzip 4"
+    pub fn name(&self) -> String {
+        match self.compression {
+            Compression::None => String::from("none"),
+            Compression::Gzip => format!("gzip {}", self.level),
+            Compression::Szip => String::from("szip"),
+        }
+    }
+}
+
+/// The format of the version attribute of the events group
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum VersionFormat {
+    /// The harmonizer name and version, as name:version
+    #[default]
+    Combined,
+    /// Only the harmonizer version
+    Semver,
+    /// A JSON object with producer and version fields
+    Json,
+}
+
+/// How the events of a harmonic file are stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum EventStorage {
+    /// A group per event
+    #[default]
+    Groups,
+    /// Extensible datasets indexed by event, with the traces stacked in 3-D
+    Stacked,
+}
+
+/// A built-in transform of the GET traces, applied before they are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TraceTransform {
+    /// Subtract the median of each trace from it
+    SubtractBaseline,
+    /// Set negative samples to 0
+    ClipNegative,
+}
+
+/// How the GET traces of an event are stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TraceLayout {
+    /// A single 2-D get_traces dataset
+    #[default]
+    Array,
+    /// A get_traces group with a 1-D channel_# dataset per trace
+    PerChannel,
+}
+
+/// How the harmonic runs are organized in the harmonic path
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputLayout {
+    /// All harmonic runs are in the harmonic path
+    #[default]
+    Flat,
+    /// Each harmonic run is in a subdirectory named after its first source run
+    BySourceRun,
+}
+
+/// Keys by which the writer can reorder events
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortBy {
+    /// Sort by the size of the event traces (in bytes)
+    Size,
+}
+
+/// The names of the GET and FRIB datasets within an event group of the
+/// 0.2.0 merger format. Some experiments' mergers use different names.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatasetNames {
+    pub get_traces: String,
+    pub frib_physics: String,
+    pub frib_traces: String,
+    pub frib_coincidence: String,
+    /// The name patterns of the 0.1.0 merger format
+    pub v010: DatasetNames010,
+}
+
+impl Default for DatasetNames {
+    fn default() -> Self {
+        Self {
+            get_traces: String::from("get_traces"),
+            frib_physics: String::from("frib_physics"),
+            frib_traces: String::from("1903"),
+            frib_coincidence: String::from("977"),
+            v010: DatasetNames010::default(),
+        }
+    }
+}
+
+/// The name patterns of the GET datasets (in the get group) and the FRIB
+/// datasets (in the frib/evt group) of the 0.1.0 merger format, where {n} is
+/// replaced by the event number
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatasetNames010 {
+    pub get_traces: String,
+    pub get_header: String,
+    pub frib_traces: String,
+    pub frib_coincidence: String,
+    pub frib_header: String,
+}
+
+impl Default for DatasetNames010 {
+    fn default() -> Self {
+        Self {
+            get_traces: String::from("evt{n}_data"),
+            get_header: String::from("evt{n}_header"),
+            frib_traces: String::from("evt{n}_1903"),
+            frib_coincidence: String::from("evt{n}_977"),
+            frib_header: String::from("evt{n}_header"),
+        }
+    }
+}
+
+impl DatasetNames010 {
+    /// The name of a dataset of an event from its name pattern
+    pub fn name(pattern: &str, event: u64) -> String {
+        pattern.replace(EVENT_PLACEHOLDER, &event.to_string())
+    }
+}
 
 /// Defines a configuration. It is Ser/De-able with serde.
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+/// Unknown keys are rejected, so that typos are not silently ignored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     pub merger_path: PathBuf,
+    /// More directories to look for merger runs in, after merger_path
+    #[serde(default)]
+    pub merger_paths: Vec<PathBuf>,
+    /// Which copy of a run found in more than one merger directory to read
+    #[serde(default)]
+    pub duplicate_runs: DuplicateRunPolicy,
+    /// Read merger runs from this tar(.gz) archive instead of merger_path
+    #[serde(default)]
+    pub merger_archive: Option<PathBuf>,
+    /// Directory for temporary files, the system temporary directory if None
+    #[serde(default)]
+    pub temp_dir: Option<PathBuf>,
     pub harmonic_path: PathBuf,
-    harmonic_size_gb: u64,
+    /// More directories to spread the harmonic runs over, after harmonic_path
+    #[serde(default)]
+    pub harmonic_paths: Vec<PathBuf>,
+    /// The size of each harmonic file, in GB
+    #[serde(default)]
+    pub harmonic_size_gb: Option<u64>,
+    /// Split the data evenly into this many harmonic files instead of by size
+    #[serde(default)]
+    pub num_output_files: Option<usize>,
+    /// Write everything to a single harmonic file, disabling splitting
+    #[serde(default)]
+    pub single_file: bool,
+    /// Split files by their total number of GET pads instead of by size
+    #[serde(default)]
+    pub pads_per_file: Option<u64>,
+    /// A file is also finished once it has this many events
+    #[serde(default)]
+    pub max_events_per_file: Option<u64>,
+    /// Stop harmonizing once the output reaches this many bytes in total
+    #[serde(default)]
+    pub max_output_bytes: Option<u64>,
+    /// Also finish a file whenever the source run changes
+    #[serde(default)]
+    pub split_on_run_boundary: bool,
+    /// Number of events written to a file before its size limit is checked
+    #[serde(default)]
+    pub min_events_per_file: u64,
     pub min_run: i32,
     pub max_run: i32,
+    /// Merger run files given explicitly (with --stdin), in the order to read them
+    #[serde(skip)]
+    pub merger_files: Option<Vec<(i32, PathBuf)>>,
+    /// The gas and beam of the runs, recorded in the info file
+    #[serde(default)]
+    pub gas_beam: Option<String>,
+    /// Filename template of merger runs, with a `{run}` placeholder
+    #[serde(default = "default_merger_pattern")]
+    pub merger_pattern: String,
+    /// Zero-padded width of the run number in merger filenames
+    #[serde(default = "default_merger_run_width")]
+    pub merger_run_width: usize,
+    /// Names of the datasets in 0.2.0 merger events
+    #[serde(default)]
+    pub dataset_names: DatasetNames,
+    /// In 0.1.0 files, pair GET and FRIB data by event id rather than index
+    #[serde(default)]
+    pub match_frib_by_event_id: bool,
+    /// How to handle missing id and timestamp_other attributes of GET traces
+    #[serde(default)]
+    pub on_missing_attr: MissingAttrPolicy,
+    /// Require every run to have this merger version
+    #[serde(default)]
+    pub require_version: RequireVersion,
+    /// How the harmonic size bounds the size of harmonic files
+    #[serde(default)]
+    pub size_policy: SizePolicy,
+    /// Bytes an at_most file may exceed the harmonic size by
+    #[serde(default)]
+    pub size_tolerance_bytes: u64,
+    /// Maximum number of read events waiting to be written
+    #[serde(default = "default_max_buffered_events")]
+    pub max_buffered_events: usize,
+    /// The maximum number of events taken from any one source run
+    #[serde(default)]
+    pub max_events_per_source_run: Option<u64>,
+    /// Only harmonize the events from this global index on
+    #[serde(default)]
+    pub event_start: Option<u64>,
+    /// Only harmonize the events before this global index
+    #[serde(default)]
+    pub event_end: Option<u64>,
+    /// Read the runs, and the events of each run, from last to first
+    #[serde(default)]
+    pub reverse: bool,
+    /// Experimental: reorder events within a window before writing
+    #[serde(default)]
+    pub sort_by: Option<SortBy>,
+    /// Number of events in a sorting window
+    #[serde(default = "default_sort_window")]
+    pub sort_window: usize,
+    /// Renumber the runs in the output, by an offset or a map
+    #[serde(default)]
+    pub run_remap: Option<RunRemap>,
+    /// Where the run remapping is written, outside of the output directories
+    #[serde(default)]
+    pub run_remap_path: Option<PathBuf>,
+    /// CSV file of run,event pairs of merger events to exclude
+    #[serde(default)]
+    pub blacklist_path: Option<PathBuf>,
+    /// Drop events whose GET or FRIB quality flag marks them as bad
+    #[serde(default)]
+    pub drop_bad_events: bool,
+    /// File recording cumulative progress across invocations
+    #[serde(default)]
+    pub progress_state_path: Option<PathBuf>,
+    /// Total number of events in the whole job, for the progress bar
+    #[serde(default)]
+    pub progress_total_events: Option<u64>,
+    /// Number of events between updates of the progress bar
+    #[serde(default = "default_progress_update_events")]
+    pub progress_update_events: u64,
+    /// Error if GET traces within a harmonic file have differing widths
+    #[serde(default)]
+    pub check_trace_widths: bool,
+    /// The format of the combined scaler file
+    #[serde(default)]
+    pub scaler_output: ScalerOutput,
+    /// Only write these scaler columns (run and event are always written)
+    #[serde(default)]
+    pub scaler_columns_include: Option<Vec<String>>,
+    /// The order of the scaler values of each merger version
+    #[serde(default)]
+    pub scaler_order: ScalerOrder,
+    /// The name pattern of the scaler datasets of each merger version
+    #[serde(default)]
+    pub scaler_names: ScalerNames,
+    /// The types of the parquet scaler columns, u32 if not given
+    #[serde(default)]
+    pub scaler_schema: BTreeMap<String, ScalerType>,
+    /// Row group size of scalers.parquet, polars' default if None
+    #[serde(default)]
+    pub scaler_row_group_size: Option<usize>,
+    /// Extract the scalers after harmonizing (disabled by --no-scalers)
+    #[serde(default = "default_extract_scalers")]
+    pub extract_scalers: bool,
+    /// Extract the scalers on their own thread while the events are harmonized
+    #[serde(default)]
+    pub concurrent_scalers: bool,
+    /// Times to retry writing a parquet scaler file which fails with an I/O error
+    #[serde(default)]
+    pub scaler_write_retries: u32,
+    /// Only read the scaler events of each run from this one on
+    #[serde(default)]
+    pub scaler_event_min: Option<u32>,
+    /// Only read the scaler events of each run up to this one (inclusive)
+    #[serde(default)]
+    pub scaler_event_max: Option<u32>,
+    /// Warn if a run's first GET timestamp precedes the previous run's last one
+    #[serde(default = "default_warn_timestamp_overlap")]
+    pub warn_timestamp_overlap: bool,
+    /// Enable strict data-integrity checks (also set by --strict)
+    #[serde(default)]
+    pub strict: bool,
+    /// Partition each harmonic file into blocks of this many events
+    #[serde(default)]
+    pub events_per_block: Option<u64>,
+    /// Stamp each event group with the wall-clock time it was written
+    #[serde(default)]
+    pub write_written_at: bool,
+    /// Stamp each event group with the run number of its harmonic file
+    #[serde(default)]
+    pub write_harmonic_run: bool,
+    /// Store the metadata of each event in a single compound attribute
+    #[serde(default)]
+    pub pack_event_metadata: bool,
+    /// Record which source runs contributed to each harmonic file
+    #[serde(default)]
+    pub write_source_runs: bool,
+    /// Transforms applied to the GET traces before writing, in order
+    #[serde(default)]
+    pub trace_transforms: Vec<TraceTransform>,
+    /// Pad (or truncate) the time axis of the GET traces to this length
+    #[serde(default)]
+    pub pad_traces_to: Option<usize>,
+    /// The value padded GET traces are filled with
+    #[serde(default)]
+    pub pad_fill: i16,
+    /// Store GET traces transposed, with an orientation attribute
+    #[serde(default)]
+    pub transpose_traces: bool,
+    /// Compression of the trace datasets
+    #[serde(default)]
+    pub compression: Compression,
+    /// The gzip compression level, from 0 to 9
+    #[serde(default = "default_compression_level")]
+    pub compression_level: u8,
+    /// The compression settings compared by the compress-report command
+    #[serde(default = "default_compress_report_settings")]
+    pub compress_report_settings: Vec<CompressionSetting>,
+    /// Store each GET trace as its own dataset
+    #[serde(default)]
+    pub trace_layout: TraceLayout,
+    /// Store the events of a file in a group each, or stacked in datasets
+    #[serde(default)]
+    pub event_storage: EventStorage,
+    /// Pad traces with zeros to the largest shape in the file, so that they
+    /// can be stacked
+    #[serde(default)]
+    pub stacked_padding: bool,
+    /// Do not write the FRIB coincidence (977) dataset
+    #[serde(default)]
+    pub exclude_frib_coincidence: bool,
+    /// Stop writing if the free space of harmonic_path drops below this many GB
+    #[serde(default)]
+    pub min_free_space_gb: Option<u64>,
+    /// Check the free space every this many harmonic files
+    #[serde(default = "default_free_space_check_interval")]
+    pub free_space_check_interval: u64,
+    /// How the harmonic runs are organized in harmonic_path
+    #[serde(default)]
+    pub output_layout: OutputLayout,
+    /// What to do with existing harmonic run files
+    #[serde(default)]
+    pub overwrite: OverwritePolicy,
+    /// Append to the last harmonic file in harmonic_path instead of starting anew
+    #[serde(default)]
+    pub append: bool,
+    /// File holding the next harmonic run number, shared between invocations
+    #[serde(default)]
+    pub run_counter_file: Option<PathBuf>,
+    /// Chunk cache parameters of the harmonic files, the HDF5 default if None
+    #[serde(default)]
+    pub chunk_cache: Option<ChunkCache>,
+    /// Alignment of the objects in the harmonic files, the HDF5 default if None
+    #[serde(default)]
+    pub hdf5_alignment: Option<Alignment>,
+    /// The HDF5 file driver of the harmonic files, the HDF5 default if None
+    #[serde(default)]
+    pub hdf5_driver: Option<Hdf5Driver>,
+    /// The HDF5 format version bound of the harmonic files, the HDF5 default if None
+    #[serde(default)]
+    pub libver: Option<Libver>,
+    /// Use HDF5 file locking (HDF5_USE_FILE_LOCKING)
+    #[serde(default = "default_hdf5_file_locking")]
+    pub hdf5_file_locking: bool,
+    /// Times to retry opening a merger run locked by another process
+    #[serde(default)]
+    pub lock_retries: u32,
+    /// Seconds to wait for a merger run to respond before skipping it
+    #[serde(default)]
+    pub open_timeout_secs: Option<u64>,
+    /// The maximum number of merger and harmonic files held open at once
+    #[serde(default)]
+    pub max_open_files: Option<usize>,
+    /// Write a JSON metadata userblock at the start of each harmonic file
+    #[serde(default)]
+    pub write_userblock: bool,
+    /// The format of the version attribute of the events group
+    #[serde(default)]
+    pub version_format: VersionFormat,
+    /// Also write producer and producer_version attributes to the events group
+    #[serde(default)]
+    pub write_producer_attrs: bool,
+    /// Write a provisional target_event_count attribute to each new file
+    #[serde(default)]
+    pub write_target_event_count: bool,
+    /// Reopen each finished harmonic file and verify its metadata
+    #[serde(default)]
+    pub verify_output: bool,
+    /// Write a parquet table with the metadata of every harmonic event
+    #[serde(default)]
+    pub write_event_metadata: bool,
+    /// Write a human readable HARMONIZATION_INFO.txt to the harmonic path
+    #[serde(default = "default_write_info_file")]
+    pub write_info_file: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            merger_path: PathBuf::default(),
+            merger_paths: Vec::new(),
+            duplicate_runs: DuplicateRunPolicy::default(),
+            merger_archive: None,
+            temp_dir: None,
+            harmonic_path: PathBuf::default(),
+            harmonic_paths: Vec::new(),
+            harmonic_size_gb: Some(10),
+            num_output_files: None,
+            single_file: false,
+            pads_per_file: None,
+            max_events_per_file: None,
+            max_output_bytes: None,
+            split_on_run_boundary: false,
+            min_events_per_file: 0,
+            min_run: 0,
+            max_run: 0,
+            merger_files: None,
+            gas_beam: None,
+            merger_pattern: default_merger_pattern(),
+            merger_run_width: default_merger_run_width(),
+            dataset_names: DatasetNames::default(),
+            match_frib_by_event_id: false,
+            on_missing_attr: MissingAttrPolicy::default(),
+            require_version: RequireVersion::default(),
+            size_policy: SizePolicy::default(),
+            size_tolerance_bytes: 0,
+            max_buffered_events: default_max_buffered_events(),
+            max_events_per_source_run: None,
+            event_start: None,
+            event_end: None,
+            reverse: false,
+            sort_by: None,
+            sort_window: default_sort_window(),
+            run_remap: None,
+            run_remap_path: None,
+            blacklist_path: None,
+            drop_bad_events: false,
+            progress_state_path: None,
+            progress_total_events: None,
+            progress_update_events: default_progress_update_events(),
+            check_trace_widths: false,
+            scaler_output: ScalerOutput::default(),
+            scaler_columns_include: None,
+            scaler_order: ScalerOrder::default(),
+            scaler_names: ScalerNames::default(),
+            scaler_schema: BTreeMap::new(),
+            scaler_row_group_size: None,
+            extract_scalers: default_extract_scalers(),
+            concurrent_scalers: false,
+            scaler_write_retries: 0,
+            scaler_event_min: None,
+            scaler_event_max: None,
+            warn_timestamp_overlap: default_warn_timestamp_overlap(),
+            strict: false,
+            events_per_block: None,
+            write_written_at: false,
+            write_harmonic_run: false,
+            pack_event_metadata: false,
+            write_source_runs: false,
+            trace_transforms: Vec::new(),
+            pad_traces_to: None,
+            pad_fill: 0,
+            transpose_traces: false,
+            compression: Compression::default(),
+            compression_level: default_compression_level(),
+            compress_report_settings: default_compress_report_settings(),
+            trace_layout: TraceLayout::default(),
+            event_storage: EventStorage::default(),
+            stacked_padding: false,
+            exclude_frib_coincidence: false,
+            min_free_space_gb: None,
+            free_space_check_interval: default_free_space_check_interval(),
+            output_layout: OutputLayout::default(),
+            overwrite: OverwritePolicy::default(),
+            append: false,
+            run_counter_file: None,
+            chunk_cache: None,
+            hdf5_alignment: None,
+            hdf5_driver: None,
+            libver: None,
+            hdf5_file_locking: default_hdf5_file_locking(),
+            lock_retries: 0,
+            open_timeout_secs: None,
+            max_open_files: None,
+            write_userblock: false,
+            version_format: VersionFormat::default(),
+            write_producer_attrs: false,
+            write_target_event_count: false,
+            verify_output: false,
+            write_event_metadata: false,
+            write_info_file: default_write_info_file(),
+        }
+    }
 }
 
 impl Config {
@@ -25,19 +908,1044 @@ impl Config {
         }
 
         let yaml_str = std::fs::read_to_string(path)?;
-        Ok(serde_yaml::from_str::<Self>(&yaml_str)?)
+        let config = serde_yaml::from_str::<Self>(&yaml_str)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Serialize this configuration to YAML.
+    pub fn to_yaml(&self) -> Result<String> {
+        Ok(serde_yaml::to_string(self)?)
     }
 
     /// Save this configuration to a YAML file.
     pub fn save(&self, path: &Path) -> Result<()> {
-        let yaml_str = serde_yaml::to_string(self)?;
+        let yaml_str = self.to_yaml()?;
         let mut file = std::fs::File::create(path)?;
         file.write_all(yaml_str.as_bytes())?;
         Ok(())
     }
 
-    /// Convert the harmonic size gb rep to bytes.
-    pub fn get_harmonic_size(&self) -> u64 {
-        self.harmonic_size_gb * 1_000_000_000
+    /// Check that the configuration values are sensible.
+    pub fn validate(&self) -> Result<()> {
+        match (self.harmonic_size_gb, self.num_output_files) {
+            (Some(_), Some(_)) => {
+                return Err(eyre!(
+                    "Only one of harmonic_size_gb or num_output_files can be set"
+                ))
+            }
+            (None, None) if !self.single_file && self.pads_per_file.is_none() => {
+                return Err(eyre!(
+                    "One of harmonic_size_gb or num_output_files must be set"
+                ))
+            }
+            (None, Some(0)) => return Err(eyre!("num_output_files must be greater than 0")),
+            _ => (),
+        }
+        if self.append && self.run_counter_file.is_some() {
+            return Err(eyre!("Only one of append or run_counter_file can be set"));
+        }
+        if self.append && self.output_layout != OutputLayout::Flat {
+            return Err(eyre!("append requires the flat output_layout"));
+        }
+        if let Some(RunRemap::Map(map)) = &self.run_remap {
+            let mut numbers: Vec<i32> = map.values().copied().collect();
+            numbers.sort();
+            numbers.dedup();
+            if numbers.len() != map.len() {
+                return Err(eyre!("run_remap maps several runs to the same number"));
+            }
+        }
+        match (&self.run_remap, &self.run_remap_path) {
+            (Some(_), None) => {
+                return Err(eyre!(
+                    "run_remap_path must be set with run_remap, to make it reversible"
+                ));
+            }
+            (None, Some(_)) => return Err(eyre!("run_remap_path requires run_remap")),
+            (Some(_), Some(path)) => {
+                let path = normalized_path(path)?;
+                for dir in self.output_dirs() {
+                    if path.starts_with(normalized_path(&dir)?) {
+                        return Err(eyre!(
+                            "run_remap_path {} is in the output directory {}, where it would be shared with the data",
+                            path.display(),
+                            dir.display()
+                        ));
+                    }
+                }
+            }
+            (None, None) => (),
+        }
+        if self.compression_level > 9 {
+            return Err(eyre!("compression_level must be between 0 and 9"));
+        }
+        if self.compress_report_settings.is_empty() {
+            return Err(eyre!("compress_report_settings cannot be empty"));
+        }
+        if self
+            .compress_report_settings
+            .iter()
+            .any(|setting| setting.level > 9)
+        {
+            return Err(eyre!(
+                "The levels of compress_report_settings must be between 0 and 9"
+            ));
+        }
+        if self.transpose_traces && self.trace_layout == TraceLayout::PerChannel {
+            return Err(eyre!(
+                "transpose_traces cannot be combined with the per_channel trace_layout"
+            ));
+        }
+        if self.event_storage == EventStorage::Stacked {
+            if self.trace_layout == TraceLayout::PerChannel
+                || self.transpose_traces
+                || self.write_written_at
+                || self.write_harmonic_run
+                || self.append
+            {
+                return Err(eyre!(
+                    "The stacked event_storage cannot be combined with the per_channel trace_layout, transpose_traces, write_written_at, write_harmonic_run, or append"
+                ));
+            }
+            if !self.exclude_frib_coincidence {
+                return Err(eyre!(
+                    "The stacked event_storage does not store the FRIB coincidence data, set exclude_frib_coincidence"
+                ));
+            }
+        }
+        for (version, order) in [
+            ("v010", &self.scaler_order.v010),
+            ("v020", &self.scaler_order.v020),
+        ] {
+            let mut sorted = order.clone();
+            sorted.sort();
+            if sorted != default_scaler_order() {
+                return Err(eyre!(
+                    "scaler_order {version} must be a permutation of 0 to {}",
+                    default_scaler_order().len() - 1
+                ));
+            }
+        }
+        if self.progress_update_events == 0 {
+            return Err(eyre!("progress_update_events must be greater than 0"));
+        }
+        if self.free_space_check_interval == 0 {
+            return Err(eyre!("free_space_check_interval must be greater than 0"));
+        }
+        if self.max_events_per_file == Some(0) {
+            return Err(eyre!("max_events_per_file must be greater than 0"));
+        }
+        if self
+            .max_events_per_file
+            .is_some_and(|max_events| max_events < self.min_events_per_file)
+        {
+            return Err(eyre!(
+                "min_events_per_file cannot be greater than max_events_per_file"
+            ));
+        }
+        if self.concurrent_scalers
+            && (self.max_output_bytes.is_some()
+                || self.min_free_space_gb.is_some()
+                || self.scaler_output == ScalerOutput::ParquetPerHarmonicRun)
+        {
+            return Err(eyre!(
+                "concurrent_scalers cannot be combined with max_output_bytes, min_free_space_gb, or the parquet_per_harmonic_run scaler_output, which depend on the harmonized events"
+            ));
+        }
+        if self.hdf5_driver == Some(Hdf5Driver::Core) && self.max_output_bytes.is_some() {
+            return Err(eyre!(
+                "The core hdf5_driver cannot be combined with max_output_bytes, as the files are only written to disk when they are closed"
+            ));
+        }
+        if self.max_output_bytes == Some(0) {
+            return Err(eyre!("max_output_bytes must be greater than 0"));
+        }
+        if self.pads_per_file == Some(0) {
+            return Err(eyre!("pads_per_file must be greater than 0"));
+        }
+        if self.single_file
+            && (self.harmonic_size_gb.is_some()
+                || self.num_output_files.is_some()
+                || self.pads_per_file.is_some())
+        {
+            return Err(eyre!(
+                "single_file cannot be combined with harmonic_size_gb, num_output_files or pads_per_file"
+            ));
+        }
+        if self.pads_per_file.is_some()
+            && (self.harmonic_size_gb.is_some() || self.num_output_files.is_some())
+        {
+            return Err(eyre!(
+                "pads_per_file cannot be combined with harmonic_size_gb or num_output_files"
+            ));
+        }
+        if self.events_per_block == Some(0) {
+            return Err(eyre!("events_per_block must be greater than 0"));
+        }
+        if let (Some(start), Some(end)) = (self.event_start, self.event_end) {
+            if start >= end {
+                return Err(eyre!("event_start must be less than event_end"));
+            }
+        }
+        if self.pad_traces_to == Some(0) {
+            return Err(eyre!("pad_traces_to must be greater than 0"));
+        }
+        if self.open_timeout_secs == Some(0) {
+            return Err(eyre!("open_timeout_secs must be greater than 0"));
+        }
+        if self.max_events_per_source_run == Some(0) {
+            return Err(eyre!("max_events_per_source_run must be greater than 0"));
+        }
+        if self.scaler_row_group_size == Some(0) {
+            return Err(eyre!("scaler_row_group_size must be greater than 0"));
+        }
+        if self.scaler_events().is_empty() {
+            return Err(eyre!(
+                "scaler_event_min cannot be greater than scaler_event_max"
+            ));
+        }
+        if self.sort_by.is_some() && self.sort_window == 0 {
+            return Err(eyre!("sort_window must be greater than 0 when sorting"));
+        }
+        if let Some(unknown) = self
+            .scaler_columns_include
+            .iter()
+            .flatten()
+            .chain(self.scaler_schema.keys())
+            .find(|column| !SCALER_COLUMNS.contains(&column.as_str()))
+        {
+            return Err(eyre!(
+                "Unknown scaler column {unknown}; scaler columns are {}",
+                SCALER_COLUMNS.join(", ")
+            ));
+        }
+        for (version, pattern) in [
+            ("v010", &self.scaler_names.v010),
+            ("v020", &self.scaler_names.v020),
+        ] {
+            if pattern.matches(SCALER_PLACEHOLDER).count() != 1 || pattern.contains('/') {
+                return Err(eyre!(
+                    "scaler_names {version} {pattern} must contain exactly one {SCALER_PLACEHOLDER} placeholder and no /"
+                ));
+            }
+        }
+        if self.merger_pattern.matches(RUN_PLACEHOLDER).count() != 1 {
+            return Err(eyre!(
+                "Merger pattern {} must contain exactly one {RUN_PLACEHOLDER} placeholder",
+                self.merger_pattern
+            ));
+        }
+        if self.merger_pattern.contains(std::path::is_separator) {
+            return Err(eyre!(
+                "Merger pattern {} must be a filename, not a path",
+                self.merger_pattern
+            ));
+        }
+        if let Some(cache) = self.chunk_cache {
+            if !(0.0..=1.0).contains(&cache.w0) {
+                return Err(eyre!("chunk_cache w0 must be between 0 and 1"));
+            }
+        }
+        if self
+            .hdf5_alignment
+            .is_some_and(|alignment| alignment.alignment == 0)
+        {
+            return Err(eyre!("hdf5_alignment alignment must be greater than 0"));
+        }
+        // The reader and the writer each briefly hold two files when moving on
+        if self
+            .max_open_files
+            .is_some_and(|max| max < MIN_MAX_OPEN_FILES)
+        {
+            return Err(eyre!(
+                "max_open_files must be at least {MIN_MAX_OPEN_FILES}"
+            ));
+        }
+        if let Some(dir) = &self.temp_dir {
+            check_writable_dir(dir)?;
+        }
+        for dir in self.harmonic_paths.iter() {
+            check_writable_dir(dir)?;
+        }
+        if self.merger_archive.is_some() && !self.merger_paths.is_empty() {
+            return Err(eyre!("merger_archive cannot be combined with merger_paths"));
+        }
+        if self.append && !self.harmonic_paths.is_empty() {
+            return Err(eyre!("append cannot be combined with harmonic_paths"));
+        }
+        let names = &self.dataset_names;
+        if let Some(name) = [
+            &names.get_traces,
+            &names.frib_physics,
+            &names.frib_traces,
+            &names.frib_coincidence,
+        ]
+        .into_iter()
+        .find(|name| name.is_empty() || name.contains('/'))
+        {
+            return Err(eyre!(
+                "Dataset name \"{name}\" must be a non-empty name without a /"
+            ));
+        }
+        let patterns = &names.v010;
+        if let Some(pattern) = [
+            &patterns.get_traces,
+            &patterns.get_header,
+            &patterns.frib_traces,
+            &patterns.frib_coincidence,
+            &patterns.frib_header,
+        ]
+        .into_iter()
+        .find(|pattern| pattern.matches(EVENT_PLACEHOLDER).count() != 1 || pattern.contains('/'))
+        {
+            return Err(eyre!(
+                "Dataset name pattern \"{pattern}\" must contain exactly one {EVENT_PLACEHOLDER} placeholder and no /"
+            ));
+        }
+        if patterns.get_traces == patterns.get_header
+            || [&patterns.frib_traces, &patterns.frib_coincidence].contains(&&patterns.frib_header)
+            || patterns.frib_traces == patterns.frib_coincidence
+        {
+            return Err(eyre!(
+                "The 0.1.0 dataset name patterns of a group must differ"
+            ));
+        }
+        Ok(())
+    }
+
+    /// The bound on the files held open at once: max_open_files, or, when the
+    /// scalers are extracted concurrently with the events, a default bound, so
+    /// that the parallel scaler reads do not open every run at once alongside
+    /// the reader.
+    pub fn open_file_limit(&self) -> Option<usize> {
+        match self.max_open_files {
+            Some(max) => Some(max),
+            None if self.extract_scalers && self.concurrent_scalers => {
+                Some(CONCURRENT_MAX_OPEN_FILES)
+            }
+            None => None,
+        }
+    }
+
+    /// Get the size of a harmonic file in bytes. Either the harmonic size gb rep
+    /// is converted to bytes, or if a number of output files was requested, the
+    /// total amount of merger data is split evenly. A single file, or files
+    /// split by pad count, have no harmonic size, so files never reach it.
+    pub fn get_harmonic_size(&self) -> Result<u64> {
+        if self.single_file || self.pads_per_file.is_some() {
+            return Ok(u64::MAX);
+        }
+        match (self.harmonic_size_gb, self.num_output_files) {
+            (Some(size_gb), None) => Ok(size_gb * 1_000_000_000),
+            (None, Some(n_files)) => Ok(get_total_merger_bytes(self)?.div_ceil(n_files as u64)),
+            _ => Err(eyre!(
+                "Exactly one of harmonic_size_gb or num_output_files must be set"
+            )),
+        }
+    }
+
+    /// The directories merger runs are looked for in: the merger path,
+    /// followed by any additional merger paths.
+    pub fn input_dirs(&self) -> Vec<PathBuf> {
+        std::iter::once(self.merger_path.clone())
+            .chain(self.merger_paths.iter().cloned())
+            .collect()
+    }
+
+    /// Every copy of a merger run file in the merger directories, in the order
+    /// of the directories
+    pub fn merger_run_copies(&self, run_number: i32) -> Vec<PathBuf> {
+        let file_name = self.merger_run_file_name(run_number);
+        self.input_dirs()
+            .into_iter()
+            .map(|dir| dir.join(&file_name))
+            .filter(|path| path.exists())
+            .collect()
+    }
+
+    /// The merger runs of the run range found in more than one merger
+    /// directory, with all of their copies
+    pub fn duplicate_merger_runs(&self) -> Vec<(i32, Vec<PathBuf>)> {
+        if self.merger_paths.is_empty() || self.merger_files.is_some() {
+            return Vec::new();
+        }
+        self.runs()
+            .into_iter()
+            .map(|run| (run, self.merger_run_copies(run)))
+            .filter(|(_, copies)| copies.len() > 1)
+            .collect()
+    }
+
+    /// The directories the harmonic runs are written to, in turn: the
+    /// harmonic path, followed by any additional harmonic paths.
+    pub fn output_dirs(&self) -> Vec<PathBuf> {
+        std::iter::once(self.harmonic_path.clone())
+            .chain(self.harmonic_paths.iter().cloned())
+            .collect()
+    }
+
+    /// The directory in which temporary files are created
+    pub fn temp_dir(&self) -> PathBuf {
+        self.temp_dir.clone().unwrap_or_else(std::env::temp_dir)
+    }
+
+    /// Read a list of merger run files, one path per line, to be read in the
+    /// given order instead of the run range. The run number of each file is
+    /// parsed from its name using the merger pattern, and the run range is
+    /// set to span the listed runs.
+    pub fn read_merger_files(&mut self, input: impl BufRead) -> Result<()> {
+        if self.merger_archive.is_some() {
+            return Err(eyre!(
+                "A list of merger files cannot be combined with merger_archive"
+            ));
+        }
+        let (prefix, suffix) = self
+            .merger_pattern
+            .split_once(RUN_PLACEHOLDER)
+            .ok_or_else(|| eyre!("Merger pattern {} has no run", self.merger_pattern))?;
+        let mut files: Vec<(i32, PathBuf)> = Vec::new();
+        for line in input.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let path = PathBuf::from(line);
+            let run = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix(prefix))
+                .and_then(|name| name.strip_suffix(suffix))
+                .and_then(|run| run.parse::<i32>().ok())
+                .ok_or_else(|| {
+                    eyre!(
+                        "Could not parse a run number from {} using the merger pattern {}",
+                        path.display(),
+                        self.merger_pattern
+                    )
+                })?;
+            if files.iter().any(|(other, _)| *other == run) {
+                return Err(eyre!("Run {run} is listed more than once"));
+            }
+            files.push((run, path));
+        }
+        let runs = files.iter().map(|(run, _)| *run);
+        (self.min_run, self.max_run) = match (runs.clone().min(), runs.max()) {
+            (Some(min), Some(max)) => (min, max),
+            _ => return Err(eyre!("No merger run files were listed")),
+        };
+        self.merger_files = Some(files);
+        Ok(())
+    }
+
+    /// The merger runs to read, in order. These are the listed merger files
+    /// if there are any, otherwise the run range.
+    pub fn runs(&self) -> Vec<i32> {
+        match &self.merger_files {
+            Some(files) => files.iter().map(|(run, _)| *run).collect(),
+            None => (self.min_run..(self.max_run + 1)).collect(),
+        }
+    }
+
+    /// The scaler events of each run which are read
+    pub fn scaler_events(&self) -> RangeInclusive<u32> {
+        self.scaler_event_min.unwrap_or(0)..=self.scaler_event_max.unwrap_or(u32::MAX)
+    }
+
+    /// The runs in the order they are read, which is reversed in reverse
+    pub fn read_runs(&self) -> Vec<i32> {
+        let mut runs = self.runs();
+        if self.reverse {
+            runs.reverse();
+        }
+        runs
+    }
+
+    /// Drop the runs read after the given run, so that only the runs up to
+    /// and including it (in the order they are read) are read.
+    pub fn truncate_runs(&mut self, last_run: i32) {
+        match &mut self.merger_files {
+            Some(files) => {
+                if let Some(index) = files.iter().position(|(run, _)| *run == last_run) {
+                    if self.reverse {
+                        files.drain(..index);
+                    } else {
+                        files.truncate(index + 1);
+                    }
+                }
+            }
+            None if self.reverse => self.min_run = last_run,
+            None => self.max_run = last_run,
+        }
+    }
+
+    /// The number a run is written with in the output, applying the run_remap
+    pub fn remap_run(&self, run: i32) -> Result<i32> {
+        match &self.run_remap {
+            Some(remap) => remap.apply(run),
+            None => Ok(run),
+        }
+    }
+
+    /// Save the run_remap of the runs in the range to the run_remap_path, if
+    /// the runs are remapped
+    pub fn save_run_remap(&self) -> Result<()> {
+        if let (Some(remap), Some(path)) = (&self.run_remap, &self.run_remap_path) {
+            remap.save(path, &self.runs())?;
+        }
+        Ok(())
+    }
+
+    /// The merger run of a run number written in the output, undoing the
+    /// run_remap
+    pub fn unmap_run(&self, run: i32) -> Result<i32> {
+        match &self.run_remap {
+            Some(remap) => remap.invert(run),
+            None => Ok(run),
+        }
+    }
+
+    /// Construct the file name of a merger run using the merger pattern.
+    pub fn merger_run_file_name(&self, run_number: i32) -> String {
+        let run = format!("{:0>width$}", run_number, width = self.merger_run_width);
+        self.merger_pattern.replace(RUN_PLACEHOLDER, &run)
+    }
+
+    /// Construct the path to a merger run file in the merger path, or find
+    /// it in the listed merger files. With more merger directories, the copy
+    /// to read of a run found in several of them is picked by the duplicate
+    /// run policy.
+    pub fn merger_run_path(&self, run_number: i32) -> Result<PathBuf> {
+        let listed = self
+            .merger_files
+            .iter()
+            .flatten()
+            .find(|(run, _)| *run == run_number);
+        if let Some((_, path)) = listed {
+            return Ok(path.clone());
+        }
+        let default_path = self.merger_path.join(self.merger_run_file_name(run_number));
+        if self.merger_paths.is_empty() {
+            return Ok(default_path);
+        }
+        let mut copies = self.merger_run_copies(run_number);
+        if copies.len() < 2 {
+            return Ok(copies.pop().unwrap_or(default_path));
+        }
+        match self.duplicate_runs {
+            DuplicateRunPolicy::Error => Err(eyre!(
+                "Run {run_number} was found in more than one merger directory: {}",
+                copies
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+            DuplicateRunPolicy::First => Ok(copies.swap_remove(0)),
+            DuplicateRunPolicy::Last => Ok(copies.pop().unwrap()),
+            DuplicateRunPolicy::Largest => Ok(copies
+                .into_iter()
+                .max_by_key(|path| path.metadata().map_or(0, |meta| meta.len()))
+                .unwrap()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ScratchDir;
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let yaml = "merger_path: /merger\nharmonic_path: /harmonic\nharmonik_size_gb: 1\nmin_run: 0\nmax_run: 1\n";
+        let error = serde_yaml::from_str::<Config>(yaml).unwrap_err();
+        assert!(error.to_string().contains("harmonik_size_gb"));
+    }
+
+    #[test]
+    fn the_template_config_is_valid() {
+        let yaml = Config::default().to_yaml().unwrap();
+        serde_yaml::from_str::<Config>(&yaml)
+            .unwrap()
+            .validate()
+            .unwrap();
+    }
+
+    #[test]
+    fn num_output_files_splits_the_merger_bytes_evenly() {
+        let dir = ScratchDir::new("num_output_files");
+        for (run, size) in [(1, 1000), (2, 2500), (4, 600)] {
+            std::fs::write(
+                dir.path().join(format!("run_{run:0>4}.h5")),
+                vec![0u8; size],
+            )
+            .unwrap();
+        }
+        let config = Config {
+            merger_path: dir.path().to_path_buf(),
+            min_run: 1,
+            max_run: 4,
+            harmonic_size_gb: None,
+            num_output_files: Some(3),
+            ..Default::default()
+        };
+        config.validate().unwrap();
+        assert_eq!(config.get_harmonic_size().unwrap(), 4100u64.div_ceil(3));
+    }
+
+    #[test]
+    fn colliding_runs_follow_the_duplicate_run_policy() {
+        let dir = ScratchDir::new("duplicate_runs");
+        let (first, second) = (dir.path().join("first"), dir.path().join("second"));
+        // Run 1 is larger in the second directory, run 3 in the first
+        for (merger_dir, runs) in [
+            (&first, vec![(1, 100), (2, 100), (3, 500)]),
+            (&second, vec![(1, 300), (3, 200)]),
+        ] {
+            std::fs::create_dir_all(merger_dir).unwrap();
+            for (run, size) in runs {
+                std::fs::write(
+                    merger_dir.join(format!("run_{run:0>4}.h5")),
+                    vec![0u8; size],
+                )
+                .unwrap();
+            }
+        }
+        let config = |duplicate_runs| Config {
+            merger_path: first.clone(),
+            merger_paths: vec![second.clone()],
+            min_run: 1,
+            max_run: 3,
+            duplicate_runs,
+            ..Default::default()
+        };
+        let run_path = |dir: &Path, run: i32| dir.join(format!("run_{run:0>4}.h5"));
+
+        let collisions = config(DuplicateRunPolicy::Error).duplicate_merger_runs();
+        assert_eq!(
+            collisions,
+            vec![
+                (1, vec![run_path(&first, 1), run_path(&second, 1)]),
+                (3, vec![run_path(&first, 3), run_path(&second, 3)]),
+            ]
+        );
+        let error = config(DuplicateRunPolicy::Error)
+            .merger_run_path(1)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains(&run_path(&first, 1).display().to_string()),
+            "{error}"
+        );
+        assert!(
+            error.contains(&run_path(&second, 1).display().to_string()),
+            "{error}"
+        );
+
+        for (policy, run_1, run_3) in [
+            (DuplicateRunPolicy::First, &first, &first),
+            (DuplicateRunPolicy::Last, &second, &second),
+            (DuplicateRunPolicy::Largest, &second, &first),
+        ] {
+            let config = config(policy);
+            assert_eq!(
+                config.merger_run_path(1).unwrap(),
+                run_path(run_1, 1),
+                "{policy:?}"
+            );
+            assert_eq!(
+                config.merger_run_path(3).unwrap(),
+                run_path(run_3, 3),
+                "{policy:?}"
+            );
+            // Runs without a collision are read from where they are
+            assert_eq!(
+                config.merger_run_path(2).unwrap(),
+                run_path(&first, 2),
+                "{policy:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn concurrent_scalers_bound_the_open_files() {
+        let config = |concurrent_scalers, max_open_files| Config {
+            concurrent_scalers,
+            max_open_files,
+            ..Default::default()
+        };
+        assert_eq!(config(false, None).open_file_limit(), None);
+        assert_eq!(
+            config(true, None).open_file_limit(),
+            Some(CONCURRENT_MAX_OPEN_FILES)
+        );
+        assert_eq!(config(true, Some(6)).open_file_limit(), Some(6));
+        assert_eq!(config(false, Some(6)).open_file_limit(), Some(6));
+        // Without scalers, nothing is extracted concurrently
+        let no_scalers = Config {
+            extract_scalers: false,
+            ..config(true, None)
+        };
+        assert_eq!(no_scalers.open_file_limit(), None);
+    }
+
+    #[test]
+    fn the_core_driver_conflicts_with_an_output_budget() {
+        let core = Config {
+            hdf5_driver: Some(Hdf5Driver::Core),
+            write_userblock: true,
+            ..Default::default()
+        };
+        core.validate().unwrap();
+        let budgeted = Config {
+            max_output_bytes: Some(1_000_000),
+            ..core.clone()
+        };
+        let error = budgeted.validate().unwrap_err().to_string();
+        assert!(error.contains("max_output_bytes"), "{error}");
+        for driver in [Hdf5Driver::Sec2, Hdf5Driver::Stdio] {
+            let config = Config {
+                hdf5_driver: Some(driver),
+                ..budgeted.clone()
+            };
+            config.validate().unwrap();
+        }
+    }
+
+    #[test]
+    fn concurrent_scalers_conflict_with_early_stops() {
+        let concurrent = Config {
+            concurrent_scalers: true,
+            ..Default::default()
+        };
+        concurrent.validate().unwrap();
+        for config in [
+            Config {
+                max_output_bytes: Some(1_000_000),
+                ..concurrent.clone()
+            },
+            Config {
+                min_free_space_gb: Some(10),
+                ..concurrent.clone()
+            },
+            Config {
+                scaler_output: ScalerOutput::ParquetPerHarmonicRun,
+                ..concurrent.clone()
+            },
+        ] {
+            let error = config.validate().unwrap_err().to_string();
+            assert!(error.contains("concurrent_scalers"), "{error}");
+        }
+        // Each of them is fine when the scalers are extracted afterwards
+        let sequential = Config {
+            min_free_space_gb: Some(10),
+            ..Default::default()
+        };
+        sequential.validate().unwrap();
+    }
+
+    #[test]
+    fn num_output_files_conflicts_with_a_harmonic_size() {
+        let config = Config {
+            num_output_files: Some(3),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        let config = Config {
+            harmonic_size_gb: None,
+            num_output_files: Some(0),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn merger_file_names_follow_the_pattern() {
+        let config = Config {
+            merger_pattern: String::from("run-{run}.hdf5"),
+            merger_run_width: 3,
+            ..Default::default()
+        };
+        assert_eq!(config.merger_run_file_name(7), "run-007.hdf5");
+        assert_eq!(config.merger_run_file_name(1234), "run-1234.hdf5");
+    }
+
+    #[test]
+    fn temp_dir_must_be_a_writable_directory() {
+        let dir = ScratchDir::new("temp_dir");
+        assert_eq!(Config::default().temp_dir(), std::env::temp_dir());
+        let config = Config {
+            temp_dir: Some(dir.path().to_path_buf()),
+            ..Default::default()
+        };
+        config.validate().unwrap();
+        assert_eq!(config.temp_dir(), dir.path());
+
+        let file = dir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        for path in [dir.path().join("missing"), file] {
+            let config = Config {
+                temp_dir: Some(path),
+                ..Default::default()
+            };
+            let error = config.validate().unwrap_err();
+            assert!(error.to_string().contains("does not exist"));
+        }
+    }
+
+    #[test]
+    fn merger_files_are_read_in_order() {
+        let mut config = Config::default();
+        let input = "/data/run_0062.h5\n\n  /other/run_0005.h5  \nrun_0100.h5\n";
+        config.read_merger_files(input.as_bytes()).unwrap();
+        assert_eq!(config.runs(), [62, 5, 100]);
+        assert_eq!((config.min_run, config.max_run), (5, 100));
+        assert_eq!(
+            config.merger_files.as_ref().unwrap()[1],
+            (5, PathBuf::from("/other/run_0005.h5"))
+        );
+
+        for (input, message) in [
+            (
+                "/data/run_0001.h5\n/data/run_0001.h5\n",
+                "listed more than once",
+            ),
+            ("/data/events.h5\n", "Could not parse a run number"),
+            ("\n", "No merger run files"),
+        ] {
+            let error = Config::default()
+                .read_merger_files(input.as_bytes())
+                .unwrap_err();
+            assert!(error.to_string().contains(message), "{error}");
+        }
+    }
+
+    #[test]
+    fn single_file_conflicts_with_the_other_splits() {
+        let single = Config {
+            single_file: true,
+            harmonic_size_gb: None,
+            ..Default::default()
+        };
+        single.validate().unwrap();
+        for config in [
+            Config {
+                harmonic_size_gb: Some(1),
+                ..single.clone()
+            },
+            Config {
+                num_output_files: Some(2),
+                ..single.clone()
+            },
+            Config {
+                pads_per_file: Some(1000),
+                ..single.clone()
+            },
+        ] {
+            let error = config.validate().unwrap_err();
+            assert!(error.to_string().contains("single_file cannot be combined"));
+        }
+    }
+
+    #[test]
+    fn pads_per_file_conflicts_with_the_size_splits() {
+        let pads = Config {
+            pads_per_file: Some(1000),
+            harmonic_size_gb: None,
+            ..Default::default()
+        };
+        pads.validate().unwrap();
+        for config in [
+            Config {
+                harmonic_size_gb: Some(1),
+                ..pads.clone()
+            },
+            Config {
+                num_output_files: Some(2),
+                ..pads.clone()
+            },
+        ] {
+            let error = config.validate().unwrap_err();
+            assert!(error
+                .to_string()
+                .contains("pads_per_file cannot be combined"));
+        }
+    }
+
+    #[test]
+    fn scaler_name_patterns_are_validated() {
+        for (v010, v020) in [("sca_{n}", "scaler_{n}"), ("{n}", "s{n}x")] {
+            let config = Config {
+                scaler_names: ScalerNames {
+                    v010: v010.to_string(),
+                    v020: v020.to_string(),
+                },
+                ..Default::default()
+            };
+            config.validate().unwrap();
+        }
+        for pattern in ["scaler", "{n}_{n}", "scalers/{n}", ""] {
+            let config = Config {
+                scaler_names: ScalerNames {
+                    v020: pattern.to_string(),
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let error = config.validate().unwrap_err().to_string();
+            assert!(error.contains("scaler_names v020"), "{pattern}: {error}");
+        }
+    }
+
+    #[test]
+    fn scaler_events_are_bounded_by_the_config() {
+        for (min, max, expected) in [
+            (None, None, 0..=u32::MAX),
+            (Some(2), None, 2..=u32::MAX),
+            (None, Some(5), 0..=5),
+            (Some(3), Some(3), 3..=3),
+        ] {
+            let config = Config {
+                scaler_event_min: min,
+                scaler_event_max: max,
+                ..Default::default()
+            };
+            assert_eq!(config.scaler_events(), expected);
+            config.validate().unwrap();
+        }
+        let config = Config {
+            scaler_event_min: Some(4),
+            scaler_event_max: Some(3),
+            ..Default::default()
+        };
+        assert!(config.scaler_events().is_empty());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn compress_report_settings_are_validated() {
+        let names: Vec<String> = Config::default()
+            .compress_report_settings
+            .iter()
+            .map(CompressionSetting::name)
+            .collect();
+        assert_eq!(names, ["none", "gzip 1", "gzip 4", "gzip 9", "szip"]);
+
+        let yaml = "merger_path: /merger\nharmonic_path: /harmonic\nmin_run: 0\nmax_run: 1\ncompress_report_settings: [{compression: gzip, level: 3}, {compression: szip}]\n";
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(
+            config.compress_report_settings,
+            [
+                CompressionSetting::new(Compression::Gzip, 3),
+                CompressionSetting::new(Compression::Szip, 0)
+            ]
+        );
+        config.validate().unwrap();
+        for settings in [vec![], vec![CompressionSetting::new(Compression::Gzip, 10)]] {
+            let config = Config {
+                compress_report_settings: settings,
+                ..Default::default()
+            };
+            assert!(config.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn run_remap_is_inverted() {
+        let offset = RunRemap::Offset(1000);
+        assert_eq!(offset.apply(55).unwrap(), 1055);
+        assert_eq!(offset.invert(1055).unwrap(), 55);
+        let map = RunRemap::Map(BTreeMap::from([(55, 2), (56, 1)]));
+        for run in [55, 56] {
+            assert_eq!(map.invert(map.apply(run).unwrap()).unwrap(), run);
+        }
+        assert!(map.apply(57).is_err());
+        assert!(map.invert(3).is_err());
+        assert!(RunRemap::Offset(1).invert(i32::MIN).is_err());
+    }
+
+    #[test]
+    fn run_remap_path_must_be_outside_the_output() {
+        let dir = ScratchDir::new("run_remap_path");
+        let config = Config {
+            harmonic_path: dir.path().join("harmonic"),
+            run_remap: Some(RunRemap::Offset(1000)),
+            run_remap_path: Some(dir.path().join("run_remap.yml")),
+            ..Default::default()
+        };
+        config.validate().unwrap();
+
+        let error = Config {
+            run_remap_path: None,
+            ..config.clone()
+        }
+        .validate()
+        .unwrap_err();
+        assert!(error.to_string().contains("run_remap_path must be set"));
+        let error = Config {
+            run_remap: None,
+            ..config.clone()
+        }
+        .validate()
+        .unwrap_err();
+        assert!(error.to_string().contains("requires run_remap"));
+        for inside in [
+            dir.path().join("harmonic/run_remap.yml"),
+            dir.path().join("other/../harmonic/maps/run_remap.yml"),
+        ] {
+            let error = Config {
+                run_remap_path: Some(inside),
+                ..config.clone()
+            }
+            .validate()
+            .unwrap_err();
+            assert!(error.to_string().contains("is in the output directory"));
+        }
+    }
+
+    #[test]
+    fn dataset_name_patterns_are_validated() {
+        let with_names = |names: DatasetNames010| Config {
+            dataset_names: DatasetNames {
+                v010: names,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(with_names(DatasetNames010::default()).validate().is_ok());
+        for names in [
+            DatasetNames010 {
+                get_traces: String::from("data"),
+                ..Default::default()
+            },
+            DatasetNames010 {
+                frib_header: String::from("evt{n}_{n}"),
+                ..Default::default()
+            },
+            DatasetNames010 {
+                frib_traces: String::from("frib/evt{n}"),
+                ..Default::default()
+            },
+            DatasetNames010 {
+                get_header: String::from("evt{n}_data"),
+                ..Default::default()
+            },
+        ] {
+            assert!(with_names(names).validate().is_err());
+        }
+        assert_eq!(DatasetNames010::name("evt{n}_977", 12), "evt12_977");
+    }
+
+    #[test]
+    fn merger_patterns_need_one_placeholder_and_no_directory() {
+        for pattern in ["run.h5", "run_{run}_{run}.h5", "old/run_{run}.h5"] {
+            let config = Config {
+                merger_pattern: String::from(pattern),
+                ..Default::default()
+            };
+            assert!(config.validate().is_err(), "{pattern} was accepted");
+        }
     }
 }
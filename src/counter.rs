@@ -0,0 +1,154 @@
+//! A run counter file shared between invocations of the harmonizer, so that
+//! successive passes produce globally unique harmonic run numbers.
+use color_eyre::eyre::{eyre, Result};
+use fs4::fs_std::FileExt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// The number of harmonic run numbers reserved from the counter at a time
+const RESERVE_BLOCK: i32 = 10;
+
+/// Harmonic run numbers reserved from a run counter file. The file contains
+/// the next harmonic run number which has not been reserved. The file is
+/// only locked while numbers are reserved or released, so concurrent jobs
+/// sharing a counter each get their own numbers without waiting on each
+/// other for the whole harmonization.
+#[derive(Debug)]
+pub struct RunCounter {
+    path: PathBuf,
+    reserved: Range<i32>,
+}
+
+impl RunCounter {
+    /// Reserve a first block of run numbers from the counter file, creating
+    /// it if it does not exist
+    pub fn reserve(path: &Path) -> Result<Self> {
+        let mut counter = Self {
+            path: path.to_path_buf(),
+            reserved: 0..0,
+        };
+        counter.reserve_block()?;
+        Ok(counter)
+    }
+
+    /// Take the next reserved run number, reserving another block if all of
+    /// the reserved numbers have been used
+    pub fn next_run(&mut self) -> Result<i32> {
+        if self.reserved.is_empty() {
+            self.reserve_block()?;
+        }
+        Ok(self.reserved.next().expect("A block was just reserved"))
+    }
+
+    /// Reserve the next block of run numbers. Blocks until the counter file
+    /// can be locked.
+    fn reserve_block(&mut self) -> Result<()> {
+        let mut file = lock_counter(&self.path)?;
+        let start = read_counter(&mut file)?;
+        let end = start.checked_add(RESERVE_BLOCK).ok_or_else(|| {
+            eyre!(
+                "Run counter {} has run out of run numbers",
+                self.path.display()
+            )
+        })?;
+        write_counter(&mut file, end)?;
+        self.reserved = start..end;
+        Ok(())
+    }
+
+    /// Give back the reserved run numbers which were not used, so that the
+    /// next invocation continues from the given next run. Numbers can only be
+    /// given back if no other job has reserved numbers since; otherwise they
+    /// are left unused.
+    pub fn release(self, next_run: i32) -> Result<()> {
+        let mut file = lock_counter(&self.path)?;
+        if read_counter(&mut file)? == self.reserved.end {
+            write_counter(&mut file, next_run)?;
+        }
+        Ok(())
+    }
+}
+
+/// Open the counter file, creating it if it does not exist, and lock it. The
+/// lock is released when the file is closed.
+fn lock_counter(path: &Path) -> Result<File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    FileExt::lock_exclusive(&file)?;
+    Ok(file)
+}
+
+/// Read the next unreserved run number. An empty (new) counter starts at 0.
+fn read_counter(file: &mut File) -> Result<i32> {
+    let mut contents = String::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_string(&mut contents)?;
+    let contents = contents.trim();
+    if contents.is_empty() {
+        return Ok(0);
+    }
+    contents
+        .parse::<i32>()
+        .map_err(|_| eyre!("Run counter file contains an invalid run number: {contents}"))
+}
+
+/// Store the next unreserved run number.
+fn write_counter(file: &mut File, next_run: i32) -> Result<()> {
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    writeln!(file, "{next_run}")?;
+    file.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ScratchDir;
+
+    fn counter_value(path: &Path) -> String {
+        std::fs::read_to_string(path).unwrap().trim().to_string()
+    }
+
+    #[test]
+    fn unused_runs_are_given_back() {
+        let dir = ScratchDir::new("counter_release");
+        let path = dir.path().join("counter.txt");
+        let mut counter = RunCounter::reserve(&path).unwrap();
+        assert_eq!(counter_value(&path), "10");
+        assert_eq!(counter.next_run().unwrap(), 0);
+        assert_eq!(counter.next_run().unwrap(), 1);
+        counter.release(2).unwrap();
+        assert_eq!(counter_value(&path), "2");
+
+        let mut counter = RunCounter::reserve(&path).unwrap();
+        assert_eq!(counter.next_run().unwrap(), 2);
+    }
+
+    #[test]
+    fn concurrent_counters_reserve_their_own_runs() {
+        let dir = ScratchDir::new("counter_concurrent");
+        let path = dir.path().join("counter.txt");
+        let mut first = RunCounter::reserve(&path).unwrap();
+        // Reserving does not wait for the first counter to be released
+        let mut second = RunCounter::reserve(&path).unwrap();
+        let first_runs: Vec<i32> = (0..12).map(|_| first.next_run().unwrap()).collect();
+        let second_runs: Vec<i32> = (0..3).map(|_| second.next_run().unwrap()).collect();
+        assert_eq!(first_runs[..10], (0..10).collect::<Vec<_>>());
+        assert_eq!(first_runs[10..], [20, 21]);
+        assert_eq!(second_runs, [10, 11, 12]);
+
+        // The second counter has reserved since, so it keeps its numbers
+        second.release(13).unwrap();
+        assert_eq!(counter_value(&path), "30");
+        // The first counter reserved the last block, so it gives it back
+        first.release(22).unwrap();
+        assert_eq!(counter_value(&path), "22");
+    }
+}
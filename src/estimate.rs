@@ -0,0 +1,63 @@
+//! Estimates of the harmonic output of a config, made from the sizes and
+//! event counts of the merger runs without harmonizing anything.
+use super::config::Config;
+use super::reader::{get_total_merger_bytes, read_event_at};
+use color_eyre::eyre::Result;
+
+/// Estimate how many harmonic files harmonizing with the given config
+/// will produce, without any side effects. Harmonic files are about as large
+/// as the merger data they contain, so this is an estimate rather than exact.
+pub fn estimate_output_files(config: &Config) -> Result<usize> {
+    let total_bytes = get_total_merger_bytes(config)?;
+    let harmonic_size = config.get_harmonic_size()?.max(1);
+    // The writer always produces at least one file
+    Ok((total_bytes.div_ceil(harmonic_size) as usize).max(1))
+}
+
+/// Number of events sampled for the average pads of an event
+const PAD_SAMPLES: u64 = 8;
+
+/// Estimate the number of events in a harmonic file from the number of events
+/// in the run range, without any side effects. Files split by size are
+/// estimated from the average size of the events, and files split by pad count
+/// from the average pads of a sample of the events.
+pub fn estimate_events_per_file(config: &Config, total_events: u64) -> Result<u64> {
+    let per_file = if config.single_file {
+        total_events
+    } else if let Some(pads_per_file) = config.pads_per_file {
+        let pads = sample_event_pads(config, total_events)?;
+        if pads > 0.0 {
+            (pads_per_file as f64 / pads).ceil() as u64
+        } else {
+            total_events
+        }
+    } else if let Some(n_files) = config.num_output_files {
+        total_events.div_ceil(n_files as u64)
+    } else {
+        match get_total_merger_bytes(config)? {
+            0 => total_events,
+            total_bytes => (total_events as f64 * config.get_harmonic_size()? as f64
+                / total_bytes as f64)
+                .ceil() as u64,
+        }
+    };
+    let per_file = per_file.clamp(1, total_events.max(1));
+    Ok(match config.max_events_per_file {
+        Some(max_events) => per_file.min(max_events),
+        None => per_file,
+    })
+}
+
+/// The average number of GET pads of an event, from a sample of the events
+/// spread evenly over the run range
+fn sample_event_pads(config: &Config, total_events: u64) -> Result<f64> {
+    let samples = PAD_SAMPLES.min(total_events);
+    let first = config.event_start.unwrap_or(0);
+    let mut pads = 0;
+    for sample in 0..samples {
+        if let Some(event) = read_event_at(config, first + sample * total_events / samples)? {
+            pads += event.get_pads();
+        }
+    }
+    Ok(pads as f64 / samples.max(1) as f64)
+}
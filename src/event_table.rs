@@ -0,0 +1,75 @@
+//! A table of the metadata of every harmonic event, written as parquet
+//! so that the dataset can be queried without opening the HDF5 files.
+use super::reader::MergerEvent;
+use color_eyre::eyre::Result;
+use polars::prelude::*;
+use std::path::Path;
+
+/// The name of the event metadata table in the harmonic directory
+pub const EVENT_TABLE_NAME: &str = "events.parquet";
+
+/// Accumulates one row of metadata per harmonic event
+#[derive(Debug, Default)]
+pub struct EventTable {
+    harmonic_run: Vec<i32>,
+    local_event: Vec<u64>,
+    orig_run: Vec<i32>,
+    orig_event: Vec<u64>,
+    get_present: Vec<bool>,
+    frib_present: Vec<bool>,
+    get_timestamp: Vec<Option<u64>>,
+    frib_timestamp: Vec<Option<u32>>,
+    trace_rows: Vec<u64>,
+    get_pads: Vec<Option<u64>>,
+}
+
+impl EventTable {
+    /// Add the row of an event written as event_{local_event} of a harmonic run
+    pub fn push(&mut self, harmonic_run: i32, local_event: u64, event: &MergerEvent) {
+        self.harmonic_run.push(harmonic_run);
+        self.local_event.push(local_event);
+        self.orig_run.push(event.run_number);
+        self.orig_event.push(event.event);
+        self.get_present.push(event.get.is_some());
+        self.frib_present.push(event.frib.is_some());
+        self.get_timestamp
+            .push(event.get.as_ref().map(|get| get.timestamp));
+        self.frib_timestamp
+            .push(event.frib.as_ref().map(|frib| frib.timestamp));
+        self.trace_rows.push(
+            event
+                .get
+                .as_ref()
+                .map_or(0, |get| get.traces.nrows() as u64),
+        );
+        self.get_pads
+            .push(event.get.as_ref().map(|get| get.traces.nrows() as u64));
+    }
+
+    /// Write the table to a parquet file. If merging, the rows are added
+    /// after those of an existing table.
+    pub fn write(self, path: &Path, merge: bool) -> Result<()> {
+        let mut frame = DataFrame::new(vec![
+            Column::new("harmonic_run".into(), self.harmonic_run),
+            Column::new("local_event".into(), self.local_event),
+            Column::new("orig_run".into(), self.orig_run),
+            Column::new("orig_event".into(), self.orig_event),
+            Column::new("get_present".into(), self.get_present),
+            Column::new("frib_present".into(), self.frib_present),
+            Column::new("get_timestamp".into(), self.get_timestamp),
+            Column::new("frib_timestamp".into(), self.frib_timestamp),
+            Column::new("trace_rows".into(), self.trace_rows),
+            Column::new("get_pads".into(), self.get_pads),
+        ])?;
+
+        if merge && path.exists() {
+            let existing = ParquetReader::new(std::fs::File::open(path)?).finish()?;
+            frame = existing.vstack(&frame)?;
+        }
+
+        let mut parquet_file = std::fs::File::create(path)?;
+        ParquetWriter::new(&mut parquet_file).finish(&mut frame)?;
+
+        Ok(())
+    }
+}
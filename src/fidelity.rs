@@ -0,0 +1,135 @@
+//! Verification of the fidelity of harmonized data. Harmonic events are
+//! read back and compared to the merger events they came from (found with
+//! their orig_run and orig_event), which catches read or write bugs that
+//! event counts cannot.
+use super::combine::read_harmonic_event_at;
+use super::config::Config;
+use super::reader::{read_run_event, MergerEvent};
+use super::transform::{apply_transforms, pad_traces};
+use super::validate::find_harmonic_files;
+use color_eyre::eyre::Result;
+use hdf5_metno::File;
+use std::path::PathBuf;
+
+/// A harmonic event which does not match its merger event
+#[derive(Debug, Clone)]
+pub struct FidelityMismatch {
+    pub path: PathBuf,
+    pub event: u64,
+    pub orig_run: i32,
+    pub orig_event: u64,
+    pub reason: String,
+}
+
+/// Describe how a harmonic event differs from its merger event, if it does.
+/// The traces must be bit-identical.
+fn compare_events(harmonic: &MergerEvent, merger: &MergerEvent) -> Option<String> {
+    match (&harmonic.get, &merger.get) {
+        (Some(harmonic), Some(merger)) => {
+            if harmonic.traces != merger.traces {
+                return Some(format!(
+                    "GET traces differ (shapes {:?} and {:?})",
+                    harmonic.traces.shape(),
+                    merger.traces.shape()
+                ));
+            }
+            if harmonic.timestamp != merger.timestamp {
+                return Some(String::from("GET timestamps differ"));
+            }
+            if harmonic.ok != merger.ok {
+                return Some(String::from("GET quality flags differ"));
+            }
+        }
+        (None, None) => (),
+        _ => return Some(String::from("GET data is only present in one of them")),
+    }
+    match (&harmonic.frib, &merger.frib) {
+        (Some(harmonic), Some(merger)) => {
+            if harmonic.traces != merger.traces {
+                return Some(String::from("FRIB traces differ"));
+            }
+            if harmonic.ok != merger.ok {
+                return Some(String::from("FRIB quality flags differ"));
+            }
+        }
+        (None, None) => (),
+        _ => return Some(String::from("FRIB data is only present in one of them")),
+    }
+    None
+}
+
+/// Transform and pad the GET traces of a merger event as the writer did, so
+/// that they can be compared with the harmonic traces
+fn prepare_merger_event(config: &Config, merger: &mut MergerEvent) {
+    if let Some(get) = merger.get.as_mut() {
+        get.traces = apply_transforms(std::mem::take(&mut get.traces), &config.trace_transforms);
+        if let Some(length) = config.pad_traces_to {
+            get.traces = pad_traces(std::mem::take(&mut get.traces), length, config.pad_fill);
+        }
+    }
+}
+
+/// Compare harmonic events to their merger events. With a sample size, only
+/// about that many events spread evenly over the harmonic data are compared,
+/// otherwise every event is. Returns the number of events compared and the
+/// mismatches found.
+pub fn verify_fidelity(
+    config: &Config,
+    sample: Option<u64>,
+) -> Result<(u64, Vec<FidelityMismatch>)> {
+    let mut files = Vec::new();
+    for path in config
+        .output_dirs()
+        .iter()
+        .map(|dir| find_harmonic_files(dir))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .flatten()
+    {
+        let n_events = File::open(&path)?
+            .group("events")?
+            .attr("max_event")?
+            .read_scalar::<u64>()?;
+        files.push((path, n_events));
+    }
+    let total: u64 = files.iter().map(|(_, n_events)| n_events).sum();
+    let stride = match sample {
+        Some(sample) => (total / sample.max(1)).max(1),
+        None => 1,
+    };
+
+    let mut checked = 0;
+    let mut mismatches = Vec::new();
+    let mut index = 0;
+    for (path, n_events) in files {
+        let events_group = File::open(&path)?.group("events")?;
+        for event in 0..n_events {
+            let sampled = index % stride == 0;
+            index += 1;
+            if !sampled {
+                continue;
+            }
+            let harmonic = read_harmonic_event_at(&events_group, event)?;
+            // Remapped runs are read from the merger run they came from
+            let merger_run = config.unmap_run(harmonic.run_number)?;
+            let reason = match read_run_event(config, merger_run, harmonic.event)? {
+                Some(mut merger) => {
+                    prepare_merger_event(config, &mut merger);
+                    compare_events(&harmonic, &merger)
+                }
+                None => Some(String::from("The merger event does not exist")),
+            };
+            checked += 1;
+            if let Some(reason) = reason {
+                mismatches.push(FidelityMismatch {
+                    path: path.clone(),
+                    event,
+                    orig_run: harmonic.run_number,
+                    orig_event: harmonic.event,
+                    reason,
+                });
+            }
+        }
+    }
+    Ok((checked, mismatches))
+}
@@ -0,0 +1,94 @@
+//! A human readable note describing a harmonization, written into the
+//! harmonic directory alongside the data.
+use super::config::Config;
+use super::stats::HarmonizeStats;
+use color_eyre::eyre::Result;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The name of the info file in the harmonic directory
+pub const INFO_FILE_NAME: &str = "HARMONIZATION_INFO.txt";
+
+/// Write the info file describing the harmonization with the given config,
+/// which produced the given statistics. When appending to a harmonic dataset
+/// with an info file, a note of the appended events is added to it instead.
+pub fn write_info_file(config: &Config, stats: &HarmonizeStats) -> Result<()> {
+    let path = config.harmonic_path.join(INFO_FILE_NAME);
+    if config.append && path.exists() {
+        return append_info_note(&path, config, stats);
+    }
+    let created = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let (source, run_range) = describe_source(config);
+    let text = format!(
+        "AT-TPC Harmonized Data
+======================
+
+This directory contains AT-TPC data harmonized by {name} version {version}.
+
+Created (unix seconds): {created}
+Source merger data: {source}
+Merger run range: {run_range}
+Gas/beam: {gas_beam}
+Events written: {events}
+Harmonic runs produced: {files}
+
+The harmonic runs (run_#.h5) follow the 0.2.0 attpc_merger format, with the
+following differences:
+
+- The scalers are not stored in the harmonic runs. They are combined into
+  a single file for the whole run range (scalers.parquet or scalers.h5).
+- Events are renumbered continuously within each harmonic run. Each event
+  group has orig_run and orig_event attributes giving the merger run and
+  event number it came from.
+- The version attribute of the events group gives the harmonizer version.
+",
+        name = env!("CARGO_PKG_NAME"),
+        version = env!("CARGO_PKG_VERSION"),
+        gas_beam = config.gas_beam.as_deref().unwrap_or("unspecified"),
+        events = stats.events_written,
+        files = stats.files_produced,
+    );
+    std::fs::write(path, text)?;
+    Ok(())
+}
+
+/// The source of the merger data and the run range read, as written in the
+/// info file
+fn describe_source(config: &Config) -> (String, String) {
+    let source = match &config.merger_archive {
+        Some(archive) => archive.display().to_string(),
+        None => config.merger_path.display().to_string(),
+    };
+    // The real run numbers are not given for remapped (pseudonymized) data
+    let run_range = match config.run_remap {
+        Some(_) => String::from("withheld (the runs are remapped)"),
+        None => format!("{} to {} (inclusive)", config.min_run, config.max_run),
+    };
+    (source, run_range)
+}
+
+/// Add a note of a harmonization which appended to the dataset to the end of
+/// its info file
+fn append_info_note(path: &Path, config: &Config, stats: &HarmonizeStats) -> Result<()> {
+    let appended = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let (source, run_range) = describe_source(config);
+    let mut file = OpenOptions::new().append(true).open(path)?;
+    write!(
+        file,
+        "
+Appended by {name} version {version}
+Appended (unix seconds): {appended}
+Source merger data: {source}
+Merger run range: {run_range}
+Events written: {events}
+Harmonic runs produced: {files}
+",
+        name = env!("CARGO_PKG_NAME"),
+        version = env!("CARGO_PKG_VERSION"),
+        events = stats.events_written,
+        files = stats.files_produced,
+    )?;
+    Ok(())
+}
@@ -26,6 +26,8 @@
 //!
 //! This will install the harmonizer executable to your cargo installs and it will be available on your path as `harmonizer`.
 //!
+//! The tests are run with `cargo test`. The benchmarks (for example the effect of `sort_by` on the output size) are ignored by default, and are run with `cargo test --release --test benchmarks -- --ignored --nocapture`.
+//!
 //! ## Use
 //!
 //! The harmonizer uses the following CLI:
@@ -40,24 +42,270 @@
 //! harmonizer --config/-c /path/to/some/config.yml new
 //! ```
 //!
+//! If event harmonization succeeded but scaler extraction failed (for example due to a transient I/O error), the scalers can be extracted on their own with the `scalers` command, without rerunning the harmonization. The `scalers` command is also the way to get only the combined scalers of a run range when the event data is not needed: it reads nothing but the scalers of the merger runs and needs no harmonic output, and the `harmonic_path` is created if it does not exist yet.
+//!
+//! ```txt
+//! harmonizer --config/-c /path/to/some/config.yml scalers
+//! ```
+//!
+//! This uses the same configuration. To avoid accidentally clobbering a good scaler file, it fails if the scaler output (`scalers.parquet`, `scalers.h5`, or a `scalers_run_#.parquet`) already exists in the `harmonic_path`, unless `--overwrite-scalers` is given:
+//!
+//! ```txt
+//! harmonizer --config/-c /path/to/some/config.yml scalers --overwrite-scalers
+//! ```
+//!
+//! A full harmonization always overwrites the scaler output. With a `run_remap`, the `scalers` command also writes the remapping to the `run_remap_path`.
+//!
+//! After harmonizing, the harmonic data can be checked for lost (or duplicated) events with the `validate` command.
+//!
+//! ```txt
+//! harmonizer --config/-c /path/to/some/config.yml validate
+//! ```
+//!
+//! For each merger run in the run range, this compares the number of events in the run (respecting `max_events_per_source_run`, and less its blacklisted events and, with `drop_bad_events`, its bad events) to the number of harmonic events whose `orig_run` is that run, across all of the harmonic runs (`run_#.h5`) in the `harmonic_path` and its `src_#` subdirectories. Every run with a mismatch is reported, as are harmonic events from runs outside of the range, and the command fails if there are any. If harmonization stopped early (at `max_output_bytes` or `min_free_space_gb`), this is recorded in `stopped_early.yml` in the `harmonic_path` with the last merger run which was read (its remapped number, with a `run_remap`), and `validate` says so and only expects the runs up to that one; as it may have been cut short, the last run only needs some of its events. A harmonization which runs to the end of its run range removes the record. The `harmonic_path` should only contain the harmonic runs of this configuration.
+//!
+//! Event counts do not catch events whose contents were corrupted. For the strongest check, the `verify-fidelity` command reads harmonic events back and compares each to the merger event it came from (found with its `orig_run` and `orig_event`): the GET and FRIB traces must be bit-identical (after applying the configured `trace_transforms` and `pad_traces_to` to the merger traces), and the GET timestamps equal.
+//!
+//! ```txt
+//! harmonizer --config/-c /path/to/some/config.yml verify-fidelity --sample 1000
+//! ```
+//!
+//! With `--sample N`, about `N` events spread evenly over the harmonic data are checked; without it every event is checked, which reads all of the data twice. Every mismatch is reported with its harmonic file, event, and origin, and the command fails if there are any.
+//!
+//! To inspect a specific event (for example one referenced in a bug report), the `get` command prints a single event by its index over the whole run range, counting from 0 in the order the harmonizer reads the events. Only the event itself is read.
+//!
+//! ```txt
+//! harmonizer --config/-c /path/to/some/config.yml get --index 12345
+//! ```
+//!
+//! The event's `orig_run` and `orig_event`, its GET and FRIB timestamps, and the shapes of its traces are printed.
+//!
+//! Datasets harmonized separately (for example two run ranges) can be joined into a single dataset with the `combine` command, without re-reading the merger data. It takes the harmonic directories to combine, followed by the output directory, which must exist.
+//!
+//! ```txt
+//! harmonizer --config/-c /path/to/some/config.yml combine /data/harmonic_a /data/harmonic_b /data/harmonic_ab
+//! ```
+//!
+//! The events of the harmonic runs of each directory (including its `src_#` subdirectories) are read back in order and written to the output directory with the writer settings of the configuration (`harmonic_size_gb`, `pack_event_metadata`, and so on), renumbered continuously. Each event keeps its `orig_run` and `orig_event`. The `scalers.parquet` files of the directories are stacked in the same order into the output directory; directories without one are skipped with a warning. The merger settings of the configuration are not used.
+//!
+//! To pick a compression setting, the `compress-report` command harmonizes the first events of the run set (1000 by default, or `--events N`) under each of the `compress_report_settings` of the configuration (by default `none`, `gzip` at levels 1, 4 and 9, and `szip`), each into a single scratch file in the `temp_dir`, and prints a table of the resulting size and write time. The scratch files are removed afterwards. A setting which fails (such as `szip` without szip support) is reported as failed. Each of the `compress_report_settings` is a `compression` and a `level` (from 0 to 9, only used by `gzip`, and 0 if left out), for example `compress_report_settings: [{compression: gzip, level: 2}, {compression: gzip, level: 6}]`; the list cannot be empty.
+//!
+//! ```txt
+//! harmonizer --config/-c /path/to/some/config.yml compress-report --events 5000
+//! ```
+//!
+//! The following optional flags are also available:
+//!
+//! - `--strict`: enable strict data-integrity checks (see the `strict` configuration setting)
+//! - `--no-scalers`: skip the scaler extraction after harmonizing, so that the output has no `scalers.parquet` (see the `extract_scalers` configuration setting)
+//! - `--profile`: at the end, print a breakdown of the wall time spent counting events, reading events, writing events, and extracting scalers
+//! - `--preflight`: before committing to a long harmonization, open every run in the range, detect its merger version, and read its first event, then exit. A table of run, status (`OK`, `MISSING` or `ERROR`) and the merger version or error is printed, and the harmonizer exits with an error if any existing run could not be read. Missing runs are reported but are not an error, as they are skipped during harmonization. Runs are checked in parallel.
+//! - `--estimate-remaining`: before resuming an interrupted harmonization, estimate how much work remains, then exit. The resume point is the merger run and event of the last event of the highest numbered harmonic run in the harmonic output (in `harmonic_path` and any `harmonic_paths`). The events of the run range read after it (following `reverse` and `max_events_per_source_run`, and within `event_start` and `event_end`) are counted, and their size, and the number of harmonic runs they would fill, are estimated from the average event size of the run range. Without any harmonic output, the whole run range remains. The harmonizer does not itself resume from this point: narrow the run range (e.g. `min_run`) to continue from it. Remapped runs (`run_remap`) cannot be estimated.
+//! - `--print-config`: print the effective configuration (the configuration file with the defaults filled in and the command line flags applied) as YAML, and exit without harmonizing. With `--continue` as well, the harmonizer carries on after printing it, with the harmonization or the given subcommand, so that the exact configuration of a job can be captured alongside its output for reproducibility.
+//! - `--summary-json PATH`: when harmonization completes, also write its outcome as JSON to `PATH`, for pipelines which would otherwise parse the printed output. The summary has `events_written`, `files_produced`, `bytes_written`, `runs_skipped` (runs which were missing or had all of their events blacklisted or dropped), `runs_capped` (runs cut short by `max_events_per_source_run`), `frib_events_unpaired` (0.1.0 FRIB events dropped by `match_frib_by_event_id`), `output_budget_reached`, `free_space_exhausted`, `duration_seconds`, the per phase `timings_seconds` (`counting`, `reading`, `writing`, `scalers`), and a `reconciliation` of the events counted before harmonizing (`events_counted`, after the per run cap) against their fates (`events_written`, `events_blacklisted`, `events_dropped_bad`, and `events_capped` for reference). `events_unaccounted` is the counted events which were neither written nor dropped, and is 0 unless harmonization stopped early (see `max_output_bytes` and `min_free_space_gb`).
+//! - `--stdin`: read the merger run files from stdin, one path per line, instead of looking them up in `merger_path` over the run range. The runs are read in the order they are listed, and the run number of each file (used for `orig_run`) is parsed from its file name using `merger_pattern`. `min_run` and `max_run` are ignored, and a run can only be listed once. For example `find /data/merger -name "run_00[5-6]*.h5" | sort | harmonizer -c config.yml --stdin`. This cannot be combined with `merger_archive`.
+//!
 //! ### Configuration
 //!
 //! Configurations are defined as the following YAML:
 //!
 //! ```yaml
 //! merger_path: "/path/to/some/merger/data/"
+//! merger_paths: []
+//! duplicate_runs: error
+//! merger_archive: null
+//! temp_dir: null
 //! harmonic_path: "/path/to/some/harmonic/data/"
+//! harmonic_paths: []
 //! harmonic_size_gb: 10
+//! num_output_files: null
+//! single_file: false
+//! pads_per_file: null
+//! split_on_run_boundary: false
+//! min_events_per_file: 0
+//! max_events_per_file: null
+//! max_output_bytes: null
 //! min_run: 55
 //! max_run: 69
+//! gas_beam: null
+//! merger_pattern: "run_{run}.h5"
+//! merger_run_width: 4
+//! dataset_names:
+//!   get_traces: get_traces
+//!   frib_physics: frib_physics
+//!   frib_traces: "1903"
+//!   frib_coincidence: "977"
+//!   v010:
+//!     get_traces: "evt{n}_data"
+//!     get_header: "evt{n}_header"
+//!     frib_traces: "evt{n}_1903"
+//!     frib_coincidence: "evt{n}_977"
+//!     frib_header: "evt{n}_header"
+//! match_frib_by_event_id: false
+//! on_missing_attr: error
+//! require_version: any
+//! size_policy: at_least
+//! size_tolerance_bytes: 0
+//! max_buffered_events: 100
+//! max_events_per_source_run: null
+//! event_start: null
+//! event_end: null
+//! reverse: false
+//! sort_by: null
+//! sort_window: 1000
+//! run_remap: null
+//! run_remap_path: null
+//! blacklist_path: null
+//! drop_bad_events: false
+//! progress_state_path: null
+//! progress_total_events: null
+//! progress_update_events: 1000
+//! extract_scalers: true
+//! concurrent_scalers: false
+//! scaler_output: parquet
+//! scaler_columns_include: null
+//! scaler_order:
+//!   v010: [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10]
+//!   v020: [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10]
+//! scaler_names:
+//!   v010: "scaler{n}_data"
+//!   v020: "event_{n}"
+//! scaler_schema: {}
+//! scaler_row_group_size: null
+//! scaler_write_retries: 0
+//! scaler_event_min: null
+//! scaler_event_max: null
+//! warn_timestamp_overlap: true
+//! strict: false
+//! check_trace_widths: false
+//! events_per_block: null
+//! write_written_at: false
+//! write_harmonic_run: false
+//! pack_event_metadata: false
+//! write_source_runs: false
+//! trace_transforms: []
+//! pad_traces_to: null
+//! pad_fill: 0
+//! transpose_traces: false
+//! compression: none
+//! compression_level: 4
+//! compress_report_settings:
+//!   - compression: none
+//!     level: 0
+//!   - compression: gzip
+//!     level: 1
+//!   - compression: gzip
+//!     level: 4
+//!   - compression: gzip
+//!     level: 9
+//!   - compression: szip
+//!     level: 0
+//! trace_layout: array
+//! event_storage: groups
+//! stacked_padding: false
+//! exclude_frib_coincidence: false
+//! min_free_space_gb: null
+//! free_space_check_interval: 1
+//! output_layout: flat
+//! overwrite: error
+//! append: false
+//! run_counter_file: null
+//! chunk_cache: null
+//! hdf5_alignment: null
+//! hdf5_driver: null
+//! libver: null
+//! hdf5_file_locking: true
+//! lock_retries: 0
+//! open_timeout_secs: null
+//! max_open_files: null
+//! write_userblock: false
+//! version_format: combined
+//! write_producer_attrs: false
+//! write_target_event_count: false
+//! verify_output: false
+//! write_event_metadata: false
+//! write_info_file: true
 //! ```
 //!
 //! Some important notes:
 //!
+//! - Unknown keys are an error. A typo such as `harmonik_size_gb` will cause the configuration to fail to load, naming the offending key.
 //! - The path given as the `harmonic_path` must exist before running the harmonizer
+//! - `merger_paths` is a list of additional directories to look for merger runs in, after `merger_path`, for datasets split across several disks or mount points. Each run is read from whichever directory has it. If a run of the run range is found in more than one of the directories, every copy of it is reported when the harmonizer starts, and `duplicate_runs` decides what happens: with `error` (the default) the harmonizer stops, rather than silently read the wrong copy; with `first` or `last` the copy in the first or last directory (in the order `merger_path`, then `merger_paths`) is read; with `largest` the largest copy is read. `merger_paths` cannot be combined with `merger_archive`, and is ignored for runs listed with `--stdin`. These settings are optional and default to no additional directories and `error`.
+//! - If `merger_archive` is set to the path of a tar archive (optionally gzip compressed, with a `.gz` or `.tgz` extension), the merger runs are read out of the archive instead of `merger_path`. Each run is extracted to a temporary file in the temporary directory (see `temp_dir`) when it is needed, and the temporary file is removed once the run is done, so only one run at a time needs to fit on disk. Members are matched by file name (using `merger_pattern`), ignoring any directories within the archive. The archive is read through once, when it is first needed, to index its members. Each extraction then reads only the requested run out of an uncompressed tar, while a gzip compressed archive has to be decompressed up to the requested run (gzip streams cannot be seeked), so an uncompressed tar is much faster to read. This setting is optional and unused by default.
+//! - `temp_dir` is the directory in which the harmonizer creates temporary files, such as runs extracted from the `merger_archive`. Point it at fast local storage (for example an NVMe scratch disk) when the system temporary directory is small or slow. The directory must exist and be writable; this is checked when the configuration is loaded. This setting is optional and defaults to the system temporary directory.
+//! - `harmonic_paths` is a list of additional output directories, to spread the write load over several disks or mount points. The harmonic runs are written to `harmonic_path` and each of the `harmonic_paths` in turn (the first harmonic run to `harmonic_path`, the second to the first of `harmonic_paths`, and so on, cycling). Everything else (the scalers, `events.parquet`, the info file) is written to `harmonic_path`, along with `harmonic_run_paths.csv`, a table of the path each harmonic run was written to (relative to `harmonic_path` for the runs in it, otherwise absolute). The directories must exist and be writable. The `validate` and `verify-fidelity` commands look in all of the directories. The `overwrite` policy also applies across them: a harmonic run which already exists in any of the directories is an error, skipped, or overwritten (and the old copy removed, wherever it is). This cannot be combined with `append`. This setting is optional and defaults to no additional directories.
 //! - The harmonic size is given in units of GB. This is the size of a harmonic run.
+//! - The `size_policy` controls how the harmonic size bounds a harmonic run. With `at_least` (the default), a harmonic run is finished once it reaches the harmonic size, so each run slightly overshoots it by (at most) one event. With `at_most`, the size of each incoming event is estimated from its traces and a new harmonic run is started *before* writing an event that would push the current run past the harmonic size. HDF5 overhead makes the estimate approximate, and a single event larger than the harmonic size still gets a run of its own. The size is checked *after* each event is written with `at_least` and *before* each event with `at_most`, so `after` and `before` are accepted as aliases of them. With `at_most`, `size_tolerance_bytes` lets a harmonic run exceed the harmonic size by up to that many bytes before a new run is started, so that a run which is nearly full still takes an event which only slightly overshoots it (with `at_least` it has no effect). `size_policy` is optional and defaults to `at_least`, and `size_tolerance_bytes` is optional and defaults to 0.
+//! - Alternatively, `num_output_files` can be given instead of `harmonic_size_gb` to split the data evenly into (roughly) that many harmonic runs. The harmonic size is then the total size of the merger data divided by `num_output_files`. The last harmonic run may be smaller than the others, and HDF5 overhead means the number of files produced can differ slightly from the request. Exactly one of `harmonic_size_gb` and `num_output_files` must be set; setting both (or neither) is an error.
+//! - If `single_file` is true, splitting is disabled and all of the data is written to a single harmonic run (`run_0000.h5`, or the first run of the `run_counter_file`), regardless of its size. This is useful for quickly consolidating small datasets. It cannot be combined with the other ways of splitting the harmonic runs, so `harmonic_size_gb`, `num_output_files` and `pads_per_file` must be left unset. This setting is optional and defaults to false.
+//! - `pads_per_file` is an *experimental* alternative to splitting by size. When it is set, harmonic runs are split by their cumulative number of active GET pads (the number of rows of the GET traces, summed over the events) instead of by bytes, following the `size_policy` as usual. Sparse events are cheap to analyze, so the pad count is a heuristic that approximates the compute cost of a harmonic run better than its size does, giving a more even analysis load. The number of harmonic runs is not estimated beforehand. It cannot be combined with the other ways of splitting the harmonic runs, so `harmonic_size_gb`, `num_output_files` and `single_file` must be left unset. This setting is optional and unused by default.
+//! - If `split_on_run_boundary` is true, a harmonic run is also finished whenever the source run changes, so that no harmonic run contains events from more than one merger run (for example to simplify per-run systematics). Large merger runs are still split by the harmonic size. This works against the purpose of the harmonizer: every small merger run (and the tail of every large one) becomes its own small harmonic run, so the harmonic runs are no longer balanced for parallel analysis. `min_events_per_file` does not apply across a run boundary. This setting is optional and defaults to false.
+//! - `min_events_per_file` is the number of events written to each harmonic run before its size limit (`harmonic_size_gb`, or `pads_per_file`) is checked at all. HDF5 buffers writes, so the size of a file on disk right after its first events can be misleading; with a minimum, these tiny startup sizes cannot cause a premature split. Every harmonic run except the last then has at least this many events, even if that takes it past the harmonic size. `max_events_per_file` still applies, and cannot be less than the minimum. This setting is optional and defaults to 0 (the size is checked after every event).
+//! - `max_events_per_file` is a hard limit on the number of events in a harmonic run, in addition to the harmonic size (or `pads_per_file`): a harmonic run is finished as soon as either limit is reached, so no harmonic run ever has more than `max_events_per_file` events (for example for downstream code with fixed size arrays). This setting is optional and unused by default.
+//! - `max_output_bytes` is a budget for the total size of the harmonic runs, in bytes, for example to fill fixed-size media for a transfer. Before each event is written (with its traces as they will be written, after the `trace_transforms` and `pad_traces_to`), the size of the finished harmonic runs and of the current one is checked; if the event would take the output past the budget, harmonization stops cleanly: the current harmonic run is finished, and the scalers are extracted only for the merger runs up to the last one which contributed events. The remaining events are not read. The HDF5 metadata written when a harmonic run is finished is not counted in advance, so leave a small margin. This setting is optional and unused by default.
 //! - Min run and max run are the range of run numbers (*merger run numbers*) to be harmonized. The range is inclusive; run numbers can be missing in the range.
 //! - The harmonizer should **only ever be run on a set of runs from the same gas and beam combination**. If your range includes multiple gas/beams it will mix them together and it will become very difficult to disentangle these datasets.
+//! - The `merger_pattern` is the filename of a merger run, where `{run}` is replaced by the run number zero-padded to `merger_run_width` digits. For example, legacy exports named `run-0055.h5` or `0055.h5` can be read with the patterns `"run-{run}.h5"` or `"{run}.h5"`. The pattern must contain exactly one `{run}` and must not contain a path separator. These settings are optional and default to the standard merger naming (`run_0055.h5`).
+//! - `dataset_names` sets the names the harmonizer looks for within each event group of a 0.2.0 merger file, for experiments whose merger names them differently (for example `get` instead of `get_traces`). `get_traces` is the GET traces dataset, `frib_physics` the FRIB group, and `frib_traces` and `frib_coincidence` the FRIB `1903` and `977` datasets within it. Any name can be left out to use its default. The names of the 0.1.0 format, whose datasets are named by event number, are set under `v010` as patterns in which `{n}` is replaced by the event number: `get_traces` and `get_header` are the GET traces and header datasets in the `get` group, and `frib_traces`, `frib_coincidence` and `frib_header` the FRIB `1903`, `977` and header datasets in the `frib/evt` group. Each pattern must contain `{n}` exactly once, and the patterns of a group must differ. The harmonic output always uses the standard names. This setting is optional and defaults to the standard merger names.
+//! - Some mergers store the GET traces channel-major, with shape (column, pad) rather than (pad, column). A merger GET traces dataset with an `orientation` attribute of `transposed` or `channel_major` is transposed back when it is read, so that the harmonic output (and everything else) always sees a row per pad, regardless of how the merger stored it; any other orientation is read as is. The harmonic output is written in the orientation set by `transpose_traces`. Harmonic files written with `transpose_traces` are read back the same way by the `combine` and `verify-fidelity` commands.
+//! - In the 0.1.0 merger format the GET and FRIB data of an event are stored under the same event index. If the two systems are numbered differently in a file, the FRIB data of an event is silently missed. If `match_frib_by_event_id` is true, the FRIB data of an event is instead the FRIB event whose header event id matches the GET event id, regardless of its index. FRIB events with a duplicated event id, and FRIB events which are never paired with a GET event (for example as their GET event is missing), are reported as warnings and are not written; their number is printed at the end of harmonization, and is `frib_events_unpaired` in the `--summary-json` summary. This setting is optional and defaults to false; it has no effect on the 0.2.0 format.
+//! - `on_missing_attr` controls what happens when the `id` or `timestamp_other` attribute of a GET traces dataset is missing from a 0.2.0 merger file, as some merger versions do not write them. With `error` (the default) the harmonizer stops with an error naming the dataset. With `default` the attribute is written with the value 0. With `skip` the attribute is left out of the harmonic output. This setting is optional and defaults to `error`.
+//! - `require_version` enforces that every run in the range has the same merger version, to catch the accidental inclusion of, for example, a reprocessed run. With `any` (the default) runs of both versions are accepted and can be mixed. With `v010` or `v020` the harmonizer stops with an error naming the run as soon as a run of the other version is opened. The `--preflight` check reports such runs as errors, so a whole range can be checked beforehand. This setting is optional and defaults to `any`.
+//! - GET traces are normally stored by the merger as a 2-D integer array. Traces stored as an HDF5 compound type (for example a sample and a set of flags per element) are also accepted, as long as the compound has a `sample` field: the samples are extracted into a regular 2-D array, and the other fields are not written to the harmonic output.
+//! - Some early 0.2.0 merger files have no `min_event` attribute on their `events` group, only `max_event`. Their events are assumed to start at 0, with a warning.
+//! - Events are read on a separate thread from the one writing them. `max_buffered_events` is the maximum number of events which have been read but not yet written; once it is reached the reader waits for the writer. Memory use scales with this setting: a typical AT-TPC event is on the order of a MB, so the default of 100 buffers roughly 100 MB. Lower it on memory constrained nodes. A value of 0 means the reader hands each event directly to the writer.
+//! - `max_events_per_source_run` caps how many events any single merger run contributes, so that one very large run does not dominate the harmonized data. Once a run has contributed that many events, the rest of its events are skipped and the harmonizer moves on to the next run, reporting how many events were skipped. The total number of capped events, and of runs cut short, is reported at the end. This setting is optional and unused by default.
+//! - `event_start` and `event_end` restrict harmonization to a slice of the events, for reprocessing part of a large dataset: only the events whose global index (their position over the whole run range, counting from 0 in the order the harmonizer reads them, as for the `get` command) is at least `event_start` and less than `event_end` are harmonized. The reader seeks directly to `event_start`, reading only the layout of the runs before it, and stops at `event_end`. The `orig_run` and `orig_event` of each event are those of the merger event, as usual, and the harmonic events are numbered from 0. The `validate` command and `--estimate-remaining` only count the events of the slice, so a slice validates on its own, with the runs cut by it expected to hold just their events in the slice. Either setting can be left out, to start at the first event or end after the last one. `event_start` must be less than `event_end`, and an `event_start` past the last event is an error. These settings are optional and default to null (all events).
+//! - If `reverse` is true, events are emitted latest first: the runs are read from `max_run` down to `min_run` (or, with `--stdin`, in the reverse of the order they are listed), and the events of each run from its `max_event` down to its `min_event`. The `orig_run` and `orig_event` of each event are unchanged. Everything which follows the reading order follows the reversed order: the per run cap `max_events_per_source_run` keeps the latest events of a run, the strict mode order check expects decreasing event numbers, the global index of the `get` command counts from the last event, and `max_output_bytes` keeps the latest runs. The `warn_timestamp_overlap` check is not done in reverse. This setting is optional and defaults to false.
+//! - `sort_by: size` is an *experimental* mode which buffers `sort_window` events and writes them ordered by the size of their traces, which can improve compression locality. This **breaks the time ordering** of events within a harmonic run; use the `orig_run` and `orig_event` attributes to recover it. These settings are optional; by default events are written in the order they are read.
+//! - `run_remap` renumbers the runs in the output, for sharing pseudonymized data. It is either an offset added to every run number (e.g. `run_remap: 1000`), or a map from each merger run to the number it is written with (e.g. `run_remap: {55: 1, 56: 2}`), which must cover every run read and give each a different number. The merger runs are read with their real numbers, but every run number written uses the remapped one: the `orig_run` attributes (or packed metadata), `source_runs`, the `src_#` directories of the `by_source_run` layout, the `orig_run` column of `events.parquet`, the run column of the scalers, and the `scalers_run_#.parquet` names. The info file does not give the merger run range, and the configuration in the userblock (see `write_userblock`) leaves out `run_remap`, `run_remap_path`, `min_run`, `max_run`, and the merger locations (`merger_path`, `merger_paths` and `merger_archive`). To make the remapping reversible, the map from each run read to its number is written as YAML to `run_remap_path`, which must be set with `run_remap` and must be outside of the `harmonic_path` (and `harmonic_paths`), so that it is not shared along with the data; keep it with authorized users. `validate` and `verify-fidelity` undo the remapping using the `run_remap` of the configuration, so they need the original configuration. These settings are optional and unused by default.
+//! - `blacklist_path` is a CSV file of specific merger events to exclude from harmonization (for example bad events found in a QA pass), one `run,event` pair per line, where `event` is the event number in the merger run (i.e. the `orig_event` it would have). Blank lines, lines starting with `#`, and a header line are ignored. Blacklisted events are read but not written, and the number dropped is reported at the end. The `validate` command accounts for the blacklisted events, assuming each entry is an event of its run within the run range. The progress bar total and the `get` command still count blacklisted events. This setting is optional and unused by default.
+//! - Some merger versions mark the quality of each event with an `ok` attribute (an integer, 0 for bad data) on the GET traces dataset and/or the FRIB group of a 0.2.0 event. When present it is copied to the same place in the harmonic output (or into the packed metadata), so that upstream QA decisions carry through harmonization; the 0.1.0 format has no such flag. If `drop_bad_events` is true, events whose GET or FRIB flag is 0 are instead read but not written, and the number dropped is reported at the end. Events without a flag are always kept. The `validate` command expects the bad events of each run to be missing when they are dropped, which takes reading the flags of every event of the runs. This setting is optional and defaults to false.
+//! - If a harmonization is split over several invocations (each over a subrange of runs), `progress_state_path` can be set to a JSON file that records the cumulative number of events processed. Each invocation starts its progress bar from the recorded count and adds its own events at the end. Set `progress_total_events` to the number of events in the whole job to make the bar (and ETA) reflect the overall progress; otherwise the bar ends at the end of the current invocation. The state file is created if it does not exist; to reset the progress delete the file. These settings are optional and unused by default.
+//! - `progress_update_events` is the number of events written between updates of the progress bar. Updating it for every event adds overhead (and flicker) for datasets of millions of tiny events. The remaining events are always added at the end, so the final count is exact. This setting is optional and defaults to 1000.
+//! - `extract_scalers` controls whether the scalers are extracted after the events are harmonized. Turning it off (or passing `--no-scalers`) skips the scaler extraction entirely, which saves time on quick event-only iterations; the output then has no `scalers.parquet` (or other scaler output), and the `scalers` command can extract them later. The `scalers` command ignores this setting. This setting is optional and defaults to true.
+//! - If `concurrent_scalers` is true, the scalers are extracted on their own thread while the events are harmonized, instead of afterwards. The scaler extraction only reads the merger runs and writes its own files, so the two overlap I/O and CPU for a net speedup, and the output is the same as when they run one after the other. Both read the same merger runs, so to keep them from contending for the files, the files held open by both together are bounded by `max_open_files`, or by 16 if it is not set. The scalers have no progress bar of their own when they are extracted concurrently. Since it cannot know which runs the events will come from, `concurrent_scalers` cannot be combined with `max_output_bytes`, `min_free_space_gb`, or the `parquet_per_harmonic_run` `scaler_output`. With `--profile`, the scaler time overlaps the reading and writing times. This setting is optional and defaults to false.
+//! - `scaler_output` selects the format of the combined scalers. Runs without any scalers (common for runs where FRIB was not recording) contribute no scaler rows, with a warning. With `parquet` (the default) they are written to `scalers.parquet`. With `hdf5` they are written to `scalers.h5`, which mirrors the `scalers` group of the 0.2.0 merger format: a `scalers` group with `min_event` and `max_event` attributes, containing one `event_#` dataset of scaler values per scaler event. The `columns` attribute of the group names the scaler columns of the values, in order and comma separated. The scaler events are renumbered continuously over the run range, and each dataset has `orig_run` and `orig_event` attributes with its original run and scaler event number. With `parquet_per_run` each run's scalers are instead written to their own `scalers_run_#.parquet` file (runs without scalers produce no file). With `parquet_per_harmonic_run` the scalers are instead partitioned to match the harmonic runs: for each harmonic run `run_#.h5`, the scalers of the merger runs which contributed events to it are written to `scalers_run_#.parquet`, with the same `#`, in the same directory as the harmonic run (following `harmonic_paths` and the `by_source_run` layout), so that scaler files line up with event files for per-file analysis. A merger run whose events span two harmonic runs has its scalers in both scaler files, though it is only read once. When appending, only the merger runs of the newly written events count for the reopened harmonic run. As it follows the harmonization, `parquet_per_harmonic_run` cannot be used with the `scalers` command. The runs are always read in parallel; only `parquet_per_run` and `parquet_per_harmonic_run` also write in parallel, as the other formats produce a single file.
+//! - `scaler_columns_include` is an optional list of scaler column names (for example `[clock_live, trig_live]`) to write to the scaler output. The `run` and `event` columns are always written (with `hdf5` scaler output, as the `orig_run` and `orig_event` attributes). The available columns are `clock_free`, `clock_live`, `trig_free`, `trig_live`, `ic_sca`, `mesh_sca`, `si1_cfd`, `si2`, `sipm`, `ic_ds`, and `ic_cfd`; unknown names are an error. By default all columns are written.
+//! - `scaler_order` gives, for each merger version (`v010` and `v020`), the index in a run's scaler data of the value of each scaler column, in the order `clock_free`, `clock_live`, `trig_free`, `trig_live`, `ic_sca`, `mesh_sca`, `si1_cfd`, `si2`, `sipm`, `ic_ds`, `ic_cfd`. For example, if a version stored `clock_live` before `clock_free`, its order would start `[1, 0, 2, ...]`. This keeps each column meaning the same thing when runs of both versions are combined. Each order must be a permutation of 0 to 10. Either version can be left out. This setting is optional and defaults to the identity order for both versions.
+//! - `scaler_names` gives, for each merger version (`v010` and `v020`), the name of the scaler datasets in a run's scaler group, where `{n}` is replaced by the scaler number. Some merger builds name the 0.2.0 scaler datasets `scaler_{n}` instead of `event_{n}`; those can be read with `v020: "scaler_{n}"`. Each name must contain exactly one `{n}` and no `/`. Either version can be left out. This setting is optional and defaults to the names shown above.
+//! - `scaler_schema` sets the types of the scaler columns in the parquet scalers, as a map from column name to type, for query tools which prefer particular types (for example DuckDB, with signed integers). The types are `u32`, `i32`, `u64`, `i64`, and `f64`; for example `scaler_schema: {run: i64, event: i64}`. The columns are cast before they are written, and a value which does not fit its type (such as a `u32` above the `i32` range) is an error. Columns which are not given stay `u32`, and unknown names are an error. It has no effect on `hdf5` scaler output. This setting is optional and defaults to every column being `u32`.
+//! - `scaler_row_group_size` is the number of rows per row group in `scalers.parquet` (it has no effect on `hdf5` scaler output). Small scaler files are unaffected, but for long campaigns with many scaler rows tuning it can improve read performance. This setting is optional and defaults to the polars default.
+//! - `scaler_write_retries` is the number of times to retry writing a parquet scaler file (`scalers.parquet` or a `scalers_run_#.parquet`) which fails with an I/O error, as can happen transiently when the `harmonic_path` is on networked storage (e.g. NFS) under load. The scalers are encoded in memory first, so a retry only rewrites the file, waiting 1 s before the first retry and twice as long before each further retry. Each retry is reported. This setting is optional and defaults to 0 retries.
+//! - `scaler_event_min` and `scaler_event_max` restrict the scalers read from each run to the scaler events (by their index in the run) from `scaler_event_min` to `scaler_event_max`, inclusive, for example to match the scalers to a trimmed event selection. Scaler events outside of the range are skipped. The range applies to every run and every scaler output. These settings are optional and default to null, which reads all of the scaler events.
+//! - If `warn_timestamp_overlap` is true, the harmonizer prints a warning when the first GET timestamp of a run is earlier than the largest GET timestamp of the previous run, reporting the pair of runs and the size of the overlap. When combining runs of the same gas and beam this can indicate a clock reset or a mislabeled run. The check is a heuristic and does not stop the harmonization. This setting is optional and defaults to true; set it to false to suppress the warnings.
+//! - `strict` enables additional data-integrity checks, and can also be enabled with the `--strict` command line flag. In strict mode the harmonizer errors if, within a source run, events are ever read with a non-increasing event index. Strict mode also enables `check_trace_widths`. This setting is optional and defaults to false.
+//! - If `check_trace_widths` is true, the harmonizer errors if the GET traces of an event have a different number of columns (time buckets) than those of the first event in the same harmonic run, reporting the `orig_run` and `orig_event` of the offending event. This setting is optional and defaults to false, as some datasets are legitimately ragged.
+//! - If `events_per_block` is set, each harmonic run is partitioned into blocks of (at most) that many events, for analyses which assign blocks of a file to threads. The partitioning is metadata only: the `events` group gets an `events_per_block` attribute and two array attributes, `block_start` and `block_end`. Block `i` contains the events `event_{block_start[i]}` up to (but not including) `event_{block_end[i]}`; the last block may be smaller. This setting is optional and unused by default.
+//! - If `write_written_at` is true, each event group gets a `written_at` attribute with the wall-clock time (in unix nanoseconds) at which the harmonizer wrote it. Correlating the write times with the `orig_run` boundaries can help diagnose throughput problems, such as the reader stalling at the start of a run. The times come from the system clock, so they are only increasing if the clock is not adjusted during harmonization. This setting is optional and defaults to false, to avoid bloating the files.
+//! - If `write_harmonic_run` is true, each event group gets a `harmonic_run` attribute with the run number of the harmonic run (`run_#.h5`) it is written to. This is redundant with the file name, but it stays with an event once it is loaded into memory, which simplifies bookkeeping in analysis code that no longer knows which file an event came from. This setting is optional and defaults to false.
+//! - If `pack_event_metadata` is true, the per-event metadata is packed into a single compound attribute named `metadata` on each event group, instead of the `orig_run` and `orig_event` attributes of the event group, the `id`, `timestamp` and `timestamp_other` attributes of `get_traces`, and the `event` and `timestamp` attributes of `frib_physics`. For datasets of many small events this per-attribute overhead can dominate the file size. The compound has the fields `orig_run` (i32), `orig_event` (u64), `flags` (u8), `get_id` (u32), `get_timestamp` (u64), `get_timestamp_other` (u64), `frib_event` (u32), `frib_timestamp` (u32), `get_ok` (u8) and `frib_ok` (u8), in that order. The bits of `flags` say which fields are present: 1 for GET data, 2 for the GET `id`, 4 for `timestamp_other`, 8 for FRIB data, 16 for the GET quality flag, and 32 for the FRIB quality flag. Fields which are not present are 0. In h5py the attribute reads as a numpy structured scalar, e.g. `event.attrs["metadata"]["orig_run"]`. `written_at` is still a separate attribute. The `validate` subcommand understands both layouts. This setting is optional and defaults to false.
+//! - If `write_source_runs` is true, the `events` group of each harmonic run gets two array attributes, `source_runs` and `source_run_events`, listing the merger runs which contributed events to the file (in increasing order) and how many events each contributed. The counts sum to the number of events in the file. This answers questions such as "does run 62 appear in this file?" with a single attribute read, without scanning the events. Two more array attributes, `source_run_min_event` and `source_run_max_event`, give the smallest and largest `orig_event` each of those runs contributed. Since `source_runs` is sorted, its first and last entries are the range of `orig_run` in the file, so a tool looking for a specific (`orig_run`, `orig_event`) can skip every file whose ranges cannot contain it by reading only these attributes. When appending to a file written by an older harmonizer without these ranges, they are not written for that file. This setting is optional and defaults to false.
+//! - `trace_transforms` is a list of built-in transforms applied, in order, to the GET traces of each event before it is written, to bake common preprocessing into harmonization. With `subtract_baseline`, the baseline of each trace (the median of its samples, which is insensitive to the few samples of a pulse) is subtracted from it. With `clip_negative`, negative samples are set to 0. For example, `trace_transforms: [subtract_baseline, clip_negative]` subtracts the pedestals and then removes the noise below them. The transforms act on every column of the traces, and values saturate at the limits of `i16`. The FRIB traces are not transformed. `verify-fidelity` applies the same transforms to the merger events before comparing them, so transformed events still match. This setting is optional and defaults to no transforms, which leaves the traces unchanged.
+//! - If `pad_traces_to` is set, the time axis (columns) of the GET traces of every event is brought to that length before it is written: shorter traces are right-padded with `pad_fill`, and longer traces are *truncated*, discarding their last columns. This gives every event traces of the same width, for rectangular array layouts (such as `event_storage: stacked`, or GPU pipelines which need them). Padding adds samples which were never recorded and truncating loses recorded ones, so only enable this if the analysis expects it; padded samples cannot be told apart from real samples equal to `pad_fill`. The padding is applied after the `trace_transforms`, and the FRIB traces are not changed. Like the transforms, `verify-fidelity` applies the padding to the merger events before comparing them. These settings are optional and default to null (the traces are written as they are) and 0.
+//! - If `transpose_traces` is true, the `get_traces` datasets are stored transposed, with shape (column, pad) rather than (pad, column), for downstream code which reads traces column-major. Transposed datasets have an `orientation` attribute with the value `transposed`; the attribute is absent when the setting is disabled. This setting is optional and defaults to false.
+//! - `compression` selects the HDF5 compression filter applied to the trace datasets (the GET traces and the FRIB 1903 traces): `none` (the default), `gzip`, or `szip`. The compressed datasets are chunked. `compression_level` is the gzip level, from 0 (fastest) to 9 (smallest); it is ignored by the other filters. `szip` requires an HDF5 library built with szip support. The `harmonic_size_gb` applies to the compressed size. These settings are optional and default to `none` and 4.
+//! - `trace_layout` selects how the GET traces of an event are stored. With `array` (the default) they are a single 2-D `get_traces` dataset. With `per_channel`, `get_traces` is instead a group containing one 1-D dataset per trace (row), `channel_0`, `channel_1`, ..., in the order of the rows, so that single channels can be read without reading the whole event. The group has an `n_channels` attribute with the number of channels and a `layout` attribute with the value `per_channel`, and carries the `id`, `timestamp` and `timestamp_other` attributes normally on the dataset. The 2-D array is rebuilt by stacking `channel_0` to `channel_{n_channels - 1}`, which the `combine` and `verify-fidelity` commands do. This is considerably more verbose, so it is meant for analyses which need selective reads. It cannot be combined with `transpose_traces`. This setting is optional and defaults to `array`.
+//! - `event_storage` selects how the events of a harmonic run are stored. With `groups` (the default) each event is an `event_#` group, as described in the output format below. Creating millions of groups is slow and their metadata bloats the files, so with `stacked` the `events` group instead holds a few extensible datasets indexed by event number: `metadata`, the packed metadata of each event (the compound described under `pack_event_metadata`, whose flags say whether the event has GET and FRIB data); `get_traces`, the GET traces stacked into a 3-D dataset (event, pad, time bucket); `frib_traces`, the FRIB 1903 traces stacked the same way; and `get_rows`, `get_columns`, `frib_rows` and `frib_columns`, the number of rows (pads) and columns (time buckets) of each event's traces. The `events` group has a `storage` attribute with the value `stacked`. Every event of a harmonic run must have traces of the same shape as the first event with traces in the run; otherwise the harmonizer stops with an error, unless `stacked_padding` is true, in which case the stacked datasets grow to the largest traces of the run (GET pad counts vary from event to event) and smaller traces are padded with zeros. The padding is dropped when the harmonizer reads the events back (with `combine` or `verify-fidelity`); other readers can drop it using the rows and columns of each event. Events without GET (or FRIB) data have zero traces. The FRIB coincidence (977) data is not stored, so `exclude_frib_coincidence` must be set, and `stacked` cannot be combined with `trace_layout: per_channel`, `transpose_traces`, `write_written_at`, `write_harmonic_run`, or `append`. The `combine`, `validate`, and `verify-fidelity` commands understand both storages. These settings are optional and default to `groups` and false.
+//! - If `exclude_frib_coincidence` is true, the FRIB coincidence (`977`) dataset is not written. The FRIB physics traces (`1903`) and the `frib_physics` attributes are kept. This setting is optional and defaults to false.
+//! - If `min_free_space_gb` is set, the harmonizer checks the free space of the filesystem of the `harmonic_path` before it starts, and again every `free_space_check_interval` harmonic runs (by default after every one). If the free space has dropped below `min_free_space_gb` GB, it stops after finishing the current harmonic run, rather than filling the disk (and angering the other users of a shared scratch filesystem): no further events are written, and the harmonization is wrapped up as if the run range ended there, like when `max_output_bytes` is reached. The scalers are only extracted for the runs which were written, the statistics are reported, and `free_space_exhausted` is true in the `--summary-json` summary. The finished harmonic runs are complete and can be used. If the free space is already too low when the harmonizer starts, it stops with an error. These settings are optional; by default the free space is not checked.
+//! - `output_layout` controls how the harmonic runs are organized in the `harmonic_path`. With `flat` (the default) they are all written directly to the `harmonic_path`. With `by_source_run` each harmonic run is placed in a subdirectory named after the merger run of its first event, for example `harmonic_path/src_055/run_0000.h5`; the subdirectories are created as needed. Harmonic runs are written to the `harmonic_path` and moved into their subdirectory once they are finished, and `harmonic_run_paths.csv` in the `harmonic_path` lists the path of each harmonic run relative to the `harmonic_path` (e.g. `0,src_055/run_0000.h5`). A harmonic run number counts as taken (for `overwrite`) if the run exists in any of the subdirectories; with `overwrite: overwrite` the old copy is removed, as the new run may be placed in another subdirectory. The other output files (such as `scalers.parquet`) stay in the `harmonic_path`. `by_source_run` cannot be combined with `append`. This setting is optional and defaults to `flat`.
+//! - `overwrite` controls what happens when a harmonic run file (`run_#.h5`) about to be written already exists in the `harmonic_path`. With `error` (the default) the harmonizer stops rather than destroy the existing file; this is checked before anything is written, for every harmonic run from the first one to be written on (unless a `run_counter_file` is shared with other jobs). With `overwrite` the existing file is replaced, and any harmonic runs after the last one written, left over from earlier larger output, are reported with a warning (they are not removed). With `skip` the existing file is left alone and the harmonizer moves on to the next free run number, which is useful when resuming into a directory with earlier output. Other output files (such as `scalers.parquet`) are always overwritten. This setting is optional and defaults to `error`.
+//! - If `append` is true, new events are added to an existing harmonic dataset rather than starting a new one. The last harmonic run in the `harmonic_path` (the `run_#.h5` with the highest number) is reopened, and the new events are appended to it, continuing its event numbering, until it reaches the harmonic size; the harmonizer then rolls over to new harmonic runs as usual. The `max_event`, `events_per_block`, and `source_runs` metadata of the reopened file are rewritten to cover both its old and new events. A reopened file which is already full is left as it is, and the new events start in the next harmonic run. The new scalers are added to the existing `scalers.parquet` or `scalers.h5` (or, with `parquet_per_harmonic_run`, to the scaler file of the reopened harmonic run), and the new rows of `events.parquet` to the existing table; combined scalers with different columns are an error. A note of the appended events is added to the end of the info file. The events, files, and bytes reported (and counted towards `max_output_bytes`) are only those added by this harmonization: the reopened file is not counted as produced, and only its growth is counted as written. If the `harmonic_path` has no harmonic runs yet, harmonization starts from `run_0000.h5`. `append` cannot be combined with `run_counter_file`. This setting is optional and defaults to false.
+//! - `run_counter_file` is an optional path to a text file holding the next harmonic run number. When it is set, the first harmonic run is numbered from the counter (a new or empty counter starts at 0) and the counter is updated when harmonization completes, so that successive harmonizer invocations (for example in a multi-stage pipeline) produce globally unique run numbers. Run numbers are reserved from the counter in blocks of 10: the counter file is exclusively locked only while a block is reserved, so concurrent jobs sharing a counter each get their own run numbers without waiting for each other. When harmonization completes, the unused numbers of the last block are given back, so the next invocation continues right after the last harmonic run, unless another job has reserved numbers in the meantime, in which case they are left unused. If a harmonization fails its reserved numbers are not given back, so the next invocation does not reuse them.
+//! - `chunk_cache` sets the parameters of the HDF5 chunk cache used when writing harmonic files, as `nbytes` (the size of the cache in bytes), `nslots` (the number of slots in its hash table, ideally a prime about 100 times the number of chunks that fit in the cache), and `w0` (the preemption policy, between 0 and 1). For example `chunk_cache: {nbytes: 67108864, nslots: 12421, w0: 0.75}`. A cache too small for the chunks of large chunked trace datasets causes repeated reads and writes of the same chunks. The cache only affects chunked datasets. This setting is optional and defaults to the HDF5 default cache.
+//! - `hdf5_alignment` and `hdf5_driver` are escape hatches for tuning the HDF5 file access of the harmonic files on specialized filesystems. `hdf5_alignment` is given as `threshold` and `alignment` (in bytes), and aligns every object at least `threshold` bytes large to a multiple of `alignment` (`H5Pset_alignment`), for example to the stripe size of a parallel filesystem. `hdf5_driver` selects the HDF5 virtual file driver: `sec2` (`H5Pset_fapl_sec2`, POSIX unbuffered I/O), `stdio` (`H5Pset_fapl_stdio`, buffered C standard I/O), or `core` (`H5Pset_fapl_core`, the file is built in memory and written to disk when it is closed, which needs enough memory to hold a whole harmonic run). With `core`, the harmonic runs are split by their size in memory, and `max_output_bytes`, which reads the size of the files on disk, cannot be set. Like `chunk_cache`, these only apply to newly created harmonic files. These settings are optional and default to the HDF5 defaults.
+//! - `libver` sets the lower bound of the HDF5 file format versions used to write the harmonic files (`H5Pset_libver_bounds`, with the upper bound at the latest version), which decides which HDF5 (and h5py) versions can read them. With `earliest` each object is written in the earliest format that can hold it, readable by the oldest readers. With `v18` or `v110` the formats of HDF5 1.8 or 1.10 are used, which older readers may not be able to open. With `latest` the newest formats of the linked HDF5 library are used, which can be faster for files with many objects but can only be read by a reader at least as new. Like `chunk_cache`, this only applies to newly created harmonic files. This setting is optional and defaults to the HDF5 default, which is `earliest`.
+//! - On shared filesystems another process may hold a merger run open with HDF5 file locking, so that opening it fails. When an open fails, the harmonizer checks whether the file is locked (with the same kind of lock HDF5 uses), and if so reports the run as locked by another process, distinct from a corrupt file. `lock_retries` is the number of times to retry opening a locked merger run, waiting 1 s before the first retry and twice as long before each further retry. If `hdf5_file_locking` is false, HDF5 file locking is disabled altogether (the harmonizer sets `HDF5_USE_FILE_LOCKING=FALSE` for itself), which also avoids errors on filesystems which do not support locking; only do this if nothing writes to the files while they are read. These settings are optional and default to 0 retries and file locking enabled.
+//! - `open_timeout_secs` bounds how long the harmonizer waits for a merger run to open, in seconds. On a cluster, a dead NFS mount can make opening a file hang indefinitely, stalling the whole job without any diagnostic. With a timeout, before a run is opened its file is probed (opened and read with plain filesystem calls) on a worker thread. If the probe does not finish in time, a warning naming the run and its file is printed and the run is skipped like a missing run (counted in `runs_skipped`, and not expected by `validate`), and the file is not probed again. Caveats: a probe hung in the kernel cannot be interrupted, so its worker thread is abandoned rather than stopped, and it (and the mount) may keep the process from exiting promptly. Only the probe is bounded: HDF5 serializes all of its calls behind one process-wide lock, so opening the file with HDF5 on a worker thread could not be abandoned without hanging every later HDF5 call. A mount which dies between the probe and the HDF5 open (or while the run is read) still hangs the harmonizer. Runs extracted from a `merger_archive` are not probed. On systems where the mount is `hard` mounted the timeout is the only protection, whereas `soft` mounts may eventually fail the open on their own. This setting is optional and defaults to null (no timeout).
+//! - `max_open_files` bounds the number of merger run files and harmonic files the harmonizer holds open at once. Runs are counted, checked with `--preflight`, and read for their scalers in parallel, which on systems with a low `ulimit -n` can fail with a "too many open files" error. With a bound, opening a file instead waits until another one is closed. The reader and the writer each briefly hold two files when moving to the next run, so the bound must be at least 4. Other files (such as `scalers.parquet`, or the harmonic files read by `validate`) are not counted, so leave some headroom below `ulimit -n`. This setting is optional and unbounded by default (or 16 with `concurrent_scalers`).
+//! - If `write_userblock` is true, each harmonic file starts with a null-terminated JSON userblock containing the harmonizer version, the harmonic run number, the creation time (unix seconds), and the configuration. The userblock is written once the harmonic file is finished and closed (HDF5 owns the file while it is open, and the `core` `hdf5_driver` writes all of it out when it is closed), so an unfinished file starts with zeros. Tools without an HDF5 library can identify harmonic files by reading the first few KB. This setting is optional and defaults to false.
+//! - `version_format` selects the format of the `version` attribute of the `events` group of each harmonic file. With `combined` (the default) it is the harmonizer name and version, e.g. `harmonizer:0.1.0`. With `semver` it is only the version, e.g. `0.1.0`. With `json` it is a JSON object, e.g. `{"producer":"harmonizer","version":"0.1.0"}`. If `write_producer_attrs` is true, the `events` group also gets separate `producer` and `producer_version` string attributes, whatever the format. A file which is appended to keeps the attributes it was created with. These settings are optional and default to `combined` and false.
+//! - If `write_target_event_count` is true, the `events` group of each harmonic file gets a `target_event_count` attribute, so that a reader streaming a file while it is still being written knows roughly how many events to expect. When the file is created the attribute is *provisional*: an estimate of the events the file will hold, following how the harmonic runs are split: from the average event size of the run range and the harmonic size, from the events of the run range divided among `num_output_files`, from the average pads of a sample of the events with `pads_per_file`, or every event with `single_file` (capped by `max_events_per_file` and by the events left in the run range). When the file is finished the attribute is overwritten with the *final* count, the same value as `max_event`. The estimate ignores blacklisted, dropped, or budgeted-out events, so readers should treat the provisional value as a hint and rely on `max_event` once the file is finished. The estimate reuses the events counted for the progress bar, and only `pads_per_file` reads a few extra events when the harmonizer starts. This setting is optional and defaults to false.
+//! - If `verify_output` is true, each harmonic file is reopened read-only once it is finished, and its `events` group and `max_event` attribute are checked against the number of events written. A failed check aborts the harmonizer with the harmonic run number. This setting is optional and defaults to false.
+//! - If `write_event_metadata` is true, the harmonizer also writes `events.parquet` to the `harmonic_path`, a table with one row per harmonic event. Its columns are `harmonic_run`, `local_event` (the `#` in `event_#`), `orig_run`, `orig_event`, `get_present`, `frib_present`, `get_timestamp` and `frib_timestamp` (null when the corresponding data is absent), `trace_rows` (the number of GET traces, 0 when absent), and `get_pads` (the GET multiplicity, i.e. the number of active pads, null when there is no GET data). This allows querying the metadata of the whole dataset without opening the HDF5 files. The table is written once harmonization completes. This setting is optional and defaults to false.
+//! - If `write_info_file` is true, a human readable `HARMONIZATION_INFO.txt` is written to the `harmonic_path` once harmonization completes. It records the harmonizer version, the creation time, the source of the merger data, the run range, the gas and beam, the number of events and harmonic runs, and how the harmonic format differs from the merger format. `gas_beam` is an optional free-form description of the gas and beam (for example `"d2 + 16C"`) recorded in the info file; it is listed as unspecified if it is not set. This setting is optional and defaults to true; set it to false to skip the info file.
 //!
 //! ### Output Format
 //!
@@ -87,52 +335,194 @@
 //!
 //! Note that just because they have the same amount of data (in terms of size in bytes), does not mean each run will have *exactly* the same load in an analysis. Some events are garbage to be thrown out, some are really complicated, etc. The harmonizer doesn't know about any of that. Those are silly human concerns. The harmonizer only knows bytes.
 //!
+mod archive;
+mod blacklist;
+mod combine;
+mod compress_report;
 mod config;
+mod counter;
+mod estimate;
+mod event_table;
+mod fidelity;
+mod info;
+mod metadata;
+mod open_files;
+mod progress;
 mod reader;
+mod remaining;
 mod scalers;
+mod stacked;
+mod stats;
+#[cfg(test)]
+mod testing;
+mod transform;
+mod validate;
 mod writer;
 
-use clap::{Arg, Command};
-use color_eyre::eyre::Result;
-use config::Config;
+use clap::{Arg, ArgAction, Command};
+use color_eyre::eyre::{eyre, Result};
+use combine::combine_harmonic_data;
+use compress_report::compression_report;
+use config::{Config, DuplicateRunPolicy};
+use estimate::estimate_output_files;
+use fidelity::verify_fidelity;
 use human_bytes::human_bytes;
 use indicatif::{ProgressBar, ProgressStyle};
-use reader::{get_total_merger_bytes, get_total_merger_events, MergerReader};
+use info::write_info_file;
+use progress::{ProgressState, ThrottledProgress};
+use rayon::prelude::*;
+use reader::{
+    get_total_merger_bytes, get_total_merger_events, preflight_run, read_event_at, MergerEvent,
+    MergerReader,
+};
+use remaining::estimate_remaining;
 use scalers::process_scalers;
+use stats::{HarmonizeStats, PhaseTimings};
 use std::path::PathBuf;
+use std::sync::mpsc::sync_channel;
+use std::time::{Duration, Instant};
+use validate::{validate_harmonic_output, StopRecord};
 use writer::HarmonicWriter;
 
 /// Main processing loop. Takes the config and harmonizes the data.
-pub fn harmonize(config: Config) -> Result<()> {
-    let total_events =
-        get_total_merger_events(&config.merger_path, config.min_run, config.max_run)?;
-    let progress = ProgressBar::new(total_events)
-        .with_style(ProgressStyle::with_template(
-            "{msg}: {bar:40.cyan/blue} [{human_pos}/{human_len} - {percent}%] (ETA: {eta}, Duration: {elapsed})",
-        )?)
-        .with_message("Progress");
-    let mut reader = MergerReader::new(&config.merger_path, config.min_run, config.max_run)?;
-    let mut writer = HarmonicWriter::new(&config.harmonic_path, config.get_harmonic_size())?;
-    loop {
-        let event = reader.read_event()?;
-        match event {
-            Some(e) => {
-                writer.write(e)?;
-                progress.inc(1);
+/// Returns statistics describing the outcome.
+pub fn harmonize(mut config: Config) -> Result<HarmonizeStats> {
+    let start = Instant::now();
+    let mut timings = PhaseTimings::default();
+    let total_events = get_total_merger_events(&config)?;
+    timings.counting = start.elapsed();
+    let mut progress_state = match &config.progress_state_path {
+        Some(path) => ProgressState::load(path)?,
+        None => ProgressState::default(),
+    };
+    let progress = ProgressBar::new(
+        config
+            .progress_total_events
+            .unwrap_or(progress_state.events_processed + total_events),
+    )
+    .with_position(progress_state.events_processed)
+    .with_style(ProgressStyle::with_template(
+        "{msg}: {bar:40.cyan/blue} [{human_pos}/{human_len} - {percent}%] (ETA: {eta}, Duration: {elapsed})",
+    )?)
+    .with_message("Progress");
+    // Events from previous invocations should not count towards our rate
+    progress.reset_eta();
+    let mut reader = MergerReader::new(&config)?;
+    let mut writer = HarmonicWriter::with_total_events(&config, Some(total_events))?;
+    // The reader runs ahead of the writer on its own thread, but blocks
+    // once max_buffered_events are waiting to be written.
+    let (sender, receiver) = sync_channel::<MergerEvent>(config.max_buffered_events);
+    let concurrent_scalers = config.extract_scalers && config.concurrent_scalers;
+    let (reader, reading, scalers) = std::thread::scope(|scope| -> Result<_> {
+        // The scalers only read the merger runs, so they can be extracted
+        // alongside the events, sharing the open file limit with them
+        let scaler_stage = concurrent_scalers.then(|| {
+            scope.spawn(|| -> Result<Duration> {
+                let scaler_start = Instant::now();
+                process_scalers(&config, true, config.append, None)?;
+                Ok(scaler_start.elapsed())
+            })
+        });
+        let read_stage = scope.spawn(move || -> Result<(MergerReader, Duration)> {
+            let mut reading = Duration::ZERO;
+            loop {
+                let read_start = Instant::now();
+                let event = reader.read_event()?;
+                reading += read_start.elapsed();
+                let Some(event) = event else {
+                    break;
+                };
+                // The writer hung up, so there is no point in reading further
+                if sender.send(event).is_err() {
+                    break;
+                }
+            }
+            Ok((reader, reading))
+        });
+        let mut throttled = ThrottledProgress::new(progress.clone(), config.progress_update_events);
+        for event in receiver {
+            let write_start = Instant::now();
+            writer.write(event)?;
+            timings.writing += write_start.elapsed();
+            throttled.inc();
+            // Hanging up stops the reader
+            if writer.has_stopped() {
+                break;
             }
-            None => break,
         }
-    }
-    writer.close()?;
+        throttled.flush();
+        let (reader, reading) = read_stage
+            .join()
+            .map_err(|_| eyre!("The reader thread panicked!"))??;
+        let scalers = scaler_stage
+            .map(|stage| {
+                stage
+                    .join()
+                    .map_err(|_| eyre!("The scaler thread panicked!"))?
+            })
+            .transpose()?;
+        Ok((reader, reading, scalers))
+    })?;
+    timings.reading = reading;
+    let last_source_run = writer.last_source_run();
+    let write_start = Instant::now();
+    let writer_stats = writer.close()?;
+    timings.writing += write_start.elapsed();
     progress.finish();
-    println!("Extracting scalers...");
-    process_scalers(
-        &config.merger_path,
-        &config.harmonic_path,
-        config.min_run,
-        config.max_run,
-    )?;
-    Ok(())
+    if let Some(path) = &config.progress_state_path {
+        progress_state.events_processed += writer_stats.events_written;
+        progress_state.save(path)?;
+    }
+    // Only the runs which were (at least partially) written have their scalers extracted
+    if writer_stats.output_budget_reached || writer_stats.free_space_exhausted {
+        if writer_stats.output_budget_reached {
+            println!("Reached the maximum output size, stopped harmonizing.");
+        } else {
+            println!("The free space of the output ran low, stopped harmonizing.");
+        }
+        if let Some(run) = last_source_run {
+            config.truncate_runs(run);
+        }
+        StopRecord {
+            last_run: last_source_run
+                .map(|run| config.remap_run(run))
+                .transpose()?,
+        }
+        .save(&config.harmonic_path)?;
+    } else {
+        StopRecord::clear(&config.harmonic_path)?;
+    }
+    if let Some(scalers) = scalers {
+        println!("Extracted the scalers alongside the events.");
+        timings.scalers = scalers;
+    } else if config.extract_scalers {
+        println!("Extracting scalers...");
+        let scaler_start = Instant::now();
+        process_scalers(
+            &config,
+            true,
+            config.append,
+            Some(&writer_stats.file_source_runs),
+        )?;
+        timings.scalers = scaler_start.elapsed();
+    }
+    let stats = HarmonizeStats {
+        runs_skipped: reader.runs_skipped(),
+        runs_capped: reader.runs_capped(),
+        events_capped: reader.events_capped(),
+        events_counted: total_events,
+        events_blacklisted: reader.events_blacklisted(),
+        events_dropped_bad: reader.events_dropped_bad(),
+        frib_events_unpaired: reader.frib_events_unpaired(),
+        duration: start.elapsed(),
+        timings,
+        ..writer_stats
+    };
+    if config.write_info_file {
+        write_info_file(&config, &stats)?;
+    }
+    config.save_run_remap()?;
+    Ok(stats)
 }
 
 /// Program entry point. Handles the CLI.
@@ -142,12 +532,124 @@ fn main() -> Result<()> {
     let cli = Command::new("harmonizer")
         .arg_required_else_help(true)
         .subcommand(Command::new("new").about("Create a new template config file"))
+        .subcommand(
+            Command::new("get")
+                .about("Print a single event, by its index over the whole run range")
+                .arg(
+                    Arg::new("index")
+                        .long("index")
+                        .required(true)
+                        .value_parser(clap::value_parser!(u64))
+                        .help("The index of the event, counting from 0"),
+                ),
+        )
+        .subcommand(
+            Command::new("combine")
+                .about("Combine harmonic datasets into a single renumbered dataset")
+                .arg(
+                    Arg::new("dirs")
+                        .required(true)
+                        .num_args(3..)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("The harmonic directories to combine, followed by the output directory"),
+                ),
+        )
+        .subcommand(
+            Command::new("validate")
+                .about("Check the harmonic data against the event counts of the merger runs"),
+        )
+        .subcommand(
+            Command::new("verify-fidelity")
+                .about("Check that harmonic events are identical to the merger events they came from")
+                .arg(
+                    Arg::new("sample")
+                        .long("sample")
+                        .value_parser(clap::value_parser!(u64))
+                        .help("Only check about this many events, spread over the harmonic data"),
+                ),
+        )
+        .subcommand(
+            Command::new("compress-report")
+                .about("Compare the output size and write time of the compression settings on a sample of events")
+                .arg(
+                    Arg::new("events")
+                        .long("events")
+                        .default_value("1000")
+                        .value_parser(clap::value_parser!(u64))
+                        .help("The number of events in the sample"),
+                ),
+        )
+        .subcommand(
+            Command::new("scalers")
+                .about("Only extract the scalers, without harmonizing events")
+                .arg(
+                    Arg::new("overwrite-scalers")
+                        .long("overwrite-scalers")
+                        .action(ArgAction::SetTrue)
+                        .help("Replace existing scaler files instead of failing"),
+                ),
+        )
         .arg(
             Arg::new("config")
                 .short('c')
                 .long("config")
                 .help("Path to a configuration file (YAML)"),
         )
+        .arg(
+            Arg::new("preflight")
+                .long("preflight")
+                .action(ArgAction::SetTrue)
+                .help("Read the first event of every run to check that they can be harmonized, then exit"),
+        )
+        .arg(
+            Arg::new("estimate-remaining")
+                .long("estimate-remaining")
+                .action(ArgAction::SetTrue)
+                .help("Estimate the work remaining after the last event in the harmonic output, then exit"),
+        )
+        .arg(
+            Arg::new("summary-json")
+                .long("summary-json")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Write a JSON summary of the harmonization to this file"),
+        )
+        .arg(
+            Arg::new("print-config")
+                .long("print-config")
+                .action(ArgAction::SetTrue)
+                .help("Print the effective configuration as YAML and exit"),
+        )
+        .arg(
+            Arg::new("continue")
+                .long("continue")
+                .action(ArgAction::SetTrue)
+                .requires("print-config")
+                .help("With --print-config, carry on with the run after printing the configuration"),
+        )
+        .arg(
+            Arg::new("profile")
+                .long("profile")
+                .action(ArgAction::SetTrue)
+                .help("Print a breakdown of the time spent in each phase"),
+        )
+        .arg(
+            Arg::new("stdin")
+                .long("stdin")
+                .action(ArgAction::SetTrue)
+                .help("Read the list of merger run files from stdin"),
+        )
+        .arg(
+            Arg::new("strict")
+                .long("strict")
+                .action(ArgAction::SetTrue)
+                .help("Enable strict data-integrity checks"),
+        )
+        .arg(
+            Arg::new("no-scalers")
+                .long("no-scalers")
+                .action(ArgAction::SetTrue)
+                .help("Skip extracting the scalers after harmonizing"),
+        )
         .get_matches();
 
     println!("--------------------- AT-TPC Harmonizer ---------------------");
@@ -165,19 +667,74 @@ fn main() -> Result<()> {
         return Ok(());
     }
 
-    let config = Config::load(&config_path)?;
+    let mut config = Config::load(&config_path)?;
+    // HDF5 reads this whenever a file is opened. Setting the environment is
+    // not thread safe, so this must come before any threads (rayon's pool,
+    // the read stage, open timeouts) are started.
+    if !config.hdf5_file_locking {
+        std::env::set_var("HDF5_USE_FILE_LOCKING", "FALSE");
+    }
+    config.strict |= cli.get_flag("strict");
+    config.extract_scalers &= !cli.get_flag("no-scalers");
+    if cli.get_flag("stdin") {
+        config.read_merger_files(std::io::stdin().lock())?;
+    }
+    open_files::set_max_open_files(config.open_file_limit());
     println!(
         "Successfully loaded configuration from {}",
         config_path.display()
     );
 
-    if !config.merger_path.exists() {
+    if cli.get_flag("print-config") {
+        print!("{}", config.to_yaml()?);
+        println!("-------------------------------------------------------------");
+        if !cli.get_flag("continue") {
+            return Ok(());
+        }
+    }
+
+    // Handle the combine subcommand, which does not read the merger data
+    if let Some(("combine", combine_args)) = cli.subcommand() {
+        let mut inputs: Vec<PathBuf> = combine_args
+            .get_many::<PathBuf>("dirs")
+            .expect("The directories are required")
+            .cloned()
+            .collect();
+        config.harmonic_path = inputs.pop().expect("There are at least 3 directories");
+        if !config.harmonic_path.exists() {
+            return Err(eyre!(
+                "Output path {} does not exist! Please create it before combining.",
+                config.harmonic_path.display()
+            ));
+        }
+        println!("Combining {} harmonic datasets...", inputs.len());
+        let stats = combine_harmonic_data(&config, &inputs)?;
+        println!("Events written: {}", stats.events_written);
+        println!(
+            "Harmonic files produced: {} ({})",
+            stats.files_produced,
+            human_bytes(stats.bytes_written as f64)
+        );
+        println!("-------------------------------------------------------------");
+        return Ok(());
+    }
+
+    if let Some(archive) = config.merger_archive.as_ref().filter(|path| !path.exists()) {
+        println!(
+            "Merger archive {} does not exist! Quitting.",
+            archive.display()
+        );
+        println!("-------------------------------------------------------------");
+    } else if config.merger_archive.is_none()
+        && config.merger_files.is_none()
+        && !config.merger_path.exists()
+    {
         println!(
             "Merger path {} does not exist! Quitting.",
             config.merger_path.display()
         );
         println!("-------------------------------------------------------------");
-    } else if !config.harmonic_path.exists() {
+    } else if !config.harmonic_path.exists() && !matches!(cli.subcommand(), Some(("scalers", _))) {
         println!(
             "Harmonic path {} does not exist! Please create it before running the harmonizer.",
             config.harmonic_path.display()
@@ -185,15 +742,256 @@ fn main() -> Result<()> {
         println!("-------------------------------------------------------------");
     }
 
+    let duplicates = config.duplicate_merger_runs();
+    for (run, copies) in duplicates.iter() {
+        println!("Run {run} was found in more than one merger directory:");
+        for path in copies {
+            println!("    {}", path.display());
+        }
+    }
+    if !duplicates.is_empty() {
+        let copy = match config.duplicate_runs {
+            DuplicateRunPolicy::Error => {
+                println!("-------------------------------------------------------------");
+                return Err(eyre!(
+                    "{} runs were found in more than one merger directory; set duplicate_runs to pick a copy",
+                    duplicates.len()
+                ));
+            }
+            DuplicateRunPolicy::First => "first",
+            DuplicateRunPolicy::Last => "last",
+            DuplicateRunPolicy::Largest => "largest",
+        };
+        println!("Reading the {copy} copy of each of them.");
+    }
+
+    if cli.get_flag("preflight") {
+        println!("Checking runs...");
+        let results: Vec<_> = config
+            .runs()
+            .into_par_iter()
+            .map(|run| (run, preflight_run(&config, run)))
+            .collect();
+        let mut failed = 0;
+        println!("{:>6} | {:<7} | Details", "Run", "Status");
+        for (run, result) in results {
+            match result {
+                Ok(Some(version)) => println!("{run:>6} | {:<7} | merger {version}", "OK"),
+                Ok(None) => println!("{run:>6} | {:<7} | will be skipped", "MISSING"),
+                Err(error) => {
+                    failed += 1;
+                    println!("{run:>6} | {:<7} | {error}", "ERROR");
+                }
+            }
+        }
+        println!("-------------------------------------------------------------");
+        if failed > 0 {
+            return Err(eyre!("Preflight failed: {failed} runs could not be read"));
+        }
+        println!("Preflight passed: all existing runs can be read.");
+        return Ok(());
+    }
+
+    if cli.get_flag("estimate-remaining") {
+        println!("Estimating the remaining work...");
+        let remaining = estimate_remaining(&config)?;
+        match remaining.resume_point {
+            Some((run, event)) => {
+                println!("Last harmonized event: run {run}, event {event}")
+            }
+            None => println!("No harmonized events found, the whole run range remains"),
+        }
+        println!("Remaining events: {}", remaining.events);
+        println!(
+            "Remaining data (estimated): {}",
+            human_bytes(remaining.bytes as f64)
+        );
+        if config.pads_per_file.is_none() {
+            println!(
+                "Estimated number of remaining harmonic files: {}",
+                remaining.bytes.div_ceil(config.get_harmonic_size()?.max(1))
+            );
+        }
+        println!("-------------------------------------------------------------");
+        return Ok(());
+    }
+
+    // Handle the scalers subcommand, which does not need any harmonic output
+    if let Some(("scalers", scaler_args)) = cli.subcommand() {
+        if !config.harmonic_path.exists() {
+            std::fs::create_dir_all(&config.harmonic_path)?;
+            println!("Created harmonic path {}", config.harmonic_path.display());
+        }
+        println!("Extracting scalers...");
+        process_scalers(
+            &config,
+            scaler_args.get_flag("overwrite-scalers"),
+            false,
+            None,
+        )?;
+        config.save_run_remap()?;
+        println!("Done.");
+        println!("-------------------------------------------------------------");
+        return Ok(());
+    }
+
+    // Handle the get subcommand
+    if let Some(("get", get_args)) = cli.subcommand() {
+        let index = *get_args
+            .get_one::<u64>("index")
+            .expect("The index is required");
+        let event = read_event_at(&config, index)?
+            .ok_or_else(|| eyre!("There is no event with index {index}!"))?;
+        println!("Event {index}:");
+        println!("  orig_run: {}", event.run_number);
+        println!("  orig_event: {}", event.event);
+        match &event.get {
+            Some(get) => {
+                println!("  GET id: {:?}", get.id);
+                println!("  GET timestamp: {}", get.timestamp);
+                println!("  GET timestamp_other: {:?}", get.timestamp_other);
+                println!("  GET ok: {:?}", get.ok);
+                println!("  GET traces shape: {:?}", get.traces.shape());
+            }
+            None => println!("  No GET data"),
+        }
+        match &event.frib {
+            Some(frib) => {
+                println!("  FRIB event: {}", frib.event);
+                println!("  FRIB timestamp: {}", frib.timestamp);
+                println!("  FRIB ok: {:?}", frib.ok);
+                println!("  FRIB traces shape: {:?}", frib.traces.shape());
+            }
+            None => println!("  No FRIB data"),
+        }
+        println!("-------------------------------------------------------------");
+        return Ok(());
+    }
+
+    // Handle the validate subcommand
+    if let Some(("validate", _)) = cli.subcommand() {
+        println!("Validating harmonic data...");
+        if let Some(stop) = StopRecord::load(&config.harmonic_path)? {
+            match stop.last_run.map(|run| config.unmap_run(run)).transpose()? {
+                Some(run) => println!(
+                    "Harmonization stopped early: validating the runs up to run {run}, which may be partially written."
+                ),
+                None => println!("Harmonization stopped early, before any events were written."),
+            }
+        }
+        let discrepancies = validate_harmonic_output(&config)?;
+        for discrepancy in discrepancies.iter() {
+            println!(
+                "Run {}: expected {} events, found {} in the harmonic data",
+                discrepancy.run, discrepancy.expected, discrepancy.found
+            );
+        }
+        println!("-------------------------------------------------------------");
+        if !discrepancies.is_empty() {
+            return Err(eyre!(
+                "Validation failed: {} runs have mismatched event counts",
+                discrepancies.len()
+            ));
+        }
+        println!("Validation passed: all event counts match.");
+        return Ok(());
+    }
+
+    // Handle the compress-report subcommand
+    if let Some(("compress-report", report_args)) = cli.subcommand() {
+        let sample = *report_args
+            .get_one::<u64>("events")
+            .expect("The sample size has a default");
+        println!("Harmonizing a sample of {sample} events under each compression setting...");
+        println!(
+            "{:<8} | {:>8} | {:>12} | {:>12}",
+            "Setting", "Events", "Size", "Write time"
+        );
+        for (name, result) in compression_report(&config, sample)? {
+            match result {
+                Ok(result) => println!(
+                    "{name:<8} | {:>8} | {:>12} | {:>12.2?}",
+                    result.events,
+                    human_bytes(result.bytes as f64),
+                    result.writing
+                ),
+                Err(error) => println!("{name:<8} | failed: {error}"),
+            }
+        }
+        println!("-------------------------------------------------------------");
+        return Ok(());
+    }
+
+    // Handle the verify-fidelity subcommand
+    if let Some(("verify-fidelity", fidelity_args)) = cli.subcommand() {
+        println!("Verifying harmonic events against the merger data...");
+        let sample = fidelity_args.get_one::<u64>("sample").copied();
+        let (checked, mismatches) = verify_fidelity(&config, sample)?;
+        for mismatch in mismatches.iter() {
+            println!(
+                "{} event_{} (orig_run {}, orig_event {}): {}",
+                mismatch.path.display(),
+                mismatch.event,
+                mismatch.orig_run,
+                mismatch.orig_event,
+                mismatch.reason
+            );
+        }
+        println!("-------------------------------------------------------------");
+        if !mismatches.is_empty() {
+            return Err(eyre!(
+                "Fidelity verification failed: {} of {checked} events do not match",
+                mismatches.len()
+            ));
+        }
+        println!("Fidelity verification passed: all {checked} events checked match.");
+        return Ok(());
+    }
+
     println!(
         "Total amount of data to be harmonized: {}",
-        human_bytes(
-            get_total_merger_bytes(&config.merger_path, config.min_run, config.max_run)? as f64
-        )
+        human_bytes(get_total_merger_bytes(&config)? as f64)
     );
+    // The number of files split by pad count can't be estimated from sizes
+    if config.pads_per_file.is_none() {
+        println!(
+            "Estimated number of harmonic files: {}",
+            estimate_output_files(&config)?
+        );
+    }
     println!("Harmonizing...");
-    harmonize(config)?;
+    let stats = harmonize(config)?;
     println!("Complete.");
+    println!("Events written: {}", stats.events_written);
+    println!(
+        "Harmonic files produced: {} ({})",
+        stats.files_produced,
+        human_bytes(stats.bytes_written as f64)
+    );
+    println!("Runs skipped: {}", stats.runs_skipped);
+    println!("Runs cut short by the per run cap: {}", stats.runs_capped);
+    println!("Events skipped by the per run cap: {}", stats.events_capped);
+    println!("Blacklisted events dropped: {}", stats.events_blacklisted);
+    println!("Bad events dropped: {}", stats.events_dropped_bad);
+    if stats.frib_events_unpaired > 0 {
+        println!(
+            "FRIB events not paired with a GET event: {}",
+            stats.frib_events_unpaired
+        );
+    }
+    println!("Duration: {:.2?}", stats.duration);
+    if cli.get_flag("profile") {
+        let timings = &stats.timings;
+        println!("Time spent per phase (reading and writing overlap):");
+        println!("  Counting events: {:.2?}", timings.counting);
+        println!("  Reading events: {:.2?}", timings.reading);
+        println!("  Writing events: {:.2?}", timings.writing);
+        println!("  Extracting scalers: {:.2?}", timings.scalers);
+    }
+    if let Some(path) = cli.get_one::<PathBuf>("summary-json") {
+        stats.write_summary_json(path)?;
+        println!("Wrote the summary to {}", path.display());
+    }
 
     println!("-------------------------------------------------------------");
 
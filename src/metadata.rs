@@ -0,0 +1,189 @@
+//! The packed layout of the per-event metadata. Instead of several scalar
+//! attributes spread over the event group and its datasets, all of the
+//! metadata of an event is stored in a single compound attribute, which
+//! greatly reduces the attribute overhead of files with many small events.
+use super::reader::MergerEvent;
+use color_eyre::eyre::Result;
+use hdf5_metno::{Group, H5Type};
+
+/// The name of the packed metadata attribute of an event group
+pub const PACKED_METADATA_NAME: &str = "metadata";
+
+/// Set in the flags if the event has GET data
+pub const HAS_GET: u8 = 1;
+/// Set in the flags if the GET data has an id
+pub const HAS_GET_ID: u8 = 1 << 1;
+/// Set in the flags if the GET data has a timestamp_other
+pub const HAS_GET_TIMESTAMP_OTHER: u8 = 1 << 2;
+/// Set in the flags if the event has FRIB data
+pub const HAS_FRIB: u8 = 1 << 3;
+/// Set in the flags if the GET data has a quality flag
+pub const HAS_GET_OK: u8 = 1 << 4;
+/// Set in the flags if the FRIB data has a quality flag
+pub const HAS_FRIB_OK: u8 = 1 << 5;
+
+/// The packed metadata of an event. Fields whose flag is not set are 0.
+#[derive(H5Type, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct EventMetadata {
+    pub orig_run: i32,
+    pub orig_event: u64,
+    pub flags: u8,
+    pub get_id: u32,
+    pub get_timestamp: u64,
+    pub get_timestamp_other: u64,
+    pub frib_event: u32,
+    pub frib_timestamp: u32,
+    pub get_ok: u8,
+    pub frib_ok: u8,
+}
+
+impl EventMetadata {
+    /// Pack the metadata of an event
+    pub fn from_event(event: &MergerEvent) -> Self {
+        let mut metadata = Self {
+            orig_run: event.run_number,
+            orig_event: event.event,
+            ..Default::default()
+        };
+        if let Some(get) = event.get.as_ref() {
+            metadata.flags |= HAS_GET;
+            metadata.get_timestamp = get.timestamp;
+            if let Some(id) = get.id {
+                metadata.flags |= HAS_GET_ID;
+                metadata.get_id = id;
+            }
+            if let Some(timestamp_other) = get.timestamp_other {
+                metadata.flags |= HAS_GET_TIMESTAMP_OTHER;
+                metadata.get_timestamp_other = timestamp_other;
+            }
+            if let Some(ok) = get.ok {
+                metadata.flags |= HAS_GET_OK;
+                metadata.get_ok = ok;
+            }
+        }
+        if let Some(frib) = event.frib.as_ref() {
+            metadata.flags |= HAS_FRIB;
+            metadata.frib_event = frib.event;
+            metadata.frib_timestamp = frib.timestamp;
+            if let Some(ok) = frib.ok {
+                metadata.flags |= HAS_FRIB_OK;
+                metadata.frib_ok = ok;
+            }
+        }
+        metadata
+    }
+
+    /// Write the packed metadata to an event group
+    pub fn write(&self, event_group: &Group) -> Result<()> {
+        event_group
+            .new_attr::<Self>()
+            .create(PACKED_METADATA_NAME)?
+            .write_scalar(self)?;
+        Ok(())
+    }
+
+    /// Read the packed metadata of an event group. Returns None if the
+    /// event was written with the separate attributes instead.
+    pub fn read(event_group: &Group) -> Result<Option<Self>> {
+        if !event_group
+            .attr_names()?
+            .iter()
+            .any(|attr| attr == PACKED_METADATA_NAME)
+        {
+            return Ok(None);
+        }
+        Ok(Some(event_group.attr(PACKED_METADATA_NAME)?.read_scalar()?))
+    }
+}
+
+/// Read the original run of a harmonic event, from whichever metadata layout
+/// it was written with.
+pub fn read_orig_run(event_group: &Group) -> Result<i32> {
+    match EventMetadata::read(event_group)? {
+        Some(metadata) => Ok(metadata.orig_run),
+        None => Ok(event_group.attr("orig_run")?.read_scalar()?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::{Coincidence, FribEvent, GetEvent};
+    use crate::testing::ScratchDir;
+    use hdf5_metno::File;
+    use ndarray::{Array1, Array2};
+
+    fn event(get_id: Option<u32>, frib: bool) -> MergerEvent {
+        MergerEvent {
+            get: Some(GetEvent {
+                traces: Array2::zeros((2, 4)),
+                id: get_id,
+                timestamp: 1234,
+                timestamp_other: None,
+                ok: Some(1),
+            }),
+            frib: frib.then(|| FribEvent {
+                traces: Array2::zeros((2, 4)),
+                coincidence: Coincidence::U8(Array1::zeros(4)),
+                event: 17,
+                timestamp: 5678,
+                ok: None,
+            }),
+            run_number: 12,
+            event: 3,
+        }
+    }
+
+    #[test]
+    fn packed_metadata_round_trips() {
+        let dir = ScratchDir::new("packed_metadata");
+        let file = File::create(dir.path().join("packed.h5")).unwrap();
+        for (index, (get_id, frib)) in [(Some(9), true), (None, false)].into_iter().enumerate() {
+            let metadata = EventMetadata::from_event(&event(get_id, frib));
+            let group = file.create_group(&format!("event_{index}")).unwrap();
+            metadata.write(&group).unwrap();
+            assert_eq!(EventMetadata::read(&group).unwrap(), Some(metadata));
+            assert_eq!(read_orig_run(&group).unwrap(), 12);
+        }
+
+        let full = EventMetadata::read(&file.group("event_0").unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            full,
+            EventMetadata {
+                orig_run: 12,
+                orig_event: 3,
+                flags: HAS_GET | HAS_GET_ID | HAS_GET_OK | HAS_FRIB,
+                get_id: 9,
+                get_timestamp: 1234,
+                get_timestamp_other: 0,
+                frib_event: 17,
+                frib_timestamp: 5678,
+                get_ok: 1,
+                frib_ok: 0,
+            }
+        );
+        let partial = EventMetadata::read(&file.group("event_1").unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(partial.flags, HAS_GET | HAS_GET_OK);
+        assert_eq!((partial.get_id, partial.frib_event), (0, 0));
+    }
+
+    #[test]
+    fn separate_attributes_are_not_packed() {
+        let dir = ScratchDir::new("unpacked_metadata");
+        let file = File::create(dir.path().join("unpacked.h5")).unwrap();
+        let group = file.create_group("event_0").unwrap();
+        group
+            .new_attr::<i32>()
+            .create("orig_run")
+            .unwrap()
+            .write_scalar(&4)
+            .unwrap();
+        assert_eq!(EventMetadata::read(&group).unwrap(), None);
+        assert_eq!(read_orig_run(&group).unwrap(), 4);
+    }
+}
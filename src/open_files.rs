@@ -0,0 +1,133 @@
+//! A process-wide bound on the number of files held open at once. Runs are
+//! read in parallel, so on systems with a low `ulimit -n` opening them could
+//! fail with "too many open files". Instead, each open file holds a permit,
+//! and opening a file waits until a permit is free.
+use std::sync::{Condvar, Mutex};
+
+/// The permits of a bound on the number of open files
+#[derive(Debug)]
+struct OpenFiles {
+    /// The number of permits held, and the maximum (None if unbounded)
+    held: Mutex<(usize, Option<usize>)>,
+    /// Signalled whenever a permit is released
+    released: Condvar,
+}
+
+impl OpenFiles {
+    const fn new() -> Self {
+        Self {
+            held: Mutex::new((0, None)),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Set the maximum number of permits held at once (None is unbounded)
+    fn set_max(&self, max: Option<usize>) {
+        self.held.lock().unwrap().1 = max;
+        self.released.notify_all();
+    }
+
+    /// Acquire a permit, waiting until one is free if the maximum number are
+    /// already held
+    fn acquire(&'static self) -> OpenFilePermit {
+        let mut held = self.held.lock().unwrap();
+        while held.1.is_some_and(|max| held.0 >= max) {
+            held = self.released.wait(held).unwrap();
+        }
+        held.0 += 1;
+        OpenFilePermit(self)
+    }
+}
+
+/// The bound shared by every file the harmonizer opens
+static OPEN_FILES: OpenFiles = OpenFiles::new();
+
+/// Set the maximum number of files held open at once (None is unbounded)
+pub fn set_max_open_files(max: Option<usize>) {
+    OPEN_FILES.set_max(max);
+}
+
+/// A permit to hold a file open. It is released when dropped, so it should
+/// be dropped after the file it was acquired for.
+#[derive(Debug)]
+pub struct OpenFilePermit(&'static OpenFiles);
+
+impl OpenFilePermit {
+    /// Acquire a permit, waiting until one is free if the maximum number of
+    /// files are already open
+    pub fn acquire() -> Self {
+        OPEN_FILES.acquire()
+    }
+}
+
+impl Drop for OpenFilePermit {
+    fn drop(&mut self) {
+        self.0.held.lock().unwrap().0 -= 1;
+        self.0.released.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[test]
+    fn permits_never_exceed_the_maximum() {
+        static BOUND: OpenFiles = OpenFiles::new();
+        BOUND.set_max(Some(3));
+        let open = AtomicUsize::new(0);
+        let most_open = AtomicUsize::new(0);
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..20 {
+                        let _permit = BOUND.acquire();
+                        let now = open.fetch_add(1, Ordering::SeqCst) + 1;
+                        most_open.fetch_max(now, Ordering::SeqCst);
+                        std::thread::sleep(Duration::from_micros(200));
+                        open.fetch_sub(1, Ordering::SeqCst);
+                    }
+                });
+            }
+        });
+        assert!(most_open.load(Ordering::SeqCst) <= 3);
+        assert_eq!(BOUND.held.lock().unwrap().0, 0);
+    }
+
+    #[test]
+    fn acquiring_waits_for_a_release() {
+        static BOUND: OpenFiles = OpenFiles::new();
+        BOUND.set_max(Some(2));
+        let first = BOUND.acquire();
+        let _second = BOUND.acquire();
+        let acquired = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            let waiter = scope.spawn(|| {
+                let permit = BOUND.acquire();
+                acquired.store(true, Ordering::SeqCst);
+                permit
+            });
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(!acquired.load(Ordering::SeqCst));
+            drop(first);
+            let _third = waiter.join().unwrap();
+            assert!(acquired.load(Ordering::SeqCst));
+            assert_eq!(BOUND.held.lock().unwrap().0, 2);
+        });
+    }
+
+    #[test]
+    fn raising_the_maximum_wakes_waiters() {
+        static BOUND: OpenFiles = OpenFiles::new();
+        BOUND.set_max(Some(1));
+        let _first = BOUND.acquire();
+        std::thread::scope(|scope| {
+            let waiter = scope.spawn(|| BOUND.acquire());
+            std::thread::sleep(Duration::from_millis(50));
+            BOUND.set_max(None);
+            let _second = waiter.join().unwrap();
+        });
+    }
+}
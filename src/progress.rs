@@ -0,0 +1,97 @@
+//! Persistence of progress across invocations of the harmonizer, so that
+//! a job split over several run subranges shows its overall progress.
+use color_eyre::eyre::Result;
+use indicatif::ProgressBar;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// The cumulative progress of a harmonization job. It is Ser/De-able with serde
+/// and stored as JSON.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProgressState {
+    pub events_processed: u64,
+}
+
+impl ProgressState {
+    /// Load the progress state from a JSON file. If the file does not
+    /// exist yet, the job is starting fresh.
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let json_str = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str::<Self>(&json_str)?)
+    }
+
+    /// Save the progress state to a JSON file.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+}
+
+/// A progress bar which is only updated every so many events, as updating it
+/// for every event is costly for high event rates. The events not yet added
+/// are added by flush, so that the final count is exact.
+pub struct ThrottledProgress {
+    bar: ProgressBar,
+    update_events: u64,
+    pending: u64,
+}
+
+impl ThrottledProgress {
+    pub fn new(bar: ProgressBar, update_events: u64) -> Self {
+        Self {
+            bar,
+            update_events,
+            pending: 0,
+        }
+    }
+
+    /// Count an event, updating the bar if enough events are pending
+    pub fn inc(&mut self) {
+        self.pending += 1;
+        if self.pending >= self.update_events {
+            self.flush();
+        }
+    }
+
+    /// Add the pending events to the bar
+    pub fn flush(&mut self) {
+        self.bar.inc(self.pending);
+        self.pending = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttled_progress_ends_at_the_exact_count() {
+        for update_events in [1, 7, 1000] {
+            let bar = ProgressBar::hidden();
+            let mut progress = ThrottledProgress::new(bar.clone(), update_events);
+            for _ in 0..250 {
+                progress.inc();
+            }
+            // Updates only happen every update_events events
+            assert_eq!(bar.position(), 250 / update_events * update_events);
+            progress.flush();
+            assert_eq!(bar.position(), 250, "update every {update_events}");
+        }
+    }
+
+    #[test]
+    fn throttled_progress_continues_from_the_saved_state() {
+        let bar = ProgressBar::hidden().with_position(40);
+        let mut progress = ThrottledProgress::new(bar.clone(), 1000);
+        for _ in 0..3 {
+            progress.inc();
+        }
+        progress.flush();
+        progress.flush();
+        assert_eq!(bar.position(), 43);
+    }
+}
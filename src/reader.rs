@@ -1,10 +1,29 @@
 //! Implementation of an attpc_merger Reader.
 //! Also contains utility functions for getting cummulative statsistics about
 //! the set of runs to be harmonized.
-use color_eyre::eyre::{eyre, Result};
-use hdf5_metno::File;
+use super::archive::{extract_member, member_sizes, TempFile};
+use super::blacklist::Blacklist;
+use super::config::{
+    Config, DatasetNames010, MissingAttrPolicy, RequireVersion, EVENT_PLACEHOLDER,
+};
+use super::open_files::OpenFilePermit;
+use super::writer::TRANSPOSED_ORIENTATION;
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use fs4::fs_std::FileExt;
+use hdf5_metno::types::{IntSize, TypeDescriptor, VarLenUnicode};
+use hdf5_metno::{Dataset, File, Group, H5Type, Location};
 use ndarray::{Array1, Array2};
+use rayon::prelude::*;
+use rustc_hash::FxHashMap;
+use std::collections::BTreeSet;
+use std::ops::{Deref, Range};
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// The delay before the first retry of opening a locked file. It doubles
+/// with every retry.
+const LOCK_RETRY_DELAY: Duration = Duration::from_secs(1);
 
 /// Enum for what version of the merger we are dealing with.
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -14,57 +33,596 @@ enum MergerVersion {
     Invalid,
 }
 
+impl MergerVersion {
+    /// The merger version as it is usually written
+    fn name(&self) -> &'static str {
+        match self {
+            Self::V010 => "0.1.0",
+            Self::V020 => "0.2.0",
+            Self::Invalid => "invalid",
+        }
+    }
+
+    /// Whether this version satisfies the required version
+    fn satisfies(&self, required: RequireVersion) -> bool {
+        match required {
+            RequireVersion::Any => true,
+            RequireVersion::V010 => *self == Self::V010,
+            RequireVersion::V020 => *self == Self::V020,
+        }
+    }
+}
+
 /// Construct the formated run path from a parent path and run number.
 pub fn construct_run_path(path: &Path, run_number: i32) -> PathBuf {
     path.join(format!("run_{:0>4}.h5", run_number))
 }
 
-/// Traverse the set of runs and see how much data there is (in bytes).
-pub fn get_total_merger_bytes(merger_path: &Path, min_run: i32, max_run: i32) -> Result<u64> {
-    let mut bytes = 0;
-    for run in min_run..(max_run + 1) {
-        if let Ok(meta) = construct_run_path(merger_path, run).metadata() {
-            bytes += meta.len();
+/// An open merger run file. If the run was extracted from the merger
+/// archive, the extracted copy is removed once this is dropped.
+#[derive(Debug)]
+pub struct MergerRun {
+    // Declared first so that the file is closed before the copy is removed
+    // and before its permit is released
+    file: File,
+    _extracted: Option<TempFile>,
+    _permit: OpenFilePermit,
+}
+
+impl Deref for MergerRun {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        &self.file
+    }
+}
+
+/// Whether another process holds a conflicting lock on a file, as HDF5 file
+/// locking does for a file open for writing. The file is probed with a
+/// shared lock of our own (the same `flock` HDF5 uses), rather than by
+/// matching the text of the HDF5 error, which differs between versions.
+fn is_locked(path: &Path) -> bool {
+    let Ok(file) = std::fs::File::open(path) else {
+        return false;
+    };
+    // Called through fs4, like the other locks of the harmonizer, rather than
+    // the inherent lock methods of std::fs::File
+    FileExt::try_lock_shared(&file)
+        .is_err_and(|error| error.kind() == fs4::lock_contended_error().kind())
+}
+
+/// The merger run files whose probe timed out, which are skipped from then on
+/// without probing them again
+static UNRESPONSIVE_FILES: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// Check whether a file exists and can be read, giving up if it takes longer
+/// than the timeout. The check is done with plain filesystem calls on a worker
+/// thread, which is abandoned if it times out: a read hung on a dead mount
+/// cannot be interrupted, but unlike an HDF5 call it holds no lock the rest
+/// of the harmonizer needs. Returns None if the check timed out, now or for
+/// an earlier open of the file.
+fn probe_with_timeout(path: &Path, run: i32, timeout: Duration) -> Option<bool> {
+    if UNRESPONSIVE_FILES.lock().unwrap().contains(path) {
+        return None;
+    }
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let worker_path = path.to_path_buf();
+    std::thread::spawn(move || {
+        let probe = std::fs::File::open(&worker_path)
+            .and_then(|mut file| std::io::Read::read(&mut file, &mut [0u8]));
+        // The receiver is gone if we timed out
+        let _ = sender.send(probe);
+    });
+    match receiver.recv_timeout(timeout) {
+        Ok(Err(error)) if error.kind() == std::io::ErrorKind::NotFound => Some(false),
+        // Any other error is left for HDF5 to report when opening the file
+        Ok(_) => Some(true),
+        Err(_) => {
+            println!(
+                "Warning: run {run} ({}) did not respond within {timeout:?}, its filesystem may be unresponsive. Skipping it.",
+                path.display()
+            );
+            UNRESPONSIVE_FILES
+                .lock()
+                .unwrap()
+                .insert(path.to_path_buf());
+            None
+        }
+    }
+}
+
+/// Open a merger file read-only. If it is locked by another process, the
+/// open is retried (with exponential backoff) up to the lock retries of the
+/// config, and a lock failure is reported as such rather than as a bad file.
+fn open_merger_file(path: &Path, config: &Config) -> Result<File> {
+    let retries = config.lock_retries;
+    let mut delay = LOCK_RETRY_DELAY;
+    let mut attempt = 0;
+    loop {
+        match File::open(path) {
+            Ok(file) => return Ok(file),
+            Err(error) if is_locked(path) => {
+                if attempt >= retries {
+                    return Err(eyre!(
+                        "{} is locked by another process: {error}. Set lock_retries to wait for it, or disable hdf5_file_locking.",
+                        path.display()
+                    ));
+                }
+                println!(
+                    "Warning: {} is locked by another process, retrying in {delay:?}",
+                    path.display()
+                );
+                std::thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(error) => return Err(error.into()),
         }
     }
+}
+
+/// Open a merger run, either from the merger path or by extracting it
+/// from the merger archive. Returns None if the run does not exist, or if
+/// it does not respond within the open timeout of the config (so that it is
+/// skipped like a missing run).
+pub fn open_merger_run(config: &Config, run: i32) -> Result<Option<MergerRun>> {
+    let permit = OpenFilePermit::acquire();
+    if let Some(archive) = &config.merger_archive {
+        let file_name = config.merger_run_file_name(run);
+        return match extract_member(archive, &file_name, &config.temp_dir())? {
+            Some(extracted) => Ok(Some(MergerRun {
+                file: open_merger_file(extracted.path(), config)?,
+                _extracted: Some(extracted),
+                _permit: permit,
+            })),
+            None => Ok(None),
+        };
+    }
+
+    let path = config.merger_run_path(run)?;
+    let exists = match config.open_timeout_secs.map(Duration::from_secs) {
+        Some(timeout) => match probe_with_timeout(&path, run, timeout) {
+            Some(exists) => exists,
+            None => return Ok(None),
+        },
+        None => path.exists(),
+    };
+    if !exists {
+        return Ok(None);
+    }
+    Ok(Some(MergerRun {
+        file: open_merger_file(&path, config)?,
+        _extracted: None,
+        _permit: permit,
+    }))
+}
+
+/// Read an event index attribute (min_event, max_event). Some merger builds
+/// store these as signed integers, so both signed and unsigned are accepted.
+fn read_event_attr(group: &Group, name: &str) -> Result<u64> {
+    let attr = group.attr(name)?;
+    match attr.dtype()?.to_descriptor()? {
+        TypeDescriptor::Integer(_) => {
+            let value = attr.read_scalar::<i64>()?;
+            u64::try_from(value).map_err(|_| {
+                eyre!(
+                    "Attribute {name} of {} has negative value {value}!",
+                    group.name()
+                )
+            })
+        }
+        _ => Ok(attr.read_scalar::<u64>()?),
+    }
+}
+
+/// The name of the quality flag attribute which some merger versions write to
+/// the GET traces and the FRIB group of an event. A flag of 0 marks bad data.
+pub const QUALITY_FLAG_NAME: &str = "ok";
+
+/// Read the quality flag of GET traces or an FRIB group, if it has one
+pub fn read_quality_flag(location: &Location) -> Result<Option<u8>> {
+    if !location
+        .attr_names()?
+        .iter()
+        .any(|attr| attr == QUALITY_FLAG_NAME)
+    {
+        return Ok(None);
+    }
+    Ok(Some(location.attr(QUALITY_FLAG_NAME)?.read_scalar()?))
+}
+
+/// Read a scalar attribute which some merger versions omit. If it is missing
+/// the policy decides whether that is an error, the attribute defaults to 0,
+/// or it is skipped (None).
+fn read_optional_attr<T: H5Type + Default>(
+    dataset: &Dataset,
+    name: &str,
+    policy: MissingAttrPolicy,
+) -> Result<Option<T>> {
+    if dataset.attr_names()?.iter().any(|attr| attr == name) {
+        return Ok(Some(dataset.attr(name)?.read_scalar()?));
+    }
+    match policy {
+        MissingAttrPolicy::Error => {
+            Err(eyre!("Attribute {name} is missing from {}", dataset.name()))
+        }
+        MissingAttrPolicy::Default => Ok(Some(T::default())),
+        MissingAttrPolicy::Skip => Ok(None),
+    }
+}
+
+/// Traverse the set of runs and see how much data there is (in bytes).
+/// Runs are inspected in parallel.
+pub fn get_total_merger_bytes(config: &Config) -> Result<u64> {
+    if let Some(archive) = &config.merger_archive {
+        let sizes = member_sizes(archive)?;
+        return Ok(config
+            .runs()
+            .into_iter()
+            .filter_map(|run| sizes.get(&config.merger_run_file_name(run)))
+            .sum());
+    }
+
+    let bytes = config
+        .runs()
+        .into_par_iter()
+        .filter_map(|run| config.merger_run_path(run).ok()?.metadata().ok())
+        .map(|meta| meta.len())
+        .sum();
     Ok(bytes)
 }
 
-/// Traverse the set of runs and see how many events there are.
-pub fn get_total_merger_events(merger_path: &Path, min_run: i32, max_run: i32) -> Result<u64> {
-    let mut events = 0;
-    for run in min_run..(max_run + 1) {
-        let path = construct_run_path(merger_path, run);
-        if let Ok(merger_file) = File::open(&path) {
-            if let Ok(meta_group) = merger_file.group("meta") {
-                let meta_data = meta_group.dataset("meta")?;
-                let meta_array = meta_data.read_1d::<f64>()?;
-                events += (meta_array[2] - meta_array[0]) as u64 + 1;
-            } else if let Ok(event_group) = merger_file.group("events") {
-                events += event_group.attr("max_event")?.read_scalar::<u64>()?
-                    - event_group.attr("min_event")?.read_scalar::<u64>()?;
+/// Traverse the set of runs and see how many events there are, within the
+/// event index range. Runs are inspected in parallel.
+pub fn get_total_merger_events(config: &Config) -> Result<u64> {
+    let total = config
+        .runs()
+        .into_par_iter()
+        .map(|run| get_run_events(config, run))
+        .sum::<Result<u64>>()?;
+    Ok(total
+        .min(config.event_end.unwrap_or(u64::MAX))
+        .saturating_sub(config.event_start.unwrap_or(0)))
+}
+
+/// Count the events in a single run which will be read, respecting the
+/// per run cap. Missing runs have no events, while runs which cannot be
+/// read are an error, as they would be when harmonizing.
+/// Both merger versions give an inclusive range of event numbers,
+/// so the same count applies to V010 and V020 runs.
+pub fn get_run_events(config: &Config, run: i32) -> Result<u64> {
+    let Some(merger_file) = open_merger_run(config, run)? else {
+        return Ok(0);
+    };
+    let (_, min_event, max_event) = read_run_layout(&merger_file)
+        .wrap_err_with(|| format!("Failed to read the layout of run {run}"))?;
+    Ok(count_run_events(config, min_event, max_event))
+}
+
+/// Count the events of a run expected in the harmonic output: those at the
+/// given positions among the events read from the run (in the order they are
+/// read, respecting the per run cap), less the blacklisted events, and, when
+/// bad events are dropped, those whose GET or FRIB flag is 0. Only 0.2.0 runs
+/// have quality flags. Missing runs have no events.
+pub fn get_run_expected_events(
+    config: &Config,
+    run: i32,
+    positions: Range<u64>,
+    blacklist: &Blacklist,
+) -> Result<u64> {
+    let Some(merger_file) = open_merger_run(config, run)? else {
+        return Ok(0);
+    };
+    let (version, min_event, max_event) = read_run_layout(&merger_file)?;
+    let read = count_run_events(config, min_event, max_event);
+    let events_group = match config.drop_bad_events && version == MergerVersion::V020 {
+        true => Some(merger_file.group("events")?),
+        false => None,
+    };
+    let names = &config.dataset_names;
+    let mut expected = 0;
+    for position in positions.start.min(read)..positions.end.min(read) {
+        let event = match config.reverse {
+            true => max_event - position,
+            false => min_event + position,
+        };
+        if blacklist.contains(run, event) {
+            continue;
+        }
+        let event_group = events_group
+            .as_ref()
+            .and_then(|group| group.group(&format!("event_{event}")).ok());
+        if let Some(event_group) = event_group {
+            let get_ok = match event_group.dataset(&names.get_traces) {
+                Ok(get_data) => read_quality_flag(&get_data)?,
+                Err(_) => None,
+            };
+            let frib_ok = match event_group.group(&names.frib_physics) {
+                Ok(frib_group) => read_quality_flag(&frib_group)?,
+                Err(_) => None,
+            };
+            if get_ok == Some(0) || frib_ok == Some(0) {
+                continue;
+            }
+        }
+        expected += 1;
+    }
+    Ok(expected)
+}
+
+/// Count the events of a run which are read up to and including the given
+/// event of it (in the order they are read), respecting the per run cap.
+/// Missing runs have no events.
+pub fn get_run_events_through(config: &Config, run: i32, event: u64) -> Result<u64> {
+    let Some(merger_file) = open_merger_run(config, run)? else {
+        return Ok(0);
+    };
+    let (_, min_event, max_event) = read_run_layout(&merger_file)?;
+    let event = event.clamp(min_event, max_event);
+    let consumed = if config.reverse {
+        max_event - event + 1
+    } else {
+        event - min_event + 1
+    };
+    Ok(consumed.min(count_run_events(config, min_event, max_event)))
+}
+
+/// The positions, among the events read from each run, of those within the
+/// global event index range (from event_start to event_end), given the number
+/// of events read from each run in the order they are read. Runs outside of
+/// the range have none of their events in it.
+pub fn clamp_to_event_range(config: &Config, run_events: &[u64]) -> Vec<Range<u64>> {
+    let start = config.event_start.unwrap_or(0);
+    let end = config.event_end.unwrap_or(u64::MAX);
+    let mut first = 0;
+    run_events
+        .iter()
+        .map(|events| {
+            let positions =
+                start.saturating_sub(first).min(*events)..end.saturating_sub(first).min(*events);
+            first += events;
+            positions
+        })
+        .collect()
+}
+
+/// The number of events read from a run with the given inclusive range of
+/// event numbers, respecting the per run cap.
+fn count_run_events(config: &Config, min_event: u64, max_event: u64) -> u64 {
+    let events = max_event - min_event + 1;
+    config
+        .max_events_per_source_run
+        .map_or(events, |cap| events.min(cap))
+}
+
+/// Read a single event by its global index, i.e. its position in the
+/// sequence of events read from all of the runs (counting from 0). Runs
+/// before the event only have their layout read. Returns None if the
+/// index is past the last event.
+pub fn read_event_at(config: &Config, index: u64) -> Result<Option<MergerEvent>> {
+    let runs = config.read_runs();
+    let Some((run_index, merger_file, offset, _)) = seek_event(config, &runs, index)? else {
+        return Ok(None);
+    };
+    let mut reader = MergerReader::open(config, runs, run_index, merger_file)?;
+    reader.skip_events(offset);
+    reader.read_event()
+}
+
+/// Find the run holding the event with the given global index, and open it.
+/// Returns the index of the run in the runs, its file, the position of the
+/// event among the events read from the run, and the number of missing runs
+/// passed over. Runs before the event only have their layout read. Returns
+/// None if the index is past the last event.
+fn seek_event(
+    config: &Config,
+    runs: &[i32],
+    index: u64,
+) -> Result<Option<(usize, MergerRun, u64, u64)>> {
+    let mut remaining = index;
+    let mut missing = 0;
+    for (run_index, run) in runs.iter().enumerate() {
+        let Some(merger_file) = open_merger_run(config, *run)? else {
+            missing += 1;
+            continue;
+        };
+        let (_, min_event, max_event) = read_run_layout(&merger_file)?;
+        let n_events = count_run_events(config, min_event, max_event);
+        if remaining < n_events {
+            return Ok(Some((run_index, merger_file, remaining, missing)));
+        }
+        remaining -= n_events;
+    }
+    Ok(None)
+}
+
+/// Read a single event of a run by its event number. Returns None if the
+/// run does not exist or does not have the event.
+pub fn read_run_event(config: &Config, run: i32, event: u64) -> Result<Option<MergerEvent>> {
+    let Some(merger_file) = open_merger_run(config, run)? else {
+        return Ok(None);
+    };
+    let mut reader = MergerReader::open(config, vec![run], 0, merger_file)?;
+    let (min_event, max_event) = (reader.current_min_event, reader.current_max_event);
+    if event < min_event || event > max_event {
+        return Ok(None);
+    }
+    reader.current_event = if config.reverse {
+        min_event + max_event - event
+    } else {
+        event
+    };
+    reader.read_next_event()
+}
+
+/// Check that a run can be harmonized by opening it, detecting its merger
+/// version, and reading its first event. Returns the merger version, or None
+/// if the run does not exist.
+pub fn preflight_run(config: &Config, run: i32) -> Result<Option<&'static str>> {
+    let Some(merger_file) = open_merger_run(config, run)? else {
+        return Ok(None);
+    };
+    let mut reader = MergerReader::open(config, vec![run], 0, merger_file)?;
+    if reader.read_event()?.is_none() {
+        return Err(eyre!("Run {run} has no events"));
+    }
+    Ok(Some(reader.version.name()))
+}
+
+/// Whether the events group of a 0.2.0 run has a min_event attribute. Some
+/// early 0.2.0 files only have max_event, in which case events start at 0.
+fn has_min_event(file: &File) -> Result<bool> {
+    Ok(file
+        .group("events")?
+        .attr_names()?
+        .iter()
+        .any(|attr| attr == "min_event"))
+}
+
+/// Determine the merger version of a run file, and its inclusive range of
+/// event numbers (min, max).
+fn read_run_layout(file: &File) -> Result<(MergerVersion, u64, u64)> {
+    let parent_groups = file.member_names()?;
+    if parent_groups.contains(&String::from("meta")) {
+        let meta_group = file.group("meta")?;
+        let meta_data = meta_group.dataset("meta")?;
+        let meta_array = meta_data.read_1d::<u64>()?;
+        Ok((MergerVersion::V010, meta_array[0], meta_array[2]))
+    } else if parent_groups.contains(&String::from("events")) {
+        let event_group = file.group("events")?;
+        let min_event = if has_min_event(file)? {
+            read_event_attr(&event_group, "min_event")?
+        } else {
+            0
+        };
+        Ok((
+            MergerVersion::V020,
+            min_event,
+            read_event_attr(&event_group, "max_event")?,
+        ))
+    } else {
+        Err(eyre!("Invalid Merger Version!"))
+    }
+}
+
+/// The name of the field holding the trace sample in compound GET traces
+const TRACE_SAMPLE_FIELD: &str = "sample";
+
+/// A GET trace element stored as a compound type. HDF5 converts compound
+/// types by field name, so only the sample field is read and any other
+/// fields (such as flags) are dropped.
+#[derive(H5Type, Debug, Clone, Copy)]
+#[repr(C)]
+struct CompoundSample {
+    sample: i16,
+}
+
+/// The values of the orientation attribute of GET traces stored channel-major
+/// (a row per time bucket, a column per pad), as written with transpose_traces
+/// or by some mergers
+const TRANSPOSED_ORIENTATIONS: [&str; 2] = [TRANSPOSED_ORIENTATION, "channel_major"];
+
+/// Whether a GET traces dataset has an orientation attribute marking it as
+/// transposed
+pub fn is_transposed(dataset: &Dataset) -> Result<bool> {
+    if !dataset
+        .attr_names()?
+        .iter()
+        .any(|attr| attr == "orientation")
+    {
+        return Ok(false);
+    }
+    let orientation = dataset
+        .attr("orientation")?
+        .read_scalar::<VarLenUnicode>()?;
+    Ok(TRANSPOSED_ORIENTATIONS.contains(&orientation.as_str()))
+}
+
+/// Read a GET traces dataset. Traces are normally a 2-D integer array, but
+/// may also be stored as a compound type with a sample field, in which case
+/// the samples are extracted. Transposed traces are returned to the usual
+/// orientation of a row per pad.
+pub fn read_get_traces(dataset: &Dataset) -> Result<Array2<i16>> {
+    let traces = read_trace_data(dataset)?;
+    if is_transposed(dataset)? {
+        return Ok(traces.t().as_standard_layout().into_owned());
+    }
+    Ok(traces)
+}
+
+/// Read the samples of a GET traces dataset as stored
+fn read_trace_data(dataset: &Dataset) -> Result<Array2<i16>> {
+    match dataset.dtype()?.to_descriptor()? {
+        TypeDescriptor::Compound(compound) => {
+            if !compound
+                .fields
+                .iter()
+                .any(|field| field.name == TRACE_SAMPLE_FIELD)
+            {
+                return Err(eyre!(
+                    "Compound GET traces {} have no {TRACE_SAMPLE_FIELD} field",
+                    dataset.name()
+                ));
             }
+            Ok(dataset
+                .read_2d::<CompoundSample>()?
+                .mapv(|element| element.sample))
         }
+        _ => Ok(dataset.read_2d()?),
     }
-    Ok(events)
 }
 
 /// Unified definition of a GET event from the merger
 #[derive(Debug)]
 pub struct GetEvent {
     pub traces: Array2<i16>,
-    pub id: u32,
+    pub id: Option<u32>,
     pub timestamp: u64,
-    pub timestamp_other: u64,
+    pub timestamp_other: Option<u64>,
+    pub ok: Option<u8>,
+}
+
+/// The FRIBDAQ coincidence (977) array. Merger versions store it with
+/// different integer widths, so the element type is preserved as read.
+#[derive(Debug)]
+pub enum Coincidence {
+    U8(Array1<u8>),
+    U16(Array1<u16>),
+    U32(Array1<u32>),
+}
+
+impl Coincidence {
+    /// Read a coincidence array from a dataset, keeping its element type.
+    pub fn read(dataset: &Dataset) -> Result<Self> {
+        match dataset.dtype()?.to_descriptor()? {
+            TypeDescriptor::Unsigned(IntSize::U1) => Ok(Self::U8(dataset.read_1d()?)),
+            TypeDescriptor::Unsigned(IntSize::U2) => Ok(Self::U16(dataset.read_1d()?)),
+            TypeDescriptor::Unsigned(IntSize::U4) => Ok(Self::U32(dataset.read_1d()?)),
+            other => Err(eyre!(
+                "Unsupported FRIB coincidence data type {other} in {}",
+                dataset.name()
+            )),
+        }
+    }
+
+    /// The size of the coincidence array in bytes
+    pub fn nbytes(&self) -> usize {
+        match self {
+            Self::U8(data) => data.len(),
+            Self::U16(data) => data.len() * 2,
+            Self::U32(data) => data.len() * 4,
+        }
+    }
 }
 
 /// Unified definition of an FRIBDAQ event from the merger
 #[derive(Debug)]
 pub struct FribEvent {
     pub traces: Array2<u16>,
-    pub coincidence: Array1<u16>,
+    pub coincidence: Coincidence,
     pub event: u32,
     pub timestamp: u32,
+    pub ok: Option<u8>,
 }
 
 /// Unified definition of a complete event from the merger
@@ -76,42 +634,160 @@ pub struct MergerEvent {
     pub event: u64,
 }
 
+impl MergerEvent {
+    /// The number of active GET pads (trace rows) of the event
+    pub fn get_pads(&self) -> u64 {
+        self.get.as_ref().map_or(0, |get| get.traces.nrows() as u64)
+    }
+
+    /// Whether the quality flag of the GET or FRIB data marks the event as bad
+    pub fn is_bad(&self) -> bool {
+        self.get.as_ref().is_some_and(|get| get.ok == Some(0))
+            || self.frib.as_ref().is_some_and(|frib| frib.ok == Some(0))
+    }
+
+    /// The total size of the GET and FRIB trace data in bytes
+    pub fn trace_bytes(&self) -> usize {
+        let get_bytes = self
+            .get
+            .as_ref()
+            .map_or(0, |get| get.traces.len() * std::mem::size_of::<i16>());
+        let frib_bytes = self.frib.as_ref().map_or(0, |frib| {
+            frib.traces.len() * std::mem::size_of::<u16>() + frib.coincidence.nbytes()
+        });
+        get_bytes + frib_bytes
+    }
+}
+
 /// Representation of a Reader for data from attpc_merger. It is
 /// capable of determining which version of the merger produced the
 /// data and then parsing it appropriately.
 #[derive(Debug)]
 pub struct MergerReader {
-    merger_path: PathBuf,
-    max_run: i32,
+    config: Config,
     version: MergerVersion,
+    runs: Vec<i32>,
+    run_index: usize,
     current_run: i32,
-    current_file: File,
+    current_file: MergerRun,
+    // The position within the events of the current run, which is the event
+    // number unless the events are read in reverse
     current_event: u64,
+    current_min_event: u64,
     current_max_event: u64,
+    runs_skipped: u64,
+    // Whether an event of the current run has been emitted
+    run_emitted: bool,
+    run_events_read: u64,
+    events_capped: u64,
+    runs_capped: u64,
+    last_emitted: Option<(i32, u64)>,
+    run_timestamp: Option<(i32, u64)>,
+    previous_run_timestamp: Option<(i32, u64)>,
+    frib_index: FxHashMap<u32, u64>,
+    frib_events_unpaired: u64,
+    blacklist: Blacklist,
+    events_blacklisted: u64,
+    events_dropped_bad: u64,
+    // The global index of the next event, and where to stop
+    event_index: u64,
+    event_end: Option<u64>,
 }
 
 impl MergerReader {
-    /// Create a new reader. The first run is opened and initialized.
-    pub fn new(merger_path: &Path, min_run: i32, max_run: i32) -> Result<Self> {
-        let first_file = File::open(construct_run_path(merger_path, min_run))?;
+    /// Create a new reader. The first existing run is opened and initialized,
+    /// or with an event_start, the run holding that event, positioned at it.
+    /// Missing runs before it are counted as skipped.
+    pub fn new(config: &Config) -> Result<Self> {
+        let runs = config.read_runs();
+        if runs.is_empty() {
+            return Err(eyre!("There are no runs to read!"));
+        }
+        let start = config.event_start.unwrap_or(0);
+        let (run_index, file, offset, missing) =
+            seek_event(config, &runs, start)?.ok_or_else(|| match config.event_start {
+                Some(start) => eyre!("event_start {start} is past the last event!"),
+                None => eyre!("None of the runs to read exist!"),
+            })?;
+        let mut reader = Self::open(config, runs, run_index, file)?;
+        reader.skip_events(offset);
+        reader.event_index = start;
+        reader.runs_skipped = missing;
+        reader.event_end = config.event_end;
+        if let Some(path) = &config.blacklist_path {
+            reader.blacklist = Blacklist::read(path)?;
+        }
+        Ok(reader)
+    }
+
+    /// Create a reader starting at the run with the given index in the runs
+    /// to read, whose file has already been opened. The run is initialized.
+    fn open(config: &Config, runs: Vec<i32>, run_index: usize, file: MergerRun) -> Result<Self> {
         let mut reader = Self {
-            merger_path: merger_path.to_path_buf(),
-            max_run,
+            config: config.clone(),
             version: MergerVersion::Invalid,
-            current_run: min_run,
-            current_file: first_file,
+            current_run: runs[run_index],
+            runs,
+            run_index,
+            current_file: file,
             current_event: 0,
+            current_min_event: 0,
             current_max_event: 0,
+            runs_skipped: 0,
+            run_emitted: false,
+            run_events_read: 0,
+            events_capped: 0,
+            runs_capped: 0,
+            last_emitted: None,
+            run_timestamp: None,
+            previous_run_timestamp: None,
+            frib_index: FxHashMap::default(),
+            frib_events_unpaired: 0,
+            blacklist: Blacklist::default(),
+            events_blacklisted: 0,
+            events_dropped_bad: 0,
+            event_index: 0,
+            event_end: None,
         };
         reader.init_file()?;
         Ok(reader)
     }
 
-    /// Read the next event from the run set.
+    /// Skip over the given number of events of the current run, as if they
+    /// had been read
+    fn skip_events(&mut self, n_events: u64) {
+        self.current_event += n_events;
+        self.run_events_read += n_events;
+    }
+
+    /// Read the next event from the run set, skipping blacklisted events (and
+    /// bad events, if they are dropped).
     /// If the currently open run is finished, the next run that
     /// exists within the range is opened. If there is no more data
     /// to be read it returns a None.
     pub fn read_event(&mut self) -> Result<Option<MergerEvent>> {
+        loop {
+            match self.read_next_event()? {
+                Some(event) if self.blacklist.contains(event.run_number, event.event) => {
+                    self.events_blacklisted += 1;
+                }
+                Some(event) if self.config.drop_bad_events && event.is_bad() => {
+                    self.events_dropped_bad += 1;
+                }
+                event => {
+                    self.run_emitted |= event.is_some();
+                    return Ok(event);
+                }
+            }
+        }
+    }
+
+    /// Read the next event from the run set, whether or not it is blacklisted
+    fn read_next_event(&mut self) -> Result<Option<MergerEvent>> {
+        if self.event_end.is_some_and(|end| self.event_index >= end) {
+            return Ok(None);
+        }
+        self.apply_run_cap();
         if self.current_event > self.current_max_event {
             let result = self.find_next_file()?;
             match result {
@@ -129,124 +805,537 @@ impl MergerReader {
         };
 
         self.current_event += 1;
+        self.event_index += 1;
+
+        if let Ok(Some(event)) = &result {
+            self.run_events_read += 1;
+            self.check_order(event)?;
+            self.check_timestamps(event);
+        }
 
         result
     }
 
+    /// The number of runs in the range which were skipped: the runs which did
+    /// not exist, and those which were read without any of their events being
+    /// emitted (as they were all blacklisted or dropped)
+    pub fn runs_skipped(&self) -> u64 {
+        self.runs_skipped
+    }
+
+    /// The number of runs which were cut short by the per run cap
+    pub fn runs_capped(&self) -> u64 {
+        self.runs_capped
+    }
+
+    /// The number of 0.1.0 FRIB events which were dropped when matching by
+    /// event id, as no GET event had their id (or another FRIB event of the
+    /// run had the same id)
+    pub fn frib_events_unpaired(&self) -> u64 {
+        self.frib_events_unpaired
+    }
+
+    /// The number of events which were dropped as they were blacklisted
+    pub fn events_blacklisted(&self) -> u64 {
+        self.events_blacklisted
+    }
+
+    /// The number of events dropped for a bad quality flag
+    pub fn events_dropped_bad(&self) -> u64 {
+        self.events_dropped_bad
+    }
+
+    /// The number of events which were not read due to the per run cap
+    pub fn events_capped(&self) -> u64 {
+        self.events_capped
+    }
+
+    /// If the current run has reached the per run cap, skip the rest of
+    /// its events.
+    fn apply_run_cap(&mut self) {
+        let Some(cap) = self.config.max_events_per_source_run else {
+            return;
+        };
+        if self.run_events_read < cap || self.current_event > self.current_max_event {
+            return;
+        }
+        let skipped = self.current_max_event - self.current_event + 1;
+        println!(
+            "Run {} reached the cap of {cap} events, skipping its remaining {skipped} events",
+            self.current_run
+        );
+        self.events_capped += skipped;
+        self.runs_capped += 1;
+        self.current_event = self.current_max_event + 1;
+    }
+
+    /// In strict mode, check that events within a source run are produced
+    /// with strictly increasing event indices (decreasing, in reverse).
+    fn check_order(&mut self, event: &MergerEvent) -> Result<()> {
+        if !self.config.strict {
+            return Ok(());
+        }
+        if let Some((last_run, last_event)) = self.last_emitted {
+            let out_of_order = if self.config.reverse {
+                event.event >= last_event
+            } else {
+                event.event <= last_event
+            };
+            if last_run == event.run_number && out_of_order {
+                return Err(eyre!(
+                    "Event order violation in run {last_run}: event {} was read after event {last_event}",
+                    event.event
+                ));
+            }
+        }
+        self.last_emitted = Some((event.run_number, event.event));
+        Ok(())
+    }
+
+    /// Track the largest GET timestamp of each run, and warn if the first GET
+    /// timestamp of a run precedes the largest one of the previous run. This
+    /// can indicate a clock reset or a mislabeled run. Runs read in reverse
+    /// are not checked.
+    fn check_timestamps(&mut self, event: &MergerEvent) {
+        if !self.config.warn_timestamp_overlap || self.config.reverse {
+            return;
+        }
+        let Some(timestamp) = event.get.as_ref().map(|get| get.timestamp) else {
+            return;
+        };
+        match self.run_timestamp {
+            Some((_, max_timestamp)) => {
+                self.run_timestamp = Some((event.run_number, max_timestamp.max(timestamp)))
+            }
+            None => {
+                if let Some((previous_run, previous_timestamp)) = self.previous_run_timestamp {
+                    if timestamp < previous_timestamp {
+                        println!(
+                            "Warning: run {} starts at GET timestamp {timestamp}, {} before the end of run {previous_run} (timestamp {previous_timestamp})",
+                            event.run_number,
+                            previous_timestamp - timestamp
+                        );
+                    }
+                }
+                self.run_timestamp = Some((event.run_number, timestamp));
+            }
+        }
+    }
+
     /// Initialize the current file, and update our state
     fn init_file(&mut self) -> Result<()> {
-        let parent_groups = self.current_file.member_names()?;
-        if parent_groups.contains(&String::from("meta")) {
-            self.version = MergerVersion::V010;
-            let meta_group = self.current_file.group("meta")?;
-            let meta_data = meta_group.dataset("meta")?;
-            let meta_array = meta_data.read_1d::<u64>()?;
-            self.current_event = meta_array[0];
-            self.current_max_event = meta_array[2];
-        } else if parent_groups.contains(&String::from("events")) {
-            self.version = MergerVersion::V020;
-            let event_group = self.current_file.group("events")?;
-            self.current_event = event_group.attr("min_event")?.read_scalar::<u64>()?;
-            self.current_max_event = event_group.attr("max_event")?.read_scalar::<u64>()?;
-        } else {
-            return Err(eyre!("Invalid Merger Version!"));
+        (self.version, self.current_min_event, self.current_max_event) =
+            read_run_layout(&self.current_file)?;
+        self.current_event = self.current_min_event;
+        if !self.version.satisfies(self.config.require_version) {
+            return Err(eyre!(
+                "Run {} has merger version {}, but require_version is {:?}",
+                self.current_run,
+                self.version.name(),
+                self.config.require_version
+            ));
+        }
+        self.run_events_read = 0;
+        self.run_emitted = false;
+        if self.version == MergerVersion::V020 && !has_min_event(&self.current_file)? {
+            println!(
+                "Warning: run {} has no min_event attribute, assuming its events start at 0",
+                self.current_run
+            );
+        }
+        if self.run_timestamp.is_some() {
+            self.previous_run_timestamp = self.run_timestamp.take();
+        }
+        if self.version == MergerVersion::V010 && self.config.match_frib_by_event_id {
+            self.index_frib_010()?;
         }
+        Ok(())
+    }
 
+    /// Map the event id in the header of each FRIB event of the current
+    /// 0.1.0 file to the index of its datasets.
+    fn index_frib_010(&mut self) -> Result<()> {
+        self.frib_index.clear();
+        let frib_evt_group = self.current_file.group("frib")?.group("evt")?;
+        let (prefix, suffix) = self
+            .config
+            .dataset_names
+            .v010
+            .frib_header
+            .split_once(EVENT_PLACEHOLDER)
+            .expect("Dataset name patterns are validated to have a placeholder");
+        for name in frib_evt_group.member_names()? {
+            let Some(index) = name
+                .strip_prefix(prefix)
+                .and_then(|name| name.strip_suffix(suffix))
+                .and_then(|index| index.parse::<u64>().ok())
+            else {
+                continue;
+            };
+            let event_id = frib_evt_group.dataset(&name)?.read_1d::<u32>()?[0];
+            if let Some(other) = self.frib_index.insert(event_id, index) {
+                self.frib_events_unpaired += 1;
+                println!(
+                    "Warning: run {} has FRIB events {other} and {index} with the same event id {event_id}, only the latter will be used",
+                    self.current_run
+                );
+            }
+        }
         Ok(())
     }
 
-    /// Find the next available file in the run range.
+    /// Report the FRIB events of the current run which were never paired
+    /// with a GET event when matching by event id.
+    fn report_unmatched_frib(&mut self) {
+        if self.frib_index.is_empty() {
+            return;
+        }
+        let mut unmatched: Vec<u32> = self.frib_index.drain().map(|(id, _)| id).collect();
+        unmatched.sort();
+        self.frib_events_unpaired += unmatched.len() as u64;
+        println!(
+            "Warning: {} FRIB events of run {} were not paired with a GET event (event ids {:?})",
+            unmatched.len(),
+            self.current_run,
+            unmatched
+        );
+    }
+
+    /// The event number at the current position in the current run. In
+    /// reverse the events are read from the last to the first.
+    fn event_number(&self) -> u64 {
+        if self.config.reverse {
+            self.current_min_event + self.current_max_event - self.current_event
+        } else {
+            self.current_event
+        }
+    }
+
+    /// Find the next available file in the runs to read.
     /// If there are no more runs, returns None.
     fn find_next_file(&mut self) -> Result<Option<()>> {
-        let mut path;
+        self.report_unmatched_frib();
+        if !self.run_emitted {
+            self.runs_skipped += 1;
+        }
+        // Only counted once, even if this is called again at the end
+        self.run_emitted = true;
         loop {
-            self.current_run += 1;
-            if self.current_run > self.max_run {
+            self.run_index += 1;
+            let Some(&run) = self.runs.get(self.run_index) else {
                 return Ok(None);
+            };
+            self.current_run = run;
+            match open_merger_run(&self.config, self.current_run)? {
+                Some(run_file) => {
+                    self.current_file = run_file;
+                    break;
+                }
+                None => self.runs_skipped += 1,
             }
-            path = construct_run_path(&self.merger_path, self.current_run);
-            if !path.exists() {
-                continue;
-            }
-            break;
         }
-        self.current_file = File::open(path)?;
         self.init_file()?;
         Ok(Some(()))
     }
 
-    /// Read an event from the modern merger format.
+    /// Read an event from the modern merger format, using the configured
+    /// dataset names.
     fn read_event_020(&mut self) -> Result<Option<MergerEvent>> {
         let event_group = self
             .current_file
             .group("events")?
-            .group(&format!("event_{}", self.current_event))?;
+            .group(&format!("event_{}", self.event_number()))?;
 
+        let names = &self.config.dataset_names;
         let mut maybe_get = None;
         let mut maybe_frib = None;
-        if let Ok(get_data) = event_group.dataset("get_traces") {
+        if let Ok(get_data) = event_group.dataset(&names.get_traces) {
             maybe_get = Some(GetEvent {
-                traces: get_data.read_2d()?,
-                id: get_data.attr("id")?.read_scalar()?,
+                traces: read_get_traces(&get_data)?,
+                id: read_optional_attr(&get_data, "id", self.config.on_missing_attr)?,
                 timestamp: get_data.attr("timestamp")?.read_scalar()?,
-                timestamp_other: get_data.attr("timestamp_other")?.read_scalar()?,
+                timestamp_other: read_optional_attr(
+                    &get_data,
+                    "timestamp_other",
+                    self.config.on_missing_attr,
+                )?,
+                ok: read_quality_flag(&get_data)?,
             });
         }
-        if let Ok(frib_group) = event_group.group("frib_physics") {
-            let frib_977 = frib_group.dataset("977")?;
-            let frib_1903 = frib_group.dataset("1903")?;
+        if let Ok(frib_group) = event_group.group(&names.frib_physics) {
+            let frib_977 = frib_group.dataset(&names.frib_coincidence)?;
+            let frib_1903 = frib_group.dataset(&names.frib_traces)?;
             maybe_frib = Some(FribEvent {
                 traces: frib_1903.read_2d()?,
-                coincidence: frib_977.read_1d()?,
+                coincidence: Coincidence::read(&frib_977)?,
                 event: frib_group.attr("event")?.read_scalar()?,
                 timestamp: frib_group.attr("timestamp")?.read_scalar()?,
+                ok: read_quality_flag(&frib_group)?,
             })
         }
         Ok(Some(MergerEvent {
             get: maybe_get,
             frib: maybe_frib,
             run_number: self.current_run,
-            event: self.current_event,
+            event: self.event_number(),
         }))
     }
 
-    /// Read an event from the 0.1.0 merger format
+    /// Read an event from the 0.1.0 merger format. The FRIB data is either the
+    /// data with the same index as the GET data, or, when matching by event id,
+    /// the FRIB event whose header event id is the GET event id.
     fn read_event_010(&mut self) -> Result<Option<MergerEvent>> {
         let mut maybe_get = None;
         let mut maybe_frib = None;
+        let names = &self.config.dataset_names.v010;
         let get_group = self.current_file.group("get")?;
-        if let Ok(get_data) = get_group.dataset(&format!("evt{}_data", self.current_event)) {
+        let event = self.event_number();
+        if let Ok(get_data) = get_group.dataset(&DatasetNames010::name(&names.get_traces, event)) {
             let get_header = get_group
-                .dataset(&format!("evt{}_header", self.current_event))?
+                .dataset(&DatasetNames010::name(&names.get_header, event))?
                 .read_1d::<f64>()?;
             maybe_get = Some(GetEvent {
-                traces: get_data.read_2d()?,
-                id: get_header[0] as u32,
+                traces: read_get_traces(&get_data)?,
+                id: Some(get_header[0] as u32),
                 timestamp: get_header[1] as u64,
-                timestamp_other: get_header[2] as u64,
+                timestamp_other: Some(get_header[2] as u64),
+                ok: None,
             });
         }
+        let frib_event = if self.config.match_frib_by_event_id {
+            maybe_get
+                .as_ref()
+                .and_then(|get| get.id)
+                .and_then(|id| self.frib_index.remove(&id))
+        } else {
+            Some(event)
+        };
         let frib_evt_group = self.current_file.group("frib")?.group("evt")?;
-        if let Ok(frib_1903_data) =
-            frib_evt_group.dataset(&format!("evt{}_1903", self.current_event))
-        {
-            let frib_977_data =
-                frib_evt_group.dataset(&format!("evt{}_977", self.current_event))?;
-            let frib_header = frib_evt_group
-                .dataset(&format!("evt{}_header", self.current_event))?
-                .read_1d::<u32>()?;
-            maybe_frib = Some(FribEvent {
-                traces: frib_1903_data.read_2d()?,
-                coincidence: frib_977_data.read_1d()?,
-                event: frib_header[0],
-                timestamp: frib_header[1],
-            });
+        if let Some(index) = frib_event {
+            if let Ok(frib_1903_data) =
+                frib_evt_group.dataset(&DatasetNames010::name(&names.frib_traces, index))
+            {
+                let frib_977_data = frib_evt_group
+                    .dataset(&DatasetNames010::name(&names.frib_coincidence, index))?;
+                let frib_header = frib_evt_group
+                    .dataset(&DatasetNames010::name(&names.frib_header, index))?
+                    .read_1d::<u32>()?;
+                maybe_frib = Some(FribEvent {
+                    traces: frib_1903_data.read_2d()?,
+                    coincidence: Coincidence::read(&frib_977_data)?,
+                    event: frib_header[0],
+                    timestamp: frib_header[1],
+                    ok: None,
+                });
+            }
         }
         Ok(Some(MergerEvent {
             get: maybe_get,
             frib: maybe_frib,
             run_number: self.current_run,
-            event: self.current_event,
+            event,
         }))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ScratchDir;
+    use std::time::Instant;
+
+    #[test]
+    fn locked_files_are_detected() {
+        let dir = ScratchDir::new("locked_files");
+        let path = dir.path().join("run_0001.h5");
+        std::fs::write(&path, b"data").unwrap();
+        assert!(!is_locked(&path));
+
+        let holder = std::fs::File::open(&path).unwrap();
+        FileExt::lock_exclusive(&holder).unwrap();
+        assert!(is_locked(&path));
+        FileExt::unlock(&holder).unwrap();
+        assert!(!is_locked(&path));
+        assert!(!is_locked(&dir.path().join("missing.h5")));
+    }
+
+    #[test]
+    fn unresponsive_runs_are_skipped_after_the_timeout() {
+        let dir = ScratchDir::new("open_timeout");
+        write_layout_run(dir.path(), 1, 0, 4);
+        // Opening a named pipe blocks until it has a writer, like opening a
+        // file on a dead mount
+        let pipe = construct_run_path(dir.path(), 2);
+        let status = std::process::Command::new("mkfifo")
+            .arg(&pipe)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let config = Config {
+            merger_path: dir.path().to_path_buf(),
+            open_timeout_secs: Some(1),
+            ..Default::default()
+        };
+
+        let start = Instant::now();
+        assert!(open_merger_run(&config, 2).unwrap().is_none());
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        // The unresponsive run is skipped from then on without waiting
+        let start = Instant::now();
+        assert_eq!(get_run_events(&config, 2).unwrap(), 0);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        // Responsive and missing runs are unaffected
+        assert_eq!(get_run_events(&config, 1).unwrap(), 5);
+        assert!(open_merger_run(&config, 3).unwrap().is_none());
+
+        // Let the abandoned probe finish
+        drop(std::fs::OpenOptions::new().write(true).open(&pipe).unwrap());
+    }
+
+    /// Write a 0.2.0 run with only the layout which the counting reads
+    fn write_layout_run(dir: &Path, run: i32, min_event: u64, max_event: u64) {
+        let file = File::create(construct_run_path(dir, run)).unwrap();
+        let events = file.create_group("events").unwrap();
+        for (name, value) in [("min_event", min_event), ("max_event", max_event)] {
+            events
+                .new_attr::<u64>()
+                .create(name)
+                .unwrap()
+                .write_scalar(&value)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn unreadable_runs_are_not_counted_as_empty() {
+        let dir = ScratchDir::new("unreadable_runs");
+        write_layout_run(dir.path(), 1, 0, 4);
+        std::fs::write(construct_run_path(dir.path(), 2), "not an HDF5 file").unwrap();
+        let config = Config {
+            merger_path: dir.path().to_path_buf(),
+            min_run: 1,
+            max_run: 3,
+            ..Default::default()
+        };
+        assert_eq!(get_run_events(&config, 1).unwrap(), 5);
+        assert!(get_run_events(&config, 2).is_err());
+        // Missing runs are still empty
+        assert_eq!(get_run_events(&config, 3).unwrap(), 0);
+        assert!(get_total_merger_events(&config).is_err());
+    }
+
+    #[test]
+    fn parallel_totals_match_sequential_totals() {
+        let dir = ScratchDir::new("parallel_totals");
+        // Every third run is missing
+        for run in (0..30).filter(|run| run % 3 != 0) {
+            write_layout_run(dir.path(), run, run as u64, 2 * run as u64 + 5);
+        }
+        let config = Config {
+            merger_path: dir.path().to_path_buf(),
+            min_run: 0,
+            max_run: 29,
+            ..Default::default()
+        };
+
+        let sequential_events: u64 = config
+            .runs()
+            .into_iter()
+            .map(|run| get_run_events(&config, run).unwrap())
+            .sum();
+        let sequential_bytes: u64 = config
+            .runs()
+            .into_iter()
+            .filter_map(|run| config.merger_run_path(run).unwrap().metadata().ok())
+            .map(|meta| meta.len())
+            .sum();
+        assert_eq!(
+            sequential_events,
+            (0..30)
+                .filter(|run| run % 3 != 0)
+                .map(|run| run as u64 + 6)
+                .sum::<u64>()
+        );
+        assert_eq!(get_total_merger_events(&config).unwrap(), sequential_events);
+        assert_eq!(get_total_merger_bytes(&config).unwrap(), sequential_bytes);
+    }
+
+    /// A strict reader over a layout only run, for checking the events it
+    /// would emit
+    fn strict_reader(dir: &ScratchDir, reverse: bool) -> MergerReader {
+        let config = Config {
+            merger_path: dir.path().to_path_buf(),
+            min_run: 1,
+            max_run: 1,
+            strict: true,
+            reverse,
+            ..Default::default()
+        };
+        MergerReader::new(&config).unwrap()
+    }
+
+    #[test]
+    fn event_attributes_are_read_as_unsigned_or_signed() {
+        let dir = ScratchDir::new("event_attrs");
+        let file = File::create(dir.path().join("attrs.h5")).unwrap();
+        let events = file.create_group("events").unwrap();
+        events
+            .new_attr::<u64>()
+            .create("unsigned")
+            .unwrap()
+            .write_scalar(&7u64)
+            .unwrap();
+        events
+            .new_attr::<i64>()
+            .create("signed")
+            .unwrap()
+            .write_scalar(&9i64)
+            .unwrap();
+        events
+            .new_attr::<i64>()
+            .create("negative")
+            .unwrap()
+            .write_scalar(&-1i64)
+            .unwrap();
+
+        assert_eq!(read_event_attr(&events, "unsigned").unwrap(), 7);
+        assert_eq!(read_event_attr(&events, "signed").unwrap(), 9);
+        let error = read_event_attr(&events, "negative").unwrap_err();
+        assert!(error.to_string().contains("negative value -1"), "{error}");
+    }
+
+    fn empty_event(run_number: i32, event: u64) -> MergerEvent {
+        MergerEvent {
+            get: None,
+            frib: None,
+            run_number,
+            event,
+        }
+    }
+
+    #[test]
+    fn strict_order_accepts_increasing_events() {
+        let dir = ScratchDir::new("strict_order");
+        write_layout_run(dir.path(), 1, 0, 9);
+        let mut reader = strict_reader(&dir, false);
+        for (run, event) in [(1, 0), (1, 1), (1, 5), (2, 0), (2, 3)] {
+            reader.check_order(&empty_event(run, event)).unwrap();
+        }
+        let mut reader = strict_reader(&dir, true);
+        for (run, event) in [(2, 3), (2, 0), (1, 5), (1, 1), (1, 0)] {
+            reader.check_order(&empty_event(run, event)).unwrap();
+        }
+    }
+
+    #[test]
+    fn strict_order_catches_a_desync() {
+        let dir = ScratchDir::new("strict_desync");
+        write_layout_run(dir.path(), 1, 0, 9);
+        for (repeat, reverse) in [(3, false), (2, false), (3, true), (4, true)] {
+            let mut reader = strict_reader(&dir, reverse);
+            reader.check_order(&empty_event(1, 3)).unwrap();
+            let error = reader.check_order(&empty_event(1, repeat)).unwrap_err();
+            assert!(error.to_string().contains("run 1"), "{error}");
+        }
+    }
+}
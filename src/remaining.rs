@@ -0,0 +1,104 @@
+//! An estimate of the work remaining to resume an interrupted harmonization.
+//! The last merger event which made it into the harmonic output is the
+//! resume point, and only the merger events read after it are counted.
+use super::combine::read_harmonic_event_at;
+use super::config::Config;
+use super::reader::{
+    clamp_to_event_range, get_run_events, get_run_events_through, get_total_merger_bytes,
+};
+use super::validate::find_harmonic_files;
+use color_eyre::eyre::{eyre, Result};
+use hdf5_metno::File;
+use rayon::prelude::*;
+use std::path::Path;
+
+/// The work remaining after the resume point
+#[derive(Debug, Clone)]
+pub struct RemainingEstimate {
+    /// The merger run and event of the last harmonized event, if any
+    pub resume_point: Option<(i32, u64)>,
+    pub events: u64,
+    /// Estimated from the average event size of the run range
+    pub bytes: u64,
+}
+
+/// The harmonic run number of a harmonic run file
+fn harmonic_run_number(path: &Path) -> Option<i32> {
+    path.file_stem()?
+        .to_str()?
+        .strip_prefix("run_")?
+        .parse()
+        .ok()
+}
+
+/// Find the merger run and event of the last event of the last (highest
+/// numbered) non-empty harmonic run in the output directories
+fn find_resume_point(config: &Config) -> Result<Option<(i32, u64)>> {
+    let mut files = Vec::new();
+    for dir in config.output_dirs() {
+        files.extend(find_harmonic_files(&dir)?);
+    }
+    files.sort_by_key(|path| harmonic_run_number(path));
+    for path in files.iter().rev() {
+        let events_group = File::open(path)?.group("events")?;
+        let n_events = events_group.attr("max_event")?.read_scalar::<u64>()?;
+        if n_events == 0 {
+            continue;
+        }
+        let event = read_harmonic_event_at(&events_group, n_events - 1)?;
+        return Ok(Some((event.run_number, event.event)));
+    }
+    Ok(None)
+}
+
+/// Estimate the events (and bytes) of the run range (within the global event
+/// index range) which are still to be harmonized after the resume point found
+/// in the harmonic output. Without any harmonic output, the whole run range
+/// remains.
+pub fn estimate_remaining(config: &Config) -> Result<RemainingEstimate> {
+    if config.run_remap.is_some() {
+        return Err(eyre!(
+            "The remaining work cannot be estimated for remapped runs"
+        ));
+    }
+    let runs = config.read_runs();
+    let run_events = runs
+        .par_iter()
+        .map(|run| get_run_events(config, *run))
+        .collect::<Result<Vec<_>>>()?;
+    let positions = clamp_to_event_range(config, &run_events);
+    let resume_point = find_resume_point(config)?;
+    let (later_runs, partial) = match resume_point {
+        Some((run, event)) => {
+            let index = runs.iter().position(|other| *other == run).ok_or_else(|| {
+                eyre!(
+                    "The last harmonized event came from run {run}, which is not in the run range"
+                )
+            })?;
+            let read = get_run_events_through(config, run, event)?;
+            (
+                &positions[index + 1..],
+                positions[index]
+                    .end
+                    .saturating_sub(read.max(positions[index].start)),
+            )
+        }
+        None => (&positions[..], 0),
+    };
+    let events = partial
+        + later_runs
+            .iter()
+            .map(|positions| positions.end.saturating_sub(positions.start))
+            .sum::<u64>();
+
+    let total_events: u64 = run_events.iter().sum();
+    let bytes = match total_events {
+        0 => 0,
+        total => (get_total_merger_bytes(config)? as f64 * events as f64 / total as f64) as u64,
+    };
+    Ok(RemainingEstimate {
+        resume_point,
+        events,
+        bytes,
+    })
+}
@@ -1,112 +1,710 @@
 //! Functions for processing the scalers from a run set.
-use std::path::Path;
-
-use super::reader::construct_run_path;
-use color_eyre::eyre::{eyre, Result};
+use super::config::{Config, ScalerOutput, ScalerType, SCALER_PLACEHOLDER};
+use super::reader::open_merger_run;
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use hdf5_metno::types::VarLenUnicode;
 use hdf5_metno::File;
+use indicatif::{ProgressBar, ProgressStyle};
+use ndarray::Array1;
 use polars::prelude::*;
+use rayon::prelude::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// The scalers we have
+pub const SCALER_COLUMNS: [&str; 13] = [
+    "run",
+    "event",
+    "clock_free",
+    "clock_live",
+    "trig_free",
+    "trig_live",
+    "ic_sca",
+    "mesh_sca",
+    "si1_cfd",
+    "si2",
+    "sipm",
+    "ic_ds",
+    "ic_cfd",
+];
+
+/// The scaler columns which are always written
+pub const REQUIRED_SCALER_COLUMNS: [&str; 2] = ["run", "event"];
+
+/// The delay before the first retry of writing a scaler file. It doubles
+/// with every retry.
+const WRITE_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// A progress bar over the runs whose scalers are read, with a spinner so
+/// that a slow run still shows signs of life. Concurrent scalers have no
+/// progress bar, as it would garble the harmonization progress bar.
+fn scaler_progress(config: &Config, n_runs: usize) -> Result<ProgressBar> {
+    if config.concurrent_scalers {
+        return Ok(ProgressBar::hidden());
+    }
+    let progress = ProgressBar::new(n_runs as u64).with_style(ProgressStyle::with_template(
+        "{spinner} Scalers: {bar:40.cyan/blue} [{pos}/{len} runs] {msg}",
+    )?);
+    progress.enable_steady_tick(Duration::from_millis(100));
+    Ok(progress)
+}
+
+/// Read the scalers of a run, showing the run being read
+fn read_run_scalers_with_progress(
+    config: &Config,
+    run: i32,
+    progress: &ProgressBar,
+) -> Result<Vec<Vec<u32>>> {
+    progress.set_message(format!("Reading scalers from run {run}"));
+    let scalers = read_run_scalers(config, run)?;
+    progress.inc(1);
+    Ok(scalers)
+}
+
+/// Stack the scalers of runs, in order
+fn stack_run_scalers(run_scalers: Vec<Vec<Vec<u32>>>) -> Vec<Vec<u32>> {
+    let mut scalers: Vec<Vec<u32>> = vec![vec![]; SCALER_COLUMNS.len()];
+    for run in run_scalers {
+        for (column, data) in scalers.iter_mut().zip(run) {
+            column.extend(data);
+        }
+    }
+    scalers
+}
 
-/// The main loop of processing scalers. All scalers from all runs
-/// are combined into a single polars DataFrame and written to a parquet
-/// file.
+/// The main loop of processing scalers. Runs are read in parallel. Either
+/// the scalers from all runs are combined and written to a single file, parquet
+/// (via a polars DataFrame) or HDF5, or each run's scalers are written to their
+/// own parquet file in parallel, or the scalers of the runs of each harmonic
+/// run (given by the file source runs of the harmonization) are written to
+/// their own parquet file, beside the harmonic run, in parallel. Each run is
+/// read once, however many harmonic runs hold its events. Unless overwriting, existing scaler
+/// files are an error. When appending to a harmonic dataset, the scalers are
+/// added to the existing combined scaler file, or to the existing scaler file
+/// of the harmonic run which was appended to (the first one written).
 pub fn process_scalers(
-    merger_path: &Path,
-    harmonic_path: &Path,
-    run_min: i32,
-    run_max: i32,
+    config: &Config,
+    overwrite: bool,
+    append: bool,
+    file_source_runs: Option<&[(i32, PathBuf, Vec<i32>)]>,
 ) -> Result<()> {
-    let scaler_path = harmonic_path.join("scalers.parquet");
-    let mut scalers: Vec<Vec<u32>> = vec![vec![]; 13];
-    // The scalers we have
-    let scaler_columns = [
-        "run",
-        "event",
-        "clock_free",
-        "clock_live",
-        "trig_free",
-        "trig_live",
-        "ic_sca",
-        "mesh_sca",
-        "si1_cfd",
-        "si2",
-        "sipm",
-        "ic_ds",
-        "ic_cfd",
-    ];
-    for run in run_min..(run_max + 1) {
-        if let Ok(merger_file) = File::open(construct_run_path(merger_path, run)) {
-            let parent_groups = merger_file.member_names()?;
-            if parent_groups.contains(&String::from("meta")) {
-                read_scalers_010(&mut scalers, &merger_file, run)?;
-            } else if parent_groups.contains(&String::from("events")) {
-                read_scalers_020(&mut scalers, &merger_file, run)?;
-            } else {
-                return Err(eyre!("Invalid merger version at process scalers!"));
+    if config.scaler_output == ScalerOutput::ParquetPerHarmonicRun {
+        let files = file_source_runs.ok_or_else(|| {
+            eyre!("Scalers can only be partitioned by harmonic run while harmonizing")
+        })?;
+        // A run whose events span several harmonic runs is only read once
+        let runs: BTreeSet<i32> = files
+            .iter()
+            .flat_map(|(_, _, runs)| runs.iter().copied())
+            .collect();
+        let progress = scaler_progress(config, runs.len())?;
+        let run_scalers = runs
+            .into_par_iter()
+            .map(|run| -> Result<_> {
+                Ok((run, read_run_scalers_with_progress(config, run, &progress)?))
+            })
+            .collect::<Result<BTreeMap<_, _>>>()?;
+        files.par_iter().enumerate().try_for_each(
+            |(index, (harmonic_run, harmonic_file, runs))| {
+                let path =
+                    harmonic_file.with_file_name(format!("scalers_run_{harmonic_run:0>4}.parquet"));
+                let merge = append && index == 0;
+                if !merge {
+                    check_scaler_file(&path, overwrite)?;
+                }
+                let scalers =
+                    stack_run_scalers(runs.iter().map(|run| run_scalers[run].clone()).collect());
+                if scalers[0].is_empty() {
+                    return Ok(());
+                }
+                write_scalers_parquet(&scalers, &path, config, merge)
+            },
+        )?;
+        progress.finish_with_message("Done");
+        return Ok(());
+    }
+    if config.scaler_output == ScalerOutput::ParquetPerRun {
+        let runs = config.runs();
+        let progress = scaler_progress(config, runs.len())?;
+        runs.into_par_iter().try_for_each(|run| {
+            let path = config.harmonic_path.join(format!(
+                "scalers_run_{:0>4}.parquet",
+                config.remap_run(run)?
+            ));
+            check_scaler_file(&path, overwrite)?;
+            let scalers = read_run_scalers_with_progress(config, run, &progress)?;
+            if scalers[0].is_empty() {
+                return Ok(());
             }
+            write_scalers_parquet(&scalers, &path, config, false)
+        })?;
+        progress.finish_with_message("Done");
+        return Ok(());
+    }
+
+    let path = match config.scaler_output {
+        ScalerOutput::Hdf5 => config.harmonic_path.join("scalers.h5"),
+        _ => config.harmonic_path.join("scalers.parquet"),
+    };
+    // Checked first, so that we do not read all of the scalers for nothing
+    if !append {
+        check_scaler_file(&path, overwrite)?;
+    }
+
+    let runs = config.runs();
+    let progress = scaler_progress(config, runs.len())?;
+    let run_scalers = runs
+        .into_par_iter()
+        .map(|run| read_run_scalers_with_progress(config, run, &progress))
+        .collect::<Result<Vec<_>>>()?;
+    progress.finish_with_message("Done");
+    let scalers = stack_run_scalers(run_scalers);
+
+    match config.scaler_output {
+        ScalerOutput::Parquet => write_scalers_parquet(&scalers, &path, config, append),
+        ScalerOutput::Hdf5 => write_scalers_hdf5(&scalers, &path, config, append),
+        ScalerOutput::ParquetPerRun | ScalerOutput::ParquetPerHarmonicRun => {
+            unreachable!("Per run scalers are written above")
+        }
+    }
+}
+
+/// Unless overwriting, error if a scaler file already exists
+fn check_scaler_file(path: &Path, overwrite: bool) -> Result<()> {
+    if !overwrite && path.exists() {
+        return Err(eyre!(
+            "Scaler file {} already exists! Use --overwrite-scalers to replace it.",
+            path.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Read the scalers of a single run, with the run number it is written with.
+/// Missing runs have no scalers.
+fn read_run_scalers(config: &Config, run: i32) -> Result<Vec<Vec<u32>>> {
+    let mut scalers: Vec<Vec<u32>> = vec![vec![]; SCALER_COLUMNS.len()];
+    if let Ok(Some(merger_file)) = open_merger_run(config, run) {
+        let parent_groups = merger_file.member_names()?;
+        if parent_groups.contains(&String::from("meta")) {
+            read_scalers_010(
+                &mut scalers,
+                &merger_file,
+                run,
+                &config.scaler_order.v010,
+                &config.scaler_names.v010,
+                config.scaler_events(),
+            )?;
+        } else if parent_groups.contains(&String::from("events")) {
+            read_scalers_020(
+                &mut scalers,
+                &merger_file,
+                run,
+                &config.scaler_order.v020,
+                &config.scaler_names.v020,
+                config.scaler_events(),
+            )?;
+        } else {
+            return Err(eyre!("Invalid merger version at process scalers!"));
         }
     }
+    scalers[0].fill(config.remap_run(run)? as u32);
+    Ok(scalers)
+}
 
-    let mut frame = scalers
+/// The polars data type of a scaler column type
+fn scaler_dtype(scaler_type: ScalerType) -> DataType {
+    match scaler_type {
+        ScalerType::U32 => DataType::UInt32,
+        ScalerType::I32 => DataType::Int32,
+        ScalerType::U64 => DataType::UInt64,
+        ScalerType::I64 => DataType::Int64,
+        ScalerType::F64 => DataType::Float64,
+    }
+}
+
+/// Whether a scaler column is written: the required columns always are, and
+/// the others unless they are left out of the included columns
+fn is_included(config: &Config, name: &str) -> bool {
+    REQUIRED_SCALER_COLUMNS.contains(&name)
+        || config
+            .scaler_columns_include
+            .as_ref()
+            .is_none_or(|include| include.iter().any(|column| column == name))
+}
+
+/// Write the combined scalers to a parquet file, with the column types of
+/// the scaler schema. If merging, the scalers are added after those of an
+/// existing file.
+fn write_scalers_parquet(
+    scalers: &[Vec<u32>],
+    path: &Path,
+    config: &Config,
+    merge: bool,
+) -> Result<()> {
+    let mut frame: DataFrame = scalers
         .iter()
-        .zip(scaler_columns)
+        .zip(SCALER_COLUMNS)
         .map(|(data, name)| Series::new(name.into(), data))
         .collect();
 
-    let mut parquet_file = std::fs::File::create(scaler_path)?;
-    ParquetWriter::new(&mut parquet_file).finish(&mut frame)?;
+    for (name, scaler_type) in config.scaler_schema.iter() {
+        let column = frame
+            .column(name)?
+            .strict_cast(&scaler_dtype(*scaler_type))
+            .wrap_err_with(|| format!("Could not cast scaler column {name} to {scaler_type:?}"))?;
+        frame.with_column(column)?;
+    }
+
+    if config.scaler_columns_include.is_some() {
+        frame = frame.select(
+            SCALER_COLUMNS
+                .into_iter()
+                .filter(|name| is_included(config, name)),
+        )?;
+    }
 
-    Ok(())
+    if merge && path.exists() {
+        let existing = ParquetReader::new(std::fs::File::open(path)?).finish()?;
+        frame = existing.vstack(&frame).wrap_err_with(|| {
+            format!(
+                "Cannot append to the scalers of {}, as their columns differ",
+                path.display()
+            )
+        })?;
+    }
+
+    // Encoded up front, so that only writing the file has to be retried
+    let mut encoded = Vec::new();
+    ParquetWriter::new(&mut encoded)
+        .with_row_group_size(config.scaler_row_group_size)
+        .finish(&mut frame)?;
+    retry_io(config.scaler_write_retries, WRITE_RETRY_DELAY, path, || {
+        std::fs::write(path, &encoded)
+    })
 }
 
-/// Read scalers from the 0.1.0 merger format
-fn read_scalers_010(scalers: &mut [Vec<u32>], file: &File, run: i32) -> Result<()> {
-    let scaler_group = file.group("frib")?.group("scaler")?;
-    let mut scaler: u32 = 0;
+/// Run an I/O operation on a file, retrying it (with exponential backoff from
+/// the given delay) up to the given number of times if it fails, as it can
+/// transiently on networked storage under load
+fn retry_io<T>(
+    retries: u32,
+    mut delay: Duration,
+    path: &Path,
+    mut operation: impl FnMut() -> std::io::Result<T>,
+) -> Result<T> {
+    let mut attempt = 0;
     loop {
-        if let Ok(event) = scaler_group.dataset(&format!("scaler{scaler}_data")) {
-            let data = event.read_1d()?;
+        match operation() {
+            Ok(value) => return Ok(value),
+            Err(error) if attempt < retries => {
+                println!(
+                    "Warning: writing {} failed ({error}), retrying in {delay:?}",
+                    path.display()
+                );
+                std::thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(error) => {
+                return Err(error).wrap_err_with(|| format!("Failed to write {}", path.display()))
+            }
+        }
+    }
+}
+
+/// Write the combined scalers to an HDF5 file, using the layout of the
+/// scalers group of the 0.2.0 merger format. Scaler events are renumbered
+/// continuously over the run range, and each keeps its original run and
+/// event number as the orig_run and orig_event attributes. Each scaler event
+/// holds the values of the included scaler columns, which are named (in
+/// order) by the columns attribute of the group. If merging, the scalers are
+/// added after those of an existing file.
+fn write_scalers_hdf5(
+    scalers: &[Vec<u32>],
+    path: &Path,
+    config: &Config,
+    merge: bool,
+) -> Result<()> {
+    let columns = hdf5_scaler_columns(config);
+    let column_names = columns
+        .iter()
+        .map(|column| SCALER_COLUMNS[*column])
+        .collect::<Vec<_>>()
+        .join(",");
+    let merged;
+    let scalers = if merge && path.exists() {
+        let mut existing = read_scalers_hdf5(path, &column_names, &columns)?;
+        for (column, data) in existing.iter_mut().zip(scalers) {
+            column.extend(data);
+        }
+        merged = existing;
+        &merged
+    } else {
+        scalers
+    };
+    let file = File::create(path)?;
+    let scaler_group = file.create_group("scalers")?;
+    scaler_group
+        .new_attr::<VarLenUnicode>()
+        .create("columns")?
+        .write_scalar(&VarLenUnicode::from_str(&column_names)?)?;
+    let n_scalers = scalers[0].len() as u32;
+    scaler_group
+        .new_attr::<u32>()
+        .create("min_event")?
+        .write_scalar(&0)?;
+    scaler_group
+        .new_attr::<u32>()
+        .create("max_event")?
+        .write_scalar(&n_scalers.saturating_sub(1))?;
+
+    for idx in 0..(n_scalers as usize) {
+        let data: Array1<u32> = columns.iter().map(|column| scalers[*column][idx]).collect();
+        let event = scaler_group
+            .new_dataset_builder()
+            .with_data(&data)
+            .create(format!("event_{idx}").as_str())?;
+        event
+            .new_attr::<i32>()
+            .create("orig_run")?
+            .write_scalar(&(scalers[0][idx] as i32))?;
+        event
+            .new_attr::<u64>()
+            .create("orig_event")?
+            .write_scalar(&(scalers[1][idx] as u64))?;
+    }
+
+    Ok(())
+}
+
+/// The indices of the scaler columns which are stored as the values of each
+/// event of the HDF5 scalers
+fn hdf5_scaler_columns(config: &Config) -> Vec<usize> {
+    (REQUIRED_SCALER_COLUMNS.len()..SCALER_COLUMNS.len())
+        .filter(|column| is_included(config, SCALER_COLUMNS[*column]))
+        .collect()
+}
+
+/// Read the scalers of an HDF5 scaler file, which must hold the given
+/// columns. The columns which are not stored are left as zeros.
+fn read_scalers_hdf5(path: &Path, column_names: &str, columns: &[usize]) -> Result<Vec<Vec<u32>>> {
+    let scaler_group = File::open(path)?.group("scalers")?;
+    let stored = scaler_group
+        .attr("columns")?
+        .read_scalar::<VarLenUnicode>()?;
+    if stored.as_str() != column_names {
+        return Err(eyre!(
+            "Cannot append to the scalers of {}, as their columns ({stored}) differ from {column_names}",
+            path.display()
+        ));
+    }
+    let mut scalers: Vec<Vec<u32>> = vec![vec![]; SCALER_COLUMNS.len()];
+    for idx in 0..scaler_group.len() {
+        let event = scaler_group.dataset(&format!("event_{idx}"))?;
+        let data = event.read_1d::<u32>()?;
+        scalers[0].push(event.attr("orig_run")?.read_scalar::<i32>()? as u32);
+        scalers[1].push(event.attr("orig_event")?.read_scalar::<u64>()? as u32);
+        for column in REQUIRED_SCALER_COLUMNS.len()..SCALER_COLUMNS.len() {
+            let value = columns
+                .iter()
+                .position(|stored| *stored == column)
+                .map_or(0, |index| data[index]);
+            scalers[column].push(value);
+        }
+    }
+    Ok(scalers)
+}
+
+/// Warn that a run has no scaler group, which is common for runs where FRIB
+/// was not recording. The run contributes no scaler rows.
+fn warn_missing_scalers(run: i32) {
+    println!("Warning: run {run} has no scalers, no scaler rows will be written for it");
+}
+
+/// The name of a scaler dataset from its name pattern
+fn scaler_name(pattern: &str, scaler: u32) -> String {
+    pattern.replace(SCALER_PLACEHOLDER, &scaler.to_string())
+}
+
+/// Read scalers from the 0.1.0 merger format. The order gives the index in the
+/// scaler data of the value of each scaler column, and the name pattern the
+/// names of the scaler datasets. Only the scaler events in the given range are
+/// read.
+fn read_scalers_010(
+    scalers: &mut [Vec<u32>],
+    file: &File,
+    run: i32,
+    order: &[usize],
+    name: &str,
+    events: RangeInclusive<u32>,
+) -> Result<()> {
+    let Ok(scaler_group) = file.group("frib").and_then(|frib| frib.group("scaler")) else {
+        warn_missing_scalers(run);
+        return Ok(());
+    };
+    let mut scaler: u32 = *events.start();
+    while events.contains(&scaler) {
+        if let Ok(event) = scaler_group.dataset(&scaler_name(name, scaler)) {
+            let data = event
+                .read_1d()
+                .wrap_err_with(|| format!("Failed to read scaler {scaler} of run {run}"))?;
             scalers[0].push(run as u32);
             scalers[1].push(scaler);
-            scalers[2].push(data[0]);
-            scalers[3].push(data[1]);
-            scalers[4].push(data[2]);
-            scalers[5].push(data[3]);
-            scalers[6].push(data[4]);
-            scalers[7].push(data[5]);
-            scalers[8].push(data[6]);
-            scalers[9].push(data[7]);
-            scalers[10].push(data[8]);
-            scalers[11].push(data[9]);
-            scalers[12].push(data[10]);
+            for (column, index) in scalers[2..].iter_mut().zip(order) {
+                column.push(data[*index]);
+            }
         } else {
             break;
         }
-        scaler += 1;
+        scaler = match scaler.checked_add(1) {
+            Some(next) => next,
+            None => break,
+        };
     }
     Ok(())
 }
 
-/// Read scalers from the modern merger format
-fn read_scalers_020(scalers: &mut [Vec<u32>], file: &File, run: i32) -> Result<()> {
-    let scaler_group = file.group("scalers")?;
-    let scaler_min = scaler_group.attr("min_event")?.read_scalar::<u32>()?;
-    let scaler_max = scaler_group.attr("max_event")?.read_scalar::<u32>()?;
-    for scaler in scaler_min..(scaler_max + 1) {
-        if let Ok(event) = scaler_group.dataset(&format!("event_{scaler}"))?.read_1d() {
+/// Read scalers from the modern merger format. The order gives the index in
+/// the scaler data of the value of each scaler column, and the name pattern
+/// the names of the scaler datasets. Only the scaler events in the given range
+/// are read.
+fn read_scalers_020(
+    scalers: &mut [Vec<u32>],
+    file: &File,
+    run: i32,
+    order: &[usize],
+    name: &str,
+    events: RangeInclusive<u32>,
+) -> Result<()> {
+    let Ok(scaler_group) = file.group("scalers") else {
+        warn_missing_scalers(run);
+        return Ok(());
+    };
+    let scaler_min = scaler_group
+        .attr("min_event")?
+        .read_scalar::<u32>()?
+        .max(*events.start());
+    let scaler_max = scaler_group
+        .attr("max_event")?
+        .read_scalar::<u32>()?
+        .min(*events.end());
+    for scaler in scaler_min..=scaler_max {
+        let dataset = scaler_group
+            .dataset(&scaler_name(name, scaler))
+            .wrap_err_with(|| format!("Failed to open scaler {scaler} of run {run}"))?;
+        if let Ok(event) = dataset.read_1d() {
             scalers[0].push(run as u32);
             scalers[1].push(scaler);
-            scalers[2].push(event[0]);
-            scalers[3].push(event[1]);
-            scalers[4].push(event[2]);
-            scalers[5].push(event[3]);
-            scalers[6].push(event[4]);
-            scalers[7].push(event[5]);
-            scalers[8].push(event[6]);
-            scalers[9].push(event[7]);
-            scalers[10].push(event[8]);
-            scalers[11].push(event[9]);
-            scalers[12].push(event[10]);
+            for (column, index) in scalers[2..].iter_mut().zip(order) {
+                column.push(event[*index]);
+            }
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ScratchDir;
+
+    /// Combined scalers of two runs of two and three scaler events
+    fn combined_scalers() -> Vec<Vec<u32>> {
+        let mut scalers = vec![vec![3, 3, 4, 4, 4], vec![0, 1, 0, 1, 2]];
+        for column in 2..SCALER_COLUMNS.len() {
+            scalers.push((0..5).map(|event| (column * 10 + event) as u32).collect());
+        }
+        scalers
+    }
+
+    #[test]
+    fn hdf5_scalers_round_trip() {
+        let dir = ScratchDir::new("hdf5_scalers");
+        let path = dir.path().join("scalers.h5");
+        let written = combined_scalers();
+        write_scalers_hdf5(&written, &path, &Config::default(), false).unwrap();
+
+        let file = File::open(&path).unwrap();
+        let config = Config::default();
+        let mut read: Vec<Vec<u32>> = vec![vec![]; SCALER_COLUMNS.len()];
+        read_scalers_020(
+            &mut read,
+            &file,
+            0,
+            &config.scaler_order.v020,
+            &config.scaler_names.v020,
+            config.scaler_events(),
+        )
+        .unwrap();
+        assert_eq!(read[1], vec![0, 1, 2, 3, 4]);
+        assert_eq!(read[2..], written[2..]);
+
+        let group = file.group("scalers").unwrap();
+        for (idx, (run, event)) in written[0].iter().zip(&written[1]).enumerate() {
+            let dataset = group.dataset(&format!("event_{idx}")).unwrap();
+            let orig_run: i32 = dataset.attr("orig_run").unwrap().read_scalar().unwrap();
+            let orig_event: u64 = dataset.attr("orig_event").unwrap().read_scalar().unwrap();
+            assert_eq!((orig_run, orig_event), (*run as i32, *event as u64));
+        }
+        let columns: VarLenUnicode = group.attr("columns").unwrap().read_scalar().unwrap();
+        assert_eq!(columns.as_str(), SCALER_COLUMNS[2..].join(","));
+    }
+
+    #[test]
+    fn hdf5_scalers_hold_only_the_included_columns() {
+        let dir = ScratchDir::new("hdf5_scaler_columns");
+        let path = dir.path().join("scalers.h5");
+        let written = combined_scalers();
+        let config = Config {
+            scaler_columns_include: Some(vec!["trig_live".into(), "clock_live".into()]),
+            ..Default::default()
+        };
+        write_scalers_hdf5(&written, &path, &config, false).unwrap();
+
+        let group = File::open(&path).unwrap().group("scalers").unwrap();
+        let columns: VarLenUnicode = group.attr("columns").unwrap().read_scalar().unwrap();
+        assert_eq!(columns.as_str(), "clock_live,trig_live");
+        for idx in 0..5 {
+            let data = group
+                .dataset(&format!("event_{idx}"))
+                .unwrap()
+                .read_raw::<u32>()
+                .unwrap();
+            assert_eq!(data, vec![written[3][idx], written[5][idx]]);
+        }
+    }
+
+    #[test]
+    fn merged_scalers_follow_the_existing_ones() {
+        let dir = ScratchDir::new("merged_scalers");
+        let written = combined_scalers();
+        let config = Config::default();
+        let doubled: Vec<Vec<u32>> = written.iter().map(|column| column.repeat(2)).collect();
+
+        let path = dir.path().join("scalers.h5");
+        write_scalers_hdf5(&written, &path, &config, false).unwrap();
+        write_scalers_hdf5(&written, &path, &config, true).unwrap();
+        let columns = hdf5_scaler_columns(&config);
+        let names = SCALER_COLUMNS[2..].join(",");
+        assert_eq!(read_scalers_hdf5(&path, &names, &columns).unwrap(), doubled);
+        // Scalers with other columns can't be merged
+        let other = Config {
+            scaler_columns_include: Some(vec!["clock_live".into()]),
+            ..Default::default()
+        };
+        assert!(write_scalers_hdf5(&written, &path, &other, true).is_err());
+
+        let path = dir.path().join("scalers.parquet");
+        write_scalers_parquet(&written, &path, &config, false).unwrap();
+        write_scalers_parquet(&written, &path, &config, true).unwrap();
+        let frame = ParquetReader::new(std::fs::File::open(&path).unwrap())
+            .finish()
+            .unwrap();
+        assert_eq!(frame.height(), doubled[0].len());
+        let events: Vec<String> = frame
+            .column("event")
+            .unwrap()
+            .as_materialized_series()
+            .iter()
+            .map(|value| value.to_string())
+            .collect();
+        let expected: Vec<String> = doubled[1].iter().map(|event| event.to_string()).collect();
+        assert_eq!(events, expected);
+    }
+
+    #[test]
+    fn scaler_read_errors_name_the_run_and_scaler() {
+        let dir = ScratchDir::new("scaler_errors");
+        let file = File::create(dir.path().join("run_0007.h5")).unwrap();
+        let group = file.create_group("scalers").unwrap();
+        for (name, value) in [("min_event", 0u32), ("max_event", 2)] {
+            group
+                .new_attr::<u32>()
+                .create(name)
+                .unwrap()
+                .write_scalar(&value)
+                .unwrap();
+        }
+        // Scaler 2 is missing
+        for scaler in 0..2 {
+            group
+                .new_dataset_builder()
+                .with_data(&Array1::<u32>::zeros(11))
+                .create(format!("event_{scaler}").as_str())
+                .unwrap();
+        }
+
+        let config = Config::default();
+        let mut scalers: Vec<Vec<u32>> = vec![vec![]; SCALER_COLUMNS.len()];
+        let error = read_scalers_020(
+            &mut scalers,
+            &file,
+            7,
+            &config.scaler_order.v020,
+            &config.scaler_names.v020,
+            config.scaler_events(),
+        )
+        .unwrap_err();
+        assert_eq!(error.to_string(), "Failed to open scaler 2 of run 7");
+    }
+
+    #[test]
+    fn scaler_names_follow_the_pattern() {
+        assert_eq!(scaler_name("event_{n}", 0), "event_0");
+        assert_eq!(scaler_name("scaler{n}_data", 12), "scaler12_data");
+        assert_eq!(scaler_name("{n}", 7), "7");
+    }
+
+    /// A write which fails with an I/O error the given number of times
+    /// before it succeeds, counting its attempts
+    fn flaky_write<'a>(
+        path: &'a Path,
+        failures: u32,
+        attempts: &'a mut u32,
+    ) -> impl FnMut() -> std::io::Result<()> + 'a {
+        move || {
+            *attempts += 1;
+            if *attempts <= failures {
+                return Err(std::io::Error::other("stale file handle"));
+            }
+            std::fs::write(path, b"scalers")
+        }
+    }
+
+    #[test]
+    fn failed_writes_are_retried_until_they_succeed() {
+        let dir = ScratchDir::new("retry_io");
+        let path = dir.path().join("scalers.parquet");
+        let mut attempts = 0;
+        retry_io(
+            3,
+            Duration::ZERO,
+            &path,
+            flaky_write(&path, 2, &mut attempts),
+        )
+        .unwrap();
+        assert_eq!(attempts, 3);
+        assert_eq!(std::fs::read(&path).unwrap(), b"scalers");
+    }
+
+    #[test]
+    fn failed_writes_give_up_after_the_retries() {
+        let dir = ScratchDir::new("retry_io_exhausted");
+        let path = dir.path().join("scalers.parquet");
+        let mut attempts = 0;
+        let error = retry_io(
+            2,
+            Duration::ZERO,
+            &path,
+            flaky_write(&path, 5, &mut attempts),
+        )
+        .unwrap_err();
+        assert_eq!(attempts, 3);
+        assert!(error.to_string().contains("Failed to write"), "{error}");
+        assert!(!path.exists());
+    }
+}
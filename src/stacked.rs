@@ -0,0 +1,292 @@
+//! The stacked event storage of harmonic files. Instead of a group per event
+//! (millions of groups, whose metadata dominates the writing time and file
+//! size), all of the events of a file are stored in a few extensible datasets
+//! indexed by event: the packed metadata, and the GET and FRIB traces stacked
+//! into 3-D datasets (event x row x column). Stacking requires every event of
+//! a file to have traces of the same shape, which can optionally be reached
+//! by padding the traces with zeros to the largest shape in the file.
+use super::metadata::{
+    EventMetadata, HAS_FRIB, HAS_FRIB_OK, HAS_GET, HAS_GET_ID, HAS_GET_OK, HAS_GET_TIMESTAMP_OTHER,
+};
+use super::reader::{Coincidence, FribEvent, GetEvent, MergerEvent};
+use color_eyre::eyre::{eyre, Result};
+use hdf5_metno::types::VarLenUnicode;
+use hdf5_metno::{Dataset, DatasetBuilder, Group, H5Type};
+use ndarray::{s, Array1, Array2};
+use std::str::FromStr;
+
+/// The value of the storage attribute of the events group of stacked files
+pub const STACKED_STORAGE: &str = "stacked";
+
+/// The number of events per chunk of the 1-D per-event datasets
+const EVENT_CHUNK: usize = 1024;
+
+/// Whether an events group uses the stacked storage
+pub fn is_stacked(events_group: &Group) -> Result<bool> {
+    if !events_group
+        .attr_names()?
+        .iter()
+        .any(|attr| attr == "storage")
+    {
+        return Ok(false);
+    }
+    let storage = events_group
+        .attr("storage")?
+        .read_scalar::<VarLenUnicode>()?;
+    Ok(storage.as_str() == STACKED_STORAGE)
+}
+
+/// Create an extensible 1-D per-event dataset
+fn create_event_dataset<T: H5Type>(events_group: &Group, name: &str) -> Result<Dataset> {
+    Ok(events_group
+        .new_dataset::<T>()
+        .chunk(EVENT_CHUNK)
+        .shape(0..)
+        .create(name)?)
+}
+
+/// Set the value of an event in a 1-D per-event dataset
+fn write_value<T: H5Type + Clone>(dataset: &Dataset, index: usize, value: T) -> Result<()> {
+    dataset.write_slice(&Array1::from_elem(1, value), s![index..index + 1])?;
+    Ok(())
+}
+
+/// The GET or FRIB traces of the events of a stacked events group: the
+/// traces stacked into a 3-D dataset (event x row x column), and the number
+/// of rows and columns of the traces of each event, so that padding can be
+/// trimmed when they are read.
+#[derive(Debug)]
+struct StackedTraces {
+    name: &'static str,
+    /// Created with the first traces written
+    traces: Option<Dataset>,
+    rows: Dataset,
+    columns: Dataset,
+}
+
+impl StackedTraces {
+    fn init(events_group: &Group, name: &'static str, prefix: &str) -> Result<Self> {
+        Ok(Self {
+            name,
+            traces: None,
+            rows: create_event_dataset::<u32>(events_group, &format!("{prefix}_rows"))?,
+            columns: create_event_dataset::<u32>(events_group, &format!("{prefix}_columns"))?,
+        })
+    }
+
+    /// Set the traces of an event. The rows and columns of the 3-D dataset
+    /// are those of the first traces written; if padding, they grow to fit
+    /// larger traces, and smaller traces fill only part of them (the rest is
+    /// zero). Without padding, traces of another shape are an error.
+    fn write<T: H5Type>(
+        &mut self,
+        builder: impl FnOnce() -> DatasetBuilder,
+        index: usize,
+        capacity: usize,
+        traces: Option<&Array2<T>>,
+        padding: bool,
+    ) -> Result<()> {
+        let (rows, columns) = traces.map_or((0, 0), |traces| traces.dim());
+        write_value(&self.rows, index, rows as u32)?;
+        write_value(&self.columns, index, columns as u32)?;
+        let Some(traces) = traces else {
+            return Ok(());
+        };
+        if self.traces.is_none() {
+            let dataset = builder()
+                .empty::<T>()
+                .chunk((1, rows.max(1), columns.max(1)))
+                .shape((0.., 0.., 0..))
+                .create(self.name)?;
+            dataset.resize((capacity, rows, columns))?;
+            self.traces = Some(dataset);
+        }
+        let dataset = self.traces.as_ref().expect("The traces were just created");
+        let shape = dataset.shape();
+        let (stacked_rows, stacked_columns) = (shape[1], shape[2]);
+        if (rows, columns) != (stacked_rows, stacked_columns) {
+            if !padding {
+                return Err(eyre!(
+                    "Traces of shape ({rows}, {columns}) cannot be stacked with the {} of shape ({stacked_rows}, {stacked_columns}) of the first event with traces in the file; set stacked_padding to pad them",
+                    self.name
+                ));
+            }
+            if rows > stacked_rows || columns > stacked_columns {
+                dataset.resize((
+                    shape[0],
+                    rows.max(stacked_rows),
+                    columns.max(stacked_columns),
+                ))?;
+            }
+        }
+        if rows > 0 && columns > 0 {
+            dataset.write_slice(traces, s![index, ..rows, ..columns])?;
+        }
+        Ok(())
+    }
+
+    /// Set the number of events of the datasets
+    fn resize(&self, events: usize) -> Result<()> {
+        self.rows.resize(events)?;
+        self.columns.resize(events)?;
+        if let Some(traces) = self.traces.as_ref() {
+            let shape = traces.shape();
+            traces.resize((events, shape[1], shape[2]))?;
+        }
+        Ok(())
+    }
+}
+
+/// The writing of the events of a file to a stacked events group. The
+/// datasets are looked up once per file, and grow in steps of EVENT_CHUNK
+/// events; they are trimmed to the number of events written when the file is
+/// finished.
+#[derive(Debug)]
+pub struct StackedWriter {
+    events_group: Group,
+    metadata: Dataset,
+    get: StackedTraces,
+    frib: StackedTraces,
+    /// The number of events the datasets have room for
+    capacity: usize,
+    padding: bool,
+}
+
+impl StackedWriter {
+    /// Prepare a new events group for stacked storage. If padding, traces of
+    /// different shapes are padded with zeros to be stacked.
+    pub fn init(events_group: &Group, padding: bool) -> Result<Self> {
+        events_group
+            .new_attr::<VarLenUnicode>()
+            .create("storage")?
+            .write_scalar(&VarLenUnicode::from_str(STACKED_STORAGE).unwrap())?;
+        Ok(Self {
+            events_group: events_group.clone(),
+            metadata: create_event_dataset::<EventMetadata>(events_group, "metadata")?,
+            get: StackedTraces::init(events_group, "get_traces", "get")?,
+            frib: StackedTraces::init(events_group, "frib_traces", "frib")?,
+            capacity: 0,
+            padding,
+        })
+    }
+
+    /// Write an event as the event with the given index, creating the trace
+    /// datasets with the builder when they are first needed. The FRIB
+    /// coincidence data is not stored.
+    pub fn write_event(
+        &mut self,
+        index: u64,
+        event: &MergerEvent,
+        trace_builder: impl Fn(&Group) -> DatasetBuilder,
+    ) -> Result<()> {
+        let index = index as usize;
+        if index >= self.capacity {
+            self.capacity = (index / EVENT_CHUNK + 1) * EVENT_CHUNK;
+            self.resize(self.capacity)?;
+        }
+        write_value(&self.metadata, index, EventMetadata::from_event(event))?;
+        self.get.write(
+            || trace_builder(&self.events_group),
+            index,
+            self.capacity,
+            event.get.as_ref().map(|get| &get.traces),
+            self.padding,
+        )?;
+        self.frib.write(
+            || trace_builder(&self.events_group),
+            index,
+            self.capacity,
+            event.frib.as_ref().map(|frib| &frib.traces),
+            self.padding,
+        )?;
+        Ok(())
+    }
+
+    /// Trim the datasets to the number of events written
+    pub fn finish(&self, events: u64) -> Result<()> {
+        self.resize(events as usize)
+    }
+
+    fn resize(&self, events: usize) -> Result<()> {
+        self.metadata.resize(events)?;
+        self.get.resize(events)?;
+        self.frib.resize(events)
+    }
+}
+
+/// The number of events in a stacked events group
+pub fn stacked_len(events_group: &Group) -> Result<u64> {
+    Ok(events_group.dataset("metadata")?.size() as u64)
+}
+
+/// Read the original run of every event of a stacked events group
+pub fn read_stacked_orig_runs(events_group: &Group) -> Result<Vec<i32>> {
+    Ok(events_group
+        .dataset("metadata")?
+        .read_1d::<EventMetadata>()?
+        .iter()
+        .map(|metadata| metadata.orig_run)
+        .collect())
+}
+
+/// Read the traces of an event from a 3-D dataset of stacked traces, trimmed
+/// to the given number of rows and columns
+fn read_traces<T: H5Type>(
+    events_group: &Group,
+    name: &str,
+    index: usize,
+    (rows, columns): (usize, usize),
+) -> Result<Array2<T>> {
+    Ok(events_group
+        .dataset(name)?
+        .read_slice_2d::<T, _>(s![index, ..rows, ..columns])?)
+}
+
+/// Read the event with the given index from a stacked events group
+pub fn read_stacked_event(events_group: &Group, index: u64) -> Result<MergerEvent> {
+    let index = index as usize;
+    let metadata = events_group
+        .dataset("metadata")?
+        .read_slice_1d::<EventMetadata, _>(s![index..index + 1])?[0];
+    let value = |name: &str| -> Result<usize> {
+        Ok(events_group
+            .dataset(name)?
+            .read_slice_1d::<u32, _>(s![index..index + 1])?[0] as usize)
+    };
+    let dim = |prefix: &str| -> Result<(usize, usize)> {
+        Ok((
+            value(&format!("{prefix}_rows"))?,
+            value(&format!("{prefix}_columns"))?,
+        ))
+    };
+    let has = |flag: u8| metadata.flags & flag != 0;
+
+    let get = if has(HAS_GET) {
+        Some(GetEvent {
+            traces: read_traces(events_group, "get_traces", index, dim("get")?)?,
+            id: has(HAS_GET_ID).then_some(metadata.get_id),
+            timestamp: metadata.get_timestamp,
+            timestamp_other: has(HAS_GET_TIMESTAMP_OTHER).then_some(metadata.get_timestamp_other),
+            ok: has(HAS_GET_OK).then_some(metadata.get_ok),
+        })
+    } else {
+        None
+    };
+    let frib = if has(HAS_FRIB) {
+        Some(FribEvent {
+            traces: read_traces(events_group, "frib_traces", index, dim("frib")?)?,
+            coincidence: Coincidence::U8(Array1::zeros(0)),
+            event: metadata.frib_event,
+            timestamp: metadata.frib_timestamp,
+            ok: has(HAS_FRIB_OK).then_some(metadata.frib_ok),
+        })
+    } else {
+        None
+    };
+    Ok(MergerEvent {
+        get,
+        frib,
+        run_number: metadata.orig_run,
+        event: metadata.orig_event,
+    })
+}
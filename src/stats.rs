@@ -0,0 +1,74 @@
+//! Statistics describing the outcome of a harmonization
+use color_eyre::eyre::Result;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Wall time spent in each phase of a harmonization. Reading and writing
+/// happen on separate threads, so their times overlap.
+#[derive(Debug, Clone, Default)]
+pub struct PhaseTimings {
+    pub counting: Duration,
+    pub reading: Duration,
+    pub writing: Duration,
+    pub scalers: Duration,
+}
+
+/// The outcome of a harmonization, returned by `harmonize`
+/// so that it can be inspected programmatically.
+#[derive(Debug, Clone, Default)]
+pub struct HarmonizeStats {
+    pub events_written: u64,
+    pub files_produced: u64,
+    pub bytes_written: u64,
+    pub runs_skipped: u64,
+    pub runs_capped: u64,
+    pub events_capped: u64,
+    pub events_counted: u64,
+    pub events_blacklisted: u64,
+    pub events_dropped_bad: u64,
+    pub frib_events_unpaired: u64,
+    pub output_budget_reached: bool,
+    pub free_space_exhausted: bool,
+    /// Each harmonic run written, with its file and the merger runs whose
+    /// events it holds
+    pub file_source_runs: Vec<(i32, PathBuf, Vec<i32>)>,
+    pub duration: Duration,
+    pub timings: PhaseTimings,
+}
+
+impl HarmonizeStats {
+    /// Write the statistics as a JSON summary, for pipelines which would
+    /// otherwise parse the printed output. Durations are in seconds. The
+    /// reconciliation accounts for the events counted before harmonizing;
+    /// any which are unaccounted for were not read (or were lost).
+    pub fn write_summary_json(&self, path: &Path) -> Result<()> {
+        let accounted = self.events_written + self.events_blacklisted + self.events_dropped_bad;
+        let summary = serde_json::json!({
+            "events_written": self.events_written,
+            "files_produced": self.files_produced,
+            "bytes_written": self.bytes_written,
+            "runs_skipped": self.runs_skipped,
+            "runs_capped": self.runs_capped,
+            "frib_events_unpaired": self.frib_events_unpaired,
+            "output_budget_reached": self.output_budget_reached,
+            "free_space_exhausted": self.free_space_exhausted,
+            "duration_seconds": self.duration.as_secs_f64(),
+            "reconciliation": {
+                "events_counted": self.events_counted,
+                "events_written": self.events_written,
+                "events_blacklisted": self.events_blacklisted,
+                "events_dropped_bad": self.events_dropped_bad,
+                "events_capped": self.events_capped,
+                "events_unaccounted": self.events_counted as i64 - accounted as i64,
+            },
+            "timings_seconds": {
+                "counting": self.timings.counting.as_secs_f64(),
+                "reading": self.timings.reading.as_secs_f64(),
+                "writing": self.timings.writing.as_secs_f64(),
+                "scalers": self.timings.scalers.as_secs_f64(),
+            },
+        });
+        std::fs::write(path, serde_json::to_string_pretty(&summary)?)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,36 @@
+//! Helpers shared by the unit tests
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counter used to give each scratch directory a unique name
+static SCRATCH_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// A scratch directory in the system temporary directory, which is removed
+/// (with its contents) when it is dropped
+#[derive(Debug)]
+pub struct ScratchDir {
+    path: PathBuf,
+}
+
+impl ScratchDir {
+    /// Create a new, empty, scratch directory
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "harmonizer_unit_{}_{}_{name}",
+            std::process::id(),
+            SCRATCH_COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
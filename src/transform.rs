@@ -0,0 +1,117 @@
+//! Built-in transforms of the GET traces, for common preprocessing (such as
+//! pedestal subtraction) done while harmonizing. Each transform is a pure
+//! function of the traces, applied to every row (trace) of an event.
+use super::config::TraceTransform;
+use ndarray::{s, Array2, Axis};
+
+/// Subtract from each trace its baseline, taken to be the median of its
+/// samples (robust to the pulse, which occupies only a few samples)
+pub fn subtract_baseline(traces: &Array2<i16>) -> Array2<i16> {
+    let mut transformed = traces.clone();
+    for mut row in transformed.axis_iter_mut(Axis(0)) {
+        let mut samples = row.to_vec();
+        if samples.is_empty() {
+            continue;
+        }
+        let middle = samples.len() / 2;
+        let baseline = *samples.select_nth_unstable(middle).1;
+        row.mapv_inplace(|sample| sample.saturating_sub(baseline));
+    }
+    transformed
+}
+
+/// Set negative samples to 0
+pub fn clip_negative(traces: &Array2<i16>) -> Array2<i16> {
+    traces.mapv(|sample| sample.max(0))
+}
+
+/// Right-pad (with the fill value) or truncate the time axis of each trace to
+/// the given length. Traces which already have the length are left as they are.
+pub fn pad_traces(traces: Array2<i16>, length: usize, fill: i16) -> Array2<i16> {
+    if traces.ncols() == length {
+        return traces;
+    }
+    let mut padded = Array2::from_elem((traces.nrows(), length), fill);
+    let kept = traces.ncols().min(length);
+    padded
+        .slice_mut(s![.., ..kept])
+        .assign(&traces.slice(s![.., ..kept]));
+    padded
+}
+
+/// Apply a transform to traces
+fn apply_transform(traces: &Array2<i16>, transform: TraceTransform) -> Array2<i16> {
+    match transform {
+        TraceTransform::SubtractBaseline => subtract_baseline(traces),
+        TraceTransform::ClipNegative => clip_negative(traces),
+    }
+}
+
+/// Apply the transforms to traces, in order. Without any transforms, the
+/// traces are unchanged.
+pub fn apply_transforms(traces: Array2<i16>, transforms: &[TraceTransform]) -> Array2<i16> {
+    transforms.iter().fold(traces, |traces, transform| {
+        apply_transform(&traces, *transform)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn baseline_is_the_median_of_each_trace() {
+        let traces = array![[10, 10, 50, 10, 12], [-5, -5, -5, 20, -5]];
+        assert_eq!(
+            subtract_baseline(&traces),
+            array![[0, 0, 40, 0, 2], [0, 0, 0, 25, 0]]
+        );
+        // Values saturate instead of wrapping
+        let extreme = array![[-30000, 30000, 30000]];
+        assert_eq!(subtract_baseline(&extreme), array![[i16::MIN, 0, 0]]);
+        let empty = Array2::<i16>::zeros((3, 0));
+        assert_eq!(subtract_baseline(&empty), empty);
+    }
+
+    #[test]
+    fn negative_samples_are_clipped() {
+        let traces = array![[-3, 0, 4], [i16::MIN, 7, -1]];
+        assert_eq!(clip_negative(&traces), array![[0, 0, 4], [0, 7, 0]]);
+    }
+
+    #[test]
+    fn traces_are_padded_or_truncated_to_the_target_length() {
+        let traces = array![[1, 2, 3], [4, 5, 6]];
+        let padded = pad_traces(traces.clone(), 5, -1);
+        assert_eq!(padded, array![[1, 2, 3, -1, -1], [4, 5, 6, -1, -1]]);
+        let truncated = pad_traces(traces.clone(), 2, -1);
+        assert_eq!(truncated, array![[1, 2], [4, 5]]);
+        assert_eq!(pad_traces(traces.clone(), 3, -1), traces);
+        // Events without any traces keep their (lack of) rows
+        let empty = pad_traces(Array2::zeros((0, 3)), 5, -1);
+        assert_eq!(empty.dim(), (0, 5));
+        for length in [0, 1, 4, 512] {
+            assert_eq!(pad_traces(traces.clone(), length, 0).dim(), (2, length));
+        }
+    }
+
+    #[test]
+    fn transforms_are_applied_in_order() {
+        let traces = array![[5, 3, 5, 9]];
+        assert_eq!(apply_transforms(traces.clone(), &[]), traces);
+        let baseline_first = [
+            TraceTransform::SubtractBaseline,
+            TraceTransform::ClipNegative,
+        ];
+        assert_eq!(
+            apply_transforms(traces.clone(), &baseline_first),
+            array![[0, 0, 0, 4]]
+        );
+        let clip_first = [
+            TraceTransform::ClipNegative,
+            TraceTransform::SubtractBaseline,
+        ];
+        assert_eq!(apply_transforms(traces, &clip_first), array![[0, -2, 0, 4]]);
+    }
+}
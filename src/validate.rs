@@ -0,0 +1,181 @@
+//! Validation of harmonized data against its source merger runs. Every
+//! harmonic event records the merger run it came from, so the number of
+//! harmonic events from each merger run can be compared to the run itself.
+use super::blacklist::Blacklist;
+use super::config::Config;
+use super::metadata::read_orig_run;
+use super::reader::{clamp_to_event_range, get_run_events, get_run_expected_events};
+use super::stacked::{is_stacked, read_stacked_orig_runs};
+use color_eyre::eyre::{eyre, Result};
+use hdf5_metno::File;
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// The name of the file recording that harmonization stopped early, in the
+/// harmonic directory
+pub const STOP_RECORD_FILE_NAME: &str = "stopped_early.yml";
+
+/// The record of a harmonization which stopped before the end of its run
+/// range (at max_output_bytes or min_free_space_gb), so that the harmonic
+/// data is validated against the runs which were actually read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StopRecord {
+    /// The last merger run (in the order they are read) which contributed
+    /// events, which may have been partially written, or None if no events
+    /// were written. Remapped runs are recorded with their remapped number.
+    pub last_run: Option<i32>,
+}
+
+impl StopRecord {
+    /// Write the record to the harmonic directory
+    pub fn save(&self, harmonic_path: &Path) -> Result<()> {
+        std::fs::write(
+            harmonic_path.join(STOP_RECORD_FILE_NAME),
+            serde_yaml::to_string(self)?,
+        )?;
+        Ok(())
+    }
+
+    /// Read the record from the harmonic directory, if harmonization stopped
+    /// early
+    pub fn load(harmonic_path: &Path) -> Result<Option<Self>> {
+        let path = harmonic_path.join(STOP_RECORD_FILE_NAME);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let record = serde_yaml::from_str(&std::fs::read_to_string(&path)?)
+            .map_err(|error| eyre!("Could not read {}: {error}", path.display()))?;
+        Ok(Some(record))
+    }
+
+    /// Remove the record of an earlier harmonization from the harmonic
+    /// directory
+    pub fn clear(harmonic_path: &Path) -> Result<()> {
+        let path = harmonic_path.join(STOP_RECORD_FILE_NAME);
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// A merger run whose events were not all found in the harmonic data
+/// (or which appears more often than it should).
+#[derive(Debug, Clone)]
+pub struct RunDiscrepancy {
+    pub run: i32,
+    pub expected: u64,
+    pub found: u64,
+}
+
+/// Find the harmonic run files in a directory, including those in source
+/// run subdirectories (of the by_source_run layout)
+pub fn find_harmonic_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        if name.starts_with("run_") && name.ends_with(".h5") {
+            paths.push(path);
+        } else if name.starts_with("src_") && path.is_dir() {
+            paths.extend(find_harmonic_files(&path)?);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Count the harmonic events from each merger run, using the orig_run
+/// of every event in every harmonic file (packed, stacked, or not).
+fn count_harmonic_events(config: &Config) -> Result<FxHashMap<i32, u64>> {
+    let mut counts = FxHashMap::default();
+    for dir in config.output_dirs() {
+        count_dir_events(&dir, &mut counts)?;
+    }
+    Ok(counts)
+}
+
+/// Count the harmonic events from each merger run in a directory
+fn count_dir_events(dir: &Path, counts: &mut FxHashMap<i32, u64>) -> Result<()> {
+    for path in find_harmonic_files(dir)? {
+        let events_group = File::open(&path)?.group("events")?;
+        if is_stacked(&events_group)? {
+            for orig_run in read_stacked_orig_runs(&events_group)? {
+                *counts.entry(orig_run).or_insert(0) += 1;
+            }
+            continue;
+        }
+        for name in events_group.member_names()? {
+            let orig_run = read_orig_run(&events_group.group(&name)?)?;
+            *counts.entry(orig_run).or_insert(0) += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Compare the number of events of each merger run in the run range (within
+/// the global event index range, less its blacklisted events, and its bad
+/// events if they are dropped) to the number of harmonic events which came
+/// from it. Harmonic events from runs outside of the range are also reported.
+/// If harmonization stopped early, only the runs up to the last one read are
+/// expected, and the last one may be missing some of its events.
+/// Returns the runs which do not match, in order of run number.
+pub fn validate_harmonic_output(config: &Config) -> Result<Vec<RunDiscrepancy>> {
+    let mut found = count_harmonic_events(config)?;
+    let stop = StopRecord::load(&config.harmonic_path)?;
+    let runs = match stop {
+        Some(StopRecord {
+            last_run: Some(last_run),
+        }) => {
+            let mut config = config.clone();
+            config.truncate_runs(config.unmap_run(last_run)?);
+            config.runs()
+        }
+        Some(StopRecord { last_run: None }) => Vec::new(),
+        None => config.runs(),
+    };
+    let blacklist = match &config.blacklist_path {
+        Some(path) => Blacklist::read(path)?,
+        None => Blacklist::default(),
+    };
+    // The global event index range cuts the runs in the order they are read
+    let read_runs = config.read_runs();
+    let run_events = read_runs
+        .iter()
+        .map(|run| get_run_events(config, *run))
+        .collect::<Result<Vec<_>>>()?;
+    let positions: FxHashMap<i32, Range<u64>> = read_runs
+        .into_iter()
+        .zip(clamp_to_event_range(config, &run_events))
+        .collect();
+    let mut discrepancies = Vec::new();
+    for run in runs {
+        let positions = positions.get(&run).cloned().unwrap_or_default();
+        let expected = get_run_expected_events(config, run, positions, &blacklist)?;
+        // Harmonic events record the run they were written with
+        let written_run = config.remap_run(run)?;
+        let found = found.remove(&written_run).unwrap_or(0);
+        let partial = stop.is_some_and(|stop| stop.last_run == Some(written_run));
+        if expected != found && !(partial && found > 0 && found < expected) {
+            discrepancies.push(RunDiscrepancy {
+                run,
+                expected,
+                found,
+            });
+        }
+    }
+    // Whatever is left did not come from a run in the range. Runs which no
+    // run is remapped to are reported with the number they were written with.
+    discrepancies.extend(found.into_iter().map(|(run, found)| RunDiscrepancy {
+        run: config.unmap_run(run).unwrap_or(run),
+        expected: 0,
+        found,
+    }));
+    discrepancies.sort_by_key(|discrepancy| discrepancy.run);
+    Ok(discrepancies)
+}
@@ -1,10 +1,521 @@
 //! Representation of a Writer for harmonic data
-use super::reader::{construct_run_path, MergerEvent};
-use color_eyre::eyre::Result;
+use super::config::{
+    Alignment, ChunkCache, Compression, Config, EventStorage, Hdf5Driver, Libver, OutputLayout,
+    OverwritePolicy, RunRemap, SizePolicy, SortBy, TraceLayout, TraceTransform, VersionFormat,
+};
+use super::counter::RunCounter;
+use super::estimate::estimate_events_per_file;
+use super::event_table::{EventTable, EVENT_TABLE_NAME};
+use super::metadata::EventMetadata;
+use super::open_files::OpenFilePermit;
+use super::reader::{
+    construct_run_path, get_total_merger_events, is_transposed, Coincidence, GetEvent, MergerEvent,
+    QUALITY_FLAG_NAME,
+};
+use super::stacked::{is_stacked, stacked_len, StackedWriter};
+use super::stats::HarmonizeStats;
+use super::transform::{apply_transforms, pad_traces};
+use super::validate::find_harmonic_files;
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use hdf5_metno::filters::SZip;
 use hdf5_metno::types::VarLenUnicode;
-use hdf5_metno::File;
+use hdf5_metno::{DatasetBuilder, File, Group, Location};
+use human_bytes::human_bytes;
+use ndarray::{Array1, Array2};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The value of the orientation attribute of transposed traces
+pub const TRANSPOSED_ORIENTATION: &str = "transposed";
+
+/// The name of the table of the path of each harmonic run, written to the
+/// harmonic path when the runs are spread over several directories
+pub const RUN_PATHS_NAME: &str = "harmonic_run_paths.csv";
+
+/// The value of the layout attribute of traces stored per channel
+pub const PER_CHANNEL_LAYOUT: &str = "per_channel";
+
+/// The settings left out of the userblock config of remapped runs, as they
+/// give away the real run numbers or where they were read from
+const WITHHELD_REMAP_SETTINGS: &[&str] = &[
+    "run_remap",
+    "run_remap_path",
+    "min_run",
+    "max_run",
+    "merger_path",
+    "merger_paths",
+    "merger_archive",
+];
+
+/// The HDF5 file access properties of the harmonic files. Properties which
+/// are not set keep the HDF5 defaults.
+#[derive(Debug, Clone, Copy)]
+struct FileAccess {
+    chunk_cache: Option<ChunkCache>,
+    alignment: Option<Alignment>,
+    driver: Option<Hdf5Driver>,
+    libver: Option<Libver>,
+}
+
+impl FileAccess {
+    fn new(config: &Config) -> Self {
+        Self {
+            chunk_cache: config.chunk_cache,
+            alignment: config.hdf5_alignment,
+            driver: config.hdf5_driver,
+            libver: config.libver,
+        }
+    }
+}
+
+/// The events a source run contributed to a harmonic file: how many, and
+/// the range of their original event numbers (inclusive). The range is
+/// unknown for events appended to a file which did not record it.
+#[derive(Debug, Clone, Copy)]
+struct SourceRunEvents {
+    count: u64,
+    range: Option<(u64, u64)>,
+}
+
+impl SourceRunEvents {
+    /// Account for another event with the given original event number
+    fn add(&mut self, event: u64) {
+        self.count += 1;
+        self.range = self
+            .range
+            .map(|(min, max)| (min.min(event), max.max(event)));
+    }
+}
+
+/// Write a string attribute
+fn write_str_attr(location: &Location, name: &str, value: &str) -> Result<()> {
+    location
+        .new_attr::<VarLenUnicode>()
+        .create(name)?
+        .write_scalar(&VarLenUnicode::from_str(value).unwrap())?;
+    Ok(())
+}
+
+/// The version attribute of the events group in the given format
+fn format_version(format: VersionFormat) -> Result<String> {
+    let (name, version) = (env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    Ok(match format {
+        VersionFormat::Combined => format!("{name}:{version}"),
+        VersionFormat::Semver => String::from(version),
+        VersionFormat::Json => serde_json::to_string(&serde_json::json!({
+            "producer": name,
+            "version": version,
+        }))?,
+    })
+}
+
+/// Write the attributes of the GET data to its traces (dataset or group)
+fn write_get_attrs(traces: &Location, get: &GetEvent) -> Result<()> {
+    if let Some(id) = get.id {
+        traces.new_attr::<u32>().create("id")?.write_scalar(&id)?;
+    }
+    traces
+        .new_attr::<u64>()
+        .create("timestamp")?
+        .write_scalar(&get.timestamp)?;
+    if let Some(timestamp_other) = get.timestamp_other {
+        traces
+            .new_attr::<u64>()
+            .create("timestamp_other")?
+            .write_scalar(&timestamp_other)?;
+    }
+    write_quality_flag(traces, get.ok)
+}
+
+/// Write the quality flag of GET traces or an FRIB group, if it has one
+fn write_quality_flag(location: &Location, ok: Option<u8>) -> Result<()> {
+    if let Some(ok) = ok {
+        location
+            .new_attr::<u8>()
+            .create(QUALITY_FLAG_NAME)?
+            .write_scalar(&ok)?;
+    }
+    Ok(())
+}
+
+/// Start building a trace dataset in the group, with the compression filter
+/// (and level, for gzip)
+fn trace_dataset(group: &Group, compression: (Compression, u8)) -> DatasetBuilder {
+    let builder = group.new_dataset_builder();
+    match compression {
+        (Compression::None, _) => builder,
+        (Compression::Gzip, level) => builder.deflate(level),
+        (Compression::Szip, _) => builder.szip(SZip::Entropy, 8),
+    }
+}
+
+/// Write each trace (row) of the GET traces as its own channel_# dataset in
+/// the group, with the number of channels so that the array can be rebuilt.
+fn write_channel_traces(
+    group: &Group,
+    traces: &Array2<i16>,
+    compression: (Compression, u8),
+) -> Result<()> {
+    for (channel, trace) in traces.outer_iter().enumerate() {
+        trace_dataset(group, compression)
+            .with_data(trace)
+            .create(format!("channel_{channel}").as_str())?;
+    }
+    group
+        .new_attr::<u64>()
+        .create("n_channels")?
+        .write_scalar(&(traces.nrows() as u64))?;
+    group
+        .new_attr::<VarLenUnicode>()
+        .create("layout")?
+        .write_scalar(&VarLenUnicode::from_str(PER_CHANNEL_LAYOUT).unwrap())?;
+    Ok(())
+}
+
+/// The config as written to the userblock. For remapped (pseudonymized) runs
+/// the settings which give away the real runs are left out.
+fn userblock_config(config: &Config) -> Result<serde_json::Value> {
+    let mut value = serde_json::to_value(config)?;
+    if config.run_remap.is_some() {
+        if let Some(settings) = value.as_object_mut() {
+            for key in WITHHELD_REMAP_SETTINGS {
+                settings.remove(*key);
+            }
+        }
+    }
+    Ok(value)
+}
+
+/// Create a harmonic file with the given file access properties.
+/// If a config is given, room is reserved at the start of the file for a JSON
+/// metadata userblock describing it, so that tools without an HDF5 library can
+/// identify harmonic files. The userblock is returned, to be written with
+/// [`write_userblock`] once HDF5 has closed the file: HDF5 owns the file
+/// image while it is open, and some drivers (such as core) write all of it
+/// out, the reserved userblock included, when the file is closed.
+fn create_harmonic_file(
+    path: &Path,
+    run: i32,
+    userblock: Option<&serde_json::Value>,
+    access: FileAccess,
+) -> Result<(File, Option<String>)> {
+    let mut builder = File::with_options();
+    builder.with_fapl(|p| {
+        if let Some(cache) = access.chunk_cache {
+            p.chunk_cache(cache.nslots, cache.nbytes, cache.w0);
+        }
+        if let Some(alignment) = access.alignment {
+            p.alignment(alignment.threshold, alignment.alignment);
+        }
+        match access.libver {
+            Some(Libver::Earliest) => p.libver_earliest(),
+            Some(Libver::V18) => p.libver_v18(),
+            Some(Libver::V110) => p.libver_v110(),
+            Some(Libver::Latest) => p.libver_latest(),
+            None => p,
+        };
+        match access.driver {
+            Some(Hdf5Driver::Sec2) => p.sec2(),
+            Some(Hdf5Driver::Stdio) => p.stdio(),
+            // Back the in-memory file by the file on disk, so that it is written out
+            Some(Hdf5Driver::Core) => p.core_filebacked(true),
+            None => p,
+        }
+    });
+    let Some(config) = userblock else {
+        return Ok((builder.create(path)?, None));
+    };
+
+    let created = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let metadata = serde_json::to_string(&serde_json::json!({
+        "producer": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+        "run": run,
+        "created": created,
+        "config": config,
+    }))?;
+
+    // HDF5 requires the userblock size to be a power of two, at least 512 bytes.
+    // Reserve at least one trailing null byte to terminate the JSON.
+    let size = (metadata.len() as u64 + 1).next_power_of_two().max(512);
+    let file = builder.with_fcpl(|p| p.userblock(size)).create(path)?;
+    Ok((file, Some(metadata)))
+}
+
+/// Write the JSON metadata userblock to the start of a closed harmonic file
+fn write_userblock(path: &Path, metadata: &str) -> Result<()> {
+    let mut raw = std::fs::OpenOptions::new().write(true).open(path)?;
+    raw.write_all(metadata.as_bytes())?;
+    Ok(())
+}
+
+/// The harmonic run number to use after the given one: the next number
+/// reserved from the run counter, if there is one, or else the next number.
+fn following_run(run_counter: Option<&mut RunCounter>, run: i32) -> Result<i32> {
+    match run_counter {
+        Some(counter) => counter.next_run(),
+        None => Ok(run + 1),
+    }
+}
+
+/// The existing files of a harmonic run in an output directory: the run in the
+/// directory itself and, in the by_source_run layout, the run in any of its
+/// source run subdirectories, where it would end up once finished
+fn existing_run_paths(dir: &Path, run: i32, layout: OutputLayout) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    if layout == OutputLayout::BySourceRun {
+        for entry in std::fs::read_dir(dir)? {
+            let subdir = entry?.path();
+            let is_source_dir = subdir
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("src_"));
+            if is_source_dir && subdir.is_dir() {
+                dirs.push(subdir);
+            }
+        }
+    }
+    for dir in dirs {
+        let path = construct_run_path(&dir, run);
+        if path.exists() {
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
+
+/// Find the first harmonic run number, starting from the given one, whose file
+/// may be written to the output directory `dir` under the overwrite policy.
+/// Existing files of the run in any of the output directories (wherever the
+/// output layout would place the run) are either an error, overwritten, or
+/// skipped over (taking the following run numbers from the run counter, if
+/// there is one), so that each run number is only used once across them.
+fn claim_harmonic_run(
+    output_dirs: &[PathBuf],
+    dir: &Path,
+    mut run: i32,
+    policy: OverwritePolicy,
+    layout: OutputLayout,
+    mut run_counter: Option<&mut RunCounter>,
+) -> Result<i32> {
+    loop {
+        let mut existing = Vec::new();
+        for output_dir in output_dirs {
+            existing.extend(existing_run_paths(output_dir, run, layout)?);
+        }
+        let Some(path) = existing.first() else {
+            return Ok(run);
+        };
+        match policy {
+            OverwritePolicy::Error => {
+                return Err(eyre!(
+                    "Harmonic run {} already exists! Set overwrite to overwrite or skip existing output.",
+                    path.display()
+                ))
+            }
+            OverwritePolicy::Overwrite => {
+                // The run may be placed in another output directory or source
+                // run subdirectory this time, so the old copies there are removed
+                let target = construct_run_path(dir, run);
+                for path in existing.iter().filter(|path| **path != target) {
+                    std::fs::remove_file(path)?;
+                }
+                return Ok(run);
+            }
+            OverwritePolicy::Skip => {
+                println!("Skipping existing harmonic run {}", path.display());
+                run = following_run(run_counter.as_deref_mut(), run)?;
+            }
+        }
+    }
+}
+
+/// Check that the filesystem of the harmonic path has at least the given
+/// free space (in bytes) left.
+fn check_free_space(harmonic_path: &Path, min_free_space: u64) -> Result<()> {
+    let available = fs4::available_space(harmonic_path)?;
+    if available < min_free_space {
+        return Err(eyre!(
+            "Only {} of free space is left at {}, below the minimum of {}! Stopping.",
+            human_bytes(available as f64),
+            harmonic_path.display(),
+            human_bytes(min_free_space as f64)
+        ));
+    }
+    Ok(())
+}
+
+/// The name of the subdirectory holding the harmonic runs whose first event
+/// came from the given source run, in the by_source_run layout
+fn source_run_dir_name(run: i32) -> String {
+    format!("src_{:0>3}", run)
+}
+
+/// The numbers of the harmonic runs in a directory (including its source run
+/// subdirectories), in increasing order
+fn find_harmonic_runs(dir: &Path) -> Result<Vec<i32>> {
+    let mut runs: Vec<i32> = find_harmonic_files(dir)?
+        .iter()
+        .filter_map(|path| {
+            path.file_stem()?
+                .to_str()?
+                .strip_prefix("run_")?
+                .parse()
+                .ok()
+        })
+        .collect();
+    runs.sort();
+    Ok(runs)
+}
+
+/// Find the number of the last harmonic run in the harmonic path, if any
+fn find_last_harmonic_run(harmonic_path: &Path) -> Result<Option<i32>> {
+    Ok(find_harmonic_runs(harmonic_path)?.last().copied())
+}
+
+/// Under the error policy, check that none of the harmonic runs from the
+/// first one to be written on exist in any of the output directories, so that
+/// existing output is found before anything is written rather than once the
+/// harmonization reaches it.
+fn check_no_later_runs(output_dirs: &[PathBuf], first_run: i32) -> Result<()> {
+    for dir in output_dirs {
+        let existing: Vec<i32> = find_harmonic_runs(dir)?
+            .into_iter()
+            .filter(|run| *run >= first_run)
+            .collect();
+        if !existing.is_empty() {
+            return Err(eyre!(
+                "Harmonic runs {existing:?} already exist in {}! Set overwrite to overwrite or skip existing output.",
+                dir.display()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Under the overwrite policy, warn about the harmonic runs after the last one
+/// written which remain from earlier output, as they would be mixed in with
+/// the new output
+fn warn_stale_runs(output_dirs: &[PathBuf], last_run: i32) -> Result<()> {
+    for dir in output_dirs {
+        let stale: Vec<i32> = find_harmonic_runs(dir)?
+            .into_iter()
+            .filter(|run| *run > last_run)
+            .collect();
+        if !stale.is_empty() {
+            println!(
+                "Warning: harmonic runs {stale:?} in {} are left over from earlier output and were not overwritten",
+                dir.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// What a harmonic file being appended to held before we opened it, so
+/// that only what we add to it is counted as written
+#[derive(Debug, Clone, Copy)]
+struct AppendedFile {
+    events: u64,
+    bytes: u64,
+}
+
+/// Open a finished harmonic file to append more events to it. Returns the
+/// file and what it already contains.
+fn open_harmonic_file(path: &Path) -> Result<(File, AppendedFile)> {
+    let bytes = path.metadata()?.len();
+    let file = File::open_rw(path)?;
+    let events = file
+        .group("events")?
+        .attr("max_event")?
+        .read_scalar::<u64>()?;
+    Ok((file, AppendedFile { events, bytes }))
+}
+
+/// Count the pads (rows of GET traces) of the events of a harmonic file, from
+/// the shapes of their traces
+fn count_file_pads(file: &File) -> Result<u64> {
+    let events_group = file.group("events")?;
+    let mut pads = 0;
+    for name in events_group.member_names()? {
+        let event_group = events_group.group(&name)?;
+        if let Ok(traces) = event_group.dataset("get_traces") {
+            let shape = traces.shape();
+            pads += if is_transposed(&traces)? {
+                shape[1]
+            } else {
+                shape[0]
+            } as u64;
+        } else if let Ok(channels) = event_group.group("get_traces") {
+            pads += channels.attr("n_channels")?.read_scalar::<u64>()?;
+        }
+    }
+    Ok(pads)
+}
+
+/// Delete an attribute if it exists, so that it can be written again.
+/// A file which is appended to already has its finishing attributes.
+fn clear_attr(group: &Group, name: &str) -> Result<()> {
+    if group.attr_names()?.iter().any(|attr| attr == name) {
+        group.delete_attr(name)?;
+    }
+    Ok(())
+}
+
+/// Reopen a finished harmonic file read-only and check that its events
+/// group and max_event attribute are present and consistent with the
+/// number of events we wrote.
+fn verify_harmonic_file(path: &Path, run: i32, n_events: u64) -> Result<()> {
+    let check = || -> Result<()> {
+        let events_group = File::open(path)?.group("events")?;
+        let max_event = events_group.attr("max_event")?.read_scalar::<u64>()?;
+        let n_groups = if is_stacked(&events_group)? {
+            stacked_len(&events_group)?
+        } else {
+            events_group.member_names()?.len() as u64
+        };
+        if max_event != n_events || n_groups != n_events {
+            return Err(eyre!(
+                "Expected {n_events} events, found max_event {max_event} and {n_groups} event groups"
+            ));
+        }
+        Ok(())
+    };
+    check().wrap_err_with(|| format!("Verification of harmonic run {run} failed"))
+}
+
+/// Handle a harmonic file which has been finished and closed. The file is
+/// verified if requested, and then tallied in the statistics. A file which
+/// was appended to only counts for the events and bytes added to it.
+fn retire_harmonic_file(
+    path: &Path,
+    run: i32,
+    n_events: u64,
+    appended: Option<AppendedFile>,
+    verify: bool,
+    stats: &mut HarmonizeStats,
+) -> Result<()> {
+    if verify {
+        verify_harmonic_file(path, run, n_events)?;
+    }
+    let bytes = path.metadata()?.len();
+    match appended {
+        Some(existing) => {
+            stats.events_written += n_events - existing.events;
+            stats.bytes_written += bytes.saturating_sub(existing.bytes);
+        }
+        None => {
+            stats.files_produced += 1;
+            stats.events_written += n_events;
+            stats.bytes_written += bytes;
+        }
+    }
+    Ok(())
+}
 
 /// Representation of a writer for harmonic data.
 /// It writes data with a slightly modified version of the
@@ -14,136 +525,1124 @@ use std::str::FromStr;
 #[derive(Debug)]
 pub struct HarmonicWriter {
     harmonic_path: PathBuf,
+    output_dirs: Vec<PathBuf>,
+    run_paths: Vec<(i32, PathBuf)>,
     current_path: PathBuf,
-    current_file: File,
+    // Only None while a finished file is closed to be retired
+    current_file: Option<File>,
+    /// The stacked datasets of the current file, with the stacked storage
+    stacked: Option<StackedWriter>,
+    // Declared after the file so that it is released once the file is closed
+    _file_permit: OpenFilePermit,
     current_run: i32,
     current_event: u64,
     harmonic_size: u64,
+    pads_per_file: Option<u64>,
+    current_pads: u64,
+    max_events_per_file: Option<u64>,
+    min_events_per_file: u64,
+    split_on_run_boundary: bool,
+    max_output_bytes: Option<u64>,
+    last_source_run: Option<i32>,
+    /// The merger runs (before any remap) with events in the current file
+    file_merger_runs: BTreeSet<i32>,
+    run_remap: Option<RunRemap>,
+    size_policy: SizePolicy,
+    size_tolerance_bytes: u64,
+    exclude_frib_coincidence: bool,
+    trace_transforms: Vec<TraceTransform>,
+    pad_traces_to: Option<(usize, i16)>,
+    transpose_traces: bool,
+    trace_layout: TraceLayout,
+    event_storage: EventStorage,
+    stacked_padding: bool,
+    compression: (Compression, u8),
+    events_per_block: Option<u64>,
+    write_written_at: bool,
+    write_harmonic_run: bool,
+    pack_event_metadata: bool,
+    write_source_runs: bool,
+    source_runs: BTreeMap<i32, SourceRunEvents>,
+    check_trace_widths: bool,
+    trace_width: Option<usize>,
+    userblock: Option<serde_json::Value>,
+    /// The userblock of the current file, written once it is closed
+    current_userblock: Option<String>,
+    version_format: VersionFormat,
+    write_producer_attrs: bool,
+    /// The estimated events per file, if target event counts are written
+    target_events_per_file: Option<u64>,
+    /// The events expected in the current and later files
+    expected_events: u64,
+    file_access: FileAccess,
+    verify: bool,
+    stats: HarmonizeStats,
+    sort_by: Option<SortBy>,
+    sort_window: usize,
+    sort_buffer: Vec<MergerEvent>,
+    run_counter: Option<RunCounter>,
+    overwrite: OverwritePolicy,
+    layout: OutputLayout,
+    first_source_run: Option<i32>,
+    min_free_space: Option<u64>,
+    free_space_check_interval: u64,
+    files_finished: u64,
+    append: bool,
+    /// The file being appended to, until it is finished
+    appended: Option<AppendedFile>,
+    event_table: Option<EventTable>,
 }
 
 impl HarmonicWriter {
     /// Create a new writer, the first file to be written is initialized.
-    pub fn new(harmonic_path: &Path, harmonic_size: u64) -> Result<Self> {
-        let current_run = 0;
-        let current_path = construct_run_path(harmonic_path, current_run);
-        let current_file = File::create(&current_path)?;
+    pub fn new(config: &Config) -> Result<Self> {
+        Self::with_total_events(config, None)
+    }
 
-        let writer = Self {
-            harmonic_path: harmonic_path.to_path_buf(),
+    /// Create a new writer given the number of events in the run range, if
+    /// they have already been counted, so that the target event count does
+    /// not count them again.
+    pub fn with_total_events(config: &Config, total_events: Option<u64>) -> Result<Self> {
+        let min_free_space = config.min_free_space_gb.map(|gb| gb * 1_000_000_000);
+        if let Some(min_free_space) = min_free_space {
+            check_free_space(&config.harmonic_path, min_free_space)?;
+        }
+        let mut run_counter = match &config.run_counter_file {
+            Some(path) => Some(RunCounter::reserve(path)?),
+            None => None,
+        };
+        let current_run = match run_counter.as_mut() {
+            Some(counter) => counter.next_run()?,
+            None => 0,
+        };
+        let userblock = match config.write_userblock {
+            true => Some(userblock_config(config)?),
+            false => None,
+        };
+        // Other jobs sharing a run counter write their own later runs
+        if config.overwrite == OverwritePolicy::Error && run_counter.is_none() && !config.append {
+            check_no_later_runs(&config.output_dirs(), current_run)?;
+        }
+        let last_run = if config.append {
+            find_last_harmonic_run(&config.harmonic_path)?
+        } else {
+            None
+        };
+        let current_run = match last_run {
+            Some(run) => run,
+            None => claim_harmonic_run(
+                &config.output_dirs(),
+                &config.harmonic_path,
+                current_run,
+                config.overwrite,
+                config.output_layout,
+                run_counter.as_mut(),
+            )?,
+        };
+        let current_path = construct_run_path(&config.harmonic_path, current_run);
+        let file_permit = OpenFilePermit::acquire();
+        let (current_file, current_userblock, appended) = match last_run {
+            Some(_) => {
+                let (file, appended) = open_harmonic_file(&current_path)?;
+                (file, None, Some(appended))
+            }
+            None => {
+                let (file, current_userblock) = create_harmonic_file(
+                    &current_path,
+                    current_run,
+                    userblock.as_ref(),
+                    FileAccess::new(config),
+                )?;
+                (file, current_userblock, None)
+            }
+        };
+        let appended_events = appended.map_or(0, |existing| existing.events);
+        let current_pads = match (&appended, config.pads_per_file) {
+            (Some(_), Some(_)) => count_file_pads(&current_file)?,
+            _ => 0,
+        };
+
+        let (target_events_per_file, expected_events) = if config.write_target_event_count {
+            let total_events = match total_events {
+                Some(total_events) => total_events,
+                None => get_total_merger_events(config)?,
+            };
+            (
+                Some(estimate_events_per_file(config, total_events)?),
+                total_events + appended_events,
+            )
+        } else {
+            (None, 0)
+        };
+
+        let mut writer = Self {
+            harmonic_path: config.harmonic_path.clone(),
+            output_dirs: config.output_dirs(),
+            run_paths: Vec::new(),
             current_path,
-            current_file,
+            current_file: Some(current_file),
+            stacked: None,
+            _file_permit: file_permit,
             current_run,
-            current_event: 0,
-            harmonic_size,
+            current_event: appended_events,
+            harmonic_size: config.get_harmonic_size()?,
+            pads_per_file: config.pads_per_file,
+            current_pads,
+            max_events_per_file: config.max_events_per_file,
+            min_events_per_file: config.min_events_per_file,
+            split_on_run_boundary: config.split_on_run_boundary,
+            max_output_bytes: config.max_output_bytes,
+            last_source_run: None,
+            file_merger_runs: BTreeSet::new(),
+            run_remap: config.run_remap.clone(),
+            size_policy: config.size_policy,
+            size_tolerance_bytes: config.size_tolerance_bytes,
+            exclude_frib_coincidence: config.exclude_frib_coincidence,
+            trace_transforms: config.trace_transforms.clone(),
+            pad_traces_to: config.pad_traces_to.map(|length| (length, config.pad_fill)),
+            transpose_traces: config.transpose_traces,
+            trace_layout: config.trace_layout,
+            event_storage: config.event_storage,
+            stacked_padding: config.stacked_padding,
+            compression: (config.compression, config.compression_level),
+            events_per_block: config.events_per_block,
+            write_written_at: config.write_written_at,
+            write_harmonic_run: config.write_harmonic_run,
+            pack_event_metadata: config.pack_event_metadata,
+            write_source_runs: config.write_source_runs,
+            source_runs: BTreeMap::new(),
+            check_trace_widths: config.check_trace_widths || config.strict,
+            trace_width: None,
+            userblock,
+            current_userblock,
+            version_format: config.version_format,
+            write_producer_attrs: config.write_producer_attrs,
+            target_events_per_file,
+            expected_events,
+            file_access: FileAccess::new(config),
+            verify: config.verify_output,
+            stats: HarmonizeStats::default(),
+            sort_by: config.sort_by,
+            sort_window: config.sort_window,
+            sort_buffer: Vec::new(),
+            run_counter,
+            overwrite: config.overwrite,
+            layout: config.output_layout,
+            first_source_run: None,
+            min_free_space,
+            free_space_check_interval: config.free_space_check_interval,
+            files_finished: 0,
+            append: config.append,
+            appended,
+            event_table: config.write_event_metadata.then(EventTable::default),
         };
 
-        writer.init_file()?;
+        match last_run {
+            Some(_) => {
+                writer.read_source_runs()?;
+                // A file which is already full is left as it is
+                if writer.is_full()? {
+                    writer.roll_file()?;
+                }
+            }
+            None => writer.init_file()?,
+        }
 
         Ok(writer)
     }
 
-    /// Write a MergerEvent.
+    /// Recover the source run counts and event ranges of a file being
+    /// appended to, so that they also account for the events already in it.
+    fn read_source_runs(&mut self) -> Result<()> {
+        let events_group = self.file().group("events")?;
+        let attrs = events_group.attr_names()?;
+        if !self.write_source_runs || !attrs.iter().any(|attr| attr == "source_runs") {
+            return Ok(());
+        }
+        let runs = events_group.attr("source_runs")?.read_1d::<i32>()?;
+        let counts = events_group.attr("source_run_events")?.read_1d::<u64>()?;
+        let ranges = if attrs.iter().any(|attr| attr == "source_run_min_event") {
+            let min_events = events_group
+                .attr("source_run_min_event")?
+                .read_1d::<u64>()?;
+            let max_events = events_group
+                .attr("source_run_max_event")?
+                .read_1d::<u64>()?;
+            min_events.into_iter().zip(max_events).map(Some).collect()
+        } else {
+            vec![None; runs.len()]
+        };
+        self.source_runs = runs
+            .into_iter()
+            .zip(counts.into_iter().zip(ranges))
+            .map(|(run, (count, range))| (run, SourceRunEvents { count, range }))
+            .collect();
+        Ok(())
+    }
+
+    /// Write a MergerEvent. If the writer is sorting events, the event is
+    /// buffered and written once its sorting window is full.
     pub fn write(&mut self, event: MergerEvent) -> Result<()> {
+        match self.sort_by {
+            None => self.write_event(event),
+            Some(SortBy::Size) => {
+                self.sort_buffer.push(event);
+                if self.sort_buffer.len() >= self.sort_window {
+                    self.flush_sorted()?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Write all of the buffered events, smallest first
+    fn flush_sorted(&mut self) -> Result<()> {
+        let mut events = std::mem::take(&mut self.sort_buffer);
+        events.sort_by_key(|event| event.trace_bytes());
+        for event in events {
+            self.write_event(event)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the output has reached the maximum number of bytes, after
+    /// which no more events are written
+    pub fn budget_reached(&self) -> bool {
+        self.stats.output_budget_reached
+    }
+
+    /// Whether the writer has stopped taking events, as the output budget was
+    /// reached or the free space ran low
+    pub fn has_stopped(&self) -> bool {
+        self.budget_reached() || self.stats.free_space_exhausted
+    }
+
+    /// The source run of the last event written (before any remapping), if any
+    pub fn last_source_run(&self) -> Option<i32> {
+        self.last_source_run
+    }
+
+    /// Whether writing the event would take the output past the maximum
+    /// number of bytes, counting the finished files and the current file
+    /// (less what it held before, if it is being appended to).
+    fn would_exceed_budget(&self, event: &MergerEvent) -> Result<bool> {
+        let Some(max_output_bytes) = self.max_output_bytes else {
+            return Ok(false);
+        };
+        let current_bytes = self
+            .current_path
+            .metadata()?
+            .len()
+            .saturating_sub(self.appended.map_or(0, |existing| existing.bytes));
+        let output_bytes = self.stats.bytes_written + current_bytes;
+        Ok(output_bytes + event.trace_bytes() as u64 > max_output_bytes)
+    }
+
+    /// The size of the current file. The core driver only writes the file to
+    /// disk when it is closed, so its size is taken from HDF5 instead.
+    fn current_file_bytes(&self) -> Result<u64> {
+        match self.file_access.driver {
+            Some(Hdf5Driver::Core) => Ok(self.file().size()),
+            _ => Ok(self.current_path.metadata()?.len()),
+        }
+    }
+
+    /// Whether the current file has the maximum number of events
+    fn has_max_events(&self) -> bool {
+        self.max_events_per_file
+            .is_some_and(|max_events| self.current_event >= max_events)
+    }
+
+    /// Whether writing the event would take the current file past its limit,
+    /// either the harmonic size (plus the size tolerance) or the number of
+    /// pads per file, or past the maximum number of events. The limit only
+    /// applies once the file has the minimum number of events.
+    fn would_exceed(&self, event: &MergerEvent) -> Result<bool> {
+        if self.has_max_events() {
+            return Ok(true);
+        }
+        if self.current_event < self.min_events_per_file {
+            return Ok(false);
+        }
+        Ok(match self.pads_per_file {
+            Some(limit) => self.current_pads + event.get_pads() > limit,
+            None => {
+                self.current_file_bytes()? + event.trace_bytes() as u64
+                    > self.harmonic_size + self.size_tolerance_bytes
+            }
+        })
+    }
+
+    /// Whether the current file has reached its limit, either the harmonic
+    /// size or the number of pads per file, or the maximum number of events.
+    /// The limit only applies once the file has the minimum number of events.
+    fn is_full(&self) -> Result<bool> {
+        if self.has_max_events() {
+            return Ok(true);
+        }
+        if self.current_event < self.min_events_per_file {
+            return Ok(false);
+        }
+        Ok(match self.pads_per_file {
+            Some(limit) => self.current_pads >= limit,
+            None => self.current_file_bytes()? >= self.harmonic_size,
+        })
+    }
+
+    /// Write a single MergerEvent to the current file, rolling over to
+    /// a new file when the file limit is reached (per the size policy).
+    fn write_event(&mut self, mut event: MergerEvent) -> Result<()> {
+        // Once the writer has stopped, the remaining events are dropped
+        if self.has_stopped() {
+            return Ok(());
+        }
+        let merger_run = event.run_number;
+        // Everything written from here on uses the remapped run
+        if let Some(remap) = &self.run_remap {
+            event.run_number = remap.apply(event.run_number)?;
+        }
+        if !self.trace_transforms.is_empty() {
+            if let Some(get) = event.get.as_mut() {
+                get.traces =
+                    apply_transforms(std::mem::take(&mut get.traces), &self.trace_transforms);
+            }
+        }
+        if let Some((length, fill)) = self.pad_traces_to {
+            if let Some(get) = event.get.as_mut() {
+                get.traces = pad_traces(std::mem::take(&mut get.traces), length, fill);
+            }
+        }
+        // The budget counts the traces as they are written, after any padding
+        if self.would_exceed_budget(&event)? {
+            self.stats.output_budget_reached = true;
+            return Ok(());
+        }
+
+        // Keep source runs from being mixed within a file
+        if self.split_on_run_boundary
+            && self.current_event > 0
+            && !self.source_runs.contains_key(&event.run_number)
+        {
+            self.roll_file()?;
+        }
+
+        // A file always gets at least one event, even if it alone is too big
+        if self.size_policy == SizePolicy::AtMost
+            && self.current_event > 0
+            && self.would_exceed(&event)?
+        {
+            self.roll_file()?;
+        }
+        // Rolling may have stopped the writer for lack of free space
+        if self.has_stopped() {
+            return Ok(());
+        }
+        self.last_source_run = Some(merger_run);
+        self.file_merger_runs.insert(merger_run);
+
+        if let Some(get) = event.get.as_ref() {
+            self.check_trace_width(&event, get.traces.ncols())?;
+        }
+        match self.event_storage {
+            EventStorage::Groups => self.write_event_group(&event)?,
+            EventStorage::Stacked => {
+                let compression = self.compression;
+                self.stacked
+                    .as_mut()
+                    .expect("Stacked files are initialized for stacking")
+                    .write_event(self.current_event, &event, |group| {
+                        trace_dataset(group, compression)
+                    })?;
+            }
+        }
+
+        if let Some(table) = self.event_table.as_mut() {
+            table.push(self.current_run, self.current_event, &event);
+        }
+
+        self.first_source_run.get_or_insert(event.run_number);
+        self.source_runs
+            .entry(event.run_number)
+            .or_insert(SourceRunEvents {
+                count: 0,
+                range: Some((event.event, event.event)),
+            })
+            .add(event.event);
+        self.current_pads += event.get_pads();
+        self.current_event += 1;
+
+        if self.size_policy == SizePolicy::AtLeast && self.is_full()? {
+            self.roll_file()?;
+        }
+
+        Ok(())
+    }
+
+    /// Write an event as its own event group in the current file
+    fn write_event_group(&self, event: &MergerEvent) -> Result<()> {
         let event_group = self
-            .current_file
+            .file()
             .group("events")?
             .create_group(&format!("event_{}", self.current_event))?;
 
-        event_group
-            .new_attr::<i32>()
-            .create("orig_run")?
-            .write_scalar(&event.run_number)?;
+        if self.pack_event_metadata {
+            EventMetadata::from_event(event).write(&event_group)?;
+        } else {
+            event_group
+                .new_attr::<i32>()
+                .create("orig_run")?
+                .write_scalar(&event.run_number)?;
 
-        event_group
-            .new_attr::<u64>()
-            .create("orig_event")?
-            .write_scalar(&event.event)?;
-
-        if let Some(get) = event.get.as_ref() {
-            let traces = event_group
-                .new_dataset_builder()
-                .with_data(&get.traces)
-                .create("get_traces")?;
-            traces
-                .new_attr::<u32>()
-                .create("id")?
-                .write_scalar(&get.id)?;
-            traces
+            event_group
                 .new_attr::<u64>()
-                .create("timestamp")?
-                .write_scalar(&get.timestamp)?;
-            traces
+                .create("orig_event")?
+                .write_scalar(&event.event)?;
+        }
+
+        if self.write_written_at {
+            let written_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos() as u64;
+            event_group
                 .new_attr::<u64>()
-                .create("timestamp_other")?
-                .write_scalar(&get.timestamp_other)?;
+                .create("written_at")?
+                .write_scalar(&written_at)?;
+        }
+
+        if self.write_harmonic_run {
+            event_group
+                .new_attr::<i32>()
+                .create("harmonic_run")?
+                .write_scalar(&self.current_run)?;
+        }
+
+        if let Some(get) = event.get.as_ref() {
+            if self.trace_layout == TraceLayout::PerChannel {
+                let traces = event_group.create_group("get_traces")?;
+                write_channel_traces(&traces, &get.traces, self.compression)?;
+                if !self.pack_event_metadata {
+                    write_get_attrs(&traces, get)?;
+                }
+            } else {
+                let traces = if self.transpose_traces {
+                    let transposed = get.traces.t().as_standard_layout();
+                    let traces = trace_dataset(&event_group, self.compression)
+                        .with_data(&transposed)
+                        .create("get_traces")?;
+                    traces
+                        .new_attr::<VarLenUnicode>()
+                        .create("orientation")?
+                        .write_scalar(&VarLenUnicode::from_str(TRANSPOSED_ORIENTATION).unwrap())?;
+                    traces
+                } else {
+                    trace_dataset(&event_group, self.compression)
+                        .with_data(&get.traces)
+                        .create("get_traces")?
+                };
+                if !self.pack_event_metadata {
+                    write_get_attrs(&traces, get)?;
+                }
+            }
         }
 
         if let Some(frib) = event.frib.as_ref() {
             let frib_group = event_group.create_group("frib_physics")?;
-            frib_group
-                .new_attr::<u32>()
-                .create("event")?
-                .write_scalar(&frib.event)?;
-            frib_group
-                .new_attr::<u32>()
-                .create("timestamp")?
-                .write_scalar(&frib.timestamp)?;
-            frib_group
-                .new_dataset_builder()
+            if !self.pack_event_metadata {
+                frib_group
+                    .new_attr::<u32>()
+                    .create("event")?
+                    .write_scalar(&frib.event)?;
+                frib_group
+                    .new_attr::<u32>()
+                    .create("timestamp")?
+                    .write_scalar(&frib.timestamp)?;
+                write_quality_flag(&frib_group, frib.ok)?;
+            }
+            trace_dataset(&frib_group, self.compression)
                 .with_data(&frib.traces)
                 .create("1903")?;
-            frib_group
-                .new_dataset_builder()
-                .with_data(&frib.coincidence)
-                .create("977")?;
+            if !self.exclude_frib_coincidence {
+                let coincidence = frib_group.new_dataset_builder();
+                match &frib.coincidence {
+                    Coincidence::U8(data) => coincidence.with_data(data).create("977")?,
+                    Coincidence::U16(data) => coincidence.with_data(data).create("977")?,
+                    Coincidence::U32(data) => coincidence.with_data(data).create("977")?,
+                };
+            }
         }
+        Ok(())
+    }
 
-        self.current_event += 1;
-
-        if self.current_path.metadata()?.len() >= self.harmonic_size {
-            self.finish_file()?;
-            self.current_event = 0;
-            self.current_run += 1;
-            self.current_path = construct_run_path(&self.harmonic_path, self.current_run);
-            self.current_file = File::create(&self.current_path)?;
-            self.init_file()?;
+    /// If requested, check that the width (number of time buckets) of the GET
+    /// traces matches that of the first event in the current file.
+    fn check_trace_width(&mut self, event: &MergerEvent, width: usize) -> Result<()> {
+        if !self.check_trace_widths {
+            return Ok(());
+        }
+        match self.trace_width {
+            None => self.trace_width = Some(width),
+            Some(expected) if expected != width => {
+                return Err(eyre!(
+                    "GET traces of run {} event {} have {width} columns, but the traces in harmonic run {} have {expected} columns",
+                    event.run_number,
+                    event.event,
+                    self.current_run
+                ))
+            }
+            Some(_) => (),
         }
+        Ok(())
+    }
 
+    /// The harmonic file being written
+    fn file(&self) -> &File {
+        self.current_file
+            .as_ref()
+            .expect("A harmonic file is open while writing")
+    }
+
+    /// Finish and close the current file, then move it to its place in the
+    /// output layout and retire it. It is verified (if requested) before any
+    /// other file is created, so that a broken file stops the harmonization
+    /// at its own run.
+    fn retire_current_file(&mut self) -> Result<()> {
+        self.finish_file()?;
+        // The datasets are closed before the file
+        self.stacked = None;
+        self.current_file = None;
+        if let Some(metadata) = self.current_userblock.take() {
+            write_userblock(&self.current_path, &metadata)?;
+        }
+        let first_source_run = self.first_source_run.take();
+        let finished_path = self.place_finished_file(&self.current_path, first_source_run)?;
+        retire_harmonic_file(
+            &finished_path,
+            self.current_run,
+            self.current_event,
+            self.appended.take(),
+            self.verify,
+            &mut self.stats,
+        )?;
+        self.run_paths
+            .push((self.current_run, finished_path.clone()));
+        let merger_runs = std::mem::take(&mut self.file_merger_runs);
+        self.stats.file_source_runs.push((
+            self.current_run,
+            finished_path,
+            merger_runs.into_iter().collect(),
+        ));
         Ok(())
     }
 
+    /// Finish the current file and start writing to the next one. If the free
+    /// space is due to be checked and is too low, the writer stops after
+    /// finishing it, without starting another file.
+    fn roll_file(&mut self) -> Result<()> {
+        self.retire_current_file()?;
+        self.files_finished += 1;
+        // The harmonic runs go to each of the output directories in turn
+        let dir = self.output_dirs[(self.files_finished % self.output_dirs.len() as u64) as usize]
+            .clone();
+        if let Some(min_free_space) = self.min_free_space {
+            if self.files_finished % self.free_space_check_interval == 0 {
+                if let Err(error) = check_free_space(&dir, min_free_space) {
+                    println!("Warning: {error}");
+                    println!("Stopped after finishing harmonic run {}", self.current_run);
+                    self.stats.free_space_exhausted = true;
+                    return Ok(());
+                }
+            }
+        }
+        self.trace_width = None;
+        self.source_runs.clear();
+        self.current_pads = 0;
+        self.expected_events = self.expected_events.saturating_sub(self.current_event);
+        self.current_event = 0;
+        let run = following_run(self.run_counter.as_mut(), self.current_run)?;
+        self.current_run = claim_harmonic_run(
+            &self.output_dirs,
+            &dir,
+            run,
+            self.overwrite,
+            self.layout,
+            self.run_counter.as_mut(),
+        )?;
+        self.current_path = construct_run_path(&dir, self.current_run);
+        let file_permit = OpenFilePermit::acquire();
+        let (file, current_userblock) = create_harmonic_file(
+            &self.current_path,
+            self.current_run,
+            self.userblock.as_ref(),
+            self.file_access,
+        )?;
+        self.current_file = Some(file);
+        self.current_userblock = current_userblock;
+        self._file_permit = file_permit;
+        self.init_file()
+    }
+
     /// Close the writer, ensuring that the required metadata
-    /// is written to the current file.
-    pub fn close(&self) -> Result<()> {
-        self.finish_file()
+    /// is written to the current file. Returns the statistics of
+    /// the written output; reader-side statistics are left at their defaults.
+    /// If requested, the event metadata table is written here.
+    pub fn close(mut self) -> Result<HarmonizeStats> {
+        self.flush_sorted()?;
+        // Already retired if the writer stopped for lack of free space
+        if self.current_file.is_some() {
+            self.retire_current_file()?;
+        }
+        if self.output_dirs.len() > 1 || self.layout == OutputLayout::BySourceRun {
+            self.write_run_paths()?;
+        }
+        if let Some(table) = self.event_table.take() {
+            table.write(&self.harmonic_path.join(EVENT_TABLE_NAME), self.append)?;
+        }
+        match self.run_counter.take() {
+            Some(counter) => counter.release(self.current_run + 1)?,
+            None if self.overwrite == OverwritePolicy::Overwrite => {
+                warn_stale_runs(&self.output_dirs, self.current_run)?
+            }
+            None => (),
+        }
+        Ok(self.stats)
     }
 
-    /// Initialize the current file
-    fn init_file(&self) -> Result<()> {
-        let harmonizer_version =
-            format!("{}:{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    /// Write the table of the path each harmonic run was written to. Paths
+    /// in the harmonic path are relative to it.
+    fn write_run_paths(&self) -> Result<()> {
+        let mut table = std::fs::File::create(self.harmonic_path.join(RUN_PATHS_NAME))?;
+        writeln!(table, "run,path")?;
+        for (run, path) in self.run_paths.iter() {
+            let path = path.strip_prefix(&self.harmonic_path).unwrap_or(path);
+            writeln!(table, "{run},{}", path.display())?;
+        }
+        Ok(())
+    }
 
-        let events_group = self.current_file.create_group("events")?;
+    /// Move a finished (and closed) harmonic file to its place in the output
+    /// layout, returning its final path. In the by_source_run layout, files are
+    /// moved into the subdirectory of their first source run (in the directory
+    /// they were written to), which is created if needed. Files without events
+    /// stay where they are.
+    fn place_finished_file(&self, path: &Path, first_source_run: Option<i32>) -> Result<PathBuf> {
+        let (OutputLayout::BySourceRun, Some(run)) = (self.layout, first_source_run) else {
+            return Ok(path.to_path_buf());
+        };
+        let dir = path
+            .parent()
+            .expect("Harmonic runs are in a directory")
+            .join(source_run_dir_name(run));
+        std::fs::create_dir_all(&dir)?;
+        let new_path = dir.join(path.file_name().expect("Harmonic runs have a file name"));
+        if new_path.exists() && self.overwrite != OverwritePolicy::Overwrite {
+            return Err(eyre!(
+                "Harmonic run {} already exists! Set overwrite to overwrite existing output.",
+                new_path.display()
+            ));
+        }
+        std::fs::rename(path, &new_path)?;
+        Ok(new_path)
+    }
+
+    /// Initialize the current file
+    fn init_file(&mut self) -> Result<()> {
+        let events_group = self.file().create_group("events")?;
         events_group
             .new_attr::<u64>()
             .create("min_event")?
             .write_scalar(&0)?;
         events_group.new_attr::<u64>().create("max_event")?;
-        events_group
-            .new_attr::<VarLenUnicode>()
-            .create("version")?
-            .write_scalar(&VarLenUnicode::from_str(&harmonizer_version).unwrap())?;
+        write_str_attr(
+            &events_group,
+            "version",
+            &format_version(self.version_format)?,
+        )?;
+        if self.event_storage == EventStorage::Stacked {
+            self.stacked = Some(StackedWriter::init(&events_group, self.stacked_padding)?);
+        }
+        if self.write_producer_attrs {
+            write_str_attr(&events_group, "producer", env!("CARGO_PKG_NAME"))?;
+            write_str_attr(&events_group, "producer_version", env!("CARGO_PKG_VERSION"))?;
+        }
+        // Provisional, until the file is finished
+        if let Some(per_file) = self.target_events_per_file {
+            events_group
+                .new_attr::<u64>()
+                .create("target_event_count")?
+                .write_scalar(&per_file.min(self.expected_events))?;
+        }
         Ok(())
     }
 
     /// Write the required metadata to the currently open file
     /// when we are done with it.
     fn finish_file(&self) -> Result<()> {
-        self.current_file
-            .group("events")?
+        if let Some(stacked) = self.stacked.as_ref() {
+            stacked.finish(self.current_event)?;
+        }
+        let events_group = self.file().group("events")?;
+        events_group
             .attr("max_event")?
             .write_scalar(&self.current_event)?;
+        for name in [
+            "events_per_block",
+            "block_start",
+            "block_end",
+            "source_runs",
+            "source_run_events",
+            "source_run_min_event",
+            "source_run_max_event",
+        ] {
+            clear_attr(&events_group, name)?;
+        }
+        if self.target_events_per_file.is_some() {
+            clear_attr(&events_group, "target_event_count")?;
+            events_group
+                .new_attr::<u64>()
+                .create("target_event_count")?
+                .write_scalar(&self.current_event)?;
+        }
+        if let Some(block_size) = self.events_per_block.filter(|_| self.current_event > 0) {
+            let block_start: Array1<u64> = (0..self.current_event)
+                .step_by(block_size as usize)
+                .collect();
+            let block_end = block_start.mapv(|start| (start + block_size).min(self.current_event));
+            events_group
+                .new_attr::<u64>()
+                .create("events_per_block")?
+                .write_scalar(&block_size)?;
+            events_group
+                .new_attr_builder()
+                .with_data(&block_start)
+                .create("block_start")?;
+            events_group
+                .new_attr_builder()
+                .with_data(&block_end)
+                .create("block_end")?;
+        }
+        if self.write_source_runs && self.current_event > 0 {
+            let runs: Array1<i32> = self.source_runs.keys().copied().collect();
+            let counts: Array1<u64> = self.source_runs.values().map(|run| run.count).collect();
+            events_group
+                .new_attr_builder()
+                .with_data(&runs)
+                .create("source_runs")?;
+            events_group
+                .new_attr_builder()
+                .with_data(&counts)
+                .create("source_run_events")?;
+            // The ranges are only written if they are known for every run
+            let ranges: Option<Vec<(u64, u64)>> =
+                self.source_runs.values().map(|run| run.range).collect();
+            if let Some(ranges) = ranges {
+                let min_events: Array1<u64> = ranges.iter().map(|(min, _)| *min).collect();
+                let max_events: Array1<u64> = ranges.iter().map(|(_, max)| *max).collect();
+                events_group
+                    .new_attr_builder()
+                    .with_data(&min_events)
+                    .create("source_run_min_event")?;
+                events_group
+                    .new_attr_builder()
+                    .with_data(&max_events)
+                    .create("source_run_max_event")?;
+            }
+        }
+        self.file().flush()?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ScratchDir;
+
+    /// Write a file with an events group with the given max_event and number
+    /// of event groups
+    fn write_events(path: &Path, max_event: u64, n_groups: u64) {
+        let file = File::create(path).unwrap();
+        let events = file.create_group("events").unwrap();
+        events
+            .new_attr::<u64>()
+            .create("max_event")
+            .unwrap()
+            .write_scalar(&max_event)
+            .unwrap();
+        for event in 0..n_groups {
+            events.create_group(&format!("event_{event}")).unwrap();
+        }
+    }
+
+    #[test]
+    fn verification_accepts_a_consistent_file() {
+        let dir = ScratchDir::new("verify_consistent");
+        let path = construct_run_path(dir.path(), 3);
+        write_events(&path, 3, 3);
+        verify_harmonic_file(&path, 3, 3).unwrap();
+    }
+
+    #[test]
+    fn verification_catches_a_truncated_write() {
+        let dir = ScratchDir::new("verify_truncated");
+        let path = construct_run_path(dir.path(), 3);
+        write_events(&path, 3, 3);
+        let len = path.metadata().unwrap().len();
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len / 2)
+            .unwrap();
+        let error = verify_harmonic_file(&path, 3, 3).unwrap_err();
+        assert!(error.to_string().contains("harmonic run 3"));
+    }
+
+    #[test]
+    fn verification_catches_missing_events() {
+        let dir = ScratchDir::new("verify_missing");
+        let path = construct_run_path(dir.path(), 3);
+        write_events(&path, 3, 2);
+        assert!(verify_harmonic_file(&path, 3, 3).is_err());
+    }
+
+    /// An event of 40 kB of GET traces
+    fn sized_event(event: u64) -> MergerEvent {
+        MergerEvent {
+            get: Some(GetEvent {
+                traces: Array2::zeros((40, 500)),
+                id: None,
+                timestamp: 0,
+                timestamp_other: None,
+                ok: None,
+            }),
+            frib: None,
+            run_number: 1,
+            event,
+        }
+    }
+
+    /// Write nine events of 40 kB of GET traces with a harmonic size of
+    /// 100 kB under a size policy, returning the number of events and the
+    /// size of each harmonic run with events
+    fn write_sized_events(policy: SizePolicy, tolerance: u64) -> Vec<(usize, u64)> {
+        let dir = ScratchDir::new("size_policy");
+        let config = Config {
+            harmonic_path: dir.path().to_path_buf(),
+            size_policy: policy,
+            size_tolerance_bytes: tolerance,
+            ..Default::default()
+        };
+        let mut writer = HarmonicWriter::new(&config).unwrap();
+        writer.harmonic_size = 100_000;
+        for event in 0..9 {
+            writer.write(sized_event(event)).unwrap();
+        }
+        writer.close().unwrap();
+        (0..)
+            .map(|run| construct_run_path(dir.path(), run))
+            .take_while(|path| path.exists())
+            .map(|path| {
+                let file = File::open(&path).unwrap();
+                let events = file.group("events").unwrap().member_names().unwrap();
+                (events.len(), path.metadata().unwrap().len())
+            })
+            .filter(|(events, _)| *events > 0)
+            .collect()
+    }
+
+    #[test]
+    fn whichever_of_the_event_and_size_caps_is_reached_first_splits() {
+        for (max_events, expected) in [(2, vec![2, 2, 2, 2, 1]), (5, vec![3, 3, 3])] {
+            let dir = ScratchDir::new("event_cap");
+            let config = Config {
+                harmonic_path: dir.path().to_path_buf(),
+                max_events_per_file: Some(max_events),
+                ..Default::default()
+            };
+            let mut writer = HarmonicWriter::new(&config).unwrap();
+            writer.harmonic_size = 100_000;
+            for event in 0..9 {
+                writer.write(sized_event(event)).unwrap();
+            }
+            writer.close().unwrap();
+            let events: Vec<u64> = (0..expected.len() as i32)
+                .map(|run| {
+                    File::open(construct_run_path(dir.path(), run))
+                        .unwrap()
+                        .group("events")
+                        .unwrap()
+                        .attr("max_event")
+                        .unwrap()
+                        .read_scalar()
+                        .unwrap()
+                })
+                .collect();
+            assert_eq!(events, expected, "max_events_per_file {max_events}");
+        }
+    }
+
+    #[test]
+    fn every_file_but_the_last_has_the_minimum_events() {
+        for (min_events, expected) in [(0, vec![3, 3, 3, 1]), (4, vec![4, 4, 2])] {
+            let dir = ScratchDir::new("min_events");
+            let config = Config {
+                harmonic_path: dir.path().to_path_buf(),
+                min_events_per_file: min_events,
+                ..Default::default()
+            };
+            let mut writer = HarmonicWriter::new(&config).unwrap();
+            writer.harmonic_size = 100_000;
+            for event in 0..10 {
+                writer.write(sized_event(event)).unwrap();
+            }
+            writer.close().unwrap();
+            let events: Vec<usize> = (0..)
+                .map(|run| construct_run_path(dir.path(), run))
+                .take_while(|path| path.exists())
+                .map(|path| {
+                    File::open(&path)
+                        .unwrap()
+                        .group("events")
+                        .unwrap()
+                        .member_names()
+                        .unwrap()
+                        .len()
+                })
+                .collect();
+            assert_eq!(events, expected, "min_events_per_file {min_events}");
+            let (last, full) = events.split_last().unwrap();
+            assert!(full.iter().all(|n| *n as u64 >= min_events));
+            assert!(*last > 0);
+        }
+    }
+
+    #[test]
+    fn low_free_space_stops_after_the_current_file() {
+        let dir = ScratchDir::new("free_space");
+        let config = Config {
+            harmonic_path: dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        let mut writer = HarmonicWriter::new(&config).unwrap();
+        writer.harmonic_size = 100_000;
+        // No filesystem has this much free space
+        writer.min_free_space = Some(u64::MAX);
+        for event in 0..9 {
+            writer.write(sized_event(event)).unwrap();
+        }
+        assert!(writer.has_stopped());
+        let stats = writer.close().unwrap();
+        assert!(stats.free_space_exhausted);
+        assert!(!stats.output_budget_reached);
+        assert_eq!((stats.files_produced, stats.events_written), (1, 3));
+        let path = construct_run_path(dir.path(), 0);
+        assert_eq!(stats.bytes_written, path.metadata().unwrap().len());
+        verify_harmonic_file(&path, 0, 3).unwrap();
+        assert!(!construct_run_path(dir.path(), 1).exists());
+    }
+
+    #[test]
+    fn at_least_files_reach_the_harmonic_size() {
+        let files = write_sized_events(SizePolicy::AtLeast, 0);
+        assert_eq!(
+            files.iter().map(|(events, _)| *events).collect::<Vec<_>>(),
+            vec![3, 3, 3]
+        );
+        for (_, bytes) in files {
+            assert!(bytes >= 100_000, "{bytes}");
+        }
+    }
+
+    #[test]
+    fn at_most_files_stay_under_the_harmonic_size() {
+        let files = write_sized_events(SizePolicy::AtMost, 0);
+        assert_eq!(
+            files.iter().map(|(events, _)| *events).collect::<Vec<_>>(),
+            vec![2, 2, 2, 2, 1]
+        );
+        for (_, bytes) in files {
+            assert!(bytes <= 100_000, "{bytes}");
+        }
+    }
+
+    #[test]
+    fn at_most_files_stay_within_the_size_tolerance() {
+        let files = write_sized_events(SizePolicy::AtMost, 30_000);
+        assert_eq!(
+            files.iter().map(|(events, _)| *events).collect::<Vec<_>>(),
+            vec![3, 3, 3]
+        );
+        for (_, bytes) in files {
+            assert!(bytes > 100_000 && bytes <= 130_000, "{bytes}");
+        }
+    }
+
+    #[test]
+    fn at_least_files_ignore_the_size_tolerance() {
+        assert_eq!(
+            write_sized_events(SizePolicy::AtLeast, 30_000),
+            write_sized_events(SizePolicy::AtLeast, 0)
+        );
+    }
+
+    #[test]
+    fn target_event_count_is_provisional_until_the_file_is_finished() {
+        let dir = ScratchDir::new("target_event_count");
+        let config = Config {
+            harmonic_path: dir.path().to_path_buf(),
+            harmonic_size_gb: None,
+            num_output_files: Some(3),
+            write_target_event_count: true,
+            ..Default::default()
+        };
+        let target = |writer: &HarmonicWriter| -> u64 {
+            let events = writer.file().group("events").unwrap();
+            events
+                .attr("target_event_count")
+                .unwrap()
+                .read_scalar()
+                .unwrap()
+        };
+        // Ten events over three files are estimated at four a file, but the
+        // size splits them three a file
+        let mut writer = HarmonicWriter::with_total_events(&config, Some(10)).unwrap();
+        writer.harmonic_size = 100_000;
+        assert_eq!(target(&writer), 4);
+        for event in 0..9 {
+            writer.write(sized_event(event)).unwrap();
+        }
+        // Only one event is left for the last file
+        assert_eq!(target(&writer), 1);
+        writer.write(sized_event(9)).unwrap();
+        writer.close().unwrap();
+
+        for (run, expected) in [3, 3, 3, 1].into_iter().enumerate() {
+            let events = File::open(construct_run_path(dir.path(), run as i32))
+                .unwrap()
+                .group("events")
+                .unwrap();
+            let max_event: u64 = events.attr("max_event").unwrap().read_scalar().unwrap();
+            let target: u64 = events
+                .attr("target_event_count")
+                .unwrap()
+                .read_scalar()
+                .unwrap();
+            assert_eq!((max_event, target), (expected, expected), "run {run}");
+        }
+    }
+
+    #[test]
+    fn after_and_before_split_like_their_policies() {
+        for (name, policy) in [
+            ("after", SizePolicy::AtLeast),
+            ("before", SizePolicy::AtMost),
+        ] {
+            let parsed: SizePolicy = serde_yaml::from_str(name).unwrap();
+            assert_eq!(parsed, policy, "{name}");
+            assert_eq!(
+                write_sized_events(parsed, 0),
+                write_sized_events(policy, 0),
+                "{name}"
+            );
+        }
+    }
+}
@@ -0,0 +1,78 @@
+//! Benchmarks of the harmonic output, which are ignored by default. Run them
+//! with `cargo test --release --test benchmarks -- --ignored --nocapture`.
+mod common;
+
+use common::*;
+
+/// Runs whose events have traces of a spread of sizes, so that sorting by
+/// size reorders them
+fn mixed_size_runs() -> Vec<FixtureRun> {
+    (1..=8)
+        .map(|run| FixtureRun::new(run, 50, 16 + (run as usize * 37) % 96, 512))
+        .collect()
+}
+
+/// The total size of the harmonic files in a directory
+fn harmonic_bytes(dir: &std::path::Path) -> u64 {
+    harmonic_files(dir)
+        .iter()
+        .map(|path| std::fs::metadata(path).unwrap().len())
+        .sum()
+}
+
+#[test]
+#[ignore]
+fn sorting_by_size_before_and_after() {
+    let runs = mixed_size_runs();
+    let mut sizes = Vec::new();
+    for (name, extra) in [
+        ("unsorted", "compression: gzip\n"),
+        (
+            "sorted",
+            "compression: gzip\nsort_by: size\nsort_window: 1000\n",
+        ),
+    ] {
+        let fixture = Fixture::new(name);
+        fixture.write_runs(&runs);
+        run_ok(&fixture.config(1, 8, extra), &[]);
+        sizes.push((name, harmonic_bytes(&fixture.harmonic)));
+    }
+    let (_, unsorted) = sizes[0];
+    for (name, bytes) in sizes {
+        println!(
+            "{name:>10}: {bytes} bytes ({:+.2}%)",
+            (bytes as f64 / unsorted as f64 - 1.0) * 100.0
+        );
+    }
+}
+
+#[test]
+#[ignore]
+fn stacked_storage_write_speed() {
+    // Many small events, whose group metadata dominates the groups storage
+    let runs: Vec<FixtureRun> = (1..=4)
+        .map(|run| FixtureRun::new(run, 5000, 8, 64))
+        .collect();
+    let mut times = Vec::new();
+    for (name, extra) in [
+        ("groups", ""),
+        (
+            "stacked",
+            "event_storage: stacked\nstacked_padding: true\nexclude_frib_coincidence: true\n",
+        ),
+    ] {
+        let fixture = Fixture::new(name);
+        fixture.write_runs(&runs);
+        let config = fixture.config(1, 4, extra);
+        let start = std::time::Instant::now();
+        run_ok(&config, &["--no-scalers"]);
+        times.push((name, start.elapsed(), harmonic_bytes(&fixture.harmonic)));
+    }
+    let (_, groups, _) = times[0];
+    for (name, time, bytes) in times {
+        println!(
+            "{name:>10}: {time:?} ({:.2}x), {bytes} bytes",
+            groups.as_secs_f64() / time.as_secs_f64()
+        );
+    }
+}
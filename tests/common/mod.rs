@@ -0,0 +1,560 @@
+//! Fixtures shared by the integration tests: scratch directories, merger
+//! runs of both merger versions, configs, and running the harmonizer binary.
+#![allow(dead_code)]
+
+use hdf5_metno::types::VarLenUnicode;
+use hdf5_metno::{File, Group, H5Type};
+use ndarray::{Array1, Array2};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counter used to give each scratch directory a unique name
+static DIR_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// The number of scaler values of a scaler event
+pub const N_SCALERS: usize = 11;
+
+/// A scratch directory in the system temporary directory, which is removed
+/// (with its contents) when it is dropped
+#[derive(Debug)]
+pub struct TestDir {
+    path: PathBuf,
+}
+
+impl TestDir {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "harmonizer_test_{}_{}_{name}",
+            std::process::id(),
+            DIR_COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Create a subdirectory
+    pub fn subdir(&self, name: &str) -> PathBuf {
+        let path = self.path.join(name);
+        std::fs::create_dir_all(&path).unwrap();
+        path
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// The integer type the FRIB coincidence (977) data of a fixture run is
+/// stored with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoincidenceType {
+    U8,
+    U16,
+    U32,
+}
+
+/// A merger event of a fixture run
+#[derive(Debug, Clone)]
+pub struct FixtureEvent {
+    pub get: Option<Array2<i16>>,
+    pub frib: Option<Array2<u16>>,
+    /// The quality flag of the GET traces and the FRIB group (0.2.0 only)
+    pub ok: Option<u8>,
+}
+
+impl FixtureEvent {
+    /// An event with GET traces of the given shape and FRIB traces. The
+    /// samples are derived from the run and event, so that events can be
+    /// told apart, and include negative samples.
+    pub fn new(run: i32, event: u64, rows: usize, columns: usize) -> Self {
+        let seed = run as i64 * 97 + event as i64 * 31;
+        Self {
+            get: Some(Array2::from_shape_fn((rows, columns), |(row, column)| {
+                ((seed + row as i64 * 7 + column as i64) % 2000 - 100) as i16
+            })),
+            frib: Some(Array2::from_shape_fn((2, 4), |(row, column)| {
+                (event as usize * 3 + row + column) as u16
+            })),
+            ok: None,
+        }
+    }
+}
+
+/// A merger run written by the fixtures
+#[derive(Debug, Clone)]
+pub struct FixtureRun {
+    pub run: i32,
+    pub min_event: u64,
+    pub events: Vec<FixtureEvent>,
+    /// The values of each scaler event, or None if the run has no scalers
+    pub scalers: Option<Vec<Vec<u32>>>,
+    pub coincidence: CoincidenceType,
+    /// Attributes of the GET traces left out of the file (0.2.0 only)
+    pub omitted_get_attrs: Vec<&'static str>,
+    /// Store the GET traces as a compound of the sample and flags (0.2.0
+    /// only)
+    pub compound_traces: bool,
+    /// Added to the index of the FRIB datasets of each event, so that the
+    /// GET and FRIB indices diverge (0.1.0 only)
+    pub frib_index_offset: u64,
+    /// Leave the min_event attribute of the events group out, as some early
+    /// files do (0.2.0 only)
+    pub omit_min_event: bool,
+    /// Store the GET traces channel-major, marked with this orientation
+    /// attribute (0.2.0 only)
+    pub get_orientation: Option<&'static str>,
+}
+
+/// A GET trace element stored as a compound type, as a merger may write it
+#[derive(H5Type, Debug, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct TraceElement {
+    pub sample: i16,
+    pub flags: u8,
+}
+
+impl FixtureRun {
+    /// A run of events with GET traces of the given shape, starting at event
+    /// 0, with three scaler events
+    pub fn new(run: i32, n_events: u64, rows: usize, columns: usize) -> Self {
+        Self {
+            run,
+            min_event: 0,
+            events: (0..n_events)
+                .map(|event| FixtureEvent::new(run, event, rows, columns))
+                .collect(),
+            scalers: Some(
+                (0..3u32)
+                    .map(|scaler| {
+                        (0..N_SCALERS as u32)
+                            .map(|value| run as u32 * 100 + scaler * 11 + value)
+                            .collect()
+                    })
+                    .collect(),
+            ),
+            coincidence: CoincidenceType::U8,
+            omitted_get_attrs: Vec::new(),
+            compound_traces: false,
+            frib_index_offset: 0,
+            omit_min_event: false,
+            get_orientation: None,
+        }
+    }
+
+    /// The event number of the event with the given index
+    pub fn event_number(&self, index: usize) -> u64 {
+        self.min_event + index as u64
+    }
+
+    /// The event numbers of the run, in order
+    pub fn event_numbers(&self) -> Vec<u64> {
+        (0..self.events.len())
+            .map(|index| self.event_number(index))
+            .collect()
+    }
+
+    /// Write the run to its default file name in a directory, in the 0.2.0
+    /// format. Returns the path of the file.
+    pub fn write_020(&self, dir: &Path) -> PathBuf {
+        let path = run_path(dir, self.run);
+        self.write_020_to(&path);
+        path
+    }
+
+    /// Write the run to a file in the 0.2.0 format
+    pub fn write_020_to(&self, path: &Path) {
+        let file = File::create(path).unwrap();
+        let events = file.create_group("events").unwrap();
+        if !self.omit_min_event {
+            write_attr(&events, "min_event", self.min_event);
+        }
+        write_attr(
+            &events,
+            "max_event",
+            self.min_event + self.events.len() as u64 - 1,
+        );
+        for (index, event) in self.events.iter().enumerate() {
+            let number = self.event_number(index);
+            let group = events.create_group(&format!("event_{number}")).unwrap();
+            if let Some(traces) = &event.get {
+                let builder = group.new_dataset_builder();
+                let dataset = if self.compound_traces {
+                    let elements = traces.mapv(|sample| TraceElement {
+                        sample,
+                        flags: sample.rem_euclid(4) as u8,
+                    });
+                    builder.with_data(&elements).create("get_traces")
+                } else if self.get_orientation.is_some() {
+                    let transposed = traces.t().as_standard_layout().into_owned();
+                    builder.with_data(&transposed).create("get_traces")
+                } else {
+                    builder.with_data(traces).create("get_traces")
+                }
+                .unwrap();
+                if let Some(orientation) = self.get_orientation {
+                    write_attr(
+                        &dataset,
+                        "orientation",
+                        VarLenUnicode::from_str(orientation).unwrap(),
+                    );
+                }
+                if !self.omitted_get_attrs.contains(&"id") {
+                    write_attr(&dataset, "id", number as u32);
+                }
+                write_attr(&dataset, "timestamp", get_timestamp(self.run, number));
+                if !self.omitted_get_attrs.contains(&"timestamp_other") {
+                    write_attr(&dataset, "timestamp_other", number * 2);
+                }
+                if let Some(ok) = event.ok {
+                    write_attr(&dataset, "ok", ok);
+                }
+            }
+            if let Some(traces) = &event.frib {
+                let frib = group.create_group("frib_physics").unwrap();
+                write_attr(&frib, "event", number as u32);
+                write_attr(&frib, "timestamp", number as u32 * 5);
+                if let Some(ok) = event.ok {
+                    write_attr(&frib, "ok", ok);
+                }
+                frib.new_dataset_builder()
+                    .with_data(traces)
+                    .create("1903")
+                    .unwrap();
+                self.write_coincidence(&frib, "977");
+            }
+        }
+        if let Some(scalers) = &self.scalers {
+            let group = file.create_group("scalers").unwrap();
+            write_attr(&group, "min_event", 0u32);
+            write_attr(&group, "max_event", scalers.len() as u32 - 1);
+            for (index, values) in scalers.iter().enumerate() {
+                group
+                    .new_dataset_builder()
+                    .with_data(&Array1::from_vec(values.clone()))
+                    .create(format!("event_{index}").as_str())
+                    .unwrap();
+            }
+        }
+    }
+
+    /// Write the run to its default file name in a directory, in the 0.1.0
+    /// format. Returns the path of the file.
+    pub fn write_010(&self, dir: &Path) -> PathBuf {
+        let path = run_path(dir, self.run);
+        self.write_010_to(&path);
+        path
+    }
+
+    /// Write the run to a file in the 0.1.0 format
+    pub fn write_010_to(&self, path: &Path) {
+        let file = File::create(path).unwrap();
+        let max_event = self.min_event + self.events.len() as u64 - 1;
+        file.create_group("meta")
+            .unwrap()
+            .new_dataset_builder()
+            .with_data(&Array1::from_vec(vec![self.min_event, 0, max_event, 0]))
+            .create("meta")
+            .unwrap();
+        let get = file.create_group("get").unwrap();
+        let frib = file.create_group("frib").unwrap();
+        let frib_events = frib.create_group("evt").unwrap();
+        for (index, event) in self.events.iter().enumerate() {
+            let number = self.event_number(index);
+            if let Some(traces) = &event.get {
+                get.new_dataset_builder()
+                    .with_data(traces)
+                    .create(format!("evt{number}_data").as_str())
+                    .unwrap();
+                let header = Array1::from_vec(vec![
+                    number as f64,
+                    get_timestamp(self.run, number) as f64,
+                    (number * 2) as f64,
+                ]);
+                get.new_dataset_builder()
+                    .with_data(&header)
+                    .create(format!("evt{number}_header").as_str())
+                    .unwrap();
+            }
+            if let Some(traces) = &event.frib {
+                let frib_index = number + self.frib_index_offset;
+                frib_events
+                    .new_dataset_builder()
+                    .with_data(traces)
+                    .create(format!("evt{frib_index}_1903").as_str())
+                    .unwrap();
+                self.write_coincidence(&frib_events, &format!("evt{frib_index}_977"));
+                let header = Array1::from_vec(vec![number as u32, number as u32 * 5]);
+                frib_events
+                    .new_dataset_builder()
+                    .with_data(&header)
+                    .create(format!("evt{frib_index}_header").as_str())
+                    .unwrap();
+            }
+        }
+        if let Some(scalers) = &self.scalers {
+            let group = frib.create_group("scaler").unwrap();
+            for (index, values) in scalers.iter().enumerate() {
+                group
+                    .new_dataset_builder()
+                    .with_data(&Array1::from_vec(values.clone()))
+                    .create(format!("scaler{index}_data").as_str())
+                    .unwrap();
+            }
+        }
+    }
+
+    /// Write the FRIB coincidence data, with the coincidence type of the run
+    fn write_coincidence(&self, group: &Group, name: &str) {
+        let builder = group.new_dataset_builder();
+        match self.coincidence {
+            CoincidenceType::U8 => builder
+                .with_data(&Array1::from_vec(vec![1u8, 2, 3]))
+                .create(name),
+            CoincidenceType::U16 => builder
+                .with_data(&Array1::from_vec(vec![1u16, 2, 3]))
+                .create(name),
+            CoincidenceType::U32 => builder
+                .with_data(&Array1::from_vec(vec![1u32, 2, 3]))
+                .create(name),
+        }
+        .unwrap();
+    }
+}
+
+/// The GET timestamp of an event of a fixture run, increasing over the runs
+pub fn get_timestamp(run: i32, event: u64) -> u64 {
+    run as u64 * 1_000_000 + event * 10
+}
+
+/// Write a scalar attribute
+pub fn write_attr<T: H5Type>(location: &hdf5_metno::Location, name: &str, value: T) {
+    location
+        .new_attr::<T>()
+        .create(name)
+        .unwrap()
+        .write_scalar(&value)
+        .unwrap();
+}
+
+/// The default path of a (merger or harmonic) run file in a directory
+pub fn run_path(dir: &Path, run: i32) -> PathBuf {
+    dir.join(format!("run_{run:0>4}.h5"))
+}
+
+/// A scratch directory with a merger and a harmonic directory, and a config
+/// harmonizing from one to the other
+#[derive(Debug)]
+pub struct Fixture {
+    pub dir: TestDir,
+    pub merger: PathBuf,
+    pub harmonic: PathBuf,
+}
+
+impl Fixture {
+    pub fn new(name: &str) -> Self {
+        let dir = TestDir::new(name);
+        let merger = dir.subdir("merger");
+        let harmonic = dir.subdir("harmonic");
+        Self {
+            dir,
+            merger,
+            harmonic,
+        }
+    }
+
+    /// Write runs to the merger directory in the 0.2.0 format
+    pub fn write_runs(&self, runs: &[FixtureRun]) {
+        for run in runs {
+            run.write_020(&self.merger);
+        }
+    }
+
+    /// Write the config to config.yml, returning its path. The merger and
+    /// harmonic paths and the run range are set, and unless the extra YAML
+    /// picks how files are split, a harmonic size of 1 GB (so a single file).
+    pub fn config(&self, min_run: i32, max_run: i32, extra: &str) -> PathBuf {
+        self.config_named("config.yml", min_run, max_run, extra)
+    }
+
+    /// Write a config to the given file name in the scratch directory
+    pub fn config_named(&self, name: &str, min_run: i32, max_run: i32, extra: &str) -> PathBuf {
+        let mut yaml = format!(
+            "merger_path: {:?}\nharmonic_path: {:?}\nmin_run: {min_run}\nmax_run: {max_run}\n",
+            self.merger.display().to_string(),
+            self.harmonic.display().to_string(),
+        );
+        let splits = [
+            "harmonic_size_gb",
+            "num_output_files",
+            "single_file",
+            "pads_per_file",
+        ];
+        if !splits.iter().any(|key| extra.contains(&format!("{key}:"))) {
+            yaml.push_str("harmonic_size_gb: 1\n");
+        }
+        yaml.push_str(extra);
+        let path = self.dir.path().join(name);
+        std::fs::write(&path, yaml).unwrap();
+        path
+    }
+}
+
+/// Quote a path for a YAML config
+pub fn yaml_path(path: &Path) -> String {
+    format!("{:?}", path.display().to_string())
+}
+
+/// Run the harmonizer with a config and further arguments
+pub fn harmonizer(config: &Path, args: &[&str]) -> Output {
+    harmonizer_with_stdin(config, args, "")
+}
+
+/// Run the harmonizer with a config and further arguments, writing the input
+/// to its stdin
+pub fn harmonizer_with_stdin(config: &Path, args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_harmonizer"))
+        .arg("--config")
+        .arg(config)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+/// The stdout and stderr of a run of the harmonizer
+pub fn output_text(output: &Output) -> String {
+    format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    )
+}
+
+/// Run the harmonizer, panicking (with its output) if it fails. Returns its
+/// stdout and stderr.
+pub fn run_ok(config: &Path, args: &[&str]) -> String {
+    let output = harmonizer(config, args);
+    let text = output_text(&output);
+    assert!(output.status.success(), "The harmonizer failed:\n{text}");
+    text
+}
+
+/// Run the harmonizer, panicking if it succeeds. Returns its stdout and
+/// stderr.
+pub fn run_err(config: &Path, args: &[&str]) -> String {
+    let output = harmonizer(config, args);
+    let text = output_text(&output);
+    assert!(
+        !output.status.success(),
+        "The harmonizer unexpectedly succeeded:\n{text}"
+    );
+    text
+}
+
+/// The harmonic run files in a directory, including those in source run
+/// subdirectories, in order of path
+pub fn harmonic_files(dir: &Path) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        if name.starts_with("run_") && name.ends_with(".h5") {
+            paths.push(path);
+        } else if name.starts_with("src_") && path.is_dir() {
+            paths.extend(harmonic_files(&path));
+        }
+    }
+    paths.sort();
+    paths
+}
+
+/// The events group of a harmonic file
+pub fn events_group(path: &Path) -> Group {
+    File::open(path).unwrap().group("events").unwrap()
+}
+
+/// The number of events of a harmonic file (its max_event)
+pub fn n_events(path: &Path) -> u64 {
+    events_group(path)
+        .attr("max_event")
+        .unwrap()
+        .read_scalar::<u64>()
+        .unwrap()
+}
+
+/// The orig_run and orig_event of each event of a harmonic file whose events
+/// are stored in groups with separate attributes
+pub fn orig_events(path: &Path) -> Vec<(i32, u64)> {
+    let events = events_group(path);
+    (0..n_events(path))
+        .map(|index| {
+            let event = events.group(&format!("event_{index}")).unwrap();
+            (
+                event.attr("orig_run").unwrap().read_scalar().unwrap(),
+                event.attr("orig_event").unwrap().read_scalar().unwrap(),
+            )
+        })
+        .collect()
+}
+
+/// The orig_run and orig_event of every event of the harmonic files in a
+/// directory, in order
+pub fn all_orig_events(dir: &Path) -> Vec<(i32, u64)> {
+    harmonic_files(dir)
+        .iter()
+        .flat_map(|path| orig_events(path))
+        .collect()
+}
+
+/// The orig_run and orig_event of every event of the runs, in order
+pub fn run_events(runs: &[FixtureRun]) -> Vec<(i32, u64)> {
+    runs.iter()
+        .flat_map(|run| {
+            run.event_numbers()
+                .into_iter()
+                .map(move |event| (run.run, event))
+        })
+        .collect()
+}
+
+/// The GET traces of the event with the given index of a harmonic file
+pub fn get_traces(path: &Path, index: u64) -> Array2<i16> {
+    events_group(path)
+        .group(&format!("event_{index}"))
+        .unwrap()
+        .dataset("get_traces")
+        .unwrap()
+        .read_2d()
+        .unwrap()
+}
+
+/// Parse the JSON summary written with --summary-json
+pub fn read_summary(path: &Path) -> serde_json::Value {
+    serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+}
+
+/// Read a parquet file written by the harmonizer
+pub fn read_parquet(path: &Path) -> polars::prelude::DataFrame {
+    use polars::prelude::SerReader;
+    polars::prelude::ParquetReader::new(std::fs::File::open(path).unwrap())
+        .finish()
+        .unwrap()
+}
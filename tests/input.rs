@@ -0,0 +1,600 @@
+//! Integration tests of reading the merger runs
+mod common;
+
+use common::*;
+
+#[test]
+fn runs_are_read_with_an_alternate_pattern() {
+    let fixture = Fixture::new("alternate_pattern");
+    let runs = [FixtureRun::new(1, 3, 4, 8), FixtureRun::new(2, 3, 4, 8)];
+    for run in runs.iter() {
+        run.write_020_to(&fixture.merger.join(format!("{:0>3}.hdf5", run.run)));
+    }
+    let config = fixture.config(
+        1,
+        2,
+        "merger_pattern: \"{run}.hdf5\"\nmerger_run_width: 3\n",
+    );
+    run_ok(&config, &[]);
+
+    assert_eq!(all_orig_events(&fixture.harmonic), run_events(&runs));
+    let scalers = read_parquet(&fixture.harmonic.join("scalers.parquet"));
+    assert_eq!(scalers.height(), 6);
+}
+
+#[test]
+fn strict_mode_accepts_dropped_bad_events() {
+    let fixture = Fixture::new("strict_bad_events");
+    let mut run = FixtureRun::new(1, 6, 4, 8);
+    for index in [1, 2, 4] {
+        run.events[index].ok = Some(0);
+    }
+    for event in run.events.iter_mut().filter(|event| event.ok.is_none()) {
+        event.ok = Some(1);
+    }
+    fixture.write_runs(std::slice::from_ref(&run));
+    let config = fixture.config(1, 1, "drop_bad_events: true\n");
+    run_ok(&config, &["--strict"]);
+
+    assert_eq!(
+        all_orig_events(&fixture.harmonic),
+        vec![(1, 0), (1, 3), (1, 5)]
+    );
+}
+
+#[test]
+fn quality_flags_are_copied_when_present() {
+    let fixture = Fixture::new("quality_flags");
+    let mut flagged = FixtureRun::new(1, 3, 4, 8);
+    for (event, ok) in flagged.events.iter_mut().zip([1, 0, 1]) {
+        event.ok = Some(ok);
+    }
+    let runs = [flagged, FixtureRun::new(2, 2, 4, 8)];
+    fixture.write_runs(&runs);
+    let config = fixture.config(1, 2, "");
+    run_ok(&config, &[]);
+
+    let path = &harmonic_files(&fixture.harmonic)[0];
+    assert_eq!(orig_events(path), run_events(&runs));
+    let events = events_group(path);
+    let flags: Vec<[Option<u8>; 2]> = (0..5)
+        .map(|index| {
+            let event = events.group(&format!("event_{index}")).unwrap();
+            let get = event.dataset("get_traces").unwrap();
+            let frib = event.group("frib_physics").unwrap();
+            [get.attr("ok").ok(), frib.attr("ok").ok()]
+                .map(|attr| attr.map(|attr| attr.read_scalar::<u8>().unwrap()))
+        })
+        .collect();
+    assert_eq!(
+        flags,
+        vec![
+            [Some(1), Some(1)],
+            [Some(0), Some(0)],
+            [Some(1), Some(1)],
+            [None, None],
+            [None, None],
+        ]
+    );
+    // Bad events are kept by default, so every event is accounted for
+    run_ok(&config, &["validate"]);
+}
+
+#[test]
+fn validation_expects_dropped_bad_events() {
+    let fixture = Fixture::new("validate_bad_events");
+    let mut runs = [FixtureRun::new(1, 5, 4, 8), FixtureRun::new(2, 3, 4, 8)];
+    runs[0].events[1].ok = Some(0);
+    runs[0].events[3].ok = Some(0);
+    runs[1].events[0].ok = Some(1);
+    fixture.write_runs(&runs);
+    // Event 3 of run 1 is both blacklisted and bad, and only counts once
+    let blacklist = fixture.dir.path().join("blacklist.csv");
+    std::fs::write(&blacklist, "1,3\n1,4\n").unwrap();
+    let settings = format!(
+        "drop_bad_events: true\nblacklist_path: {}\n",
+        yaml_path(&blacklist)
+    );
+    let config = fixture.config(1, 2, &settings);
+    run_ok(&config, &[]);
+
+    assert_eq!(
+        all_orig_events(&fixture.harmonic),
+        vec![(1, 0), (1, 2), (2, 0), (2, 1), (2, 2)]
+    );
+    run_ok(&config, &["validate"]);
+
+    // Without dropping them, the bad events are expected in the output
+    let keeping = fixture.config_named(
+        "keeping.yml",
+        1,
+        2,
+        &format!("blacklist_path: {}\n", yaml_path(&blacklist)),
+    );
+    let output = run_err(&keeping, &["validate"]);
+    assert!(
+        output.contains("Run 1: expected 3 events, found 2 in the harmonic data"),
+        "{output}"
+    );
+}
+
+#[test]
+fn mixed_version_ranges_are_harmonized() {
+    let fixture = Fixture::new("mixed_versions");
+    let mut late_start = FixtureRun::new(3, 3, 4, 8);
+    late_start.min_event = 5;
+    let runs = [
+        FixtureRun::new(1, 4, 4, 8),
+        FixtureRun::new(2, 2, 4, 8),
+        late_start,
+    ];
+    runs[0].write_010(&fixture.merger);
+    runs[1].write_020(&fixture.merger);
+    runs[2].write_010(&fixture.merger);
+    let summary = fixture.dir.path().join("summary.json");
+    run_ok(
+        &fixture.config(1, 3, ""),
+        &["--summary-json", summary.to_str().unwrap()],
+    );
+
+    assert_eq!(all_orig_events(&fixture.harmonic), run_events(&runs));
+    let path = &harmonic_files(&fixture.harmonic)[0];
+    for (index, (run, event)) in [(0, 0, 0), (5, 1, 1), (7, 2, 1)]
+        .into_iter()
+        .map(|(index, run, event)| (index, (&runs[run], event)))
+    {
+        assert_eq!(
+            &get_traces(path, index),
+            run.events[event].get.as_ref().unwrap()
+        );
+    }
+
+    let summary = read_summary(&summary);
+    assert_eq!(summary["events_written"], 9);
+    assert_eq!(summary["reconciliation"]["events_counted"], 9);
+    assert_eq!(summary["reconciliation"]["events_unaccounted"], 0);
+
+    let scalers = read_parquet(&fixture.harmonic.join("scalers.parquet"));
+    let scaler_runs: Vec<Option<u32>> = scalers
+        .column("run")
+        .unwrap()
+        .u32()
+        .unwrap()
+        .into_iter()
+        .collect();
+    assert_eq!(scaler_runs, [1, 1, 1, 2, 2, 2, 3, 3, 3].map(Some).to_vec());
+}
+
+#[test]
+fn required_version_rejects_a_mismatched_run() {
+    let fixture = Fixture::new("required_version");
+    let runs = [
+        FixtureRun::new(1, 3, 4, 8),
+        FixtureRun::new(2, 3, 4, 8),
+        FixtureRun::new(3, 3, 4, 8),
+    ];
+    runs[0].write_020(&fixture.merger);
+    runs[1].write_010(&fixture.merger);
+    runs[2].write_020(&fixture.merger);
+    let config = fixture.config(1, 3, "require_version: v020\n");
+
+    let output = run_err(&config, &[]);
+    assert!(
+        output.contains("Run 2 has merger version 0.1.0, but require_version is"),
+        "{output}"
+    );
+    let output = run_err(&config, &["--preflight"]);
+    assert!(
+        output.contains("     1 | OK      | merger 0.2.0"),
+        "{output}"
+    );
+    assert!(
+        output.contains("     2 | ERROR   | Run 2 has merger version 0.1.0"),
+        "{output}"
+    );
+    assert!(
+        output.contains("     3 | OK      | merger 0.2.0"),
+        "{output}"
+    );
+
+    // The same range is accepted by default
+    run_ok(
+        &fixture.config_named("any.yml", 1, 3, "overwrite: overwrite\n"),
+        &[],
+    );
+    assert_eq!(all_orig_events(&fixture.harmonic), run_events(&runs));
+}
+
+#[test]
+fn alternate_dataset_names_are_read() {
+    let fixture = Fixture::new("dataset_names");
+    let runs = [FixtureRun::new(1, 3, 4, 8), FixtureRun::new(2, 3, 4, 8)];
+    let v010 = hdf5_metno::File::open_rw(runs[0].write_010(&fixture.merger)).unwrap();
+    let v020 = hdf5_metno::File::open_rw(runs[1].write_020(&fixture.merger)).unwrap();
+    for event in 0..3 {
+        v010.group("get")
+            .unwrap()
+            .link_move(&format!("evt{event}_data"), &format!("traces_{event}"))
+            .unwrap();
+        v010.group("frib/evt")
+            .unwrap()
+            .link_move(&format!("evt{event}_1903"), &format!("evt{event}_physics"))
+            .unwrap();
+        v020.group(&format!("events/event_{event}"))
+            .unwrap()
+            .link_move("get_traces", "get")
+            .unwrap();
+    }
+    drop((v010, v020));
+    let config = fixture.config(
+        1,
+        2,
+        "dataset_names:\n  get_traces: get\n  v010:\n    get_traces: \"traces_{n}\"\n    frib_traces: \"evt{n}_physics\"\n",
+    );
+    run_ok(&config, &[]);
+
+    let path = &harmonic_files(&fixture.harmonic)[0];
+    for (index, (run, event)) in run_events(&runs).into_iter().enumerate() {
+        let merger = &runs[run as usize - 1].events[event as usize];
+        assert_eq!(
+            &get_traces(path, index as u64),
+            merger.get.as_ref().unwrap()
+        );
+        let frib = events_group(path)
+            .group(&format!("event_{index}/frib_physics"))
+            .unwrap()
+            .dataset("1903")
+            .unwrap()
+            .read_2d::<u16>()
+            .unwrap();
+        assert_eq!(&frib, merger.frib.as_ref().unwrap());
+    }
+}
+
+#[test]
+fn missing_dataset_names_are_an_error() {
+    let fixture = Fixture::new("dataset_names_missing");
+    fixture.write_runs(&[FixtureRun::new(1, 3, 4, 8)]);
+    let config = fixture.config(1, 1, "dataset_names:\n  v010:\n    get_traces: traces\n");
+    let output = run_err(&config, &[]);
+    assert!(
+        output.contains("must contain exactly one {n} placeholder"),
+        "{output}"
+    );
+}
+
+#[test]
+fn the_cap_is_enforced_per_run() {
+    for reverse in [false, true] {
+        let fixture = Fixture::new("per_run_cap");
+        let runs = [
+            FixtureRun::new(1, 5, 4, 8),
+            FixtureRun::new(2, 2, 4, 8),
+            FixtureRun::new(3, 4, 4, 8),
+        ];
+        fixture.write_runs(&runs);
+        let summary = fixture.dir.path().join("summary.json");
+        let config = fixture.config(
+            1,
+            3,
+            &format!("max_events_per_source_run: 3\nreverse: {reverse}\n"),
+        );
+        let output = run_ok(&config, &["--summary-json", summary.to_str().unwrap()]);
+
+        let mut expected = vec![
+            (1, 0),
+            (1, 1),
+            (1, 2),
+            (2, 0),
+            (2, 1),
+            (3, 0),
+            (3, 1),
+            (3, 2),
+        ];
+        if reverse {
+            expected = vec![
+                (3, 3),
+                (3, 2),
+                (3, 1),
+                (2, 1),
+                (2, 0),
+                (1, 4),
+                (1, 3),
+                (1, 2),
+            ];
+        }
+        assert_eq!(all_orig_events(&fixture.harmonic), expected);
+        assert!(
+            output.contains("Events skipped by the per run cap: 3"),
+            "{output}"
+        );
+        let summary = read_summary(&summary);
+        assert_eq!(summary["reconciliation"]["events_capped"], 3);
+        assert_eq!(summary["reconciliation"]["events_counted"], 8);
+        assert_eq!(summary["runs_capped"], 2);
+    }
+}
+
+#[test]
+fn reverse_emits_the_latest_events_first() {
+    let fixture = Fixture::new("reverse");
+    let mut late_start = FixtureRun::new(4, 3, 4, 8);
+    late_start.min_event = 7;
+    let runs = [
+        FixtureRun::new(1, 3, 4, 8),
+        FixtureRun::new(2, 2, 4, 8),
+        late_start,
+    ];
+    // Run 3 is missing, and the versions are mixed
+    runs[0].write_020(&fixture.merger);
+    runs[1].write_010(&fixture.merger);
+    runs[2].write_020(&fixture.merger);
+    let config = fixture.config(1, 4, "reverse: true\nmax_events_per_file: 3\n");
+    run_ok(&config, &[]);
+
+    let expected: Vec<(usize, u64)> = vec![
+        (2, 9),
+        (2, 8),
+        (2, 7),
+        (1, 1),
+        (1, 0),
+        (0, 2),
+        (0, 1),
+        (0, 0),
+    ];
+    assert_eq!(
+        all_orig_events(&fixture.harmonic),
+        expected
+            .iter()
+            .map(|(index, event)| (runs[*index].run, *event))
+            .collect::<Vec<_>>()
+    );
+    // Each event keeps its own data
+    let files = harmonic_files(&fixture.harmonic);
+    for (position, (index, event)) in expected.into_iter().enumerate() {
+        let run = &runs[index];
+        let fixture_event = &run.events[(event - run.min_event) as usize];
+        assert_eq!(
+            &get_traces(&files[position / 3], position as u64 % 3),
+            fixture_event.get.as_ref().unwrap(),
+            "run {} event {event}",
+            run.run
+        );
+    }
+}
+
+#[test]
+fn a_middle_slice_is_harmonized_and_checked_on_its_own() {
+    let fixture = Fixture::new("middle_slice");
+    let runs = [
+        FixtureRun::new(1, 4, 4, 8),
+        FixtureRun::new(2, 3, 4, 8),
+        FixtureRun::new(3, 4, 4, 8),
+    ];
+    fixture.write_runs(&runs);
+    // The slice starts in the first run and ends in the last
+    let config = fixture.config(
+        1,
+        3,
+        "event_start: 2\nevent_end: 9\nmax_events_per_file: 3\n",
+    );
+    run_ok(&config, &[]);
+
+    let expected = run_events(&runs)[2..9].to_vec();
+    assert_eq!((expected[0], expected[6]), ((1, 2), (3, 1)));
+    assert_eq!(all_orig_events(&fixture.harmonic), expected);
+    let output = run_ok(&config, &["validate"]);
+    assert!(output.contains("Validation passed"), "{output}");
+    let output = run_ok(&config, &["--estimate-remaining"]);
+    assert!(output.contains("Remaining events: 0"), "{output}");
+
+    // Without its last harmonic run, only the rest of the slice remains
+    std::fs::remove_file(run_path(&fixture.harmonic, 2)).unwrap();
+    let output = run_ok(&config, &["--estimate-remaining"]);
+    assert!(
+        output.contains("Last harmonized event: run 3, event 0"),
+        "{output}"
+    );
+    assert!(output.contains("Remaining events: 1"), "{output}");
+    let output = run_err(&config, &["validate"]);
+    assert!(
+        output.contains("Run 3: expected 2 events, found 1"),
+        "{output}"
+    );
+    assert!(!output.contains("Run 1:"), "{output}");
+}
+
+#[test]
+fn transposed_source_traces_are_read_row_per_pad() {
+    let fixture = Fixture::new("transposed_source");
+    let mut runs = [
+        FixtureRun::new(1, 2, 4, 8),
+        FixtureRun::new(2, 2, 4, 8),
+        FixtureRun::new(3, 2, 4, 8),
+    ];
+    runs[0].get_orientation = Some("channel_major");
+    runs[1].get_orientation = Some("transposed");
+    fixture.write_runs(&runs);
+    let config = fixture.config(1, 3, "");
+    run_ok(&config, &[]);
+
+    let path = &harmonic_files(&fixture.harmonic)[0];
+    assert_eq!(orig_events(path), run_events(&runs));
+    let events = runs.iter().flat_map(|run| run.events.iter());
+    for (index, event) in events.enumerate() {
+        let traces = get_traces(path, index as u64);
+        assert_eq!(traces.dim(), (4, 8));
+        assert_eq!(&traces, event.get.as_ref().unwrap(), "event {index}");
+        let dataset = events_group(path)
+            .group(&format!("event_{index}"))
+            .unwrap()
+            .dataset("get_traces")
+            .unwrap();
+        assert!(dataset.attr("orientation").is_err());
+    }
+    let output = run_ok(&config, &["verify-fidelity"]);
+    assert!(output.contains("Fidelity verification passed"), "{output}");
+}
+
+#[test]
+fn missing_attributes_follow_the_policy() {
+    let mut run = FixtureRun::new(1, 2, 4, 8);
+    run.omitted_get_attrs = vec!["timestamp_other"];
+    let timestamp_other = |fixture: &Fixture| -> Option<u64> {
+        let path = &harmonic_files(&fixture.harmonic)[0];
+        let traces = events_group(path)
+            .group("event_1")
+            .unwrap()
+            .dataset("get_traces")
+            .unwrap();
+        let attrs = traces.attr_names().unwrap();
+        assert!(attrs.contains(&"id".to_string()));
+        attrs.contains(&"timestamp_other".to_string()).then(|| {
+            traces
+                .attr("timestamp_other")
+                .unwrap()
+                .read_scalar()
+                .unwrap()
+        })
+    };
+
+    let fixture = Fixture::new("missing_attr_error");
+    fixture.write_runs(std::slice::from_ref(&run));
+    let output = run_err(&fixture.config(1, 1, ""), &[]);
+    assert!(
+        output.contains("Attribute timestamp_other is missing"),
+        "{output}"
+    );
+
+    let fixture = Fixture::new("missing_attr_default");
+    fixture.write_runs(std::slice::from_ref(&run));
+    run_ok(&fixture.config(1, 1, "on_missing_attr: default\n"), &[]);
+    assert_eq!(timestamp_other(&fixture), Some(0));
+
+    let fixture = Fixture::new("missing_attr_skip");
+    fixture.write_runs(std::slice::from_ref(&run));
+    run_ok(&fixture.config(1, 1, "on_missing_attr: skip\n"), &[]);
+    assert_eq!(timestamp_other(&fixture), None);
+}
+
+#[test]
+fn samples_are_extracted_from_compound_traces() {
+    let fixture = Fixture::new("compound_traces");
+    let mut run = FixtureRun::new(1, 3, 4, 8);
+    run.compound_traces = true;
+    fixture.write_runs(std::slice::from_ref(&run));
+    run_ok(&fixture.config(1, 1, ""), &[]);
+
+    let path = &harmonic_files(&fixture.harmonic)[0];
+    for (index, event) in run.events.iter().enumerate() {
+        let dataset = events_group(path)
+            .group(&format!("event_{index}"))
+            .unwrap()
+            .dataset("get_traces")
+            .unwrap();
+        // The samples are written as a plain integer array, without the flags
+        assert!(dataset.dtype().unwrap().is::<i16>());
+        assert_eq!(&get_traces(path, index as u64), event.get.as_ref().unwrap());
+    }
+}
+
+#[test]
+fn frib_events_are_matched_by_event_id() {
+    let fixture = Fixture::new("match_frib");
+    let mut run = FixtureRun::new(1, 4, 4, 8);
+    run.frib_index_offset = 1;
+    run.events[2].get = None;
+    run.write_010(&fixture.merger);
+    let summary = fixture.dir.path().join("summary.json");
+    let output = run_ok(
+        &fixture.config(1, 1, "match_frib_by_event_id: true\n"),
+        &["--summary-json", summary.to_str().unwrap()],
+    );
+
+    // The FRIB data of the event without GET data can't be paired
+    assert!(
+        output.contains("FRIB events not paired with a GET event: 1"),
+        "{output}"
+    );
+    assert_eq!(read_summary(&summary)["frib_events_unpaired"], 1);
+    let path = &harmonic_files(&fixture.harmonic)[0];
+    for (index, (_, event)) in orig_events(path).into_iter().enumerate() {
+        let group = events_group(path).group(&format!("event_{index}")).unwrap();
+        match &run.events[event as usize].get {
+            Some(_) => assert_eq!(
+                group
+                    .group("frib_physics")
+                    .unwrap()
+                    .dataset("1903")
+                    .unwrap()
+                    .read_2d::<u16>()
+                    .unwrap(),
+                run.events[event as usize].frib.clone().unwrap()
+            ),
+            None => assert!(group.group("frib_physics").is_err()),
+        }
+    }
+}
+
+#[test]
+fn runs_without_min_event_start_at_zero() {
+    let fixture = Fixture::new("missing_min_event");
+    let mut runs = [FixtureRun::new(1, 3, 4, 8), FixtureRun::new(2, 3, 4, 8)];
+    runs[0].omit_min_event = true;
+    fixture.write_runs(&runs);
+    let config = fixture.config(1, 2, "");
+    let output = run_ok(&config, &[]);
+
+    assert!(
+        output
+            .contains("Warning: run 1 has no min_event attribute, assuming its events start at 0"),
+        "{output}"
+    );
+    assert!(!output.contains("run 2 has no min_event"), "{output}");
+    let path = &harmonic_files(&fixture.harmonic)[0];
+    assert_eq!(orig_events(path), run_events(&runs));
+    assert_eq!(get_traces(path, 0), runs[0].events[0].get.clone().unwrap());
+    run_ok(&config, &["validate"]);
+}
+
+#[test]
+fn blacklisted_events_are_absent() {
+    let fixture = Fixture::new("blacklisted_events");
+    let runs = [FixtureRun::new(1, 4, 4, 8), FixtureRun::new(2, 4, 4, 8)];
+    fixture.write_runs(&runs);
+    let blacklist = fixture.dir.path().join("blacklist.csv");
+    std::fs::write(&blacklist, "run,event\n1,0\n2,2\n2,3\n7,1\n").unwrap();
+    let config = fixture.config(
+        1,
+        2,
+        &format!("blacklist_path: {}\n", yaml_path(&blacklist)),
+    );
+    let output = run_ok(&config, &[]);
+
+    assert!(output.contains("Blacklisted events dropped: 3"), "{output}");
+    let written = all_orig_events(&fixture.harmonic);
+    assert_eq!(written, [(1, 1), (1, 2), (1, 3), (2, 0), (2, 1)]);
+    // The traces of the events around the blacklisted ones are unchanged
+    let path = &harmonic_files(&fixture.harmonic)[0];
+    assert_eq!(get_traces(path, 0), runs[0].events[1].get.clone().unwrap());
+    assert_eq!(get_traces(path, 3), runs[1].events[0].get.clone().unwrap());
+}
+
+#[test]
+fn printed_config_can_precede_the_run() {
+    let fixture = Fixture::new("print_config");
+    let runs = [FixtureRun::new(1, 3, 4, 8)];
+    fixture.write_runs(&runs);
+    let config = fixture.config(1, 1, "max_events_per_file: 2\n");
+
+    // Alone, the configuration is printed without harmonizing
+    let output = run_ok(&config, &["--print-config"]);
+    assert!(output.contains("max_events_per_file: 2"), "{output}");
+    assert!(harmonic_files(&fixture.harmonic).is_empty());
+
+    let output = run_ok(&config, &["--print-config", "--continue"]);
+    assert!(output.contains("max_events_per_file: 2"), "{output}");
+    assert_eq!(all_orig_events(&fixture.harmonic), run_events(&runs));
+    // --continue only applies to --print-config
+    run_err(&config, &["--continue"]);
+}
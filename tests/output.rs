@@ -0,0 +1,1023 @@
+//! Integration tests of the contents of the harmonic files
+mod common;
+
+use common::*;
+use ndarray::{Array1, Array2, Axis};
+
+#[test]
+fn userblock_holds_the_file_metadata() {
+    let fixture = Fixture::new("userblock");
+    fixture.write_runs(&[FixtureRun::new(1, 5, 4, 8)]);
+    // The core driver writes the whole file image out when the file is closed
+    for driver in ["null", "sec2", "stdio", "core"] {
+        let config = fixture.config_named(
+            &format!("{driver}.yml"),
+            1,
+            1,
+            &format!("write_userblock: true\nhdf5_driver: {driver}\noverwrite: overwrite\n"),
+        );
+        run_ok(&config, &[]);
+
+        let files = harmonic_files(&fixture.harmonic);
+        assert_eq!(files.len(), 1);
+        let bytes = std::fs::read(&files[0]).unwrap();
+        let end = bytes.iter().position(|byte| *byte == 0).unwrap();
+        let metadata: serde_json::Value = serde_json::from_slice(&bytes[..end])
+            .unwrap_or_else(|error| panic!("{driver}: {error}"));
+        assert_eq!(metadata["producer"], "harmonizer");
+        assert_eq!(metadata["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(metadata["run"], 0);
+        assert!(metadata["created"].as_u64().unwrap() > 0);
+        assert_eq!(metadata["config"]["write_userblock"], true);
+        // The HDF5 data after the userblock is still readable
+        assert_eq!(n_events(&files[0]), 5, "{driver}");
+    }
+}
+
+#[test]
+fn version_attributes_follow_the_format() {
+    use hdf5_metno::types::VarLenUnicode;
+    let fixture = Fixture::new("version_format");
+    fixture.write_runs(&[FixtureRun::new(1, 2, 4, 8)]);
+    let (name, version) = (env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    let read_attr = |group: &hdf5_metno::Group, attr: &str| -> Option<String> {
+        let value: VarLenUnicode = group.attr(attr).ok()?.read_scalar().unwrap();
+        Some(value.to_string())
+    };
+    for (format, producer_attrs) in [
+        ("combined", false),
+        ("semver", false),
+        ("json", false),
+        ("combined", true),
+    ] {
+        let config = fixture.config_named(
+            &format!("{format}_{producer_attrs}.yml"),
+            1,
+            1,
+            &format!(
+                "version_format: {format}\nwrite_producer_attrs: {producer_attrs}\noverwrite: overwrite\n"
+            ),
+        );
+        run_ok(&config, &[]);
+
+        let events = events_group(&harmonic_files(&fixture.harmonic)[0]);
+        let written = read_attr(&events, "version").unwrap();
+        match format {
+            "combined" => assert_eq!(written, format!("{name}:{version}")),
+            "semver" => assert_eq!(written, version),
+            _ => assert_eq!(
+                serde_json::from_str::<serde_json::Value>(&written).unwrap(),
+                serde_json::json!({"producer": name, "version": version})
+            ),
+        }
+        let producer = read_attr(&events, "producer");
+        let producer_version = read_attr(&events, "producer_version");
+        if producer_attrs {
+            assert_eq!(producer.as_deref(), Some(name));
+            assert_eq!(producer_version.as_deref(), Some(version));
+        } else {
+            assert_eq!((producer, producer_version), (None, None), "{format}");
+        }
+    }
+}
+
+/// The version of the superblock of an HDF5 file (without a userblock),
+/// which decides the oldest HDF5 library which can open it: version 0 is
+/// readable by every HDF5 release, 2 needs 1.8 and 3 needs 1.10.
+fn superblock_version(path: &std::path::Path) -> u8 {
+    let header = std::fs::read(path).unwrap();
+    assert_eq!(&header[..8], b"\x89HDF\r\n\x1a\n");
+    header[8]
+}
+
+#[test]
+fn libver_bounds_the_readers_of_the_files() {
+    let fixture = Fixture::new("libver");
+    let run = FixtureRun::new(1, 2, 4, 8);
+    fixture.write_runs(std::slice::from_ref(&run));
+    for (libver, superblocks) in [
+        (None, 0..=0),
+        (Some("earliest"), 0..=0),
+        (Some("v18"), 2..=2),
+        (Some("v110"), 3..=3),
+        (Some("latest"), 3..=u8::MAX),
+    ] {
+        let setting = libver.map_or(String::from("null"), String::from);
+        let config = fixture.config_named(
+            &format!("{setting}.yml"),
+            1,
+            1,
+            &format!("libver: {setting}\noverwrite: overwrite\n"),
+        );
+        run_ok(&config, &[]);
+
+        let path = &harmonic_files(&fixture.harmonic)[0];
+        let version = superblock_version(path);
+        assert!(superblocks.contains(&version), "{setting}: {version}");
+        assert_eq!(orig_events(path), run_events(std::slice::from_ref(&run)));
+    }
+}
+
+#[test]
+fn coincidence_types_are_preserved() {
+    use hdf5_metno::types::{IntSize, TypeDescriptor};
+    for (coincidence, size) in [
+        (CoincidenceType::U8, IntSize::U1),
+        (CoincidenceType::U16, IntSize::U2),
+        (CoincidenceType::U32, IntSize::U4),
+    ] {
+        for v010 in [false, true] {
+            let fixture = Fixture::new("coincidence_types");
+            let mut run = FixtureRun::new(1, 2, 4, 8);
+            run.coincidence = coincidence;
+            match v010 {
+                true => run.write_010(&fixture.merger),
+                false => run.write_020(&fixture.merger),
+            };
+            run_ok(&fixture.config(1, 1, ""), &[]);
+
+            let path = &harmonic_files(&fixture.harmonic)[0];
+            let dataset = events_group(path)
+                .group("event_1")
+                .unwrap()
+                .group("frib_physics")
+                .unwrap()
+                .dataset("977")
+                .unwrap();
+            assert_eq!(
+                dataset.dtype().unwrap().to_descriptor().unwrap(),
+                TypeDescriptor::Unsigned(size)
+            );
+            assert_eq!(dataset.read_1d::<u32>().unwrap().to_vec(), vec![1, 2, 3]);
+        }
+    }
+}
+
+#[test]
+fn events_sorted_by_size_keep_their_provenance() {
+    let fixture = Fixture::new("sort_by_size");
+    let runs = [
+        FixtureRun::new(1, 3, 6, 8),
+        FixtureRun::new(2, 3, 2, 8),
+        FixtureRun::new(3, 2, 4, 8),
+    ];
+    fixture.write_runs(&runs);
+    let config = fixture.config(1, 3, "sort_by: size\nsort_window: 100\n");
+    run_ok(&config, &[]);
+
+    // Smallest first, and in reading order among events of the same size
+    let expected: Vec<(i32, u64)> = [&runs[1], &runs[2], &runs[0]]
+        .into_iter()
+        .flat_map(|run| {
+            run.event_numbers()
+                .into_iter()
+                .map(move |event| (run.run, event))
+        })
+        .collect();
+    let files = harmonic_files(&fixture.harmonic);
+    assert_eq!(files.len(), 1);
+    assert_eq!(orig_events(&files[0]), expected);
+    for (index, (run, event)) in expected.into_iter().enumerate() {
+        let source = runs.iter().find(|source| source.run == run).unwrap();
+        let merger = source.events[event as usize].get.as_ref().unwrap();
+        assert_eq!(&get_traces(&files[0], index as u64), merger);
+    }
+}
+
+#[test]
+fn coincidence_can_be_excluded() {
+    let fixture = Fixture::new("exclude_coincidence");
+    let run = FixtureRun::new(1, 2, 4, 8);
+    fixture.write_runs(std::slice::from_ref(&run));
+    run_ok(
+        &fixture.config(1, 1, "exclude_frib_coincidence: true\n"),
+        &[],
+    );
+
+    let path = &harmonic_files(&fixture.harmonic)[0];
+    for index in 0..2 {
+        let frib = events_group(path)
+            .group(&format!("event_{index}"))
+            .unwrap()
+            .group("frib_physics")
+            .unwrap();
+        assert_eq!(frib.member_names().unwrap(), vec!["1903"]);
+        assert_eq!(
+            frib.dataset("1903").unwrap().read_2d::<u16>().unwrap(),
+            run.events[index].frib.clone().unwrap()
+        );
+        assert!(frib
+            .attr_names()
+            .unwrap()
+            .contains(&"timestamp".to_string()));
+    }
+}
+
+#[test]
+fn mixed_trace_widths_are_reported() {
+    let fixture = Fixture::new("mixed_widths");
+    let runs = [FixtureRun::new(1, 2, 4, 8), FixtureRun::new(2, 2, 4, 10)];
+    fixture.write_runs(&runs);
+
+    let config = fixture.config_named("checked.yml", 1, 2, "check_trace_widths: true\n");
+    let output = run_err(&config, &[]);
+    assert!(
+        output.contains("GET traces of run 2 event 0 have 10 columns"),
+        "{output}"
+    );
+    let reset = || {
+        std::fs::remove_dir_all(&fixture.harmonic).unwrap();
+        std::fs::create_dir(&fixture.harmonic).unwrap();
+    };
+    reset();
+    let output = run_err(&fixture.config_named("strict.yml", 1, 2, ""), &["--strict"]);
+    assert!(output.contains("have 10 columns"), "{output}");
+
+    // Ragged traces are written as they are by default
+    reset();
+    run_ok(&fixture.config(1, 2, ""), &[]);
+    let path = &harmonic_files(&fixture.harmonic)[0];
+    assert_eq!(get_traces(path, 1).ncols(), 8);
+    assert_eq!(get_traces(path, 2).ncols(), 10);
+}
+
+#[test]
+fn block_boundaries_cover_every_event() {
+    let fixture = Fixture::new("event_blocks");
+    fixture.write_runs(&[FixtureRun::new(1, 7, 4, 8), FixtureRun::new(2, 4, 4, 8)]);
+    run_ok(
+        &fixture.config(1, 2, "events_per_block: 3\nmax_events_per_file: 9\n"),
+        &[],
+    );
+
+    let files = harmonic_files(&fixture.harmonic);
+    for (path, starts, ends) in [
+        (&files[0], vec![0u64, 3, 6], vec![3u64, 6, 9]),
+        (&files[1], vec![0], vec![2]),
+    ] {
+        let events = events_group(path);
+        assert_eq!(
+            events
+                .attr("events_per_block")
+                .unwrap()
+                .read_scalar::<u64>()
+                .unwrap(),
+            3
+        );
+        let block_start = events.attr("block_start").unwrap().read_raw::<u64>();
+        let block_end = events.attr("block_end").unwrap().read_raw::<u64>();
+        assert_eq!(block_start.unwrap(), starts);
+        assert_eq!(block_end.unwrap(), ends);
+    }
+}
+
+#[test]
+fn transposed_traces_round_trip() {
+    use hdf5_metno::types::VarLenUnicode;
+    let fixture = Fixture::new("transposed_traces");
+    let run = FixtureRun::new(1, 2, 4, 8);
+    fixture.write_runs(std::slice::from_ref(&run));
+    let transposed = fixture.config_named("transposed.yml", 1, 1, "transpose_traces: true\n");
+    run_ok(&transposed, &[]);
+
+    let path = &harmonic_files(&fixture.harmonic)[0];
+    for (index, event) in run.events.iter().enumerate() {
+        let dataset = events_group(path)
+            .group(&format!("event_{index}"))
+            .unwrap()
+            .dataset("get_traces")
+            .unwrap();
+        let orientation: VarLenUnicode =
+            dataset.attr("orientation").unwrap().read_scalar().unwrap();
+        assert_eq!(orientation.as_str(), "transposed");
+        let traces = dataset.read_2d::<i16>().unwrap();
+        assert_eq!(traces.t(), event.get.as_ref().unwrap());
+    }
+
+    // Read back by the harmonizer, they are the merger traces again
+    let output = run_ok(&transposed, &["verify-fidelity"]);
+    assert!(output.contains("Fidelity verification passed"), "{output}");
+
+    // Without the setting, the traces are stored as read, with no orientation
+    let config = fixture.config_named(
+        "plain.yml",
+        1,
+        1,
+        "overwrite: overwrite
+",
+    );
+    run_ok(&config, &[]);
+    let dataset = events_group(path)
+        .group("event_0")
+        .unwrap()
+        .dataset("get_traces")
+        .unwrap();
+    assert!(!dataset
+        .attr_names()
+        .unwrap()
+        .contains(&"orientation".to_string()));
+    assert_eq!(
+        &dataset.read_2d::<i16>().unwrap(),
+        run.events[0].get.as_ref().unwrap()
+    );
+}
+
+#[test]
+fn transformed_traces_match_their_merger_events() {
+    let fixture = Fixture::new("transformed_traces");
+    let runs = [FixtureRun::new(1, 3, 4, 8), FixtureRun::new(2, 2, 4, 8)];
+    fixture.write_runs(&runs);
+    let config = fixture.config(
+        1,
+        2,
+        "trace_transforms: [subtract_baseline, clip_negative]\n",
+    );
+    run_ok(&config, &[]);
+
+    let path = &harmonic_files(&fixture.harmonic)[0];
+    for index in 0..n_events(path) {
+        let traces = get_traces(path, index);
+        assert!(traces.iter().all(|sample| *sample >= 0), "event {index}");
+    }
+    // The merger traces are transformed the same way before comparing
+    let output = run_ok(&config, &["verify-fidelity"]);
+    assert!(output.contains("Fidelity verification passed"), "{output}");
+}
+
+#[test]
+fn padded_traces_have_the_target_length() {
+    let fixture = Fixture::new("padded_traces");
+    let mut run = FixtureRun::new(1, 3, 4, 8);
+    // Shorter, equal, and longer traces than the target
+    for (index, columns) in [6, 8, 10].into_iter().enumerate() {
+        run.events[index] = FixtureEvent::new(1, index as u64, 4, columns);
+    }
+    fixture.write_runs(std::slice::from_ref(&run));
+    let config = fixture.config(1, 1, "pad_traces_to: 8\npad_fill: -7\n");
+    run_ok(&config, &[]);
+
+    let path = &harmonic_files(&fixture.harmonic)[0];
+    for (index, event) in run.events.iter().enumerate() {
+        let traces = get_traces(path, index as u64);
+        assert_eq!(traces.dim(), (4, 8), "event {index}");
+        let merger = event.get.as_ref().unwrap();
+        let kept = merger.ncols().min(8);
+        assert_eq!(
+            traces.slice(ndarray::s![.., ..kept]),
+            merger.slice(ndarray::s![.., ..kept])
+        );
+        assert!(traces
+            .slice(ndarray::s![.., kept..])
+            .iter()
+            .all(|sample| *sample == -7));
+    }
+    // The merger traces are padded the same way before comparing
+    let output = run_ok(&config, &["verify-fidelity"]);
+    assert!(output.contains("Fidelity verification passed"), "{output}");
+}
+
+#[test]
+fn event_table_has_a_row_per_event() {
+    let fixture = Fixture::new("event_table");
+    let mut runs = [FixtureRun::new(1, 3, 4, 8), FixtureRun::new(2, 4, 6, 8)];
+    runs[1].events[2].frib = None;
+    fixture.write_runs(&runs);
+    let summary = fixture.dir.path().join("summary.json");
+    run_ok(
+        &fixture.config(1, 2, "write_event_metadata: true\nmax_events_per_file: 4\n"),
+        &["--summary-json", summary.to_str().unwrap()],
+    );
+
+    let table = read_parquet(&fixture.harmonic.join("events.parquet"));
+    assert_eq!(
+        table.height() as u64,
+        read_summary(&summary)["events_written"].as_u64().unwrap()
+    );
+    assert_eq!(table.height(), 7);
+    let column = |name: &str| -> Vec<String> {
+        table
+            .column(name)
+            .unwrap()
+            .as_materialized_series()
+            .iter()
+            .map(|value| value.to_string())
+            .collect()
+    };
+    assert_eq!(column("harmonic_run"), ["0", "0", "0", "0", "1", "1", "1"]);
+    assert_eq!(column("local_event"), ["0", "1", "2", "3", "0", "1", "2"]);
+    assert_eq!(column("orig_run"), ["1", "1", "1", "2", "2", "2", "2"]);
+    assert_eq!(column("orig_event"), ["0", "1", "2", "0", "1", "2", "3"]);
+    assert_eq!(column("trace_rows"), ["4", "4", "4", "6", "6", "6", "6"]);
+    assert_eq!(
+        column("frib_present"),
+        ["true", "true", "true", "true", "true", "false", "true"]
+    );
+}
+
+#[test]
+fn source_run_counts_sum_to_the_file_events() {
+    let fixture = Fixture::new("source_runs");
+    let runs = [
+        FixtureRun::new(1, 3, 4, 8),
+        FixtureRun::new(2, 5, 4, 8),
+        FixtureRun::new(3, 2, 4, 8),
+    ];
+    fixture.write_runs(&runs);
+    run_ok(
+        &fixture.config(1, 3, "write_source_runs: true\nmax_events_per_file: 4\n"),
+        &[],
+    );
+
+    let files = harmonic_files(&fixture.harmonic);
+    assert_eq!(files.len(), 3);
+    let mut expected = [
+        (vec![1, 2], vec![3u64, 1]),
+        (vec![2], vec![4]),
+        (vec![3], vec![2]),
+    ]
+    .into_iter();
+    for path in files.iter() {
+        let events = events_group(path);
+        let source_runs = events.attr("source_runs").unwrap().read_raw::<i32>();
+        let counts = events
+            .attr("source_run_events")
+            .unwrap()
+            .read_raw::<u64>()
+            .unwrap();
+        assert_eq!(counts.iter().sum::<u64>(), n_events(path));
+        assert_eq!((source_runs.unwrap(), counts), expected.next().unwrap());
+    }
+}
+
+#[test]
+fn written_at_is_present_and_monotonic() {
+    let fixture = Fixture::new("written_at");
+    fixture.write_runs(&[FixtureRun::new(1, 4, 4, 8), FixtureRun::new(2, 4, 4, 8)]);
+    run_ok(&fixture.config(1, 2, "write_written_at: true\n"), &[]);
+
+    let path = &harmonic_files(&fixture.harmonic)[0];
+    let written_at: Vec<u64> = (0..n_events(path))
+        .map(|index| {
+            events_group(path)
+                .group(&format!("event_{index}"))
+                .unwrap()
+                .attr("written_at")
+                .unwrap()
+                .read_scalar()
+                .unwrap()
+        })
+        .collect();
+    assert_eq!(written_at.len(), 8);
+    assert!(written_at[0] > 0);
+    assert!(written_at.windows(2).all(|pair| pair[0] <= pair[1]));
+
+    // The attribute is not written by default
+    let config = fixture.config_named("plain.yml", 1, 2, "overwrite: overwrite\n");
+    run_ok(&config, &[]);
+    assert!(!events_group(path)
+        .group("event_0")
+        .unwrap()
+        .attr_names()
+        .unwrap()
+        .contains(&"written_at".to_string()));
+}
+
+#[test]
+fn harmonic_run_attribute_matches_the_file() {
+    let fixture = Fixture::new("harmonic_run");
+    fixture.write_runs(&[FixtureRun::new(1, 4, 4, 8), FixtureRun::new(2, 4, 4, 8)]);
+    let settings = "max_events_per_file: 3\n";
+    run_ok(
+        &fixture.config(1, 2, &format!("{settings}write_harmonic_run: true\n")),
+        &[],
+    );
+
+    assert_eq!(harmonic_files(&fixture.harmonic).len(), 3);
+    for run in 0..3 {
+        let path = run_path(&fixture.harmonic, run);
+        for index in 0..n_events(&path) {
+            let harmonic_run: i32 = events_group(&path)
+                .group(&format!("event_{index}"))
+                .unwrap()
+                .attr("harmonic_run")
+                .unwrap()
+                .read_scalar()
+                .unwrap();
+            assert_eq!(harmonic_run, run, "event {index} of run {run}");
+        }
+    }
+
+    // The attribute is not written by default
+    let config = fixture.config_named(
+        "plain.yml",
+        1,
+        2,
+        &format!("{settings}overwrite: overwrite\n"),
+    );
+    run_ok(&config, &[]);
+    assert!(!events_group(&run_path(&fixture.harmonic, 1))
+        .group("event_0")
+        .unwrap()
+        .attr_names()
+        .unwrap()
+        .contains(&"harmonic_run".to_string()));
+}
+
+#[test]
+fn get_pads_match_the_trace_rows() {
+    let fixture = Fixture::new("get_pads");
+    let mut runs = [FixtureRun::new(1, 3, 4, 8), FixtureRun::new(2, 2, 7, 8)];
+    runs[0].events[1].get = None;
+    fixture.write_runs(&runs);
+    run_ok(&fixture.config(1, 2, "write_event_metadata: true\n"), &[]);
+
+    let table = read_parquet(&fixture.harmonic.join("events.parquet"));
+    let get_pads: Vec<Option<u64>> = table
+        .column("get_pads")
+        .unwrap()
+        .u64()
+        .unwrap()
+        .into_iter()
+        .collect();
+    let expected: Vec<Option<u64>> = runs
+        .iter()
+        .flat_map(|run| &run.events)
+        .map(|event| event.get.as_ref().map(|traces| traces.nrows() as u64))
+        .collect();
+    assert_eq!(get_pads, expected);
+    assert_eq!(expected[1], None);
+}
+
+/// The packed metadata compound, as a downstream tool would declare it from
+/// the documented layout
+#[derive(hdf5_metno::H5Type, Debug, Clone, Copy, PartialEq)]
+#[repr(C)]
+struct PackedMetadata {
+    orig_run: i32,
+    orig_event: u64,
+    flags: u8,
+    get_id: u32,
+    get_timestamp: u64,
+    get_timestamp_other: u64,
+    frib_event: u32,
+    frib_timestamp: u32,
+    get_ok: u8,
+    frib_ok: u8,
+}
+
+#[test]
+fn packed_metadata_round_trips() {
+    let fixture = Fixture::new("packed_metadata");
+    let mut runs = [FixtureRun::new(1, 3, 4, 8), FixtureRun::new(2, 3, 4, 8)];
+    runs[1].omitted_get_attrs = vec!["timestamp_other"];
+    runs[1].events[1].frib = None;
+    fixture.write_runs(&runs);
+    run_ok(
+        &fixture.config(1, 2, "pack_event_metadata: true\non_missing_attr: skip\n"),
+        &[],
+    );
+
+    let path = &harmonic_files(&fixture.harmonic)[0];
+    let mut events = Vec::new();
+    for index in 0..n_events(path) {
+        let group = events_group(path).group(&format!("event_{index}")).unwrap();
+        let mut attrs = group.attr_names().unwrap();
+        attrs.sort();
+        assert!(!attrs.contains(&"orig_run".to_string()), "{attrs:?}");
+        assert!(attrs.contains(&"metadata".to_string()), "{attrs:?}");
+        let get_attrs = group.dataset("get_traces").unwrap().attr_names().unwrap();
+        assert!(get_attrs.is_empty(), "{get_attrs:?}");
+
+        let metadata: PackedMetadata = group.attr("metadata").unwrap().read_scalar().unwrap();
+        let (run, event) = (metadata.orig_run, metadata.orig_event);
+        let has_other = run == 1;
+        let has_frib = !(run == 2 && event == 1);
+        let flags = 1 | 2 | if has_other { 4 } else { 0 } | if has_frib { 8 } else { 0 };
+        assert_eq!(
+            metadata,
+            PackedMetadata {
+                orig_run: run,
+                orig_event: event,
+                flags,
+                get_id: event as u32,
+                get_timestamp: get_timestamp(run, event),
+                get_timestamp_other: if has_other { event * 2 } else { 0 },
+                frib_event: if has_frib { event as u32 } else { 0 },
+                frib_timestamp: if has_frib { event as u32 * 5 } else { 0 },
+                get_ok: 0,
+                frib_ok: 0,
+            }
+        );
+        events.push((run, event));
+    }
+    assert_eq!(events, run_events(&runs));
+    run_ok(
+        &fixture.config(1, 2, "pack_event_metadata: true\non_missing_attr: skip\n"),
+        &["validate"],
+    );
+}
+
+#[test]
+fn combined_datasets_are_renumbered_continuously() {
+    let first = Fixture::new("combine_first");
+    let second = Fixture::new("combine_second");
+    let runs = [
+        FixtureRun::new(1, 3, 4, 8),
+        FixtureRun::new(2, 2, 4, 8),
+        FixtureRun::new(3, 4, 6, 8),
+    ];
+    first.write_runs(&runs[..2]);
+    second.write_runs(&runs[2..]);
+    run_ok(&first.config(1, 2, "max_events_per_file: 4\n"), &[]);
+    run_ok(&second.config(3, 3, ""), &[]);
+
+    let combined = first.dir.subdir("combined");
+    let config = first.config_named(
+        "combine.yml",
+        1,
+        2,
+        "max_events_per_file: 3\nwrite_event_metadata: true\n",
+    );
+    let output = run_ok(
+        &config,
+        &[
+            "combine",
+            first.harmonic.to_str().unwrap(),
+            second.harmonic.to_str().unwrap(),
+            combined.to_str().unwrap(),
+        ],
+    );
+    assert!(output.contains("Events written: 9"), "{output}");
+
+    let files = harmonic_files(&combined);
+    assert_eq!(files.len(), 3);
+    for (run, path) in files.iter().enumerate() {
+        assert_eq!(*path, run_path(&combined, run as i32));
+        assert_eq!(n_events(path), 3);
+    }
+    assert_eq!(all_orig_events(&combined), run_events(&runs));
+    // The traces follow their events
+    assert_eq!(
+        get_traces(&files[2], 2),
+        runs[2].events[3].get.clone().unwrap()
+    );
+
+    let table = read_parquet(&combined.join("events.parquet"));
+    let column = |name: &str| -> Vec<String> {
+        table
+            .column(name)
+            .unwrap()
+            .as_materialized_series()
+            .iter()
+            .map(|value| value.to_string())
+            .collect()
+    };
+    assert_eq!(
+        column("harmonic_run"),
+        ["0", "0", "0", "1", "1", "1", "2", "2", "2"]
+    );
+    assert_eq!(
+        column("orig_run"),
+        ["1", "1", "1", "2", "2", "3", "3", "3", "3"]
+    );
+
+    let scalers = read_parquet(&combined.join("scalers.parquet"));
+    let mut expected = read_parquet(&first.harmonic.join("scalers.parquet"));
+    expected
+        .vstack_mut(&read_parquet(&second.harmonic.join("scalers.parquet")))
+        .unwrap();
+    assert_eq!(scalers.height(), 9);
+    assert!(scalers.equals(&expected));
+}
+
+#[test]
+fn source_run_event_ranges_are_tight() {
+    let fixture = Fixture::new("source_run_ranges");
+    let mut runs = [
+        FixtureRun::new(1, 3, 4, 8),
+        FixtureRun::new(2, 6, 4, 8),
+        FixtureRun::new(3, 2, 4, 8),
+    ];
+    runs[1].min_event = 10;
+    fixture.write_runs(&runs);
+    // The first and last events of run 2 are left out, so the ranges must
+    // follow the events written rather than the run
+    let blacklist = fixture.dir.path().join("blacklist.csv");
+    std::fs::write(&blacklist, "2,10\n2,15\n").unwrap();
+    let extra = format!(
+        "write_source_runs: true\nmax_events_per_file: 4\nblacklist_path: {}\n",
+        yaml_path(&blacklist)
+    );
+    run_ok(&fixture.config(1, 3, &extra), &[]);
+
+    let files = harmonic_files(&fixture.harmonic);
+    assert_eq!(files.len(), 3);
+    let mut expected = [
+        (vec![1, 2], vec![0u64, 11], vec![2u64, 11]),
+        (vec![2, 3], vec![12, 0], vec![14, 0]),
+        (vec![3], vec![1], vec![1]),
+    ]
+    .into_iter();
+    for path in files.iter() {
+        let events = events_group(path);
+        let attr = |name: &str| events.attr(name).unwrap().read_raw::<u64>().unwrap();
+        let source_runs = events
+            .attr("source_runs")
+            .unwrap()
+            .read_raw::<i32>()
+            .unwrap();
+        let (min_events, max_events) = (attr("source_run_min_event"), attr("source_run_max_event"));
+        // Each range is exactly that of the events of the run in the file
+        let orig_events = orig_events(path);
+        for (index, run) in source_runs.iter().enumerate() {
+            let numbers: Vec<u64> = orig_events
+                .iter()
+                .filter(|(orig_run, _)| orig_run == run)
+                .map(|(_, event)| *event)
+                .collect();
+            assert_eq!(min_events[index], *numbers.iter().min().unwrap());
+            assert_eq!(max_events[index], *numbers.iter().max().unwrap());
+        }
+        assert_eq!(
+            (source_runs, min_events, max_events),
+            expected.next().unwrap()
+        );
+    }
+}
+
+#[test]
+fn remapped_runs_are_consistent_and_reversible() {
+    let fixture = Fixture::new("run_remap");
+    let runs = [FixtureRun::new(55, 3, 4, 8), FixtureRun::new(56, 2, 4, 8)];
+    fixture.write_runs(&runs);
+    let remap_path = fixture.dir.path().join("run_remap.yml");
+    let config = fixture.config(
+        55,
+        56,
+        &format!(
+            "run_remap: {{55: 2, 56: 1}}\nrun_remap_path: {}\nwrite_userblock: true\n\
+             write_source_runs: true\nwrite_event_metadata: true\nmax_events_per_file: 4\n",
+            yaml_path(&remap_path)
+        ),
+    );
+    run_ok(&config, &[]);
+
+    // Every run number written is the remapped one
+    let remapped = |run: i32| if run == 55 { 2 } else { 1 };
+    let expected: Vec<(i32, u64)> = run_events(&runs)
+        .into_iter()
+        .map(|(run, event)| (remapped(run), event))
+        .collect();
+    assert_eq!(all_orig_events(&fixture.harmonic), expected);
+    let files = harmonic_files(&fixture.harmonic);
+    let source_runs: Vec<Vec<i32>> = files
+        .iter()
+        .map(|path| {
+            events_group(path)
+                .attr("source_runs")
+                .unwrap()
+                .read_raw::<i32>()
+                .unwrap()
+        })
+        .collect();
+    assert_eq!(source_runs, [vec![1, 2], vec![1]]);
+    let column = |path: &str, name: &str| -> Vec<String> {
+        read_parquet(&fixture.harmonic.join(path))
+            .column(name)
+            .unwrap()
+            .as_materialized_series()
+            .iter()
+            .map(|value| value.to_string())
+            .collect()
+    };
+    assert_eq!(
+        column("events.parquet", "orig_run"),
+        ["2", "2", "2", "1", "1"]
+    );
+    assert_eq!(
+        column("scalers.parquet", "run"),
+        ["2", "2", "2", "1", "1", "1"]
+    );
+
+    // The map is only written outside of the output, and the userblock
+    // gives nothing away
+    assert_eq!(
+        std::fs::read_to_string(&remap_path).unwrap(),
+        "55: 2\n56: 1\n"
+    );
+    assert!(!fixture.harmonic.join("run_remap.yml").exists());
+    for path in files.iter() {
+        let bytes = std::fs::read(path).unwrap();
+        let end = bytes.iter().position(|byte| *byte == 0).unwrap();
+        let metadata: serde_json::Value = serde_json::from_slice(&bytes[..end]).unwrap();
+        let settings = metadata["config"].as_object().unwrap();
+        for key in [
+            "run_remap",
+            "run_remap_path",
+            "min_run",
+            "max_run",
+            "merger_path",
+        ] {
+            assert!(!settings.contains_key(key), "{key}");
+        }
+        assert_eq!(settings["write_userblock"], true);
+    }
+
+    // The checks read the merger runs the events came from
+    let output = run_ok(&config, &["validate"]);
+    assert!(output.contains("Validation passed"), "{output}");
+    let output = run_ok(&config, &["verify-fidelity"]);
+    assert!(output.contains("Fidelity verification passed"), "{output}");
+}
+
+#[test]
+fn per_channel_traces_rebuild_the_array() {
+    let fixture = Fixture::new("per_channel");
+    let runs = [FixtureRun::new(1, 2, 5, 8), FixtureRun::new(2, 2, 3, 6)];
+    fixture.write_runs(&runs);
+    let config = fixture.config(1, 2, "trace_layout: per_channel\n");
+    run_ok(&config, &[]);
+
+    let path = &harmonic_files(&fixture.harmonic)[0];
+    let merger_traces: Vec<Array2<i16>> = runs
+        .iter()
+        .flat_map(|run| run.events.iter().map(|event| event.get.clone().unwrap()))
+        .collect();
+    for (index, expected) in merger_traces.iter().enumerate() {
+        let group = events_group(path)
+            .group(&format!("event_{index}/get_traces"))
+            .unwrap();
+        let layout: hdf5_metno::types::VarLenUnicode =
+            group.attr("layout").unwrap().read_scalar().unwrap();
+        assert_eq!(layout.as_str(), "per_channel");
+        let n_channels: u64 = group.attr("n_channels").unwrap().read_scalar().unwrap();
+        assert_eq!(n_channels as usize, expected.nrows());
+        assert_eq!(group.member_names().unwrap().len(), expected.nrows());
+        for name in ["id", "timestamp", "timestamp_other"] {
+            assert!(group.attr(name).is_ok(), "{name}");
+        }
+
+        // Stacking the channels in order gives back the 2-D traces
+        let channels: Vec<Array1<i16>> = (0..n_channels)
+            .map(|channel| {
+                group
+                    .dataset(&format!("channel_{channel}"))
+                    .unwrap()
+                    .read_1d()
+                    .unwrap()
+            })
+            .collect();
+        let views: Vec<_> = channels.iter().map(|channel| channel.view()).collect();
+        let rebuilt = ndarray::stack(Axis(0), &views).unwrap();
+        assert_eq!(rebuilt, *expected);
+    }
+    let output = run_ok(&config, &["verify-fidelity"]);
+    assert!(output.contains("Fidelity verification passed"), "{output}");
+
+    // Combined into the array layout, the traces are 2-D datasets again
+    let combined = fixture.dir.subdir("combined");
+    let array = fixture.config_named("array.yml", 1, 2, "");
+    run_ok(
+        &array,
+        &[
+            "combine",
+            fixture.harmonic.to_str().unwrap(),
+            combined.to_str().unwrap(),
+        ],
+    );
+    let combined_path = &harmonic_files(&combined)[0];
+    for (index, expected) in merger_traces.iter().enumerate() {
+        assert_eq!(get_traces(combined_path, index as u64), *expected);
+    }
+}
+
+#[test]
+fn stacked_events_round_trip() {
+    let fixture = Fixture::new("stacked_events");
+    let mut runs = [FixtureRun::new(1, 4, 3, 8), FixtureRun::new(2, 3, 4, 8)];
+    // GET pad counts and widths vary from event to event
+    for (run, shapes) in runs.iter_mut().zip([
+        vec![(3, 8), (6, 8), (2, 8), (6, 8)],
+        vec![(5, 8), (1, 10), (7, 6)],
+    ]) {
+        for (index, (rows, columns)) in shapes.into_iter().enumerate() {
+            run.events[index] = FixtureEvent::new(run.run, index as u64, rows, columns);
+        }
+    }
+    fixture.write_runs(&runs);
+    let settings = "event_storage: stacked\nexclude_frib_coincidence: true\n";
+    let padded = fixture.config(1, 2, &format!("{settings}stacked_padding: true\n"));
+    run_ok(&padded, &[]);
+
+    let path = &harmonic_files(&fixture.harmonic)[0];
+    let events = events_group(path);
+    let metadata = events
+        .dataset("metadata")
+        .unwrap()
+        .read_1d::<PackedMetadata>()
+        .unwrap();
+    let origins: Vec<(i32, u64)> = metadata
+        .iter()
+        .map(|metadata| (metadata.orig_run, metadata.orig_event))
+        .collect();
+    assert_eq!(origins, run_events(&runs));
+    let stacked = events.dataset("get_traces").unwrap();
+    // Grown to the largest traces, and trimmed to the events written
+    assert_eq!(stacked.shape(), [7, 7, 10]);
+    let read_dims =
+        |name: &str| -> Vec<u32> { events.dataset(name).unwrap().read_raw::<u32>().unwrap() };
+    let merger_traces: Vec<Array2<i16>> = runs
+        .iter()
+        .flat_map(|run| run.events.iter().map(|event| event.get.clone().unwrap()))
+        .collect();
+    let rows: Vec<u32> = merger_traces
+        .iter()
+        .map(|traces| traces.nrows() as u32)
+        .collect();
+    let columns: Vec<u32> = merger_traces
+        .iter()
+        .map(|traces| traces.ncols() as u32)
+        .collect();
+    assert_eq!(read_dims("get_rows"), rows);
+    assert_eq!(read_dims("get_columns"), columns);
+    let stacked = stacked.read::<i16, ndarray::Ix3>().unwrap();
+    for (index, expected) in merger_traces.iter().enumerate() {
+        let (rows, columns) = expected.dim();
+        let event = stacked.index_axis(Axis(0), index);
+        assert_eq!(event.slice(ndarray::s![..rows, ..columns]), expected.view());
+        // The padding is zero
+        let total: i64 = event.iter().map(|sample| *sample as i64).sum();
+        let real: i64 = expected.iter().map(|sample| *sample as i64).sum();
+        assert_eq!(total, real, "event {index}");
+    }
+
+    // Read back by the harmonizer, the padding is dropped again
+    let output = run_ok(&padded, &["verify-fidelity"]);
+    assert!(output.contains("Fidelity verification passed"), "{output}");
+    run_ok(&padded, &["validate"]);
+    let combined = fixture.dir.subdir("combined");
+    let groups = fixture.config_named("groups.yml", 1, 2, "");
+    run_ok(
+        &groups,
+        &[
+            "combine",
+            fixture.harmonic.to_str().unwrap(),
+            combined.to_str().unwrap(),
+        ],
+    );
+    let combined_path = &harmonic_files(&combined)[0];
+    for (index, expected) in merger_traces.iter().enumerate() {
+        assert_eq!(get_traces(combined_path, index as u64), *expected);
+    }
+
+    // Without padding, the second event no longer fits
+    let unpadded = fixture.config_named(
+        "unpadded.yml",
+        1,
+        2,
+        &format!("{settings}overwrite: overwrite\n"),
+    );
+    let output = run_err(&unpadded, &[]);
+    assert!(
+        output.contains("Traces of shape (6, 8) cannot be stacked"),
+        "{output}"
+    );
+}
+
+#[test]
+fn compress_report_includes_every_setting() {
+    let fixture = Fixture::new("compress_report");
+    fixture.write_runs(&[FixtureRun::new(1, 4, 4, 8)]);
+    let lines = |output: &str| -> Vec<String> {
+        output
+            .lines()
+            .filter(|line| line.contains(" | "))
+            .skip(1)
+            .map(|line| line.split(" | ").next().unwrap().trim().to_string())
+            .collect()
+    };
+
+    let output = run_ok(
+        &fixture.config(1, 1, ""),
+        &["compress-report", "--events", "3"],
+    );
+    assert_eq!(
+        lines(&output),
+        ["none", "gzip 1", "gzip 4", "gzip 9", "szip"],
+        "{output}"
+    );
+
+    let settings = "compress_report_settings:\n  - compression: gzip\n    level: 2\n  - compression: none\n  - compression: gzip\n    level: 7\n";
+    let config = fixture.config_named("settings.yml", 1, 1, settings);
+    let output = run_ok(&config, &["compress-report", "--events", "3"]);
+    assert_eq!(lines(&output), ["gzip 2", "none", "gzip 7"], "{output}");
+    // Every setting harmonized the whole sample
+    for line in output
+        .lines()
+        .filter(|line| line.starts_with("gzip") || line.starts_with("none"))
+    {
+        assert_eq!(line.split(" | ").nth(1).unwrap().trim(), "3", "{line}");
+    }
+    // The scratch files are removed
+    assert!(harmonic_files(&fixture.harmonic).is_empty());
+}
@@ -0,0 +1,555 @@
+//! Integration tests of the scaler output
+mod common;
+
+use common::*;
+use polars::prelude::DataType;
+use std::collections::BTreeSet;
+use std::process::Command;
+
+#[test]
+fn only_the_included_scaler_columns_are_written() {
+    let fixture = Fixture::new("scaler_columns");
+    fixture.write_runs(&[FixtureRun::new(1, 2, 4, 8), FixtureRun::new(2, 2, 4, 8)]);
+    let config = fixture.config(1, 2, "scaler_columns_include: [clock_live, trig_live]\n");
+    run_ok(&config, &[]);
+
+    let scalers = read_parquet(&fixture.harmonic.join("scalers.parquet"));
+    let columns: Vec<&str> = scalers
+        .get_column_names()
+        .into_iter()
+        .map(|name| name.as_str())
+        .collect();
+    assert_eq!(columns, ["run", "event", "clock_live", "trig_live"]);
+    assert_eq!(scalers.height(), 6);
+    // clock_live is the second scaler value
+    let clock_live: Vec<Option<u32>> = scalers
+        .column("clock_live")
+        .unwrap()
+        .u32()
+        .unwrap()
+        .into_iter()
+        .collect();
+    assert_eq!(
+        clock_live,
+        [101, 112, 123, 201, 212, 223].map(Some).to_vec()
+    );
+}
+
+#[test]
+fn unknown_scaler_columns_are_rejected() {
+    let fixture = Fixture::new("unknown_scaler_column");
+    fixture.write_runs(&[FixtureRun::new(1, 2, 4, 8)]);
+    let config = fixture.config(1, 1, "scaler_columns_include: [clock_dead]\n");
+    let output = run_err(&config, &[]);
+    assert!(
+        output.contains("Unknown scaler column clock_dead"),
+        "{output}"
+    );
+}
+
+#[test]
+fn scaler_schema_sets_the_column_types() {
+    let fixture = Fixture::new("scaler_schema");
+    fixture.write_runs(&[FixtureRun::new(1, 2, 4, 8), FixtureRun::new(2, 2, 4, 8)]);
+    let schema =
+        "scaler_schema: {run: i64, event: i64, clock_free: i32, clock_live: u64, trig_free: f64}\n";
+    run_ok(&fixture.config(1, 2, schema), &[]);
+
+    let scalers = read_parquet(&fixture.harmonic.join("scalers.parquet"));
+    let types: Vec<(String, DataType)> = scalers
+        .get_columns()
+        .iter()
+        .map(|column| (column.name().to_string(), column.dtype().clone()))
+        .collect();
+    let mut expected = vec![
+        ("run".to_string(), DataType::Int64),
+        ("event".to_string(), DataType::Int64),
+        ("clock_free".to_string(), DataType::Int32),
+        ("clock_live".to_string(), DataType::UInt64),
+        ("trig_free".to_string(), DataType::Float64),
+    ];
+    for name in [
+        "trig_live",
+        "ic_sca",
+        "mesh_sca",
+        "si1_cfd",
+        "si2",
+        "sipm",
+        "ic_ds",
+        "ic_cfd",
+    ] {
+        expected.push((name.to_string(), DataType::UInt32));
+    }
+    assert_eq!(types, expected);
+
+    // The values survive the casts
+    let runs: Vec<Option<i64>> = scalers
+        .column("run")
+        .unwrap()
+        .i64()
+        .unwrap()
+        .into_iter()
+        .collect();
+    assert_eq!(runs, [1, 1, 1, 2, 2, 2].map(Some).to_vec());
+    let events: Vec<Option<i64>> = scalers
+        .column("event")
+        .unwrap()
+        .i64()
+        .unwrap()
+        .into_iter()
+        .collect();
+    assert_eq!(events, [0, 1, 2, 0, 1, 2].map(Some).to_vec());
+    let trig_free: Vec<Option<f64>> = scalers
+        .column("trig_free")
+        .unwrap()
+        .f64()
+        .unwrap()
+        .into_iter()
+        .collect();
+    assert_eq!(
+        trig_free,
+        [102.0, 113.0, 124.0, 202.0, 213.0, 224.0]
+            .map(Some)
+            .to_vec()
+    );
+
+    let unknown = fixture.config_named("unknown.yml", 1, 2, "scaler_schema: {clock_dead: i64}\n");
+    let output = run_err(&unknown, &[]);
+    assert!(
+        output.contains("Unknown scaler column clock_dead"),
+        "{output}"
+    );
+}
+
+#[test]
+fn scalers_alone_are_extracted_into_a_fresh_directory() {
+    let fixture = Fixture::new("scalers_only");
+    let runs = [FixtureRun::new(1, 2, 4, 8), FixtureRun::new(3, 2, 4, 8)];
+    fixture.write_runs(&runs);
+    // The harmonic path does not exist yet
+    std::fs::remove_dir(&fixture.harmonic).unwrap();
+    let config = fixture.config(1, 3, "");
+    run_ok(&config, &["scalers"]);
+
+    let entries: Vec<String> = std::fs::read_dir(&fixture.harmonic)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    assert_eq!(entries, ["scalers.parquet"]);
+    let scalers = read_parquet(&fixture.harmonic.join("scalers.parquet"));
+    let runs: Vec<Option<u32>> = scalers
+        .column("run")
+        .unwrap()
+        .u32()
+        .unwrap()
+        .into_iter()
+        .collect();
+    assert_eq!(runs, [1, 1, 1, 3, 3, 3].map(Some).to_vec());
+    let clock_free: Vec<Option<u32>> = scalers
+        .column("clock_free")
+        .unwrap()
+        .u32()
+        .unwrap()
+        .into_iter()
+        .collect();
+    assert_eq!(
+        clock_free,
+        [100, 111, 122, 300, 311, 322].map(Some).to_vec()
+    );
+}
+
+#[test]
+fn only_the_scalers_in_range_are_written() {
+    let fixture = Fixture::new("scaler_range");
+    let mut runs = [FixtureRun::new(1, 2, 4, 8), FixtureRun::new(2, 2, 4, 8)];
+    for run in runs.iter_mut() {
+        let scalers: Vec<Vec<u32>> = (0..5u32)
+            .map(|scaler| {
+                (0..N_SCALERS as u32)
+                    .map(|value| run.run as u32 * 100 + scaler * 11 + value)
+                    .collect()
+            })
+            .collect();
+        run.scalers = Some(scalers);
+    }
+    runs[0].write_010(&fixture.merger);
+    runs[1].write_020(&fixture.merger);
+    let config = fixture.config(1, 2, "scaler_event_min: 1\nscaler_event_max: 3\n");
+    run_ok(&config, &[]);
+
+    let scalers = read_parquet(&fixture.harmonic.join("scalers.parquet"));
+    let column = |name: &str| -> Vec<Option<u32>> {
+        scalers
+            .column(name)
+            .unwrap()
+            .u32()
+            .unwrap()
+            .into_iter()
+            .collect()
+    };
+    assert_eq!(column("run"), [1, 1, 1, 2, 2, 2].map(Some).to_vec());
+    assert_eq!(column("event"), [1, 2, 3, 1, 2, 3].map(Some).to_vec());
+    assert_eq!(
+        column("clock_free"),
+        [111, 122, 133, 211, 222, 233].map(Some).to_vec()
+    );
+}
+
+#[test]
+fn disabled_scalers_leave_no_scaler_file() {
+    for (format, extra, args) in [
+        ("parquet", "extract_scalers: false\n", vec![]),
+        ("parquet", "", vec!["--no-scalers"]),
+        ("hdf5", "extract_scalers: false\n", vec![]),
+        ("parquet_per_run", "", vec!["--no-scalers"]),
+    ] {
+        let fixture = Fixture::new("no_scalers");
+        let runs = [FixtureRun::new(1, 2, 4, 8), FixtureRun::new(2, 2, 4, 8)];
+        fixture.write_runs(&runs);
+        let config = fixture.config(1, 2, &format!("scaler_output: {format}\n{extra}"));
+        run_ok(&config, &args);
+
+        assert_eq!(all_orig_events(&fixture.harmonic), run_events(&runs));
+        let scaler_files: Vec<String> = std::fs::read_dir(&fixture.harmonic)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with("scalers"))
+            .collect();
+        assert!(scaler_files.is_empty(), "{format}: {scaler_files:?}");
+
+        // The scalers command still extracts them later
+        run_ok(&config, &["scalers"]);
+        assert!(
+            std::fs::read_dir(&fixture.harmonic)
+                .unwrap()
+                .any(|entry| entry
+                    .unwrap()
+                    .file_name()
+                    .to_str()
+                    .unwrap()
+                    .starts_with("scalers")),
+            "{format}"
+        );
+    }
+}
+
+#[test]
+fn scalers_are_read_with_alternate_names() {
+    let fixture = Fixture::new("scaler_names");
+    let runs = [FixtureRun::new(1, 2, 4, 8), FixtureRun::new(2, 2, 4, 8)];
+    let v010 = hdf5_metno::File::open_rw(runs[0].write_010(&fixture.merger)).unwrap();
+    let v020 = hdf5_metno::File::open_rw(runs[1].write_020(&fixture.merger)).unwrap();
+    for scaler in 0..3 {
+        v010.group("frib/scaler")
+            .unwrap()
+            .link_move(&format!("scaler{scaler}_data"), &format!("sca_{scaler}"))
+            .unwrap();
+        v020.group("scalers")
+            .unwrap()
+            .link_move(&format!("event_{scaler}"), &format!("scaler_{scaler}"))
+            .unwrap();
+    }
+    drop((v010, v020));
+    let config = fixture.config(
+        1,
+        2,
+        "scaler_names:\n  v010: \"sca_{n}\"\n  v020: \"scaler_{n}\"\n",
+    );
+    run_ok(&config, &[]);
+
+    let scalers = read_parquet(&fixture.harmonic.join("scalers.parquet"));
+    let clock_free: Vec<Option<u32>> = scalers
+        .column("clock_free")
+        .unwrap()
+        .u32()
+        .unwrap()
+        .into_iter()
+        .collect();
+    assert_eq!(
+        clock_free,
+        [100, 111, 122, 200, 211, 222].map(Some).to_vec()
+    );
+
+    // With the default names, the renamed scalers cannot be found
+    let defaults = fixture.config_named("defaults.yml", 1, 2, "");
+    let output = run_err(&defaults, &["scalers", "--overwrite-scalers"]);
+    assert!(
+        output.contains("Failed to open scaler 0 of run"),
+        "{output}"
+    );
+}
+
+#[test]
+fn runs_without_scalers_contribute_no_rows() {
+    let fixture = Fixture::new("missing_scalers");
+    let mut runs: Vec<FixtureRun> = (1..=4).map(|run| FixtureRun::new(run, 2, 4, 8)).collect();
+    runs[1].scalers = None;
+    runs[2].scalers = None;
+    runs[0].write_020(&fixture.merger);
+    runs[1].write_020(&fixture.merger);
+    runs[2].write_010(&fixture.merger);
+    runs[3].write_010(&fixture.merger);
+    let output = run_ok(&fixture.config(1, 4, ""), &[]);
+
+    for run in [2, 3] {
+        assert!(
+            output.contains(&format!("Warning: run {run} has no scalers")),
+            "{output}"
+        );
+    }
+    let scalers = read_parquet(&fixture.harmonic.join("scalers.parquet"));
+    let runs: Vec<String> = scalers
+        .column("run")
+        .unwrap()
+        .as_materialized_series()
+        .iter()
+        .map(|value| value.to_string())
+        .collect();
+    assert_eq!(runs, ["1", "1", "1", "4", "4", "4"]);
+    // The events of every run are still harmonized
+    assert_eq!(n_events(&harmonic_files(&fixture.harmonic)[0]), 8);
+}
+
+#[test]
+fn per_run_scalers_written_in_parallel_match_sequential_ones() {
+    let fixture = Fixture::new("parallel_per_run_scalers");
+    let runs: Vec<FixtureRun> = (1..=6).map(|run| FixtureRun::new(run, 3, 4, 8)).collect();
+    fixture.write_runs(&runs);
+    let config = fixture.config(1, 6, "scaler_output: parquet_per_run\n");
+
+    // With a single rayon thread the runs are written one after the other
+    let output = Command::new(env!("CARGO_BIN_EXE_harmonizer"))
+        .arg("--config")
+        .arg(&config)
+        .arg("scalers")
+        .env("RAYON_NUM_THREADS", "1")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", output_text(&output));
+    let sequential: Vec<_> = (1..=6)
+        .map(|run| {
+            read_parquet(
+                &fixture
+                    .harmonic
+                    .join(format!("scalers_run_{run:0>4}.parquet")),
+            )
+        })
+        .collect();
+
+    run_ok(&config, &["scalers", "--overwrite-scalers"]);
+    let combined = fixture.config_named("combined.yml", 1, 6, "");
+    run_ok(&combined, &["scalers"]);
+    let all = read_parquet(&fixture.harmonic.join("scalers.parquet"));
+    let mut stacked = sequential[0].clone();
+    for (run, frame) in (1..=6).zip(&sequential) {
+        let path = fixture
+            .harmonic
+            .join(format!("scalers_run_{run:0>4}.parquet"));
+        assert!(read_parquet(&path).equals(frame), "run {run}");
+        assert_eq!(frame.height(), 3, "run {run}");
+        if run > 1 {
+            stacked.vstack_mut(frame).unwrap();
+        }
+    }
+    // And together they are the combined scalers
+    assert!(stacked.equals(&all));
+}
+
+#[test]
+fn harmonic_run_scalers_match_the_event_partitions() {
+    let runs = [
+        FixtureRun::new(1, 4, 4, 8),
+        FixtureRun::new(2, 3, 4, 8),
+        FixtureRun::new(3, 4, 4, 8),
+    ];
+    let settings = "scaler_output: parquet_per_harmonic_run\nmax_events_per_file: 3\n";
+    for layout in ["round_robin", "by_source_run"] {
+        let fixture = Fixture::new(&format!("harmonic_run_scalers_{layout}"));
+        fixture.write_runs(&runs);
+        let disk = fixture.dir.subdir("disk_b");
+        let extra = match layout {
+            "round_robin" => format!("harmonic_paths: [{}]\n", yaml_path(&disk)),
+            _ => String::from("output_layout: by_source_run\n"),
+        };
+        run_ok(&fixture.config(1, 3, &format!("{settings}{extra}")), &[]);
+
+        let mut files = harmonic_files(&fixture.harmonic);
+        files.extend(harmonic_files(&disk));
+        // Three events a file, with the second and third spanning two runs
+        assert_eq!(files.len(), 4, "{layout}");
+        let mut scaler_files = 0;
+        for path in files {
+            let name = path.file_name().unwrap().to_str().unwrap();
+            let scaler_path = path.with_file_name(format!("scalers_{}.parquet", &name[..8]));
+            let scalers = read_parquet(&scaler_path);
+            let scaler_runs: BTreeSet<u32> = scalers
+                .column("run")
+                .unwrap()
+                .u32()
+                .unwrap()
+                .into_iter()
+                .flatten()
+                .collect();
+            let event_runs: BTreeSet<u32> = orig_events(&path)
+                .into_iter()
+                .map(|(run, _)| run as u32)
+                .collect();
+            assert_eq!(scaler_runs, event_runs, "{}", path.display());
+            // Three scaler events a run
+            assert_eq!(scalers.height(), 3 * event_runs.len(), "{}", path.display());
+            scaler_files += 1;
+        }
+        assert_eq!(scaler_files, 4);
+        // No scaler files are left anywhere else
+        assert!(!fixture.harmonic.join("scalers.parquet").exists());
+        let elsewhere = match layout {
+            // Run 1 is written to the second disk
+            "round_robin" => "scalers_run_0001.parquet",
+            // Run 0 is written to src_001
+            _ => "scalers_run_0000.parquet",
+        };
+        assert!(!fixture.harmonic.join(elsewhere).exists(), "{layout}");
+    }
+}
+
+#[test]
+fn concurrent_scalers_match_sequential_ones() {
+    let fixture = Fixture::new("concurrent_scalers");
+    let runs: Vec<FixtureRun> = (1..=5).map(|run| FixtureRun::new(run, 3, 4, 8)).collect();
+    fixture.write_runs(&runs);
+    let scaler_paths: Vec<_> = std::iter::once(String::from("scalers.parquet"))
+        .chain((1..=5).map(|run| format!("scalers_run_{run:0>4}.parquet")))
+        .map(|name| fixture.harmonic.join(name))
+        .collect();
+    let settings = "max_events_per_file: 4\noverwrite: overwrite\n";
+    let harmonize = |name: &str, extra: &str| {
+        for output in ["parquet", "parquet_per_run"] {
+            let config = fixture.config_named(
+                &format!("{name}_{output}.yml"),
+                1,
+                5,
+                &format!("{settings}scaler_output: {output}\n{extra}"),
+            );
+            run_ok(&config, &[]);
+        }
+        let scalers: Vec<_> = scaler_paths.iter().map(|path| read_parquet(path)).collect();
+        let files = harmonic_files(&fixture.harmonic);
+        let traces: Vec<Vec<_>> = files
+            .iter()
+            .map(|path| {
+                (0..n_events(path))
+                    .map(|index| get_traces(path, index))
+                    .collect()
+            })
+            .collect();
+        (scalers, all_orig_events(&fixture.harmonic), traces)
+    };
+
+    let (sequential_scalers, sequential_events, sequential_traces) = harmonize("sequential", "");
+    // Also with the default bound on the open files
+    let (concurrent_scalers, concurrent_events, concurrent_traces) =
+        harmonize("concurrent", "concurrent_scalers: true\n");
+    assert_eq!(sequential_scalers.len(), concurrent_scalers.len());
+    for (path, (sequential, concurrent)) in scaler_paths
+        .iter()
+        .zip(sequential_scalers.iter().zip(&concurrent_scalers))
+    {
+        assert!(sequential.equals(concurrent), "{}", path.display());
+    }
+    assert_eq!(sequential_scalers[0].height(), 15);
+    assert_eq!(concurrent_events, sequential_events);
+    assert_eq!(concurrent_events, run_events(&runs));
+    assert_eq!(concurrent_traces, sequential_traces);
+}
+
+#[test]
+fn scaler_orders_align_the_versions() {
+    let fixture = Fixture::new("scaler_order");
+    let mut runs = [FixtureRun::new(1, 2, 4, 8), FixtureRun::new(2, 2, 4, 8)];
+    // The 0.1.0 run stores its scaler values in the reverse order
+    for values in runs[0].scalers.as_mut().unwrap() {
+        values.reverse();
+    }
+    runs[0].write_010(&fixture.merger);
+    runs[1].write_020(&fixture.merger);
+    let reversed: Vec<String> = (0..11).rev().map(|index| index.to_string()).collect();
+    let config = fixture.config(
+        1,
+        2,
+        &format!("scaler_order:\n  v010: [{}]\n", reversed.join(", ")),
+    );
+    run_ok(&config, &[]);
+
+    // Every column holds the same value of both runs: the fixture's value
+    // index k of column k
+    let scalers = read_parquet(&fixture.harmonic.join("scalers.parquet"));
+    assert_eq!(scalers.height(), 6);
+    let column = |name: &str| -> Vec<u32> {
+        let column = scalers.column(name).unwrap();
+        column
+            .u32()
+            .unwrap()
+            .into_iter()
+            .map(Option::unwrap)
+            .collect()
+    };
+    let runs = column("run");
+    let events = column("event");
+    for (index, name) in scalers.get_column_names().into_iter().enumerate().skip(2) {
+        let expected: Vec<u32> = runs
+            .iter()
+            .zip(&events)
+            .map(|(run, event)| run * 100 + event * 11 + index as u32 - 2)
+            .collect();
+        assert_eq!(column(name.as_str()), expected, "{name}");
+    }
+
+    // Without the order, the 0.1.0 columns are reversed
+    let unordered = fixture.config_named("unordered.yml", 1, 2, "");
+    run_ok(&unordered, &["scalers", "--overwrite-scalers"]);
+    let scalers = read_parquet(&fixture.harmonic.join("scalers.parquet"));
+    let clock_free: Vec<Option<u32>> = scalers
+        .column("clock_free")
+        .unwrap()
+        .u32()
+        .unwrap()
+        .into_iter()
+        .collect();
+    assert_eq!(
+        clock_free,
+        [110, 121, 132, 200, 211, 222].map(Some).to_vec()
+    );
+}
+
+#[test]
+fn existing_scalers_follow_the_overwrite_policy() {
+    for (format, name) in [
+        ("parquet", "scalers.parquet"),
+        ("hdf5", "scalers.h5"),
+        ("parquet_per_run", "scalers_run_0001.parquet"),
+    ] {
+        let fixture = Fixture::new("overwrite_scalers");
+        fixture.write_runs(&[FixtureRun::new(1, 2, 4, 8)]);
+        let config = fixture.config(1, 1, &format!("scaler_output: {format}\n"));
+        let path = fixture.harmonic.join(name);
+        std::fs::write(&path, "stale").unwrap();
+
+        // The scalers command refuses to replace the existing file
+        let output = run_err(&config, &["scalers"]);
+        assert!(output.contains("already exists"), "{format}: {output}");
+        assert!(output.contains("--overwrite-scalers"), "{format}: {output}");
+        assert_eq!(std::fs::read(&path).unwrap(), b"stale", "{format}");
+
+        // Unless told to
+        run_ok(&config, &["scalers", "--overwrite-scalers"]);
+        assert_ne!(std::fs::read(&path).unwrap(), b"stale", "{format}");
+
+        // A full harmonization always replaces it
+        std::fs::write(&path, "stale").unwrap();
+        run_ok(&config, &[]);
+        assert_ne!(std::fs::read(&path).unwrap(), b"stale", "{format}");
+        if format != "hdf5" {
+            assert_eq!(read_parquet(&path).height(), 3, "{format}");
+        }
+    }
+}
@@ -0,0 +1,546 @@
+//! Integration tests of how the events are split into harmonic files
+mod common;
+
+use common::*;
+
+#[test]
+fn verified_files_hold_every_event() {
+    let fixture = Fixture::new("verified_files");
+    let runs = [FixtureRun::new(1, 5, 4, 8), FixtureRun::new(2, 5, 4, 8)];
+    fixture.write_runs(&runs);
+    let config = fixture.config(1, 2, "max_events_per_file: 2\nverify_output: true\n");
+    run_ok(&config, &[]);
+
+    let files = harmonic_files(&fixture.harmonic);
+    assert!(files.len() >= 5);
+    for path in files.iter().take(5) {
+        assert_eq!(n_events(path), 2);
+    }
+    assert_eq!(all_orig_events(&fixture.harmonic), run_events(&runs));
+}
+
+#[test]
+fn num_output_files_produces_about_that_many_files() {
+    let fixture = Fixture::new("num_output_files");
+    let runs: Vec<_> = (1..=3)
+        .map(|run| FixtureRun::new(run, 10, 32, 64))
+        .collect();
+    fixture.write_runs(&runs);
+    let config = fixture.config(1, 3, "num_output_files: 3\n");
+    run_ok(&config, &[]);
+
+    let non_empty = harmonic_files(&fixture.harmonic)
+        .iter()
+        .filter(|path| n_events(path) > 0)
+        .count();
+    assert!(
+        (2..=4).contains(&non_empty),
+        "{non_empty} files were written"
+    );
+    assert_eq!(all_orig_events(&fixture.harmonic), run_events(&runs));
+}
+
+#[test]
+fn core_driver_files_are_split_by_their_size_in_memory() {
+    let fixture = Fixture::new("core_driver_split");
+    let runs: Vec<_> = (1..=3)
+        .map(|run| FixtureRun::new(run, 10, 32, 64))
+        .collect();
+    fixture.write_runs(&runs);
+    let config = fixture.config(1, 3, "num_output_files: 3\nhdf5_driver: core\n");
+    run_ok(&config, &[]);
+
+    // The files are only on disk once closed, which must not keep them from splitting
+    let non_empty = harmonic_files(&fixture.harmonic)
+        .iter()
+        .filter(|path| n_events(path) > 0)
+        .count();
+    assert!(
+        (2..=4).contains(&non_empty),
+        "{non_empty} files were written"
+    );
+    assert_eq!(all_orig_events(&fixture.harmonic), run_events(&runs));
+}
+
+#[test]
+fn sequential_invocations_continue_the_run_counter() {
+    let fixture = Fixture::new("run_counter");
+    fixture.write_runs(&[FixtureRun::new(1, 5, 4, 8), FixtureRun::new(2, 4, 4, 8)]);
+    let counter = fixture.dir.path().join("counter.txt");
+    let extra = format!(
+        "run_counter_file: {}\nmax_events_per_file: 2\nextract_scalers: false\n",
+        yaml_path(&counter)
+    );
+    let first = fixture.config_named("first.yml", 1, 1, &extra);
+    run_ok(&first, &[]);
+    let first_runs = harmonic_files(&fixture.harmonic);
+    let next_run: i32 = std::fs::read_to_string(&counter)
+        .unwrap()
+        .trim()
+        .parse()
+        .unwrap();
+    assert_eq!(next_run as usize, first_runs.len());
+
+    let second = fixture.config_named("second.yml", 2, 2, &extra);
+    run_ok(&second, &[]);
+    let files = harmonic_files(&fixture.harmonic);
+    // The second invocation continues right after the runs of the first
+    let expected: Vec<_> = (0..files.len() as i32)
+        .map(|run| run_path(&fixture.harmonic, run))
+        .collect();
+    assert_eq!(files, expected);
+    assert_eq!(
+        &orig_events(&files[next_run as usize])[..],
+        &[(2, 0), (2, 1)]
+    );
+    assert_eq!(all_orig_events(&fixture.harmonic).len(), 9);
+}
+
+#[test]
+fn estimated_file_count_is_close_to_the_actual_count() {
+    for n_files in [1, 3, 5] {
+        let fixture = Fixture::new("estimate_files");
+        fixture.write_runs(
+            &(1..=4)
+                .map(|run| FixtureRun::new(run, 6, 16, 256))
+                .collect::<Vec<_>>(),
+        );
+        let config = fixture.config(1, 4, &format!("num_output_files: {n_files}\n"));
+        let output = run_ok(&config, &[]);
+
+        let estimate: i64 = output
+            .lines()
+            .find_map(|line| line.strip_prefix("Estimated number of harmonic files: "))
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        let actual = harmonic_files(&fixture.harmonic)
+            .iter()
+            .filter(|path| n_events(path) > 0)
+            .count() as i64;
+        assert!(
+            (estimate - actual).abs() <= 1,
+            "{n_files} files: estimated {estimate}, produced {actual}"
+        );
+    }
+}
+
+#[test]
+fn single_file_holds_every_event() {
+    let fixture = Fixture::new("single_file");
+    let runs = [
+        FixtureRun::new(1, 40, 64, 512),
+        FixtureRun::new(2, 30, 64, 512),
+    ];
+    fixture.write_runs(&runs);
+    run_ok(&fixture.config(1, 2, "single_file: true\n"), &[]);
+
+    let files = harmonic_files(&fixture.harmonic);
+    assert_eq!(files, vec![run_path(&fixture.harmonic, 0)]);
+    assert_eq!(all_orig_events(&fixture.harmonic), run_events(&runs));
+
+    let config = fixture.config_named(
+        "conflict.yml",
+        1,
+        2,
+        "single_file: true\nnum_output_files: 2\n",
+    );
+    let output = run_err(&config, &[]);
+    assert!(
+        output.contains("single_file cannot be combined"),
+        "{output}"
+    );
+}
+
+#[test]
+fn appending_tops_off_the_last_file() {
+    let fixture = Fixture::new("append");
+    let runs: Vec<FixtureRun> = (1..=4).map(|run| FixtureRun::new(run, 3, 4, 8)).collect();
+    fixture.write_runs(&runs);
+    let settings = "max_events_per_file: 4\nwrite_event_metadata: true\n";
+    run_ok(&fixture.config_named("first.yml", 1, 2, settings), &[]);
+    let last = run_path(&fixture.harmonic, 1);
+    assert_eq!(n_events(&last), 2);
+    let last_bytes = last.metadata().unwrap().len();
+
+    let summary = fixture.dir.path().join("summary.json");
+    let appending = format!("{settings}append: true\n");
+    run_ok(
+        &fixture.config_named("second.yml", 3, 4, &appending),
+        &["--summary-json", summary.to_str().unwrap()],
+    );
+
+    let files = harmonic_files(&fixture.harmonic);
+    assert_eq!(files.len(), 3);
+    assert_eq!(n_events(&last), 4);
+    assert_eq!(all_orig_events(&fixture.harmonic), run_events(&runs));
+    // Only what the second harmonization added is counted
+    let summary = read_summary(&summary);
+    assert_eq!(summary["events_written"], 6);
+    assert_eq!(summary["files_produced"], 1);
+    let added_bytes =
+        last.metadata().unwrap().len() - last_bytes + files[2].metadata().unwrap().len();
+    assert_eq!(summary["bytes_written"], added_bytes);
+
+    // The scalers, event table, and info file cover both harmonizations
+    let scalers = read_parquet(&fixture.harmonic.join("scalers.parquet"));
+    assert_eq!(scalers.height(), 12);
+    let table = read_parquet(&fixture.harmonic.join("events.parquet"));
+    assert_eq!(table.height(), 12);
+    let info = std::fs::read_to_string(fixture.harmonic.join("HARMONIZATION_INFO.txt")).unwrap();
+    assert!(
+        info.contains("Merger run range: 1 to 2 (inclusive)"),
+        "{info}"
+    );
+    assert!(info.contains("Appended (unix seconds)"), "{info}");
+    assert!(
+        info.contains("Merger run range: 3 to 4 (inclusive)"),
+        "{info}"
+    );
+}
+
+#[test]
+fn appending_to_a_full_file_starts_the_next_one() {
+    let fixture = Fixture::new("append_full");
+    let runs: Vec<FixtureRun> = (1..=3).map(|run| FixtureRun::new(run, 3, 4, 8)).collect();
+    fixture.write_runs(&runs);
+    run_ok(
+        &fixture.config_named("first.yml", 1, 2, "max_events_per_file: 3\n"),
+        &[],
+    );
+    let last = run_path(&fixture.harmonic, 1);
+    let last_bytes = last.metadata().unwrap().len();
+
+    let summary = fixture.dir.path().join("summary.json");
+    run_ok(
+        &fixture.config_named("second.yml", 3, 3, "max_events_per_file: 3\nappend: true\n"),
+        &["--summary-json", summary.to_str().unwrap()],
+    );
+
+    let files = harmonic_files(&fixture.harmonic);
+    assert_eq!(files.len(), 3);
+    assert_eq!(n_events(&last), 3);
+    assert_eq!(orig_events(&files[2]), run_events(&runs[2..]));
+    let summary = read_summary(&summary);
+    assert_eq!(summary["files_produced"], 1);
+    assert_eq!(
+        summary["bytes_written"],
+        files[2].metadata().unwrap().len() + last.metadata().unwrap().len() - last_bytes
+    );
+}
+
+#[test]
+fn existing_output_follows_the_overwrite_policy() {
+    let fixture = Fixture::new("overwrite_policy");
+    let runs = [FixtureRun::new(1, 2, 4, 8), FixtureRun::new(2, 2, 4, 8)];
+    fixture.write_runs(&runs);
+    let sentinel = b"not a harmonic run";
+
+    // error: a later run is found before anything is written
+    std::fs::write(run_path(&fixture.harmonic, 3), sentinel).unwrap();
+    let output = run_err(&fixture.config_named("error.yml", 1, 2, ""), &[]);
+    assert!(
+        output.contains("Harmonic runs [3] already exist"),
+        "{output}"
+    );
+    assert!(!run_path(&fixture.harmonic, 0).exists());
+    std::fs::remove_file(run_path(&fixture.harmonic, 3)).unwrap();
+
+    // overwrite: the runs are replaced, and left over later runs are reported
+    let config = fixture.config_named("many.yml", 1, 2, "max_events_per_file: 1\n");
+    run_ok(&config, &[]);
+    assert_eq!(harmonic_files(&fixture.harmonic).len(), 4);
+    let config = fixture.config_named(
+        "overwrite.yml",
+        1,
+        2,
+        "overwrite: overwrite\nmax_events_per_file: 2\n",
+    );
+    let output = run_ok(&config, &[]);
+    assert!(output.contains("harmonic runs [2, 3]"), "{output}");
+    assert!(output.contains("left over from earlier output"), "{output}");
+    assert_eq!(n_events(&run_path(&fixture.harmonic, 0)), 2);
+    assert_eq!(n_events(&run_path(&fixture.harmonic, 1)), 2);
+    assert_eq!(n_events(&run_path(&fixture.harmonic, 2)), 1);
+
+    // skip: existing runs are left alone and the next free ones are used
+    std::fs::remove_dir_all(&fixture.harmonic).unwrap();
+    std::fs::create_dir(&fixture.harmonic).unwrap();
+    std::fs::write(run_path(&fixture.harmonic, 0), sentinel).unwrap();
+    let output = run_ok(
+        &fixture.config_named("skip.yml", 1, 2, "overwrite: skip\n"),
+        &[],
+    );
+    assert!(
+        output.contains("Skipping existing harmonic run"),
+        "{output}"
+    );
+    assert_eq!(
+        std::fs::read(run_path(&fixture.harmonic, 0)).unwrap(),
+        sentinel
+    );
+    assert_eq!(
+        orig_events(&run_path(&fixture.harmonic, 1)),
+        run_events(&runs)
+    );
+}
+
+#[test]
+fn files_split_by_pad_count_have_equal_pads() {
+    let fixture = Fixture::new("pads_per_file");
+    // Sparse and dense events, each run holding 12 pads, and 3 pads left over
+    let runs = [
+        FixtureRun::new(1, 6, 2, 8),
+        FixtureRun::new(2, 3, 4, 8),
+        FixtureRun::new(3, 2, 6, 8),
+        FixtureRun::new(4, 1, 3, 8),
+    ];
+    fixture.write_runs(&runs);
+    run_ok(&fixture.config(1, 4, "pads_per_file: 12\n"), &[]);
+
+    let files = harmonic_files(&fixture.harmonic);
+    let pads: Vec<usize> = files
+        .iter()
+        .map(|path| {
+            (0..n_events(path))
+                .map(|index| get_traces(path, index).nrows())
+                .sum()
+        })
+        .collect();
+    assert_eq!(pads, [12, 12, 12, 3]);
+    assert_eq!(all_orig_events(&fixture.harmonic), run_events(&runs));
+}
+
+#[test]
+fn by_source_run_layout_is_listed_in_the_manifest() {
+    let fixture = Fixture::new("by_source_run");
+    let runs = [FixtureRun::new(55, 3, 4, 8), FixtureRun::new(56, 3, 4, 8)];
+    fixture.write_runs(&runs);
+    let settings = "output_layout: by_source_run\nmax_events_per_file: 2\n";
+    run_ok(&fixture.config_named("first.yml", 55, 56, settings), &[]);
+
+    let expected = [
+        "src_055/run_0000.h5",
+        "src_055/run_0001.h5",
+        "src_056/run_0002.h5",
+    ];
+    for path in expected {
+        assert!(fixture.harmonic.join(path).exists(), "{path}");
+    }
+    assert!(!run_path(&fixture.harmonic, 0).exists());
+    assert_eq!(all_orig_events(&fixture.harmonic), run_events(&runs));
+    let manifest =
+        std::fs::read_to_string(fixture.harmonic.join("harmonic_run_paths.csv")).unwrap();
+    let rows: Vec<String> = expected
+        .iter()
+        .enumerate()
+        .map(|(run, path)| format!("{run},{path}"))
+        .collect();
+    assert_eq!(manifest, format!("run,path\n{}\n", rows.join("\n")));
+
+    // The runs in the subdirectories count as existing output
+    let output = run_err(&fixture.config_named("again.yml", 55, 56, settings), &[]);
+    assert!(output.contains("already exist"), "{output}");
+    let skipping = format!("{settings}overwrite: skip\n");
+    run_ok(&fixture.config_named("skip.yml", 56, 56, &skipping), &[]);
+    assert!(fixture.harmonic.join("src_056/run_0003.h5").exists());
+    assert_eq!(
+        orig_events(&fixture.harmonic.join("src_056/run_0003.h5")),
+        run_events(&runs[1..])[..2]
+    );
+}
+
+#[test]
+fn validation_after_a_budget_stop_covers_the_runs_read() {
+    let fixture = Fixture::new("budget_validation");
+    // Events of 64 kB of GET traces, so that the budget runs out in run 2
+    let runs: Vec<FixtureRun> = (1..=3)
+        .map(|run| FixtureRun::new(run, 4, 64, 500))
+        .collect();
+    fixture.write_runs(&runs);
+    let summary = fixture.dir.path().join("summary.json");
+    let config = fixture.config(1, 3, "max_output_bytes: 400000\n");
+    run_ok(&config, &["--summary-json", summary.to_str().unwrap()]);
+    assert_eq!(read_summary(&summary)["output_budget_reached"], true);
+    let written = all_orig_events(&fixture.harmonic);
+    let from_run_2 = written.iter().filter(|(run, _)| *run == 2).count();
+    assert!((1..4).contains(&from_run_2), "{written:?}");
+    assert!(written.iter().all(|(run, _)| *run < 3), "{written:?}");
+
+    let record = std::fs::read_to_string(fixture.harmonic.join("stopped_early.yml")).unwrap();
+    assert_eq!(record.trim(), "last_run: 2");
+    let output = run_ok(&config, &["validate"]);
+    assert!(
+        output.contains("validating the runs up to run 2"),
+        "{output}"
+    );
+    assert!(output.contains("Validation passed"), "{output}");
+
+    // Without the record the missing events are reported
+    std::fs::rename(
+        fixture.harmonic.join("stopped_early.yml"),
+        fixture.dir.path().join("stopped_early.yml"),
+    )
+    .unwrap();
+    let output = run_err(&config, &["validate"]);
+    assert!(
+        output.contains("Run 3: expected 4 events, found 0"),
+        "{output}"
+    );
+    std::fs::rename(
+        fixture.dir.path().join("stopped_early.yml"),
+        fixture.harmonic.join("stopped_early.yml"),
+    )
+    .unwrap();
+
+    // A complete harmonization removes the record
+    let complete = fixture.config_named("complete.yml", 1, 3, "overwrite: overwrite\n");
+    run_ok(&complete, &[]);
+    assert!(!fixture.harmonic.join("stopped_early.yml").exists());
+    let output = run_ok(&complete, &["validate"]);
+    assert!(!output.contains("stopped early"), "{output}");
+}
+
+#[test]
+fn the_budget_counts_the_padded_traces() {
+    let fixture = Fixture::new("budget_padding");
+    // Events of 6.4 kB of GET traces, padded to 64 kB, so that the budget
+    // only has room for one of them once padded
+    let runs = [FixtureRun::new(1, 3, 64, 50)];
+    fixture.write_runs(&runs);
+    let summary = fixture.dir.path().join("summary.json");
+    let config = fixture.config(1, 1, "max_output_bytes: 100000\npad_traces_to: 500\n");
+    run_ok(&config, &["--summary-json", summary.to_str().unwrap()]);
+
+    assert_eq!(read_summary(&summary)["output_budget_reached"], true);
+    assert_eq!(all_orig_events(&fixture.harmonic), [(1, 0)]);
+    let bytes: u64 = harmonic_files(&fixture.harmonic)
+        .iter()
+        .map(|path| path.metadata().unwrap().len())
+        .sum();
+    assert!(bytes <= 100000, "{bytes} bytes were written");
+}
+
+#[test]
+fn run_boundaries_split_the_files() {
+    let fixture = Fixture::new("run_boundary");
+    let runs = [
+        FixtureRun::new(1, 3, 4, 8),
+        FixtureRun::new(2, 1, 4, 8),
+        FixtureRun::new(3, 5, 4, 8),
+    ];
+    fixture.write_runs(&runs);
+    run_ok(
+        &fixture.config(
+            1,
+            3,
+            "split_on_run_boundary: true\nmax_events_per_file: 4\n",
+        ),
+        &[],
+    );
+
+    let files = harmonic_files(&fixture.harmonic);
+    let file_runs: Vec<(Vec<i32>, u64)> = files
+        .iter()
+        .map(|path| {
+            let mut runs: Vec<i32> = orig_events(path).into_iter().map(|(run, _)| run).collect();
+            runs.dedup();
+            (runs, n_events(path))
+        })
+        .collect();
+    // Run 3 is still split by the event cap
+    assert_eq!(
+        file_runs,
+        [(vec![1], 3), (vec![2], 1), (vec![3], 4), (vec![3], 1)]
+    );
+    assert_eq!(all_orig_events(&fixture.harmonic), run_events(&runs));
+}
+
+#[test]
+fn harmonic_runs_are_spread_round_robin() {
+    let fixture = Fixture::new("round_robin");
+    let runs = [FixtureRun::new(1, 5, 4, 8), FixtureRun::new(2, 4, 4, 8)];
+    fixture.write_runs(&runs);
+    let disks = [fixture.dir.subdir("disk_b"), fixture.dir.subdir("disk_c")];
+    let settings = format!(
+        "max_events_per_file: 2\nharmonic_paths: [{}, {}]\n",
+        yaml_path(&disks[0]),
+        yaml_path(&disks[1])
+    );
+    run_ok(&fixture.config(1, 2, &settings), &[]);
+
+    // Runs 0 and 3 in the harmonic path, 1 and 4 in disk_b, 2 in disk_c
+    let dirs = [&fixture.harmonic, &disks[0], &disks[1]];
+    let mut events = Vec::new();
+    let mut rows = Vec::new();
+    for run in 0..5 {
+        let index = run as usize % dirs.len();
+        for (other, dir) in dirs.iter().enumerate() {
+            assert_eq!(run_path(dir, run).exists(), other == index, "run {run}");
+        }
+        let path = run_path(dirs[index], run);
+        events.extend(orig_events(&path));
+        rows.push(match index {
+            0 => format!("{run},{}", path.file_name().unwrap().to_str().unwrap()),
+            _ => format!("{run},{}", path.display()),
+        });
+    }
+    assert!(!run_path(&fixture.harmonic, 5).exists());
+    assert_eq!(events, run_events(&runs));
+
+    let manifest =
+        std::fs::read_to_string(fixture.harmonic.join("harmonic_run_paths.csv")).unwrap();
+    assert_eq!(manifest, format!("run,path\n{}\n", rows.join("\n")));
+    // Everything else stays in the harmonic path
+    assert!(fixture.harmonic.join("scalers.parquet").exists());
+    for disk in disks.iter() {
+        assert!(!disk.join("scalers.parquet").exists());
+    }
+}
+
+#[test]
+fn existing_runs_in_any_harmonic_path_follow_the_overwrite_policy() {
+    for policy in ["skip", "overwrite"] {
+        let fixture = Fixture::new(&format!("round_robin_{policy}"));
+        let runs = [FixtureRun::new(1, 5, 4, 8), FixtureRun::new(2, 4, 4, 8)];
+        fixture.write_runs(&runs);
+        let disks = [fixture.dir.subdir("disk_b"), fixture.dir.subdir("disk_c")];
+        // Left in disk_b by an earlier round-robin, while this one puts run 3
+        // in the harmonic path
+        let stale = run_path(&disks[0], 3);
+        std::fs::write(&stale, "stale").unwrap();
+        let settings = format!(
+            "max_events_per_file: 2\nharmonic_paths: [{}, {}]\noverwrite: {policy}\n",
+            yaml_path(&disks[0]),
+            yaml_path(&disks[1])
+        );
+        run_ok(&fixture.config(1, 2, &settings), &[]);
+
+        let dirs = [&fixture.harmonic, &disks[0], &disks[1]];
+        let mut events = Vec::new();
+        for dir in dirs {
+            for path in harmonic_files(dir) {
+                if path != stale {
+                    events.extend(orig_events(&path));
+                }
+            }
+        }
+        events.sort();
+        let mut expected = run_events(&runs);
+        expected.sort();
+        assert_eq!(events, expected, "{policy}");
+        match policy {
+            // Run 3 is taken, so the harmonic path gets run 4 instead
+            "skip" => {
+                assert_eq!(std::fs::read(&stale).unwrap(), b"stale");
+                assert!(!run_path(&fixture.harmonic, 3).exists());
+                assert!(run_path(&fixture.harmonic, 4).exists());
+            }
+            _ => {
+                assert!(!stale.exists());
+                assert!(run_path(&fixture.harmonic, 3).exists());
+            }
+        }
+    }
+}
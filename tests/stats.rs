@@ -0,0 +1,128 @@
+//! Integration tests of the harmonization summary
+mod common;
+
+use common::*;
+
+#[test]
+fn skipped_and_capped_runs_are_counted() {
+    let fixture = Fixture::new("skipped_runs");
+    // Runs 0 and 2 are missing, run 3 is entirely blacklisted and run 4 is
+    // cut short by the per run cap
+    let runs = [
+        FixtureRun::new(1, 2, 4, 8),
+        FixtureRun::new(3, 2, 4, 8),
+        FixtureRun::new(4, 5, 4, 8),
+        FixtureRun::new(5, 1, 4, 8),
+    ];
+    fixture.write_runs(&runs);
+    let blacklist = fixture.dir.path().join("blacklist.csv");
+    std::fs::write(&blacklist, "3,0\n3,1\n").unwrap();
+    let config = fixture.config(
+        0,
+        5,
+        &format!(
+            "blacklist_path: {}\nmax_events_per_source_run: 3\n",
+            yaml_path(&blacklist)
+        ),
+    );
+    let summary = fixture.dir.path().join("summary.json");
+    run_ok(&config, &["--summary-json", summary.to_str().unwrap()]);
+
+    let summary = read_summary(&summary);
+    assert_eq!(summary["runs_skipped"], 3);
+    assert_eq!(summary["runs_capped"], 1);
+    assert_eq!(summary["events_written"], 6);
+    assert_eq!(summary["reconciliation"]["events_blacklisted"], 2);
+    assert_eq!(summary["reconciliation"]["events_capped"], 2);
+}
+
+#[test]
+fn summary_json_has_the_schema_and_values() {
+    let fixture = Fixture::new("summary_schema");
+    let mut runs = [FixtureRun::new(1, 4, 4, 8), FixtureRun::new(2, 3, 4, 8)];
+    runs[1].events[2].ok = Some(0);
+    fixture.write_runs(&runs);
+    let blacklist = fixture.dir.path().join("blacklist.csv");
+    std::fs::write(&blacklist, "1,1\n").unwrap();
+    let config = fixture.config(
+        1,
+        2,
+        &format!(
+            "blacklist_path: {}\ndrop_bad_events: true\nmax_events_per_file: 2\n",
+            yaml_path(&blacklist)
+        ),
+    );
+    let summary = fixture.dir.path().join("summary.json");
+    run_ok(&config, &["--summary-json", summary.to_str().unwrap()]);
+
+    let summary = read_summary(&summary);
+    let keys = |value: &serde_json::Value| -> Vec<String> {
+        let mut keys: Vec<String> = value.as_object().unwrap().keys().cloned().collect();
+        keys.sort();
+        keys
+    };
+    assert_eq!(
+        keys(&summary),
+        [
+            "bytes_written",
+            "duration_seconds",
+            "events_written",
+            "files_produced",
+            "frib_events_unpaired",
+            "free_space_exhausted",
+            "output_budget_reached",
+            "reconciliation",
+            "runs_capped",
+            "runs_skipped",
+            "timings_seconds",
+        ]
+    );
+    assert_eq!(
+        keys(&summary["reconciliation"]),
+        [
+            "events_blacklisted",
+            "events_capped",
+            "events_counted",
+            "events_dropped_bad",
+            "events_unaccounted",
+            "events_written",
+        ]
+    );
+    assert_eq!(
+        keys(&summary["timings_seconds"]),
+        ["counting", "reading", "scalers", "writing"]
+    );
+
+    // 7 events, of which one is blacklisted and one is bad, in 3 files
+    let files = harmonic_files(&fixture.harmonic);
+    assert_eq!(files.len(), 3);
+    let bytes: u64 = files
+        .iter()
+        .map(|path| path.metadata().unwrap().len())
+        .sum();
+    assert_eq!(summary["events_written"], 5);
+    assert_eq!(summary["files_produced"], 3);
+    assert_eq!(summary["bytes_written"], bytes);
+    assert_eq!(summary["runs_skipped"], 0);
+    assert_eq!(summary["runs_capped"], 0);
+    assert_eq!(summary["frib_events_unpaired"], 0);
+    assert_eq!(summary["output_budget_reached"], false);
+    assert_eq!(summary["free_space_exhausted"], false);
+    assert_eq!(
+        summary["reconciliation"],
+        serde_json::json!({
+            "events_counted": 7,
+            "events_written": 5,
+            "events_blacklisted": 1,
+            "events_dropped_bad": 1,
+            "events_capped": 0,
+            "events_unaccounted": 0,
+        })
+    );
+    let duration = summary["duration_seconds"].as_f64().unwrap();
+    assert!(duration > 0.0);
+    for phase in ["counting", "reading", "scalers", "writing"] {
+        let seconds = summary["timings_seconds"][phase].as_f64().unwrap();
+        assert!((0.0..=duration).contains(&seconds), "{phase}: {seconds}");
+    }
+}